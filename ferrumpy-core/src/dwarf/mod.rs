@@ -49,6 +49,66 @@ pub fn dwarf_type_to_rust(dwarf_name: &str) -> Result<String, DwarfError> {
     Ok(result)
 }
 
+/// Split a generic type into its base path and top-level type arguments
+///
+/// Arguments are split at commas outside nested generics, tuples and
+/// arrays, and trimmed. `None` if `name` isn't generic, or its brackets
+/// don't balance.
+///
+/// Examples:
+/// - `Vec<Vec<i32>>` -> (`Vec`, [`Vec<i32>`])
+/// - `std::collections::HashMap<String, Vec<i32>>` ->
+///   (`std::collections::HashMap`, [`String`, `Vec<i32>`])
+pub fn split_generic(name: &str) -> Option<(&str, Vec<&str>)> {
+    let name = name.trim();
+    let open = name.find('<')?;
+    let inner = name[open + 1..].strip_suffix('>')?;
+    Some((name[..open].trim(), split_top_level_commas(inner)?))
+}
+
+/// Split a comma-separated list of types, e.g. generic arguments or tuple
+/// elements, at commas outside nested generics, tuples and arrays
+///
+/// Types are trimmed, and a trailing comma ends the list (`T,` -> [`T`]).
+/// `None` if the brackets don't balance.
+pub fn split_top_level_commas(list: &str) -> Option<Vec<&str>> {
+    let mut types = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    let mut prev = '\0';
+    for (i, c) in list.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            // The `>` of a `->` in a fn type closes nothing
+            '>' if prev == '-' => {}
+            '>' | ')' | ']' => {
+                depth -= 1;
+                // Closes a bracket opened before the list: `A<B>::C<D>`
+                if depth < 0 {
+                    return None;
+                }
+            }
+            ',' if depth == 0 => {
+                types.push(list[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+    if depth != 0 {
+        return None;
+    }
+    let last = list[start..].trim();
+    if !last.is_empty() {
+        types.push(last);
+    }
+    Some(types)
+}
+
+/// Longest value preview returned by `VariableInfo::pretty_value`, in chars
+pub const VALUE_PREVIEW_MAX_CHARS: usize = 80;
+
 /// Information about a local variable extracted from debug info
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct VariableInfo {
@@ -58,6 +118,10 @@ pub struct VariableInfo {
     /// String representation of the value (for primitive types)
     #[serde(default)]
     pub value: String,
+    /// The value in snapshot JSON form, for composite types whose fields
+    /// expressions can access
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub structured_value: Option<serde_json::Value>,
 }
 
 impl VariableInfo {
@@ -68,6 +132,7 @@ impl VariableInfo {
             type_name,
             rust_type,
             value: String::new(),
+            structured_value: None,
         })
     }
 
@@ -78,8 +143,30 @@ impl VariableInfo {
             type_name,
             rust_type,
             value,
+            structured_value: None,
         })
     }
+
+    /// Attach the value in snapshot JSON form
+    pub fn with_structured_value(mut self, value: serde_json::Value) -> Self {
+        self.structured_value = Some(value);
+        self
+    }
+
+    /// One-line preview of the value: whitespace runs collapsed, and cut to
+    /// `VALUE_PREVIEW_MAX_CHARS` with a trailing `...`
+    pub fn pretty_value(&self) -> String {
+        let collapsed = self.value.split_whitespace().collect::<Vec<_>>().join(" ");
+        if collapsed.chars().count() <= VALUE_PREVIEW_MAX_CHARS {
+            return collapsed;
+        }
+        let mut preview: String = collapsed
+            .chars()
+            .take(VALUE_PREVIEW_MAX_CHARS - 3)
+            .collect();
+        preview.push_str("...");
+        preview
+    }
 }
 
 #[cfg(test)]
@@ -105,4 +192,57 @@ mod tests {
             "Result<i32, String>"
         );
     }
+
+    #[test]
+    fn test_split_generic() {
+        let split = |name: &str| {
+            split_generic(name).map(|(base, args)| format!("{} | {}", base, args.join(" | ")))
+        };
+        assert_eq!(split("Vec<Vec<i32>>").unwrap(), "Vec | Vec<i32>");
+        assert_eq!(
+            split("std::collections::HashMap<String, Vec<i32>>").unwrap(),
+            "std::collections::HashMap | String | Vec<i32>"
+        );
+        assert_eq!(
+            split("Result<HashMap<String, (u8, u8)>, Box<dyn Error>>").unwrap(),
+            "Result | HashMap<String, (u8, u8)> | Box<dyn Error>"
+        );
+        assert_eq!(
+            split("HashMap<[u8; 4], fn(i32, i32) -> Vec<u8>, S>").unwrap(),
+            "HashMap | [u8; 4] | fn(i32, i32) -> Vec<u8> | S"
+        );
+        assert_eq!(split(" Option<Foo<'a>> ").unwrap(), "Option | Foo<'a>");
+
+        assert_eq!(split("String"), None);
+        assert_eq!(split("Vec<i32"), None);
+        assert_eq!(split("Vec<Vec<i32>"), None);
+        assert_eq!(split("Foo<A>::Bar<B>"), None);
+    }
+
+    #[test]
+    fn test_split_top_level_commas() {
+        assert_eq!(
+            split_top_level_commas("HashMap<String, i32>, (u8, u8), [f64; 2]"),
+            Some(vec!["HashMap<String, i32>", "(u8, u8)", "[f64; 2]"])
+        );
+        assert_eq!(split_top_level_commas("i32,"), Some(vec!["i32"]));
+        assert_eq!(split_top_level_commas(""), Some(vec![]));
+        assert_eq!(split_top_level_commas("Vec<i32, u8"), None);
+    }
+
+    #[test]
+    fn test_pretty_value() {
+        let var = |value: &str| {
+            VariableInfo::with_value("v".to_string(), "i32".to_string(), value.to_string()).unwrap()
+        };
+        assert_eq!(var("42").pretty_value(), "42");
+        assert_eq!(
+            var("Point {\n  x: 1,\n  y: 2\n}").pretty_value(),
+            "Point { x: 1, y: 2 }"
+        );
+
+        let long = var(&"é".repeat(200)).pretty_value();
+        assert_eq!(long.chars().count(), VALUE_PREVIEW_MAX_CHARS);
+        assert!(long.ends_with("..."));
+    }
 }
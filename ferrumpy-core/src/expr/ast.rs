@@ -26,6 +26,23 @@ pub enum Expr {
 
     /// Type cast: a as i64
     Cast { expr: Box<Expr>, ty: String },
+
+    /// Method call: users.len()
+    MethodCall {
+        receiver: Box<Expr>,
+        method: String,
+        args: Vec<Expr>,
+    },
+
+    /// Try operator: result?
+    Try(Box<Expr>),
+
+    /// Range: 'a'..='z'
+    Range {
+        start: Option<Box<Expr>>,
+        end: Option<Box<Expr>>,
+        inclusive: bool,
+    },
 }
 
 /// Path segment
@@ -35,6 +52,12 @@ pub enum PathSegment {
     Ident(String),
     /// Index: [0]
     Index(usize),
+    /// Range index: [1..3], [..=2], [2..] (byte offsets for strings)
+    Range {
+        start: Option<usize>,
+        end: Option<usize>,
+        inclusive: bool,
+    },
     /// Tuple index: .0
     TupleIndex(usize),
     /// Dereference: *
@@ -6,52 +6,202 @@ use std::collections::HashMap;
 
 use super::ast::{BinOp, Expr, Literal, PathSegment, UnaryOp};
 use super::error::EvalError;
+use super::format::format_expr;
 use super::value::Value;
 
 /// Variable context for evaluation
 pub type VarContext = HashMap<String, Value>;
 
+/// Most elements a range expression may evaluate to
+const MAX_RANGE_LEN: usize = 1 << 16;
+
 /// Expression evaluator
 pub struct Evaluator {
     /// Variables available in scope
     variables: VarContext,
+    /// Enum discriminants and associated constants, keyed by path
+    /// (`Priority::High`)
+    constants: HashMap<String, Value>,
 }
 
 impl Evaluator {
     pub fn new() -> Self {
         Self {
             variables: HashMap::new(),
+            constants: HashMap::new(),
         }
     }
 
     pub fn with_variables(variables: VarContext) -> Self {
-        Self { variables }
+        let mut evaluator = Self {
+            variables,
+            constants: HashMap::new(),
+        };
+        let values: Vec<_> = evaluator.variables.values().cloned().collect();
+        for value in &values {
+            evaluator.register_unit_variants(value);
+        }
+        evaluator
     }
 
     /// Add or update a variable
     pub fn set_variable(&mut self, name: impl Into<String>, value: Value) {
+        self.register_unit_variants(&value);
         self.variables.insert(name.into(), value);
     }
 
+    /// Add or update a constant, e.g. an enum discriminant `Priority::High`
+    ///
+    /// Variables shadow constants of the same name.
+    pub fn set_constant(&mut self, path: impl Into<String>, value: Value) {
+        self.constants.insert(path.into(), value);
+    }
+
+    /// Register the unit enum variants found in `value` as constants, so
+    /// `status == Status::Active` resolves the right-hand side
+    ///
+    /// Only variants seen in a value are known. Constants set explicitly
+    /// are kept.
+    fn register_unit_variants(&mut self, value: &Value) {
+        match value {
+            Value::Enum {
+                enum_name,
+                variant,
+                payload: None,
+            } => {
+                let short_name = enum_name.rsplit("::").next().unwrap_or(enum_name);
+                for name in [enum_name.as_str(), short_name] {
+                    self.constants
+                        .entry(format!("{}::{}", name, variant))
+                        .or_insert_with(|| value.clone());
+                }
+            }
+            Value::Enum {
+                payload: Some(payload),
+                ..
+            } => self.register_unit_variants(payload),
+            Value::Array(elements) => {
+                for element in elements {
+                    self.register_unit_variants(element);
+                }
+            }
+            Value::Map(entries) => {
+                for (key, value) in entries {
+                    self.register_unit_variants(key);
+                    self.register_unit_variants(value);
+                }
+            }
+            Value::Struct { fields, .. } => {
+                for (_, value) in fields {
+                    self.register_unit_variants(value);
+                }
+            }
+            _ => {}
+        }
+    }
+
     /// Evaluate an expression
     pub fn eval(&self, expr: &Expr) -> Result<Value, EvalError> {
-        match expr {
-            Expr::Path(segments) => self.eval_path(segments),
+        self.eval_node(expr, None)
+    }
+
+    /// Evaluate an expression, also returning the value of each operation in
+    /// it, innermost first, as `(format_expr(subexpression), value)`
+    ///
+    /// Variables and literals are not listed; for `a + b * 2` the trace is
+    /// `b * 2` then `a + b * 2`.
+    pub fn eval_trace(&self, expr: &Expr) -> Result<(Value, Vec<(String, Value)>), EvalError> {
+        let mut trace = Vec::new();
+        let value = self.eval_node(expr, Some(&mut trace))?;
+        Ok((value, trace))
+    }
+
+    fn eval_node(
+        &self,
+        expr: &Expr,
+        mut trace: Option<&mut Vec<(String, Value)>>,
+    ) -> Result<Value, EvalError> {
+        let value = match expr {
+            Expr::Path(segments) => return self.eval_path(segments),
+            Expr::Literal(lit) => return Ok(self.literal_to_value(lit)),
+            Expr::Paren(inner) => return self.eval_node(inner, trace),
             Expr::Binary { left, op, right } => {
-                let l = self.eval(left)?;
-                let r = self.eval(right)?;
-                self.apply_binop(&l, *op, &r)
+                let l = self.eval_node(left, trace.as_deref_mut())?;
+                let r = self.eval_node(right, trace.as_deref_mut())?;
+                self.apply_binop(&l, *op, &r)?
             }
             Expr::Unary { op, expr } => {
-                let v = self.eval(expr)?;
-                self.apply_unary(*op, &v)
+                let v = self.eval_node(expr, trace.as_deref_mut())?;
+                self.apply_unary(*op, &v)?
             }
-            Expr::Literal(lit) => Ok(self.literal_to_value(lit)),
-            Expr::Paren(inner) => self.eval(inner),
             Expr::Cast { expr, ty } => {
-                let v = self.eval(expr)?;
-                self.cast_value(&v, ty)
+                let v = self.eval_node(expr, trace.as_deref_mut())?;
+                self.cast_value(&v, ty)?
             }
+            Expr::MethodCall {
+                receiver,
+                method,
+                args,
+            } => {
+                let v = self.eval_node(receiver, trace.as_deref_mut())?;
+                self.call_method(&v, method, args)?
+            }
+            Expr::Try(inner) => {
+                // No value is a Result or Option yet, and there is no
+                // enclosing function to return early from
+                self.eval_node(inner, trace)?;
+                return Err(EvalError::unsupported("? operator outside a function"));
+            }
+            Expr::Range {
+                start,
+                end,
+                inclusive,
+            } => {
+                let mut bound = |expr: &Option<Box<Expr>>| {
+                    expr.as_deref()
+                        .map(|expr| self.eval_node(expr, trace.as_deref_mut()))
+                        .transpose()
+                };
+                let start = bound(start)?;
+                let end = bound(end)?;
+                range_value(start, end, *inclusive)?
+            }
+        };
+        if let Some(trace) = trace {
+            trace.push((format_expr(expr), value.clone()));
+        }
+        Ok(value)
+    }
+
+    /// Call a supported method on a value
+    fn call_method(&self, value: &Value, method: &str, args: &[Expr]) -> Result<Value, EvalError> {
+        match (method, args) {
+            ("len", []) => value
+                .len()
+                .map(Value::Usize)
+                .ok_or_else(|| EvalError::type_mismatch("array, map or String", value.type_name())),
+            ("len", _) => Err(EvalError::Internal(format!(
+                "len() takes no arguments, {} given",
+                args.len()
+            ))),
+            (
+                "is_alphabetic" | "is_numeric" | "is_alphanumeric" | "is_whitespace"
+                | "to_ascii_uppercase" | "to_ascii_lowercase",
+                _,
+            ) => {
+                let Value::Char(c) = value else {
+                    return Err(EvalError::type_mismatch("char", value.type_name()));
+                };
+                if !args.is_empty() {
+                    return Err(EvalError::Internal(format!(
+                        "{}() takes no arguments, {} given",
+                        method,
+                        args.len()
+                    )));
+                }
+                Ok(char_method(*c, method))
+            }
+            _ => Err(EvalError::unsupported(format!("method '{}'", method))),
         }
     }
 
@@ -69,23 +219,93 @@ impl Evaluator {
             ));
         };
 
-        let value = self
+        let mut value = self
             .variables
             .get(name)
+            .or_else(|| self.constants.get(name))
             .ok_or_else(|| EvalError::unknown_var(name))?
             .clone();
 
-        // For now, we only support simple variable lookups
-        // Field access requires SBValue integration
-        if segments.len() > 1 {
-            return Err(EvalError::unsupported(
-                "field access (requires runtime integration)",
-            ));
+        for segment in &segments[1..] {
+            value = match segment {
+                PathSegment::Range {
+                    start,
+                    end,
+                    inclusive,
+                } => self.slice_value(&value, *start, *end, *inclusive)?,
+                PathSegment::Ident(field) => field_value(value, field)?,
+                // Tuples are held as arrays, so `.0` indexes like `[0]`
+                PathSegment::Index(index) | PathSegment::TupleIndex(index) => {
+                    element_value(value, *index)?
+                }
+                // Dereferencing requires SBValue integration
+                _ => {
+                    return Err(EvalError::unsupported(
+                        "dereference/reference operators (requires runtime integration)",
+                    ))
+                }
+            };
         }
 
         Ok(value)
     }
 
+    /// Slice a string (by byte offsets) or an array, as `value[start..end]`
+    fn slice_value(
+        &self,
+        value: &Value,
+        start: Option<usize>,
+        end: Option<usize>,
+        inclusive: bool,
+    ) -> Result<Value, EvalError> {
+        let length = match value {
+            Value::String(s) => s.len(),
+            Value::Array(elems) => elems.len(),
+            other => {
+                return Err(EvalError::type_mismatch(
+                    "String or array",
+                    other.type_name(),
+                ))
+            }
+        };
+        let start = start.unwrap_or(0);
+        let end = match end {
+            Some(end) if inclusive => end
+                .checked_add(1)
+                .ok_or_else(|| EvalError::Internal("range end overflows usize".to_string()))?,
+            Some(end) => end,
+            None => length,
+        };
+        if end > length {
+            return Err(EvalError::IndexOutOfBounds { index: end, length });
+        }
+        if start > end {
+            return Err(EvalError::Internal(format!(
+                "slice index starts at {} but ends at {}",
+                start, end
+            )));
+        }
+
+        match value {
+            Value::String(s) => s
+                .get(start..end)
+                .map(|sub| Value::String(sub.to_string()))
+                .ok_or_else(|| {
+                    let index = if s.is_char_boundary(start) {
+                        end
+                    } else {
+                        start
+                    };
+                    EvalError::Internal(format!(
+                        "byte index {} is not a char boundary of {:?}",
+                        index, s
+                    ))
+                }),
+            Value::Array(elems) => Ok(Value::Array(elems[start..end].to_vec())),
+            _ => unreachable!(),
+        }
+    }
+
     /// Convert literal to Value
     fn literal_to_value(&self, lit: &Literal) -> Value {
         match lit {
@@ -109,8 +329,10 @@ impl Evaluator {
 
     /// Apply binary operator
     fn apply_binop(&self, left: &Value, op: BinOp, right: &Value) -> Result<Value, EvalError> {
-        // Type checking: operands must be same type (strict Rust semantics)
-        if left.type_name() != right.type_name() {
+        // Type checking: operands must be same type (strict Rust semantics).
+        // Shifts are the exception: the shift amount may be any integer type.
+        let is_shift = matches!(op, BinOp::Shl | BinOp::Shr);
+        if !is_shift && left.type_name() != right.type_name() {
             return Err(EvalError::InvalidOperation {
                 op: op.as_str().to_string(),
                 left: left.type_name().to_string(),
@@ -137,6 +359,11 @@ impl Evaluator {
     }
 
     fn apply_arithmetic(&self, left: &Value, op: BinOp, right: &Value) -> Result<Value, EvalError> {
+        // u128 values above i128::MAX don't fit the i128 path below
+        if let (Value::U128(l), Value::U128(r)) = (left, right) {
+            return unsigned_arithmetic(*l, op, *r).map(Value::U128);
+        }
+
         // Integer arithmetic
         if let (Some(l), Some(r)) = (left.to_i128(), right.to_i128()) {
             let result = match op {
@@ -164,22 +391,11 @@ impl Evaluator {
                 _ => unreachable!(),
             };
 
-            // Return same type as operands
-            return Ok(match left {
-                Value::I8(_) => Value::I8(result as i8),
-                Value::I16(_) => Value::I16(result as i16),
-                Value::I32(_) => Value::I32(result as i32),
-                Value::I64(_) => Value::I64(result as i64),
-                Value::I128(_) => Value::I128(result),
-                Value::Isize(_) => Value::Isize(result as isize),
-                Value::U8(_) => Value::U8(result as u8),
-                Value::U16(_) => Value::U16(result as u16),
-                Value::U32(_) => Value::U32(result as u32),
-                Value::U64(_) => Value::U64(result as u64),
-                Value::U128(_) => Value::U128(result as u128),
-                Value::Usize(_) => Value::Usize(result as usize),
-                _ => unreachable!(),
-            });
+            // Return same type as operands, which overflows as it would in
+            // Rust if the result doesn't fit
+            return Value::I128(result)
+                .coerce_to(left.type_name())
+                .ok_or(EvalError::Internal("overflow".to_string()));
         }
 
         // Float arithmetic
@@ -208,6 +424,24 @@ impl Evaluator {
     }
 
     fn apply_comparison(&self, left: &Value, op: BinOp, right: &Value) -> Result<Value, EvalError> {
+        // Enum equality, e.g. `status == Status::Active`
+        if let (Value::Enum { .. }, Value::Enum { .. }) = (left, right) {
+            return match op {
+                BinOp::Eq => Ok(Value::Bool(structurally_equal(left, right))),
+                BinOp::Ne => Ok(Value::Bool(!structurally_equal(left, right))),
+                _ => Err(EvalError::InvalidOperation {
+                    op: op.as_str().to_string(),
+                    left: "enum".to_string(),
+                    right: "enum".to_string(),
+                }),
+            };
+        }
+
+        // Char comparison, by code point
+        if let (Value::Char(l), Value::Char(r)) = (left, right) {
+            return Ok(Value::Bool(compare(l, op, r)));
+        }
+
         // Integer comparison
         if let (Some(l), Some(r)) = (left.to_i128(), right.to_i128()) {
             let result = match op {
@@ -290,8 +524,30 @@ impl Evaluator {
             BinOp::BitAnd => l & r,
             BinOp::BitOr => l | r,
             BinOp::BitXor => l ^ r,
-            BinOp::Shl => l << (r as u32),
-            BinOp::Shr => l >> (r as u32),
+            BinOp::Shl | BinOp::Shr => {
+                // Bounds-check against the operand's own width, not the widened i128
+                let bits = left.bit_width().unwrap_or(128);
+                if r < 0 {
+                    return Err(EvalError::Internal(format!(
+                        "negative shift amount {} for {}",
+                        r,
+                        left.type_name()
+                    )));
+                }
+                if r >= bits as i128 {
+                    return Err(EvalError::Internal(format!(
+                        "shift amount {} >= {} bits for {}",
+                        r,
+                        bits,
+                        left.type_name()
+                    )));
+                }
+                if op == BinOp::Shl {
+                    l << r
+                } else {
+                    l >> r
+                }
+            }
             _ => unreachable!(),
         };
 
@@ -317,21 +573,17 @@ impl Evaluator {
         match op {
             UnaryOp::Neg => {
                 if let Some(v) = value.to_i128() {
-                    Ok(match value {
-                        Value::I8(_) => Value::I8((-v) as i8),
-                        Value::I16(_) => Value::I16((-v) as i16),
-                        Value::I32(_) => Value::I32((-v) as i32),
-                        Value::I64(_) => Value::I64((-v) as i64),
-                        Value::I128(_) => Value::I128(-v),
-                        Value::Isize(_) => Value::Isize((-v) as isize),
-                        _ => {
-                            return Err(EvalError::InvalidOperation {
-                                op: "-".to_string(),
-                                left: value.type_name().to_string(),
-                                right: "".to_string(),
-                            })
-                        }
-                    })
+                    if !value.is_signed() {
+                        return Err(EvalError::InvalidOperation {
+                            op: "-".to_string(),
+                            left: value.type_name().to_string(),
+                            right: "".to_string(),
+                        });
+                    }
+                    // Negating the minimum overflows, e.g. `-i32::MIN`
+                    v.checked_neg()
+                        .and_then(|v| Value::I128(v).coerce_to(value.type_name()))
+                        .ok_or(EvalError::Internal("overflow".to_string()))
                 } else if let Some(v) = value.to_f64() {
                     Ok(match value {
                         Value::F32(_) => Value::F32((-v) as f32),
@@ -383,6 +635,23 @@ impl Evaluator {
     fn cast_value(&self, value: &Value, ty: &str) -> Result<Value, EvalError> {
         let ty = ty.trim();
 
+        // As in Rust, only `u8` casts to `char`, and `char` only to integers
+        match (value, ty) {
+            (Value::U8(v), "char") => return Ok(Value::Char(*v as char)),
+            (Value::Char(c), "char") => return Ok(Value::Char(*c)),
+            (Value::Char(c), _) if ty != "f32" && ty != "f64" => {
+                return self.cast_value(&Value::U32(*c as u32), ty);
+            }
+            (_, "char") | (Value::Char(_), _) => {
+                return Err(EvalError::unsupported(format!(
+                    "cast from {} to {}",
+                    value.type_name(),
+                    ty
+                )))
+            }
+            _ => {}
+        }
+
         // Get numeric value
         if let Some(v) = value.to_i128() {
             return Ok(match ty {
@@ -438,6 +707,179 @@ impl Default for Evaluator {
     }
 }
 
+/// Result of a comparison operator on ordered values
+fn compare<T: PartialOrd>(l: &T, op: BinOp, r: &T) -> bool {
+    match op {
+        BinOp::Eq => l == r,
+        BinOp::Ne => l != r,
+        BinOp::Lt => l < r,
+        BinOp::Le => l <= r,
+        BinOp::Gt => l > r,
+        BinOp::Ge => l >= r,
+        _ => unreachable!(),
+    }
+}
+
+/// A range as the array of its elements, e.g. `'a'..='c'` as `['a', 'b', 'c']`
+///
+/// Only char ranges with both bounds are supported.
+fn range_value(
+    start: Option<Value>,
+    end: Option<Value>,
+    inclusive: bool,
+) -> Result<Value, EvalError> {
+    let (Some(Value::Char(start)), Some(Value::Char(end))) = (&start, &end) else {
+        let bound = start.as_ref().or(end.as_ref());
+        return Err(match bound {
+            Some(Value::Char(_)) | None => EvalError::unsupported("open ranges"),
+            Some(other) => EvalError::unsupported(format!("ranges of {}", other.type_name())),
+        });
+    };
+    let chars: Vec<Value> = if inclusive {
+        (*start..=*end)
+            .map(Value::Char)
+            .take(MAX_RANGE_LEN + 1)
+            .collect()
+    } else {
+        (*start..*end)
+            .map(Value::Char)
+            .take(MAX_RANGE_LEN + 1)
+            .collect()
+    };
+    if chars.len() > MAX_RANGE_LEN {
+        return Err(EvalError::Internal(format!(
+            "range has more than {} elements",
+            MAX_RANGE_LEN
+        )));
+    }
+    Ok(Value::Array(chars))
+}
+
+/// A `char` method without arguments, one of those `call_method` accepts
+fn char_method(c: char, method: &str) -> Value {
+    match method {
+        "is_alphabetic" => Value::Bool(c.is_alphabetic()),
+        "is_numeric" => Value::Bool(c.is_numeric()),
+        "is_alphanumeric" => Value::Bool(c.is_alphanumeric()),
+        "is_whitespace" => Value::Bool(c.is_whitespace()),
+        "to_ascii_uppercase" => Value::Char(c.to_ascii_uppercase()),
+        "to_ascii_lowercase" => Value::Char(c.to_ascii_lowercase()),
+        _ => unreachable!(),
+    }
+}
+
+/// `l op r` computed in `u128`, checking for overflow
+fn unsigned_arithmetic(l: u128, op: BinOp, r: u128) -> Result<u128, EvalError> {
+    let overflow = || EvalError::Internal("overflow".to_string());
+    match op {
+        BinOp::Add => l.checked_add(r).ok_or_else(overflow),
+        BinOp::Sub => l.checked_sub(r).ok_or_else(overflow),
+        BinOp::Mul => l.checked_mul(r).ok_or_else(overflow),
+        BinOp::Div | BinOp::Rem if r == 0 => Err(EvalError::DivisionByZero),
+        BinOp::Div => Ok(l / r),
+        BinOp::Rem => Ok(l % r),
+        _ => unreachable!(),
+    }
+}
+
+/// Whether two values are equal field by field and element by element
+///
+/// Enums compare by the last segment of their name, since a snapshot may
+/// record `app::Status` where the expression says `Status`.
+fn structurally_equal(left: &Value, right: &Value) -> bool {
+    let short_name = |name: &str| name.rsplit("::").next().unwrap_or("").to_string();
+    match (left, right) {
+        (
+            Value::Enum {
+                enum_name: l_name,
+                variant: l_variant,
+                payload: l_payload,
+            },
+            Value::Enum {
+                enum_name: r_name,
+                variant: r_variant,
+                payload: r_payload,
+            },
+        ) => {
+            short_name(l_name) == short_name(r_name)
+                && l_variant == r_variant
+                && match (l_payload, r_payload) {
+                    (None, None) => true,
+                    (Some(l), Some(r)) => structurally_equal(l, r),
+                    _ => false,
+                }
+        }
+        (
+            Value::Struct {
+                type_name: l_name,
+                fields: l_fields,
+            },
+            Value::Struct {
+                type_name: r_name,
+                fields: r_fields,
+            },
+        ) => {
+            short_name(l_name) == short_name(r_name)
+                && l_fields.len() == r_fields.len()
+                && l_fields
+                    .iter()
+                    .zip(r_fields)
+                    .all(|((l_field, l), (r_field, r))| {
+                        l_field == r_field && structurally_equal(l, r)
+                    })
+        }
+        (Value::Array(l), Value::Array(r)) => {
+            l.len() == r.len() && l.iter().zip(r).all(|(l, r)| structurally_equal(l, r))
+        }
+        (Value::Map(l), Value::Map(r)) => {
+            l.len() == r.len()
+                && l.iter().zip(r).all(|((lk, lv), (rk, rv))| {
+                    structurally_equal(lk, rk) && structurally_equal(lv, rv)
+                })
+        }
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Char(l), Value::Char(r)) => l == r,
+        (Value::Bool(l), Value::Bool(r)) => l == r,
+        (Value::Unit, Value::Unit) => true,
+        _ => match (left.to_i128(), right.to_i128()) {
+            (Some(l), Some(r)) => l == r,
+            _ => matches!((left.to_f64(), right.to_f64()), (Some(l), Some(r)) if l == r),
+        },
+    }
+}
+
+/// Named field of a struct value
+fn field_value(value: Value, field: &str) -> Result<Value, EvalError> {
+    match value {
+        Value::Struct { type_name, fields } => fields
+            .into_iter()
+            .find(|(name, _)| name == field)
+            .map(|(_, value)| value)
+            .ok_or(EvalError::FieldNotFound {
+                field: field.to_string(),
+                type_name,
+            }),
+        other => Err(EvalError::FieldNotFound {
+            field: field.to_string(),
+            type_name: other.type_name().to_string(),
+        }),
+    }
+}
+
+/// Element of an array (or tuple) value
+fn element_value(value: Value, index: usize) -> Result<Value, EvalError> {
+    match value {
+        Value::Array(elements) => {
+            let length = elements.len();
+            elements
+                .into_iter()
+                .nth(index)
+                .ok_or(EvalError::IndexOutOfBounds { index, length })
+        }
+        other => Err(EvalError::type_mismatch("array", other.type_name())),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -473,6 +915,88 @@ mod tests {
         assert!(matches!(result, Value::I32(50)));
     }
 
+    #[test]
+    fn test_u128_arithmetic_above_i128_max() {
+        let mut eval = Evaluator::new();
+        eval.set_variable("max", Value::U128(u128::MAX));
+        eval.set_variable("big", Value::U128(u128::MAX - 10));
+        eval.set_variable("ten", Value::U128(10));
+        eval.set_variable("two", Value::U128(2));
+
+        let result = eval.eval(&parse_expr("big + ten").unwrap()).unwrap();
+        assert!(
+            matches!(result, Value::U128(u128::MAX)),
+            "Got: {:?}",
+            result
+        );
+        let result = eval.eval(&parse_expr("max - big").unwrap()).unwrap();
+        assert!(matches!(result, Value::U128(10)), "Got: {:?}", result);
+        let result = eval.eval(&parse_expr("max / two").unwrap()).unwrap();
+        assert!(
+            matches!(result, Value::U128(v) if v == u128::MAX / 2),
+            "Got: {:?}",
+            result
+        );
+        let result = eval.eval(&parse_expr("max % two").unwrap()).unwrap();
+        assert!(matches!(result, Value::U128(1)), "Got: {:?}", result);
+
+        let err = eval.eval(&parse_expr("max + two").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("overflow"), "Got: {}", err);
+        let err = eval.eval(&parse_expr("two - max").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("overflow"), "Got: {}", err);
+        let err = eval.eval(&parse_expr("max * two").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("overflow"), "Got: {}", err);
+    }
+
+    #[test]
+    fn test_integer_overflow_in_operand_type() {
+        let mut eval = Evaluator::new();
+        eval.set_variable("max", Value::I32(i32::MAX));
+        eval.set_variable("min", Value::I8(i8::MIN));
+        eval.set_variable("small", Value::U8(200));
+
+        for expr in ["2147483647 + 1", "max * 2", "-min", "small + small"] {
+            let err = eval.eval(&parse_expr(expr).unwrap()).unwrap_err();
+            assert!(err.to_string().contains("overflow"), "Got: {}", err);
+        }
+        let result = eval.eval(&parse_expr("max - 1").unwrap()).unwrap();
+        assert!(
+            matches!(result, Value::I32(2147483646)),
+            "Got: {:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_eval_trace() {
+        let mut eval = Evaluator::new();
+        eval.set_variable("a", Value::I32(5));
+        eval.set_variable("b", Value::I32(5));
+
+        let expr = parse_expr("a + b * 2").unwrap();
+        let (result, trace) = eval.eval_trace(&expr).unwrap();
+        assert!(matches!(result, Value::I32(15)));
+        let steps: Vec<String> = trace
+            .iter()
+            .map(|(expr, value)| format!("{} = {}", expr, value))
+            .collect();
+        assert_eq!(steps, vec!["b * 2 = 10", "a + b * 2 = 15"]);
+
+        let expr = parse_expr("-(a - 1) * (b + 1) as i32").unwrap();
+        let (_, trace) = eval.eval_trace(&expr).unwrap();
+        let exprs: Vec<&str> = trace.iter().map(|(expr, _)| expr.as_str()).collect();
+        assert_eq!(
+            exprs,
+            vec![
+                "a - 1",
+                "-(a - 1)",
+                "b + 1",
+                "(b + 1) as i32",
+                "-(a - 1) * (b + 1) as i32"
+            ]
+        );
+    }
+
     #[test]
     fn test_comparison() {
         let eval = Evaluator::new();
@@ -519,4 +1043,212 @@ mod tests {
         let result = eval.eval(&expr);
         assert!(matches!(result, Err(EvalError::DivisionByZero)));
     }
+
+    #[test]
+    fn test_shift_out_of_range() {
+        let mut eval = Evaluator::new();
+        eval.set_variable("x", Value::U8(1));
+
+        let expr = parse_expr("x << 8").unwrap();
+        let result = eval.eval(&expr);
+        match result {
+            Err(EvalError::Internal(msg)) => assert_eq!(msg, "shift amount 8 >= 8 bits for u8"),
+            other => panic!("expected shift error, got {:?}", other),
+        }
+
+        let expr = parse_expr("x >> -1").unwrap();
+        assert!(matches!(eval.eval(&expr), Err(EvalError::Internal(_))));
+    }
+
+    #[test]
+    fn test_len_method() {
+        let mut eval = Evaluator::new();
+        eval.set_variable(
+            "users",
+            Value::Array(vec![
+                Value::String("ann".to_string()),
+                Value::String("bob".to_string()),
+            ]),
+        );
+        eval.set_variable("name", Value::String("héllo".to_string()));
+        eval.set_variable(
+            "scores",
+            Value::Map(vec![(Value::String("ann".to_string()), Value::I32(3))]),
+        );
+        eval.set_variable("empty", Value::Array(vec![]));
+        eval.set_variable("n", Value::I32(1));
+
+        let len = |input: &str| eval.eval(&parse_expr(input).unwrap());
+        assert!(matches!(len("users.len()").unwrap(), Value::Usize(2)));
+        // Byte length, as in Rust
+        assert!(matches!(len("name.len()").unwrap(), Value::Usize(6)));
+        assert!(matches!(len("scores.len()").unwrap(), Value::Usize(1)));
+        assert!(matches!(len("empty.len()").unwrap(), Value::Usize(0)));
+        assert!(matches!(
+            len("\"abc\".len() + users.len()").unwrap(),
+            Value::Usize(5)
+        ));
+
+        assert!(matches!(
+            len("n.len()"),
+            Err(EvalError::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            len("users.first()"),
+            Err(EvalError::UnsupportedExpression { .. })
+        ));
+    }
+
+    #[test]
+    fn test_char_methods_ranges_and_casts() {
+        let mut evaluator = Evaluator::new();
+        evaluator.set_variable("c", Value::Char('q'));
+        evaluator.set_variable("byte", Value::U8(65));
+        let eval = |input: &str| evaluator.eval(&parse_expr(input).unwrap());
+
+        assert!(matches!(eval("'5'.is_numeric()"), Ok(Value::Bool(true))));
+        assert!(matches!(eval("c.is_alphabetic()"), Ok(Value::Bool(true))));
+        assert!(matches!(
+            eval("' '.is_alphanumeric()"),
+            Ok(Value::Bool(false))
+        ));
+        assert!(matches!(
+            eval("c.to_ascii_uppercase()"),
+            Ok(Value::Char('Q'))
+        ));
+        assert!(matches!(
+            eval("byte.is_numeric()"),
+            Err(EvalError::TypeMismatch { .. })
+        ));
+
+        assert!(matches!(
+            eval("c >= 'a' && c <= 'z'"),
+            Ok(Value::Bool(true))
+        ));
+        assert!(matches!(eval("c < 'a'"), Ok(Value::Bool(false))));
+
+        let Ok(Value::Array(letters)) = eval("'a'..='z'") else {
+            panic!("Expected array");
+        };
+        assert_eq!(letters.len(), 26);
+        assert!(matches!(letters[25], Value::Char('z')));
+        assert!(matches!(eval("('a'..'c').len()"), Ok(Value::Usize(2))));
+        assert!(matches!(
+            eval("1..3"),
+            Err(EvalError::UnsupportedExpression { .. })
+        ));
+        assert!(matches!(
+            eval("'a'.."),
+            Err(EvalError::UnsupportedExpression { .. })
+        ));
+
+        assert!(matches!(eval("c as u32"), Ok(Value::U32(113))));
+        assert!(matches!(eval("byte as char"), Ok(Value::Char('A'))));
+        assert!(matches!(
+            eval("(c as u8 - byte) as char"),
+            Ok(Value::Char('0'))
+        ));
+        assert!(matches!(
+            eval("65 as char"),
+            Err(EvalError::UnsupportedExpression { .. })
+        ));
+        assert!(matches!(
+            eval("c as f64"),
+            Err(EvalError::UnsupportedExpression { .. })
+        ));
+    }
+
+    #[test]
+    fn test_constant_paths() {
+        let mut evaluator = Evaluator::new();
+        evaluator.set_constant("Priority::High", Value::I64(2));
+        evaluator.set_constant("Config::MAX", Value::U32(8));
+        evaluator.set_variable("priority", Value::I64(2));
+        let eval = |input: &str| evaluator.eval(&parse_expr(input).unwrap());
+
+        assert!(matches!(eval("Priority::High as i32"), Ok(Value::I32(2))));
+        assert!(matches!(
+            eval("priority == Priority::High"),
+            Ok(Value::Bool(true))
+        ));
+        assert!(matches!(eval("Config::MAX"), Ok(Value::U32(8))));
+        assert!(matches!(
+            eval("Priority::Low"),
+            Err(EvalError::UnknownVariable { ref name }) if name == "Priority::Low"
+        ));
+    }
+
+    #[test]
+    fn test_string_slicing() {
+        let mut evaluator = Evaluator::new();
+        evaluator.set_variable("s", Value::String("hello".to_string()));
+        evaluator.set_variable("u", Value::String("héllo".to_string()));
+        let eval = |input: &str| evaluator.eval(&parse_expr(input).unwrap());
+
+        for (input, expected) in [
+            ("s[0..3]", "hel"),
+            ("s[1..=3]", "ell"),
+            ("s[2..]", "llo"),
+            ("s[..0]", ""),
+            ("s[..]", "hello"),
+            // `é` is two bytes
+            ("u[0..3]", "hé"),
+        ] {
+            assert!(
+                matches!(eval(input), Ok(Value::String(ref v)) if v == expected),
+                "Got: {:?} for {}",
+                eval(input),
+                input
+            );
+        }
+
+        assert!(matches!(
+            eval("s[2..9]"),
+            Err(EvalError::IndexOutOfBounds {
+                index: 9,
+                length: 5
+            })
+        ));
+        assert!(matches!(eval("s[3..1]"), Err(EvalError::Internal(_))));
+        let err = eval("u[0..2]").unwrap_err();
+        assert!(
+            matches!(err, EvalError::Internal(ref msg) if msg.contains("byte index 2 is not a char boundary")),
+            "Got: {:?}",
+            err
+        );
+        assert!(matches!(eval("u[2..]"), Err(EvalError::Internal(_))));
+    }
+
+    #[test]
+    fn test_try_operator_is_unsupported() {
+        let mut eval = Evaluator::new();
+        eval.set_variable("result", Value::I32(1));
+
+        let err = eval.eval(&parse_expr("result?").unwrap()).unwrap_err();
+        assert!(
+            err.to_string().contains("? operator outside a function"),
+            "Got: {}",
+            err
+        );
+        // The operand is still evaluated first
+        assert!(matches!(
+            eval.eval(&parse_expr("missing?").unwrap()),
+            Err(EvalError::UnknownVariable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_shift_in_range() {
+        let mut eval = Evaluator::new();
+        eval.set_variable("x", Value::U8(1));
+
+        let expr = parse_expr("x << 7").unwrap();
+        assert!(matches!(eval.eval(&expr).unwrap(), Value::U8(128)));
+
+        let expr = parse_expr("1 << 31").unwrap();
+        assert!(matches!(eval.eval(&expr).unwrap(), Value::I32(i32::MIN)));
+
+        let expr = parse_expr("-8 >> 1").unwrap();
+        assert!(matches!(eval.eval(&expr).unwrap(), Value::I32(-4)));
+    }
 }
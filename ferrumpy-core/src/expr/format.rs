@@ -0,0 +1,281 @@
+//! Expression formatter
+//!
+//! Renders our AST back to canonical Rust source, emitting parentheses only
+//! where operator precedence requires them.
+
+use super::ast::{BinOp, Expr, Literal, PathSegment, UnaryOp};
+
+/// Binding strength, from loosest to tightest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    Range,
+    Or,
+    And,
+    Compare,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
+    Sum,
+    Product,
+    Cast,
+    Prefix,
+    Primary,
+}
+
+impl Precedence {
+    fn of_binop(op: BinOp) -> Self {
+        match op {
+            BinOp::Or => Precedence::Or,
+            BinOp::And => Precedence::And,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                Precedence::Compare
+            }
+            BinOp::BitOr => Precedence::BitOr,
+            BinOp::BitXor => Precedence::BitXor,
+            BinOp::BitAnd => Precedence::BitAnd,
+            BinOp::Shl | BinOp::Shr => Precedence::Shift,
+            BinOp::Add | BinOp::Sub => Precedence::Sum,
+            BinOp::Mul | BinOp::Div | BinOp::Rem => Precedence::Product,
+        }
+    }
+
+    fn of(expr: &Expr) -> Self {
+        match expr {
+            Expr::Binary { op, .. } => Precedence::of_binop(*op),
+            Expr::Cast { .. } => Precedence::Cast,
+            Expr::Unary { .. } => Precedence::Prefix,
+            Expr::Paren(inner) => Precedence::of(inner),
+            Expr::Path(segments) => match segments.first() {
+                Some(PathSegment::Deref | PathSegment::Ref) => Precedence::Prefix,
+                _ => Precedence::Primary,
+            },
+            Expr::Literal(_) | Expr::MethodCall { .. } | Expr::Try(_) => Precedence::Primary,
+            Expr::Range { .. } => Precedence::Range,
+        }
+    }
+}
+
+/// Format an expression as canonical, minimally parenthesized Rust source
+///
+/// `format_expr(&parse_expr(s)?)` normalizes spacing (`a+b` becomes `a + b`)
+/// and drops redundant parentheses.
+pub fn format_expr(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_expr(expr, &mut out);
+    out
+}
+
+fn write_expr(expr: &Expr, out: &mut String) {
+    match expr {
+        Expr::Path(segments) => write_path(segments, out),
+        Expr::Literal(lit) => write_literal(lit, out),
+        Expr::Paren(inner) => write_expr(inner, out),
+        Expr::Unary { op, expr } => {
+            out.push_str(op.as_str());
+            write_operand(expr, Precedence::of(expr) < Precedence::Prefix, out);
+        }
+        Expr::Cast { expr, ty } => {
+            write_operand(expr, Precedence::of(expr) < Precedence::Cast, out);
+            out.push_str(" as ");
+            out.push_str(ty);
+        }
+        Expr::Binary { left, op, right } => {
+            let prec = Precedence::of_binop(*op);
+            let left_prec = Precedence::of(left);
+            // Comparisons don't chain, and `x as T < y` would start generic arguments
+            let left_parens = left_prec < prec
+                || (left_prec == prec && prec == Precedence::Compare)
+                || (matches!(op, BinOp::Lt | BinOp::Shl) && is_cast(left));
+            write_operand(left, left_parens, out);
+
+            out.push(' ');
+            out.push_str(op.as_str());
+            out.push(' ');
+
+            // Binary operators are left-associative
+            write_operand(right, Precedence::of(right) <= prec, out);
+        }
+        Expr::MethodCall {
+            receiver,
+            method,
+            args,
+        } => {
+            // Method calls bind tighter than any prefix operator: `(-a).abs()`
+            write_operand(
+                receiver,
+                Precedence::of(receiver) < Precedence::Primary,
+                out,
+            );
+            out.push('.');
+            out.push_str(method);
+            out.push('(');
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expr(arg, out);
+            }
+            out.push(')');
+        }
+        Expr::Try(inner) => {
+            write_operand(inner, Precedence::of(inner) < Precedence::Primary, out);
+            out.push('?');
+        }
+        Expr::Range {
+            start,
+            end,
+            inclusive,
+        } => {
+            // Ranges don't chain, so a bound that is a range needs parentheses
+            if let Some(start) = start {
+                write_operand(start, Precedence::of(start) <= Precedence::Range, out);
+            }
+            out.push_str(if *inclusive { "..=" } else { ".." });
+            if let Some(end) = end {
+                write_operand(end, Precedence::of(end) <= Precedence::Range, out);
+            }
+        }
+    }
+}
+
+fn write_operand(expr: &Expr, parens: bool, out: &mut String) {
+    if parens {
+        out.push('(');
+        write_expr(expr, out);
+        out.push(')');
+    } else {
+        write_expr(expr, out);
+    }
+}
+
+fn is_cast(expr: &Expr) -> bool {
+    match expr {
+        Expr::Cast { .. } => true,
+        Expr::Paren(inner) => is_cast(inner),
+        _ => false,
+    }
+}
+
+fn write_path(segments: &[PathSegment], out: &mut String) {
+    let mut first = true;
+    for segment in segments {
+        match segment {
+            PathSegment::Ident(name) => {
+                if !first {
+                    out.push('.');
+                }
+                out.push_str(name);
+                first = false;
+            }
+            PathSegment::TupleIndex(index) => {
+                out.push('.');
+                out.push_str(&index.to_string());
+            }
+            PathSegment::Index(index) => {
+                out.push('[');
+                out.push_str(&index.to_string());
+                out.push(']');
+            }
+            PathSegment::Range {
+                start,
+                end,
+                inclusive,
+            } => {
+                out.push('[');
+                if let Some(start) = start {
+                    out.push_str(&start.to_string());
+                }
+                out.push_str(if *inclusive { "..=" } else { ".." });
+                if let Some(end) = end {
+                    out.push_str(&end.to_string());
+                }
+                out.push(']');
+            }
+            PathSegment::Deref => out.push_str(UnaryOp::Deref.as_str()),
+            PathSegment::Ref => out.push_str(UnaryOp::Ref.as_str()),
+        }
+    }
+}
+
+fn write_literal(lit: &Literal, out: &mut String) {
+    let text = match lit {
+        Literal::Int(v) => v.to_string(),
+        // Debug keeps the decimal point (`1.0`), so the literal stays a float
+        Literal::Float(v) => format!("{:?}", v),
+        Literal::Bool(v) => v.to_string(),
+        // Debug output uses Rust escape syntax
+        Literal::Char(c) => format!("{:?}", c),
+        Literal::String(s) => format!("{:?}", s),
+    };
+    out.push_str(&text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::parse_expr;
+
+    fn fmt(input: &str) -> String {
+        format_expr(&parse_expr(input).unwrap())
+    }
+
+    #[test]
+    fn test_format_normalizes_spacing() {
+        assert_eq!(fmt("a+b"), "a + b");
+        assert_eq!(fmt("!flag&&x.y[2].0>=-1"), "!flag && x.y[2].0 >= -1");
+        assert_eq!(fmt("n  as   u64"), "n as u64");
+        assert_eq!(fmt("&*ptr"), "&*ptr");
+        assert_eq!(fmt("p==Priority :: High"), "p == Priority::High");
+        assert_eq!(fmt("s[1 ..= 3]"), "s[1..=3]");
+        assert_eq!(fmt("s[ ..2]"), "s[..2]");
+    }
+
+    #[test]
+    fn test_format_minimal_parentheses() {
+        assert_eq!(fmt("((a + b)) * c"), "(a + b) * c");
+        assert_eq!(fmt("a + (b * c)"), "a + b * c");
+        assert_eq!(fmt("(a - b) - c"), "a - b - c");
+        assert_eq!(fmt("a - (b - c)"), "a - (b - c)");
+        assert_eq!(fmt("-(a + 1)"), "-(a + 1)");
+        assert_eq!(fmt("(-a) as i64"), "-a as i64");
+        assert_eq!(fmt("(a + b) as i64"), "(a + b) as i64");
+        assert_eq!(fmt("(a < b) == true"), "(a < b) == true");
+        assert_eq!(fmt("(x as i32) < y"), "(x as i32) < y");
+        assert_eq!(fmt("(x as i32) > y"), "x as i32 > y");
+        assert_eq!(fmt("(a || b) && c"), "(a || b) && c");
+        assert_eq!(fmt("(users).len() + 1"), "users.len() + 1");
+        assert_eq!(fmt("(a + b).len()"), "(a + b).len()");
+        assert_eq!(fmt("-x.len()"), "-x.len()");
+        assert_eq!(fmt("(result)?.len()"), "result?.len()");
+        assert_eq!(fmt("(-x)?"), "(-x)?");
+        assert_eq!(fmt("('a' ..= 'z').len()"), "('a'..='z').len()");
+        assert_eq!(fmt("(a + 1)..(b)"), "a + 1..b");
+    }
+
+    #[test]
+    fn test_format_literals() {
+        assert_eq!(fmt("1.0 + 2.5e3"), "1.0 + 2500.0");
+        assert_eq!(fmt("'\\n'"), "'\\n'");
+        assert_eq!(fmt(r#""say \"hi\"""#), r#""say \"hi\"""#);
+        assert_eq!(fmt("0xff"), "255");
+    }
+
+    #[test]
+    fn test_format_round_trip_is_stable() {
+        let inputs = [
+            "a+b*c-d/e%f",
+            "(a|b)^(c&d)<<2",
+            "!(x.len_field>0)||y.0[1]!=z",
+            "-(-a)",
+            "((p.x as f64)*(p.x as f64)+1.5) as i64",
+            "(a==b)!=(c<=d)",
+            "&self_.inner.value",
+        ];
+        for input in inputs {
+            let once = fmt(input);
+            let twice = fmt(&once);
+            assert_eq!(once, twice, "input: {}", input);
+        }
+    }
+}
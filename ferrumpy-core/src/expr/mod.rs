@@ -5,11 +5,15 @@
 pub mod ast;
 pub mod error;
 pub mod eval;
+pub mod format;
 pub mod parser;
+pub mod snapshot;
 pub mod value;
 
 pub use ast::Expr;
 pub use error::EvalError;
-pub use eval::Evaluator;
-pub use parser::parse_expr;
+pub use eval::{Evaluator, VarContext};
+pub use format::format_expr;
+pub use parser::{parse_expr, parse_expr_with_notes};
+pub use snapshot::{value_from_snapshot_json, var_context_from_snapshot};
 pub use value::Value;
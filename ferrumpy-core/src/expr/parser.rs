@@ -2,21 +2,91 @@
 //!
 //! Converts Rust expression strings to our AST.
 
+use proc_macro2::{Group, Spacing, TokenStream, TokenTree};
 use syn::{
     Expr as SynExpr, ExprBinary, ExprCast, ExprField, ExprIndex, ExprLit, ExprParen, ExprPath,
-    ExprUnary,
+    ExprRange, ExprUnary, RangeLimits,
 };
 
 use super::ast::{BinOp, Expr, Literal, PathSegment, UnaryOp};
 use super::error::EvalError;
 
+/// syn's message for `a < b < c`
+const SYN_CHAINED_COMPARISON: &str = "comparison operators cannot be chained";
+
+/// Note returned by [`parse_expr_with_notes`] for input using unary `+`
+pub const UNARY_PLUS_NOTE: &str = "unary `+` is not Rust and has no effect; it was ignored";
+
 /// Parse an expression string into our AST
 pub fn parse_expr(input: &str) -> Result<Expr, EvalError> {
-    let syn_expr: SynExpr = syn::parse_str(input).map_err(|e| EvalError::ParseError {
-        message: e.to_string(),
-    })?;
+    parse_expr_with_notes(input).map(|(expr, _)| expr)
+}
+
+/// Like [`parse_expr`], also returning notes on input accepted although it
+/// isn't quite Rust, e.g. [`UNARY_PLUS_NOTE`]
+pub fn parse_expr_with_notes(input: &str) -> Result<(Expr, Vec<String>), EvalError> {
+    let mut notes = Vec::new();
+    let syn_expr: SynExpr = match syn::parse_str(input) {
+        Ok(expr) => expr,
+        // Rust has no unary plus, but `+x` is a common habit from C; accept it as a no-op
+        Err(e) => {
+            let expr = parse_without_unary_plus(input).ok_or_else(|| parse_error(&e))?;
+            notes.push(UNARY_PLUS_NOTE.to_string());
+            expr
+        }
+    };
+
+    Ok((convert_expr(&syn_expr)?, notes))
+}
+
+fn parse_error(e: &syn::Error) -> EvalError {
+    let message = e.to_string();
+    let message = if message == SYN_CHAINED_COMPARISON {
+        "chained comparison is not allowed; parenthesize".to_string()
+    } else {
+        message
+    };
+    EvalError::ParseError { message }
+}
+
+/// Re-parse the input with every unary `+` removed
+fn parse_without_unary_plus(input: &str) -> Option<SynExpr> {
+    let tokens: TokenStream = input.parse().ok()?;
+    syn::parse2(strip_unary_plus(tokens)).ok()
+}
+
+/// Drop `+` tokens in operand position (at the start, or after another operator)
+fn strip_unary_plus(tokens: TokenStream) -> TokenStream {
+    let mut out = Vec::new();
+    // Whether the previous token ends an operand, making a following `+` binary
+    let mut after_operand = false;
+
+    for token in tokens {
+        match token {
+            TokenTree::Punct(p) if p.as_char() == '+' && !after_operand => {
+                if p.spacing() == Spacing::Joint {
+                    // Part of a compound operator such as `+=`
+                    out.push(TokenTree::Punct(p));
+                }
+            }
+            TokenTree::Punct(p) => {
+                after_operand = p.as_char() == '?';
+                out.push(TokenTree::Punct(p));
+            }
+            TokenTree::Group(g) => {
+                let mut group = Group::new(g.delimiter(), strip_unary_plus(g.stream()));
+                group.set_span(g.span());
+                after_operand = true;
+                out.push(TokenTree::Group(group));
+            }
+            other => {
+                after_operand = true;
+                out.push(other);
+            }
+        }
+    }
 
-    convert_expr(&syn_expr)
+    out.into_iter().collect()
 }
 
 /// Convert syn expression to our AST
@@ -50,14 +120,7 @@ fn convert_expr(expr: &SynExpr) -> Result<Expr, EvalError> {
         }
 
         // Path: a, a::b
-        SynExpr::Path(ExprPath { path, .. }) => {
-            let segments = path
-                .segments
-                .iter()
-                .map(|seg| PathSegment::Ident(seg.ident.to_string()))
-                .collect();
-            Ok(Expr::Path(segments))
-        }
+        SynExpr::Path(ExprPath { path, .. }) => Ok(Expr::Path(vec![path_ident(path)])),
 
         // Field access: a.b
         SynExpr::Field(ExprField { base, member, .. }) => {
@@ -75,26 +138,11 @@ fn convert_expr(expr: &SynExpr) -> Result<Expr, EvalError> {
             Ok(Expr::Path(segments))
         }
 
-        // Index: a[0]
+        // Index: a[0], a[1..3]
         SynExpr::Index(ExprIndex { expr, index, .. }) => {
             let mut segments = extract_path_segments(expr)?;
-
-            // Index must be a literal integer
-            if let SynExpr::Lit(ExprLit {
-                lit: syn::Lit::Int(lit_int),
-                ..
-            }) = index.as_ref()
-            {
-                let idx = lit_int
-                    .base10_parse::<usize>()
-                    .map_err(|e| EvalError::ParseError {
-                        message: e.to_string(),
-                    })?;
-                segments.push(PathSegment::Index(idx));
-                Ok(Expr::Path(segments))
-            } else {
-                Err(EvalError::unsupported("dynamic index expressions"))
-            }
+            segments.push(index_segment(index)?);
+            Ok(Expr::Path(segments))
         }
 
         // Parenthesized: (a + b)
@@ -118,8 +166,42 @@ fn convert_expr(expr: &SynExpr) -> Result<Expr, EvalError> {
         // Function calls - not supported
         SynExpr::Call(_) => Err(EvalError::unsupported("function calls")),
 
-        // Method calls - not supported
-        SynExpr::MethodCall(_) => Err(EvalError::unsupported("method calls")),
+        // Method calls: users.len()
+        SynExpr::MethodCall(call) => {
+            if call.turbofish.is_some() {
+                return Err(EvalError::unsupported(
+                    "method calls with generic arguments",
+                ));
+            }
+            Ok(Expr::MethodCall {
+                receiver: Box::new(convert_expr(&call.receiver)?),
+                method: call.method.to_string(),
+                args: call
+                    .args
+                    .iter()
+                    .map(convert_expr)
+                    .collect::<Result<_, _>>()?,
+            })
+        }
+
+        // Try operator: result?
+        SynExpr::Try(t) => Ok(Expr::Try(Box::new(convert_expr(&t.expr)?))),
+
+        // Range: 'a'..='z'
+        SynExpr::Range(ExprRange {
+            start, limits, end, ..
+        }) => {
+            let bound = |expr: &Option<Box<SynExpr>>| {
+                expr.as_deref()
+                    .map(|expr| convert_expr(expr).map(Box::new))
+                    .transpose()
+            };
+            Ok(Expr::Range {
+                start: bound(start)?,
+                end: bound(end)?,
+                inclusive: matches!(limits, RangeLimits::Closed(_)),
+            })
+        }
 
         // Closures - not supported
         SynExpr::Closure(_) => Err(EvalError::unsupported("closures")),
@@ -145,11 +227,7 @@ fn convert_expr(expr: &SynExpr) -> Result<Expr, EvalError> {
 /// Extract path segments from nested field/index expressions
 fn extract_path_segments(expr: &SynExpr) -> Result<Vec<PathSegment>, EvalError> {
     match expr {
-        SynExpr::Path(ExprPath { path, .. }) => Ok(path
-            .segments
-            .iter()
-            .map(|seg| PathSegment::Ident(seg.ident.to_string()))
-            .collect()),
+        SynExpr::Path(ExprPath { path, .. }) => Ok(vec![path_ident(path)]),
         SynExpr::Field(ExprField { base, member, .. }) => {
             let mut segments = extract_path_segments(base)?;
             match member {
@@ -164,21 +242,8 @@ fn extract_path_segments(expr: &SynExpr) -> Result<Vec<PathSegment>, EvalError>
         }
         SynExpr::Index(ExprIndex { expr, index, .. }) => {
             let mut segments = extract_path_segments(expr)?;
-            if let SynExpr::Lit(ExprLit {
-                lit: syn::Lit::Int(lit_int),
-                ..
-            }) = index.as_ref()
-            {
-                let idx = lit_int
-                    .base10_parse::<usize>()
-                    .map_err(|e| EvalError::ParseError {
-                        message: e.to_string(),
-                    })?;
-                segments.push(PathSegment::Index(idx));
-                Ok(segments)
-            } else {
-                Err(EvalError::unsupported("dynamic index"))
-            }
+            segments.push(index_segment(index)?);
+            Ok(segments)
         }
         SynExpr::Unary(ExprUnary {
             op: syn::UnOp::Deref(_),
@@ -193,6 +258,49 @@ fn extract_path_segments(expr: &SynExpr) -> Result<Vec<PathSegment>, EvalError>
     }
 }
 
+/// A `::` path as one identifier, so `Priority::High` can name a constant
+fn path_ident(path: &syn::Path) -> PathSegment {
+    let names: Vec<String> = path
+        .segments
+        .iter()
+        .map(|seg| seg.ident.to_string())
+        .collect();
+    PathSegment::Ident(names.join("::"))
+}
+
+/// Path segment for the index of `a[...]`: a literal integer or a range of them
+fn index_segment(index: &SynExpr) -> Result<PathSegment, EvalError> {
+    match index {
+        SynExpr::Range(ExprRange {
+            start, limits, end, ..
+        }) => {
+            let bound =
+                |expr: &Option<Box<SynExpr>>| expr.as_deref().map(index_literal).transpose();
+            Ok(PathSegment::Range {
+                start: bound(start)?,
+                end: bound(end)?,
+                inclusive: matches!(limits, RangeLimits::Closed(_)),
+            })
+        }
+        index => Ok(PathSegment::Index(index_literal(index)?)),
+    }
+}
+
+fn index_literal(expr: &SynExpr) -> Result<usize, EvalError> {
+    let SynExpr::Lit(ExprLit {
+        lit: syn::Lit::Int(lit_int),
+        ..
+    }) = expr
+    else {
+        return Err(EvalError::unsupported("dynamic index expressions"));
+    };
+    lit_int
+        .base10_parse::<usize>()
+        .map_err(|e| EvalError::ParseError {
+            message: e.to_string(),
+        })
+}
+
 /// Convert syn binary operator to our BinOp
 fn convert_binop(op: &syn::BinOp) -> Result<BinOp, EvalError> {
     match op {
@@ -294,11 +402,138 @@ mod tests {
     }
 
     #[test]
-    fn test_unsupported_method_call() {
-        let result = parse_expr("a.len()");
+    fn test_parse_try() {
+        let expr = parse_expr("foo?").unwrap();
+        let Expr::Try(inner) = expr else {
+            panic!("Expected Try");
+        };
+        assert!(matches!(*inner, Expr::Path(ref segments) if segments.len() == 1));
+
         assert!(matches!(
-            result,
+            parse_expr("config.load()?.len()").unwrap(),
+            Expr::MethodCall { receiver, .. } if matches!(*receiver, Expr::Try(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_method_call() {
+        let expr = parse_expr("a.b.len()").unwrap();
+        let Expr::MethodCall {
+            receiver,
+            method,
+            args,
+        } = expr
+        else {
+            panic!("Expected MethodCall");
+        };
+        assert_eq!(method, "len");
+        assert!(args.is_empty());
+        assert!(matches!(*receiver, Expr::Path(ref segments) if segments.len() == 2));
+
+        assert!(matches!(
+            parse_expr("a.collect::<Vec<_>>()"),
+            Err(EvalError::UnsupportedExpression { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unary_plus_is_noop() {
+        assert!(matches!(
+            parse_expr("+42").unwrap(),
+            Expr::Literal(Literal::Int(42))
+        ));
+        assert!(matches!(parse_expr("+x").unwrap(), Expr::Path(_)));
+
+        let expr = parse_expr("a - +b").unwrap();
+        let Expr::Binary { op, right, .. } = expr else {
+            panic!("Expected Binary");
+        };
+        assert_eq!(op, BinOp::Sub);
+        assert!(matches!(*right, Expr::Path(_)));
+
+        // Binary plus is untouched
+        assert!(matches!(
+            parse_expr("(a) + +(b)").unwrap(),
+            Expr::Binary { op: BinOp::Add, .. }
+        ));
+
+        let (_, notes) = parse_expr_with_notes("a - +b").unwrap();
+        assert_eq!(notes, [UNARY_PLUS_NOTE]);
+        let (_, notes) = parse_expr_with_notes("a + b").unwrap();
+        assert!(notes.is_empty());
+    }
+
+    #[test]
+    fn test_parse_range_index() {
+        let Expr::Path(segments) = parse_expr("s[1..3]").unwrap() else {
+            panic!("Expected Path");
+        };
+        assert!(matches!(
+            segments[1],
+            PathSegment::Range {
+                start: Some(1),
+                end: Some(3),
+                inclusive: false
+            }
+        ));
+
+        let Expr::Path(segments) = parse_expr("a.b[..=2]").unwrap() else {
+            panic!("Expected Path");
+        };
+        assert!(matches!(
+            segments[2],
+            PathSegment::Range {
+                start: None,
+                end: Some(2),
+                inclusive: true
+            }
+        ));
+
+        assert!(matches!(
+            parse_expr("s[i..]"),
             Err(EvalError::UnsupportedExpression { .. })
         ));
     }
+
+    #[test]
+    fn test_parse_char_range() {
+        let Expr::Range {
+            start,
+            end,
+            inclusive,
+        } = parse_expr("'a'..='z'").unwrap()
+        else {
+            panic!("Expected Range");
+        };
+        assert!(matches!(
+            start.as_deref(),
+            Some(Expr::Literal(Literal::Char('a')))
+        ));
+        assert!(matches!(
+            end.as_deref(),
+            Some(Expr::Literal(Literal::Char('z')))
+        ));
+        assert!(inclusive);
+
+        assert!(matches!(
+            parse_expr("..n").unwrap(),
+            Expr::Range {
+                start: None,
+                inclusive: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_chained_comparison_error() {
+        for input in ["1 < 2 < 3", "a == b == c"] {
+            let err = parse_expr(input).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Parse error: chained comparison is not allowed; parenthesize"
+            );
+        }
+        assert!(parse_expr("(1 < 2) == true").is_ok());
+    }
 }
@@ -0,0 +1,471 @@
+//! Evaluator variables from a snapshot
+//!
+//! Reads the JSON `load_snapshot` consumes, so expressions can be evaluated
+//! on captured state without compiling it in a REPL.
+
+use super::eval::VarContext;
+use super::value::Value;
+use crate::dwarf::{split_generic, split_top_level_commas};
+use serde_json::Value as Json;
+
+/// Build evaluator variables from a snapshot's `variables` object
+///
+/// `types` maps variable names to their Rust types, as the snapshot's own
+/// `types` object does (which is used for names missing from `types`).
+/// Type hints pick the integer width, `char` vs `String`, and whether an
+/// object is a map or a struct; without one, values get Rust's default
+/// types (`i32`, `f64`) and objects become structs.
+pub fn var_context_from_snapshot(json: &Json, types: &Json) -> VarContext {
+    let Some(variables) = json.get("variables").and_then(Json::as_object) else {
+        return VarContext::new();
+    };
+    variables
+        .iter()
+        .map(|(name, value)| {
+            let hint = types
+                .get(name)
+                .or_else(|| json.get("types").and_then(|types| types.get(name)))
+                .and_then(Json::as_str)
+                .unwrap_or("");
+            (name.clone(), value_from_snapshot_json(value, hint))
+        })
+        .collect()
+}
+
+/// Convert one snapshot value of type `hint` ("" when unknown), as
+/// `var_context_from_snapshot` does for each variable
+pub fn value_from_snapshot_json(json: &Json, hint: &str) -> Value {
+    json_to_value(json, hint)
+}
+
+fn json_to_value(json: &Json, hint: &str) -> Value {
+    let hint = hint.trim();
+    if let Some(kind) = json.get("__ferrumpy_kind__").and_then(Json::as_str) {
+        if let Some(value) = kind_to_value(json, kind, hint) {
+            return value;
+        }
+    }
+    if let Some((pointer, args)) = split_generic(hint) {
+        if matches!(last_segment(pointer), "Box" | "Rc" | "Arc") && args.len() == 1 {
+            return json_to_value(json, args[0]);
+        }
+    }
+
+    match json {
+        Json::Null => match split_generic(hint) {
+            Some((name, _)) if last_segment(name) == "Option" => none(),
+            _ => Value::Unit,
+        },
+        Json::Bool(b) => Value::Bool(*b),
+        Json::Number(_) => number_to_value(json, hint),
+        Json::String(s) => string_to_value(s, hint),
+        Json::Array(elements) => array_to_value(elements, hint),
+        Json::Object(object) => {
+            if let Some([key_type, value_type]) = map_types(hint) {
+                let entries = object
+                    .iter()
+                    .map(|(key, value)| {
+                        (
+                            string_to_value(key, key_type),
+                            json_to_value(value, value_type),
+                        )
+                    })
+                    .collect();
+                return Value::Map(entries);
+            }
+            let fields = object
+                .iter()
+                .map(|(name, value)| (name.clone(), json_to_value(value, "")))
+                .collect();
+            Value::Struct {
+                type_name: type_name(hint),
+                fields,
+            }
+        }
+    }
+}
+
+/// Convert a value the serializer tagged with `__ferrumpy_kind__`, or
+/// `None` if its metadata is incomplete
+fn kind_to_value(json: &Json, kind: &str, hint: &str) -> Option<Value> {
+    let args = split_generic(hint)
+        .map(|(_, args)| args)
+        .unwrap_or_default();
+    let arg = |i: usize| args.get(i).copied().unwrap_or("");
+    match kind {
+        "option" | "result" => {
+            let variant = json.get("__variant__")?.as_str()?;
+            let payload_type = match variant {
+                "Some" | "Ok" => arg(0),
+                "Err" => arg(1),
+                "None" => return Some(none()),
+                _ => return None,
+            };
+            Some(Value::Enum {
+                enum_name: if kind == "option" { "Option" } else { "Result" }.to_string(),
+                variant: variant.to_string(),
+                payload: json
+                    .get("__inner__")
+                    .map(|inner| Box::new(json_to_value(inner, payload_type))),
+            })
+        }
+        "tuple" | "array" => {
+            let elements = json.get("__elements__")?.as_array()?;
+            Some(array_to_value(elements, hint))
+        }
+        "arc" | "rc" | "box" => Some(json_to_value(json.get("__inner__")?, arg(0))),
+        "enum" => {
+            let variant = json.get("__variant__")?.as_str()?;
+            let enum_name = json
+                .get("__enum_type__")
+                .and_then(Json::as_str)
+                .map(str::to_string)
+                .unwrap_or_else(|| type_name(hint));
+            let payload = match json.get("__payload__") {
+                None | Some(Json::Null) => None,
+                Some(payload) => Some(Box::new(json_to_value(payload, ""))),
+            };
+            Some(Value::Enum {
+                enum_name,
+                variant: variant.to_string(),
+                payload,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Integer of the hinted width, falling back to the widest type holding it
+fn number_to_value(json: &Json, hint: &str) -> Value {
+    macro_rules! int {
+        ($variant:ident) => {
+            if let Some(v) = json
+                .as_i64()
+                .and_then(|n| n.try_into().ok())
+                .or_else(|| json.as_u64().and_then(|n| n.try_into().ok()))
+            {
+                return Value::$variant(v);
+            }
+        };
+    }
+    match hint {
+        "i8" => int!(I8),
+        "i16" => int!(I16),
+        "i32" => int!(I32),
+        "i64" => int!(I64),
+        "i128" => int!(I128),
+        "isize" => int!(Isize),
+        "u8" => int!(U8),
+        "u16" => int!(U16),
+        "u32" => int!(U32),
+        "u64" => int!(U64),
+        "u128" => int!(U128),
+        "usize" => int!(Usize),
+        "f32" => {
+            if let Some(f) = json.as_f64() {
+                return Value::F32(f as f32);
+            }
+        }
+        _ => {}
+    }
+    if let Some(n) = json.as_i64() {
+        return i32::try_from(n).map_or(Value::I64(n), Value::I32);
+    }
+    if let Some(n) = json.as_u64() {
+        return Value::U64(n);
+    }
+    Value::F64(json.as_f64().unwrap_or(f64::NAN))
+}
+
+/// String, or a value stored as one: a `char`, a map key, an integer too
+/// large for JSON, or a non-finite float
+fn string_to_value(s: &str, hint: &str) -> Value {
+    let mut chars = s.chars();
+    match (hint, chars.next(), chars.next()) {
+        ("char", Some(c), None) => return Value::Char(c),
+        ("bool", ..) => {
+            if let Ok(b) = s.parse() {
+                return Value::Bool(b);
+            }
+        }
+        // `parse` takes the `NaN`, `inf` and `infinity` the serializer writes
+        ("f32" | "f64", ..) => {
+            if let Ok(f) = s.trim().parse::<f64>() {
+                return if hint == "f32" {
+                    Value::F32(f as f32)
+                } else {
+                    Value::F64(f)
+                };
+            }
+        }
+        ("i128", ..) => {
+            if let Ok(n) = s.parse() {
+                return Value::I128(n);
+            }
+        }
+        ("u128", ..) => {
+            if let Ok(n) = s.parse() {
+                return Value::U128(n);
+            }
+        }
+        (hint, ..) if is_integer_type(hint) => {
+            if let Ok(n) = serde_json::from_str::<Json>(s) {
+                if n.is_number() {
+                    return number_to_value(&n, hint);
+                }
+            }
+        }
+        _ => {}
+    }
+    Value::String(s.to_string())
+}
+
+/// Array, tuple, set or map given as `[key, value]` pairs
+fn array_to_value(elements: &[Json], hint: &str) -> Value {
+    if let Some([key_type, value_type]) = map_types(hint) {
+        let pairs: Option<Vec<_>> = elements
+            .iter()
+            .map(|pair| match pair.as_array().map(Vec::as_slice) {
+                Some([key, value]) => Some((
+                    json_to_value(key, key_type),
+                    json_to_value(value, value_type),
+                )),
+                _ => None,
+            })
+            .collect();
+        if let Some(pairs) = pairs {
+            return Value::Map(pairs);
+        }
+    }
+
+    let tuple_types = hint
+        .strip_prefix('(')
+        .and_then(|inner| inner.strip_suffix(')'))
+        .and_then(split_top_level_commas)
+        .unwrap_or_default();
+    let element_type = element_type(hint);
+    Value::Array(
+        elements
+            .iter()
+            .enumerate()
+            .map(|(i, element)| {
+                let hint = tuple_types.get(i).copied().unwrap_or(element_type);
+                json_to_value(element, hint)
+            })
+            .collect(),
+    )
+}
+
+/// Element type of a `Vec`, slice, array, set or deque type, or ""
+fn element_type(hint: &str) -> &str {
+    let hint = hint.trim_start_matches('&').trim();
+    if let Some(inner) = hint.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return inner.split(';').next().unwrap_or("").trim();
+    }
+    match split_generic(hint) {
+        Some((name, args))
+            if args.len() == 1
+                && matches!(
+                    last_segment(name),
+                    "Vec" | "VecDeque" | "HashSet" | "BTreeSet"
+                ) =>
+        {
+            args[0]
+        }
+        _ => "",
+    }
+}
+
+/// Key and value types of a `HashMap` or `BTreeMap` type
+fn map_types(hint: &str) -> Option<[&str; 2]> {
+    let (name, args) = split_generic(hint)?;
+    match (last_segment(name), args.as_slice()) {
+        ("HashMap" | "BTreeMap", [key, value, ..]) => Some([key, value]),
+        _ => None,
+    }
+}
+
+/// Struct name for a type hint: its last path segment, without generics
+fn type_name(hint: &str) -> String {
+    let name = hint.split('<').next().unwrap_or("");
+    last_segment(name).to_string()
+}
+
+fn last_segment(path: &str) -> &str {
+    path.trim().rsplit("::").next().unwrap_or("")
+}
+
+fn is_integer_type(hint: &str) -> bool {
+    matches!(
+        hint,
+        "i8" | "i16" | "i32" | "i64" | "isize" | "u8" | "u16" | "u32" | "u64" | "usize"
+    )
+}
+
+fn none() -> Value {
+    Value::Enum {
+        enum_name: "Option".to_string(),
+        variant: "None".to_string(),
+        payload: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::{parse_expr, EvalError, Evaluator};
+
+    fn snapshot() -> Json {
+        serde_json::json!({
+            "variables": {
+                "count": 3,
+                "big": "340282366920938463463374607431768211455",
+                "initial": "x",
+                "user": {
+                    "name": "Ann",
+                    "tags": ["a", "b"],
+                    "address": {"city": "Oslo", "zip": 150}
+                },
+                "scores": {"ann": [1, 2], "bob": []},
+                "by_id": {"1": "one", "2": "two"},
+                "pair": [1, "two"],
+                "maybe": {"__ferrumpy_kind__": "option", "__variant__": "Some", "__inner__": 5},
+                "nothing": null,
+                "status": {
+                    "__ferrumpy_kind__": "enum",
+                    "__enum_type__": "Status",
+                    "__variant__": "Active",
+                    "__payload__": null
+                }
+            },
+            "types": {"count": "u8"}
+        })
+    }
+
+    fn types() -> Json {
+        serde_json::json!({
+            "big": "u128",
+            "initial": "char",
+            "user": "app::User",
+            "scores": "HashMap<String, Vec<i64>>",
+            "by_id": "BTreeMap<u32, String>",
+            "pair": "(i32, String)",
+            "maybe": "Option<u16>",
+            "nothing": "Option<String>",
+            "status": "Status"
+        })
+    }
+
+    #[test]
+    fn test_snapshot_primitives() {
+        let vars = var_context_from_snapshot(&snapshot(), &types());
+        // The snapshot's own types fill in for names missing from `types`
+        assert!(
+            matches!(vars["count"], Value::U8(3)),
+            "Got: {:?}",
+            vars["count"]
+        );
+        assert!(
+            matches!(vars["big"], Value::U128(u128::MAX)),
+            "Got: {:?}",
+            vars["big"]
+        );
+        assert!(
+            matches!(vars["initial"], Value::Char('x')),
+            "Got: {:?}",
+            vars["initial"]
+        );
+        assert_eq!(vars["maybe"].to_string(), "Some(5)");
+        assert!(
+            matches!(vars["maybe"], Value::Enum { payload: Some(ref p), .. } if matches!(**p, Value::U16(5)))
+        );
+        assert_eq!(vars["nothing"].to_string(), "None");
+        assert_eq!(vars["status"].to_string(), "Status::Active");
+    }
+
+    #[test]
+    fn test_compare_restored_enum_to_variant_path() {
+        let vars = var_context_from_snapshot(&snapshot(), &types());
+        let evaluator = Evaluator::with_variables(vars);
+        let eval = |input: &str| evaluator.eval(&parse_expr(input).unwrap());
+
+        assert!(matches!(
+            eval("status == Status::Active"),
+            Ok(Value::Bool(true))
+        ));
+        assert!(matches!(
+            eval("status != Status::Active"),
+            Ok(Value::Bool(false))
+        ));
+        assert!(matches!(
+            eval("nothing == Option::None"),
+            Ok(Value::Bool(true))
+        ));
+        // Variants never seen in a value are unknown
+        assert!(matches!(
+            eval("status == Status::Inactive"),
+            Err(EvalError::UnknownVariable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_nested_structures() {
+        let vars = var_context_from_snapshot(&snapshot(), &types());
+        assert!(
+            matches!(vars["user"], Value::Struct { ref type_name, .. } if type_name == "User"),
+            "Got: {:?}",
+            vars["user"]
+        );
+        assert!(matches!(vars["scores"], Value::Map(_)));
+        assert!(matches!(vars["pair"], Value::Array(ref e) if e.len() == 2));
+        assert_eq!(vars["by_id"].to_string(), "{1: \"one\", 2: \"two\"}");
+
+        // Containers convert back to the JSON they came from
+        let snapshot = snapshot();
+        for name in ["user", "scores", "pair"] {
+            assert_eq!(
+                vars[name].to_json(),
+                snapshot["variables"][name],
+                "{}",
+                name
+            );
+        }
+        // except maps with integer keys, which serde writes as pairs
+        assert_eq!(
+            vars["by_id"].to_json(),
+            serde_json::json!([[1, "one"], [2, "two"]])
+        );
+    }
+
+    #[test]
+    fn test_evaluate_on_snapshot() {
+        let evaluator = Evaluator::with_variables(var_context_from_snapshot(&snapshot(), &types()));
+        let eval = |input: &str| {
+            evaluator
+                .eval(&parse_expr(input).unwrap())
+                .map(|v| v.to_string())
+        };
+        assert_eq!(eval("user.address.city").unwrap(), "\"Oslo\"");
+        assert_eq!(eval("user.address.zip + 1").unwrap(), "151");
+        assert_eq!(eval("user.tags[1]").unwrap(), "\"b\"");
+        assert_eq!(eval("pair.1").unwrap(), "\"two\"");
+        assert!(matches!(
+            evaluator.eval(&parse_expr("user.age").unwrap()),
+            Err(crate::expr::EvalError::FieldNotFound { ref field, ref type_name })
+                if field == "age" && type_name == "User"
+        ));
+        assert!(matches!(
+            evaluator.eval(&parse_expr("user.tags[5]").unwrap()),
+            Err(crate::expr::EvalError::IndexOutOfBounds {
+                index: 5,
+                length: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_without_variables() {
+        let vars = var_context_from_snapshot(&serde_json::json!({}), &serde_json::json!({}));
+        assert!(vars.is_empty());
+    }
+}
@@ -38,8 +38,28 @@ pub enum Value {
     // Unit
     Unit,
 
+    // Collections: Vec/slice/array elements, and map entries in order
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+
+    // Struct with named fields, in declaration order
+    Struct {
+        type_name: String,
+        fields: Vec<(String, Value)>,
+    },
+
+    // Enum variant: Status::Active, Some(5), Err("x")
+    Enum {
+        enum_name: String,
+        variant: String,
+        payload: Option<Box<Value>>,
+    },
+
     // Reference to complex type (handle to SBValue)
-    Ref { address: u64, type_name: String },
+    Ref {
+        address: u64,
+        type_name: String,
+    },
 }
 
 impl Value {
@@ -64,6 +84,10 @@ impl Value {
             Value::Char(_) => "char",
             Value::String(_) => "String",
             Value::Unit => "()",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Struct { .. } => "struct",
+            Value::Enum { .. } => "enum",
             Value::Ref { .. } => "ref",
         }
     }
@@ -121,6 +145,19 @@ impl Value {
         )
     }
 
+    /// Bit width of an integer type
+    pub fn bit_width(&self) -> Option<u32> {
+        match self {
+            Value::I8(_) | Value::U8(_) => Some(8),
+            Value::I16(_) | Value::U16(_) => Some(16),
+            Value::I32(_) | Value::U32(_) => Some(32),
+            Value::I64(_) | Value::U64(_) => Some(64),
+            Value::I128(_) | Value::U128(_) => Some(128),
+            Value::Isize(_) | Value::Usize(_) => Some(usize::BITS),
+            _ => None,
+        }
+    }
+
     /// Convert to i128 if integer
     pub fn to_i128(&self) -> Option<i128> {
         match self {
@@ -140,6 +177,58 @@ impl Value {
         }
     }
 
+    /// Convert to another primitive type without losing information.
+    ///
+    /// Integers convert to any integer type they fit in, and to floats when
+    /// exactly representable; floats only widen (or narrow when exact).
+    /// Returns `None` for impossible coercions such as negative → unsigned.
+    pub fn coerce_to(&self, type_name: &str) -> Option<Value> {
+        let type_name = type_name.trim();
+        if self.type_name() == type_name {
+            return Some(self.clone());
+        }
+
+        if self.is_integer() {
+            // u128 values above i128::MAX only fit in u128 itself
+            let Some(v) = self.to_i128() else {
+                return None;
+            };
+            return match type_name {
+                "i8" => i8::try_from(v).ok().map(Value::I8),
+                "i16" => i16::try_from(v).ok().map(Value::I16),
+                "i32" => i32::try_from(v).ok().map(Value::I32),
+                "i64" => i64::try_from(v).ok().map(Value::I64),
+                "i128" => Some(Value::I128(v)),
+                "isize" => isize::try_from(v).ok().map(Value::Isize),
+                "u8" => u8::try_from(v).ok().map(Value::U8),
+                "u16" => u16::try_from(v).ok().map(Value::U16),
+                "u32" => u32::try_from(v).ok().map(Value::U32),
+                "u64" => u64::try_from(v).ok().map(Value::U64),
+                "u128" => u128::try_from(v).ok().map(Value::U128),
+                "usize" => usize::try_from(v).ok().map(Value::Usize),
+                "f32" => {
+                    let f = v as f32;
+                    (f as i128 == v).then_some(Value::F32(f))
+                }
+                "f64" => {
+                    let f = v as f64;
+                    (f as i128 == v).then_some(Value::F64(f))
+                }
+                _ => None,
+            };
+        }
+
+        match (self, type_name) {
+            (Value::F32(v), "f64") => Some(Value::F64(*v as f64)),
+            (Value::F64(v), "f32") => {
+                let f = *v as f32;
+                (f as f64 == *v || v.is_nan()).then_some(Value::F32(f))
+            }
+            (Value::String(_), "&str") => Some(self.clone()),
+            _ => None,
+        }
+    }
+
     /// Convert to f64 if floating point
     pub fn to_f64(&self) -> Option<f64> {
         match self {
@@ -156,6 +245,98 @@ impl Value {
             _ => None,
         }
     }
+
+    /// Element count of an array or map, or byte length of a string
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            Value::Array(elements) => Some(elements.len()),
+            Value::Map(entries) => Some(entries.len()),
+            Value::String(s) => Some(s.len()),
+            _ => None,
+        }
+    }
+
+    /// Convert to JSON, in the form serde gives the corresponding Rust value
+    ///
+    /// Enums are externally tagged, `Option` and `Result` included:
+    /// `{"Some": 5}`, `"None"`. Integers beyond JSON's 64-bit range and maps
+    /// with non-string keys (as `[key, value]` pairs) follow serde_json too.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::Value as Json;
+        match self {
+            Value::I8(v) => Json::from(*v),
+            Value::I16(v) => Json::from(*v),
+            Value::I32(v) => Json::from(*v),
+            Value::I64(v) => Json::from(*v),
+            Value::I128(v) => i64::try_from(*v)
+                .map(Json::from)
+                .unwrap_or_else(|_| Json::String(v.to_string())),
+            Value::Isize(v) => Json::from(*v),
+            Value::U8(v) => Json::from(*v),
+            Value::U16(v) => Json::from(*v),
+            Value::U32(v) => Json::from(*v),
+            Value::U64(v) => Json::from(*v),
+            Value::U128(v) => u64::try_from(*v)
+                .map(Json::from)
+                .unwrap_or_else(|_| Json::String(v.to_string())),
+            Value::Usize(v) => Json::from(*v),
+            // NaN and infinities have no JSON number, serde_json writes null
+            Value::F32(v) => Json::from(*v),
+            Value::F64(v) => Json::from(*v),
+            Value::Bool(v) => Json::Bool(*v),
+            Value::Char(v) => Json::String(v.to_string()),
+            Value::String(v) => Json::String(v.clone()),
+            Value::Unit => Json::Null,
+            Value::Array(elements) => Json::Array(elements.iter().map(Value::to_json).collect()),
+            Value::Map(entries) => {
+                if entries
+                    .iter()
+                    .all(|(key, _)| matches!(key, Value::String(_)))
+                {
+                    let map = entries
+                        .iter()
+                        .map(|(key, value)| (key.to_string_key(), value.to_json()))
+                        .collect();
+                    Json::Object(map)
+                } else {
+                    Json::Array(
+                        entries
+                            .iter()
+                            .map(|(key, value)| Json::Array(vec![key.to_json(), value.to_json()]))
+                            .collect(),
+                    )
+                }
+            }
+            Value::Struct { fields, .. } => Json::Object(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.to_json()))
+                    .collect(),
+            ),
+            Value::Enum {
+                variant, payload, ..
+            } => match payload {
+                None => Json::String(variant.clone()),
+                Some(payload) => {
+                    let mut map = serde_json::Map::new();
+                    map.insert(variant.clone(), payload.to_json());
+                    Json::Object(map)
+                }
+            },
+            Value::Ref { address, type_name } => serde_json::json!({
+                "type": type_name,
+                "address": address,
+            }),
+        }
+    }
+
+    /// Text of a string map key, without the quotes `Display` adds
+    fn to_string_key(&self) -> String {
+        match self {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -179,6 +360,57 @@ impl fmt::Display for Value {
             Value::Char(v) => write!(f, "'{}'", v),
             Value::String(v) => write!(f, "\"{}\"", v),
             Value::Unit => write!(f, "()"),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Struct { type_name, fields } => {
+                if !type_name.is_empty() {
+                    write!(f, "{} ", type_name)?;
+                }
+                if fields.is_empty() {
+                    return write!(f, "{{}}");
+                }
+                write!(f, "{{ ")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", name, value)?;
+                }
+                write!(f, " }}")
+            }
+            Value::Enum {
+                enum_name,
+                variant,
+                payload,
+            } => {
+                // `Some(5)` and `Err(e)` read better without their prelude type
+                if enum_name != "Option" && enum_name != "Result" {
+                    write!(f, "{}::", enum_name)?;
+                }
+                write!(f, "{}", variant)?;
+                match payload {
+                    Some(payload) => write!(f, "({})", payload),
+                    None => Ok(()),
+                }
+            }
             Value::Ref { type_name, address } => write!(f, "&{} @ 0x{:x}", type_name, address),
         }
     }
@@ -203,5 +435,104 @@ mod tests {
             format!("{}", Value::String("hello".to_string())),
             "\"hello\""
         );
+        assert_eq!(
+            format!("{}", Value::Array(vec![Value::I32(1), Value::I32(2)])),
+            "[1, 2]"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Value::Map(vec![(Value::String("a".to_string()), Value::Bool(true))])
+            ),
+            "{\"a\": true}"
+        );
+    }
+
+    fn variant(enum_name: &str, variant: &str, payload: Option<Value>) -> Value {
+        Value::Enum {
+            enum_name: enum_name.to_string(),
+            variant: variant.to_string(),
+            payload: payload.map(Box::new),
+        }
+    }
+
+    #[test]
+    fn test_enum_display() {
+        assert_eq!(variant("Option", "None", None).to_string(), "None");
+        assert_eq!(
+            variant("Option", "Some", Some(Value::I32(5))).to_string(),
+            "Some(5)"
+        );
+        assert_eq!(
+            variant("Result", "Err", Some(Value::String("bad".to_string()))).to_string(),
+            "Err(\"bad\")"
+        );
+        assert_eq!(
+            variant("Status", "Active", None).to_string(),
+            "Status::Active"
+        );
+    }
+
+    #[test]
+    fn test_enum_to_json() {
+        assert_eq!(
+            variant("Option", "None", None).to_json(),
+            serde_json::json!("None")
+        );
+        assert_eq!(
+            variant("Option", "Some", Some(Value::I32(5))).to_json(),
+            serde_json::json!({"Some": 5})
+        );
+        assert_eq!(
+            variant("Result", "Err", Some(Value::String("bad".to_string()))).to_json(),
+            serde_json::json!({"Err": "bad"})
+        );
+        assert_eq!(
+            Value::Array(vec![
+                variant("Result", "Ok", Some(Value::Unit)),
+                Value::U128(u128::MAX),
+            ])
+            .to_json(),
+            serde_json::json!([{"Ok": null}, u128::MAX.to_string()])
+        );
+        assert_eq!(
+            Value::Map(vec![(Value::String("a".to_string()), Value::Char('x'))]).to_json(),
+            serde_json::json!({"a": "x"})
+        );
+    }
+
+    #[test]
+    fn test_coerce_lossless() {
+        assert!(matches!(
+            Value::I64(42).coerce_to("usize"),
+            Some(Value::Usize(42))
+        ));
+        assert!(matches!(
+            Value::I32(-1).coerce_to("i8"),
+            Some(Value::I8(-1))
+        ));
+        assert!(matches!(
+            Value::U8(255).coerce_to("i16"),
+            Some(Value::I16(255))
+        ));
+        assert!(matches!(
+            Value::U128(u128::MAX).coerce_to("u128"),
+            Some(Value::U128(u128::MAX))
+        ));
+        assert!(matches!(Value::I64(3).coerce_to("f64"), Some(Value::F64(v)) if v == 3.0));
+        assert!(matches!(Value::F32(1.5).coerce_to("f64"), Some(Value::F64(v)) if v == 1.5));
+        assert!(matches!(Value::F64(0.5).coerce_to("f32"), Some(Value::F32(v)) if v == 0.5));
+    }
+
+    #[test]
+    fn test_coerce_rejected() {
+        assert!(Value::I64(-1).coerce_to("usize").is_none());
+        assert!(Value::I32(256).coerce_to("u8").is_none());
+        assert!(Value::U128(u128::MAX).coerce_to("i128").is_none());
+        assert!(Value::I64(i64::MAX).coerce_to("f64").is_none());
+        assert!(Value::F64(1.5).coerce_to("i32").is_none());
+        assert!(Value::F64(0.1).coerce_to("f32").is_none());
+        assert!(Value::Bool(true).coerce_to("u8").is_none());
+        assert!(Value::I32(1).coerce_to("Vec<i32>").is_none());
     }
 }
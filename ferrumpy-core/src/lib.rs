@@ -19,7 +19,7 @@ pub mod repl;
 #[cfg(feature = "python")]
 mod python;
 
-pub use expr::{parse_expr, EvalError, Evaluator, Expr, Value};
+pub use expr::{format_expr, parse_expr, EvalError, Evaluator, Expr, Value};
 pub use libgen::{generate_lib, GeneratedLib, LibGenConfig};
 pub use lsp::CompletionItem;
 pub use protocol::{Request, Response};
@@ -0,0 +1,86 @@
+//! Guards for recursively copying path dependency sources
+//!
+//! Crate directories can contain build output, VCS metadata, symlink loops and
+//! large generated fixtures. The guard keeps a copy to the files that matter
+//! and stops it before it runs away.
+
+use anyhow::Result;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// Directories never copied or scanned
+const IGNORED_DIRS: &[&str] = &["target", ".git"];
+
+/// Maximum directory nesting below the copied root
+const MAX_COPY_DEPTH: usize = 32;
+
+/// Default for `LibGenConfig::max_copy_bytes`
+pub const DEFAULT_MAX_COPY_BYTES: u64 = 256 * 1024 * 1024;
+
+/// State of one recursive copy: visited directories, depth and bytes copied
+pub struct CopyGuard {
+    /// Canonical paths of the directories entered so far
+    visited: HashSet<PathBuf>,
+    depth: usize,
+    copied_bytes: u64,
+    max_bytes: u64,
+}
+
+impl CopyGuard {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            visited: HashSet::new(),
+            depth: 0,
+            copied_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Whether a directory entry name is skipped
+    pub fn is_ignored(name: &OsStr) -> bool {
+        IGNORED_DIRS.iter().any(|ignored| name == *ignored)
+    }
+
+    /// Start copying `dir`. Returns false for ignored directories and ones
+    /// already entered (a symlink cycle); pair a true result with [`leave`].
+    ///
+    /// [`leave`]: CopyGuard::leave
+    pub fn enter(&mut self, dir: &Path) -> Result<bool> {
+        if dir.file_name().is_some_and(Self::is_ignored) {
+            return Ok(false);
+        }
+        if self.depth >= MAX_COPY_DEPTH {
+            anyhow::bail!(
+                "Not copying {}: nested more than {} directories deep",
+                dir.display(),
+                MAX_COPY_DEPTH
+            );
+        }
+        if !self.visited.insert(dir.canonicalize()?) {
+            if std::env::var("FERRUMPY_DEBUG").is_ok() {
+                eprintln!("[libgen] Skipping already copied directory {:?}", dir);
+            }
+            return Ok(false);
+        }
+        self.depth += 1;
+        Ok(true)
+    }
+
+    pub fn leave(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    /// Account for a file of `bytes` copied from `dir`
+    pub fn charge(&mut self, dir: &Path, bytes: u64) -> Result<()> {
+        self.copied_bytes = self.copied_bytes.saturating_add(bytes);
+        if self.copied_bytes > self.max_bytes {
+            anyhow::bail!(
+                "Copying {} exceeds the size limit of {} bytes for a path dependency (raise max_copy_bytes to allow it)",
+                dir.display(),
+                self.max_bytes
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+//! Line diffs between a generated crate and an existing one
+//!
+//! Used to review a dry run before regenerating a snapshot crate.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Unchanged lines shown around each change
+const CONTEXT_LINES: usize = 3;
+
+enum Op<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// Produce a unified-diff-like report of the files that differ from (or are
+/// missing in) `existing_dir`. `files` holds paths relative to the crate root.
+pub fn diff_against(existing_dir: &Path, files: &[(PathBuf, String)]) -> String {
+    let mut report = String::new();
+
+    for (path, new) in files {
+        let old = fs::read_to_string(existing_dir.join(path)).ok();
+        if old.as_deref() == Some(new.as_str()) {
+            continue;
+        }
+
+        let old_name = match old {
+            Some(_) => format!("a/{}", path.display()),
+            None => "/dev/null".to_string(),
+        };
+        report.push_str(&format!("--- {}\n+++ b/{}\n", old_name, path.display()));
+
+        let old_lines: Vec<&str> = old.as_deref().unwrap_or("").lines().collect();
+        let new_lines: Vec<&str> = new.lines().collect();
+        report.push_str(&hunks(&line_ops(&old_lines, &new_lines)));
+    }
+
+    report
+}
+
+/// Edit script turning `old` into `new`, from their longest common subsequence
+fn line_ops<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Op<'a>> {
+    let (n, m) = (old.len(), new.len());
+
+    // lcs[i][j]: length of the LCS of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            ops.push(Op::Equal(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            ops.push(Op::Delete(old[i]));
+            i += 1;
+        } else {
+            ops.push(Op::Insert(new[j]));
+            j += 1;
+        }
+    }
+    ops.extend(old[i..].iter().map(|l| Op::Delete(l)));
+    ops.extend(new[j..].iter().map(|l| Op::Insert(l)));
+    ops
+}
+
+/// Render the changes of an edit script as `@@` hunks with context
+fn hunks(ops: &[Op]) -> String {
+    // (old, new) line index before each op
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for op in ops {
+        positions.push((old_pos, new_pos));
+        match op {
+            Op::Equal(_) => {
+                old_pos += 1;
+                new_pos += 1;
+            }
+            Op::Delete(_) => old_pos += 1,
+            Op::Insert(_) => new_pos += 1,
+        }
+    }
+
+    let changes: Vec<usize> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Equal(_)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut out = String::new();
+    let mut k = 0;
+    while k < changes.len() {
+        // Changes closer than twice the context share a hunk
+        let first = changes[k];
+        let mut last = first;
+        k += 1;
+        while k < changes.len() && changes[k] - last <= 2 * CONTEXT_LINES + 1 {
+            last = changes[k];
+            k += 1;
+        }
+
+        let start = first.saturating_sub(CONTEXT_LINES);
+        let end = (last + CONTEXT_LINES + 1).min(ops.len());
+        let hunk = &ops[start..end];
+        let old_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, Op::Insert(_)))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, Op::Delete(_)))
+            .count();
+        let (old_start, new_start) = positions[start];
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + 1,
+            old_len,
+            new_start + 1,
+            new_len
+        ));
+
+        for op in hunk {
+            let (prefix, line) = match op {
+                Op::Equal(l) => (' ', l),
+                Op::Delete(l) => ('-', l),
+                Op::Insert(l) => ('+', l),
+            };
+            out.push(prefix);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_against_reports_changed_and_new_files() {
+        let temp = tempfile::TempDir::new().unwrap();
+        fs::create_dir_all(temp.path().join("src")).unwrap();
+        let old: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+        fs::write(temp.path().join("src/lib.rs"), &old).unwrap();
+        fs::write(temp.path().join("Cargo.toml"), "[package]\n").unwrap();
+
+        let files = vec![
+            (PathBuf::from("Cargo.toml"), "[package]\n".to_string()),
+            (
+                PathBuf::from("src/lib.rs"),
+                old.replace("line 5\n", "line five\n"),
+            ),
+            (PathBuf::from("src/new.rs"), "pub struct New;\n".to_string()),
+        ];
+        let report = diff_against(temp.path(), &files);
+
+        assert!(!report.contains("Cargo.toml"), "Got: {}", report);
+        assert!(
+            report.contains(
+                "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -2,7 +2,7 @@\n line 2\n line 3\n line 4\n-line 5\n+line five\n line 6\n"
+            ),
+            "Got: {}",
+            report
+        );
+        assert!(
+            report.contains("--- /dev/null\n+++ b/src/new.rs\n@@ -1,0 +1,1 @@\n+pub struct New;\n"),
+            "Got: {}",
+            report
+        );
+    }
+}
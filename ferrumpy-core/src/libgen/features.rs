@@ -0,0 +1,313 @@
+//! Cargo feature resolution
+//!
+//! The generated snapshot crate declares no features of its own, so the
+//! features the debugged binary was built with are resolved here and applied
+//! up front: `#[cfg(feature = ...)]` items are kept or dropped, and features
+//! forwarded to dependencies are written into their specs.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use quote::ToTokens;
+use syn::{punctuated::Punctuated, Attribute, Meta, Token};
+
+/// Features active for a crate, resolved from its `[features]` table
+#[derive(Debug, Clone, Default)]
+pub struct FeatureSet {
+    /// Active features of the crate itself
+    pub features: HashSet<String>,
+    /// Features forwarded to dependencies (`dep/feature`, `dep?/feature`)
+    pub dep_features: BTreeMap<String, BTreeSet<String>>,
+    /// Optional dependencies enabled by an active feature
+    pub enabled_deps: HashSet<String>,
+}
+
+impl FeatureSet {
+    /// Resolve the transitive closure of `requested` (plus `default` unless
+    /// `no_default_features`) over a manifest's `[features]` table
+    pub fn resolve(
+        manifest: &toml::Value,
+        requested: &[String],
+        no_default_features: bool,
+    ) -> Self {
+        let table = manifest.get("features").and_then(|f| f.as_table());
+        let optional_deps: HashSet<&str> = manifest
+            .get("dependencies")
+            .and_then(|d| d.as_table())
+            .map(|deps| {
+                deps.iter()
+                    .filter(|(_, v)| v.get("optional").and_then(|o| o.as_bool()) == Some(true))
+                    .map(|(k, _)| k.as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut set = FeatureSet::default();
+        let mut pending: Vec<String> = requested.to_vec();
+        if !no_default_features && table.is_some_and(|t| t.contains_key("default")) {
+            pending.push("default".to_string());
+        }
+
+        while let Some(feature) = pending.pop() {
+            if let Some((dep, dep_feature)) = feature.split_once('/') {
+                // `dep?/feature` forwards without enabling an optional dep
+                let (dep, weak) = match dep.strip_suffix('?') {
+                    Some(dep) => (dep, true),
+                    None => (dep, false),
+                };
+                if !weak && optional_deps.contains(dep) {
+                    set.enabled_deps.insert(dep.to_string());
+                }
+                set.dep_features
+                    .entry(dep.to_string())
+                    .or_default()
+                    .insert(dep_feature.to_string());
+                continue;
+            }
+            if let Some(dep) = feature.strip_prefix("dep:") {
+                set.enabled_deps.insert(dep.to_string());
+                continue;
+            }
+            if !set.features.insert(feature.clone()) {
+                continue;
+            }
+            // Optional deps define an implicit feature of the same name
+            if optional_deps.contains(feature.as_str()) {
+                set.enabled_deps.insert(feature.clone());
+            }
+            if let Some(implied) = table
+                .and_then(|t| t.get(&feature))
+                .and_then(|v| v.as_array())
+            {
+                pending.extend(implied.iter().filter_map(|v| v.as_str()).map(String::from));
+            }
+        }
+
+        // Weak forwards only apply if the dependency ends up enabled
+        set.dep_features.retain(|dep, _| {
+            !optional_deps.contains(dep.as_str()) || set.enabled_deps.contains(dep)
+        });
+        set
+    }
+
+    /// Adjust a dependency spec for the active features.
+    /// Returns `None` for optional dependencies that no active feature enables.
+    pub fn apply_to_dependency(&self, name: &str, value: &toml::Value) -> Option<toml::Value> {
+        let forwarded = self.dep_features.get(name);
+        match value {
+            toml::Value::Table(t) => {
+                let optional = t.get("optional").and_then(|o| o.as_bool()) == Some(true);
+                if optional && !self.enabled_deps.contains(name) {
+                    return None;
+                }
+                let mut t = t.clone();
+                t.remove("optional");
+                if let Some(forwarded) = forwarded {
+                    let mut features: Vec<toml::Value> = t
+                        .get("features")
+                        .and_then(|f| f.as_array())
+                        .cloned()
+                        .unwrap_or_default();
+                    for feature in forwarded {
+                        if !features.iter().any(|f| f.as_str() == Some(feature)) {
+                            features.push(toml::Value::String(feature.clone()));
+                        }
+                    }
+                    t.insert("features".to_string(), toml::Value::Array(features));
+                }
+                Some(toml::Value::Table(t))
+            }
+            toml::Value::String(version) if forwarded.is_some() => {
+                let mut t = toml::value::Table::new();
+                t.insert("version".to_string(), toml::Value::String(version.clone()));
+                t.insert(
+                    "features".to_string(),
+                    toml::Value::Array(
+                        forwarded
+                            .into_iter()
+                            .flatten()
+                            .map(|f| toml::Value::String(f.clone()))
+                            .collect(),
+                    ),
+                );
+                Some(toml::Value::Table(t))
+            }
+            _ => Some(value.clone()),
+        }
+    }
+
+    /// Evaluate a `#[cfg(...)]` attribute against the active features.
+    ///
+    /// Returns `None` when the attribute isn't a cfg or depends on predicates
+    /// other than features (`unix`, `test`, ...), which are left to rustc.
+    pub fn eval_cfg(&self, attr: &Attribute) -> Option<bool> {
+        eval_cfg_with(attr, &|meta| match meta {
+            Meta::NameValue(nv) if nv.path.is_ident("feature") => {
+                let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(s),
+                    ..
+                }) = &nv.value
+                else {
+                    return None;
+                };
+                Some(self.features.contains(&s.value()))
+            }
+            _ => None,
+        })
+    }
+
+    /// Apply feature cfgs to an attribute list: `false` if the owner should be
+    /// removed, otherwise strips the cfgs that evaluated to true
+    pub fn retain_attrs(&self, attrs: &mut Vec<Attribute>) -> bool {
+        let mut keep = true;
+        attrs.retain(|attr| match self.eval_cfg(attr) {
+            Some(true) => false,
+            Some(false) => {
+                keep = false;
+                true
+            }
+            None => true,
+        });
+        keep
+    }
+}
+
+/// Evaluate a `#[cfg(...)]` attribute outside test builds: `Some(false)`
+/// if its owner only exists under `cfg(test)`, e.g. `#[cfg(test)]` or
+/// `#[cfg(all(test, unix))]`, but not `#[cfg(not(test))]`
+///
+/// Features and other predicates are unknown, so `None` when the result
+/// depends on them.
+pub fn eval_cfg_without_test(attr: &Attribute) -> Option<bool> {
+    eval_cfg_with(attr, &|meta| match meta {
+        Meta::Path(path) if path.is_ident("test") => Some(false),
+        _ => None,
+    })
+}
+
+/// Evaluate a `#[cfg(...)]` attribute, with `predicate` deciding single
+/// predicates (`None` when unknown) and `all`/`any`/`not` combining them
+fn eval_cfg_with(attr: &Attribute, predicate: &dyn Fn(&Meta) -> Option<bool>) -> Option<bool> {
+    if !attr.path().is_ident("cfg") {
+        return None;
+    }
+    let meta: Meta = attr.parse_args().ok()?;
+    eval_predicate(&meta, predicate)
+}
+
+fn eval_predicate(meta: &Meta, predicate: &dyn Fn(&Meta) -> Option<bool>) -> Option<bool> {
+    let Meta::List(list) = meta else {
+        return predicate(meta);
+    };
+    let name = list.path.to_token_stream().to_string();
+    if !matches!(name.as_str(), "not" | "all" | "any") {
+        return predicate(meta);
+    }
+    let nested = list
+        .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+        .ok()?;
+    let results: Vec<Option<bool>> = nested
+        .iter()
+        .map(|m| eval_predicate(m, predicate))
+        .collect();
+    match name.as_str() {
+        "not" if results.len() == 1 => results[0].map(|r| !r),
+        "all" => {
+            if results.contains(&Some(false)) {
+                Some(false)
+            } else if results.iter().all(|r| *r == Some(true)) {
+                Some(true)
+            } else {
+                None
+            }
+        }
+        "any" => {
+            if results.contains(&Some(true)) {
+                Some(true)
+            } else if results.iter().all(|r| *r == Some(false)) {
+                Some(false)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> toml::Value {
+        r#"
+[dependencies]
+log = "0.4"
+tokio = { version = "1", optional = true }
+uuid = { version = "1", optional = true }
+
+[features]
+default = ["basic"]
+basic = []
+extra = ["basic", "log/std", "uuid?/serde"]
+runtime = ["dep:tokio", "tokio/rt"]
+"#
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_default_and_forwarded() {
+        let set = FeatureSet::resolve(&manifest(), &["extra".to_string()], false);
+        assert!(set.features.contains("default"));
+        assert!(set.features.contains("basic"));
+        assert!(set.features.contains("extra"));
+        assert_eq!(set.dep_features["log"].iter().collect::<Vec<_>>(), ["std"]);
+        // Weak forward to a disabled optional dep is dropped
+        assert!(!set.dep_features.contains_key("uuid"));
+
+        let set = FeatureSet::resolve(&manifest(), &[], true);
+        assert!(set.features.is_empty());
+    }
+
+    #[test]
+    fn test_apply_to_dependency() {
+        let m = manifest();
+        let deps = m.get("dependencies").unwrap();
+        let set = FeatureSet::resolve(&m, &["runtime".to_string()], true);
+
+        let tokio = set.apply_to_dependency("tokio", &deps["tokio"]).unwrap();
+        assert!(tokio.get("optional").is_none());
+        assert_eq!(tokio["features"].as_array().unwrap().len(), 1);
+        assert!(set.apply_to_dependency("uuid", &deps["uuid"]).is_none());
+    }
+
+    #[test]
+    fn test_eval_cfg() {
+        let set = FeatureSet::resolve(&manifest(), &[], false);
+        let eval = |tokens: proc_macro2::TokenStream| {
+            let attr: Attribute = syn::parse_quote!(#[cfg(#tokens)]);
+            set.eval_cfg(&attr)
+        };
+        assert_eq!(eval(quote::quote!(feature = "basic")), Some(true));
+        assert_eq!(eval(quote::quote!(feature = "extra")), Some(false));
+        assert_eq!(eval(quote::quote!(not(feature = "extra"))), Some(true));
+        assert_eq!(
+            eval(quote::quote!(all(unix, feature = "extra"))),
+            Some(false)
+        );
+        assert_eq!(eval(quote::quote!(any(unix, feature = "extra"))), None);
+        assert_eq!(eval(quote::quote!(test)), None);
+    }
+
+    #[test]
+    fn test_eval_cfg_without_test() {
+        let eval = |tokens: proc_macro2::TokenStream| {
+            let attr: Attribute = syn::parse_quote!(#[cfg(#tokens)]);
+            eval_cfg_without_test(&attr)
+        };
+        assert_eq!(eval(quote::quote!(test)), Some(false));
+        assert_eq!(eval(quote::quote!(all(test, unix))), Some(false));
+        assert_eq!(eval(quote::quote!(not(test))), Some(true));
+        assert_eq!(eval(quote::quote!(any(test, feature = "mock"))), None);
+        assert_eq!(eval(quote::quote!(feature = "test")), None);
+    }
+}
@@ -0,0 +1,187 @@
+//! Cargo.lock version pinning
+//!
+//! The snapshot crate gets its own lockfile, so without pins cargo may pick
+//! newer versions than the ones the debugged binary was built with. Registry
+//! dependencies are pinned to their exact locked versions (`=1.0.203`).
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Registry package versions recorded in a Cargo.lock
+#[derive(Debug, Default)]
+pub struct Lockfile {
+    versions: HashMap<String, Vec<String>>,
+}
+
+impl Lockfile {
+    /// Load the lockfile of a project, falling back to its workspace root's
+    pub fn find(project_path: &Path, workspace_root: Option<&Path>) -> Option<Self> {
+        std::iter::once(project_path)
+            .chain(workspace_root)
+            .find_map(|dir| std::fs::read_to_string(dir.join("Cargo.lock")).ok())
+            .and_then(|content| Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Option<Self> {
+        let lock: toml::Value = content.parse().ok()?;
+        let mut versions: HashMap<String, Vec<String>> = HashMap::new();
+        for package in lock.get("package")?.as_array()? {
+            // Path and workspace packages have no source and are not pinned
+            let from_registry = package
+                .get("source")
+                .and_then(|s| s.as_str())
+                .is_some_and(|s| s.starts_with("registry+") || s.starts_with("sparse+"));
+            let name = package.get("name").and_then(|n| n.as_str());
+            let version = package.get("version").and_then(|v| v.as_str());
+            if let (true, Some(name), Some(version)) = (from_registry, name, version) {
+                versions
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(version.to_string());
+            }
+        }
+        Some(Self { versions })
+    }
+
+    /// Locked version of `package` satisfying `requirement`: the only locked
+    /// version, or the highest compatible one when several are locked
+    pub fn locked_version(&self, package: &str, requirement: Option<&str>) -> Option<&str> {
+        let candidates = self.versions.get(package)?;
+        if let [only] = candidates.as_slice() {
+            return Some(only);
+        }
+        let requirement = requirement?;
+        candidates
+            .iter()
+            .filter(|v| is_compatible(requirement, v))
+            .max_by_key(|v| version_key(v))
+            .map(String::as_str)
+    }
+
+    /// Dependency spec pinned to its locked version, or `None` when the
+    /// dependency is not a locked registry dependency
+    pub fn pin(&self, name: &str, value: &toml::Value) -> Option<toml::Value> {
+        match value {
+            toml::Value::String(requirement) => {
+                let version = self.locked_version(name, Some(requirement))?;
+                Some(toml::Value::String(format!("={}", version)))
+            }
+            toml::Value::Table(t) => {
+                if t.contains_key("path") || t.contains_key("git") {
+                    return None;
+                }
+                let package = t.get("package").and_then(|p| p.as_str()).unwrap_or(name);
+                let requirement = t.get("version").and_then(|v| v.as_str());
+                let version = self.locked_version(package, requirement)?;
+                let mut pinned = t.clone();
+                pinned.insert("version".to_string(), format!("={}", version).into());
+                Some(toml::Value::Table(pinned))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Numeric `(major, minor, patch)` of a version, ignoring pre-release and build
+fn version_key(version: &str) -> (u64, u64, u64) {
+    let core = version.split(['-', '+']).next().unwrap_or("");
+    let mut parts = core.split('.').map(|p| p.parse().unwrap_or(0));
+    (
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+    )
+}
+
+/// Whether `version` matches the first comparator of a requirement, with
+/// cargo's caret (default), tilde and exact semantics. Range comparators
+/// (`>=`, `<`) are treated as compatible.
+fn is_compatible(requirement: &str, version: &str) -> bool {
+    let comparator = requirement.split(',').next().unwrap_or("").trim();
+    if comparator.starts_with(['>', '<']) {
+        return true;
+    }
+    let (op, req) = match comparator.chars().next() {
+        Some(op @ ('^' | '~' | '=')) => (op, comparator[1..].trim()),
+        _ => ('^', comparator),
+    };
+
+    // Components given in the requirement; `*` and missing ones match anything
+    let req: Vec<Option<u64>> = req.split('.').map(|p| p.parse().ok()).collect();
+    let (major, minor, patch) = version_key(version);
+    let matches = |index: usize, actual: u64| match req.get(index) {
+        Some(Some(wanted)) => *wanted == actual,
+        _ => true,
+    };
+
+    match op {
+        '=' => matches(0, major) && matches(1, minor) && matches(2, patch),
+        '~' => matches(0, major) && matches(1, minor),
+        // Caret: the left-most non-zero component must match
+        _ => {
+            matches(0, major)
+                && (major != 0 || matches(1, minor))
+                && (major != 0 || minor != 0 || matches(2, patch))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCKFILE: &str = r#"
+version = 3
+
+[[package]]
+name = "project"
+version = "0.1.0"
+
+[[package]]
+name = "serde"
+version = "1.0.203"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "rand"
+version = "0.7.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "rand"
+version = "0.8.5"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    #[test]
+    fn test_locked_version() {
+        let lock = Lockfile::parse(LOCKFILE).unwrap();
+        assert_eq!(lock.locked_version("serde", Some("1")), Some("1.0.203"));
+        assert_eq!(lock.locked_version("rand", Some("0.8")), Some("0.8.5"));
+        assert_eq!(lock.locked_version("rand", Some("~0.7.1")), Some("0.7.3"));
+        assert_eq!(lock.locked_version("rand", Some(">=0.7")), Some("0.8.5"));
+        assert_eq!(lock.locked_version("rand", None), None);
+        assert_eq!(lock.locked_version("project", Some("0.1")), None);
+    }
+
+    #[test]
+    fn test_pin() {
+        let lock = Lockfile::parse(LOCKFILE).unwrap();
+        assert_eq!(
+            lock.pin("serde", &"1.0".into()),
+            Some(toml::Value::from("=1.0.203"))
+        );
+
+        let renamed: toml::Value =
+            "package = \"rand\"\nversion = \"0.7\"\nfeatures = [\"small_rng\"]"
+                .parse()
+                .unwrap();
+        let pinned = lock.pin("old_rand", &renamed).unwrap();
+        assert_eq!(pinned["version"].as_str(), Some("=0.7.3"));
+        assert_eq!(pinned["features"], renamed["features"]);
+
+        let path_dep: toml::Value = "path = \"../serde\"".parse().unwrap();
+        assert_eq!(lock.pin("serde", &path_dep), None);
+        assert_eq!(lock.pin("tokio", &"1".into()), None);
+    }
+}
@@ -3,22 +3,77 @@
 //! Transforms a user's main.rs project into a lib crate that can be
 //! depended upon by the REPL environment.
 
+mod copy_guard;
+mod diff;
+mod features;
+mod lockfile;
+mod output;
+mod prelude;
+mod reexport;
 mod resolver;
 mod transformer;
+mod update;
+mod workspace;
 
+pub use diff::diff_against;
+pub use features::FeatureSet;
+pub use reexport::ReexportAlias;
 pub use resolver::resolve_modules;
-pub use transformer::transform_to_lib;
+pub use transformer::{
+    transform_module_with, transform_to_lib, transform_with_output, ExportedItem, ExportedItemKind,
+    ModuleRelocation, TransformOptions, TransformOutput, DEFAULT_MAIN_ATTRIBUTES,
+};
+pub use update::{update_lib, UpdateReport};
 
 use anyhow::Result;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+use copy_guard::{CopyGuard, DEFAULT_MAX_COPY_BYTES};
+use lockfile::Lockfile;
+use output::Output;
+pub use output::StrippedSection;
+
 /// Configuration for lib generation
 pub struct LibGenConfig {
     /// Add serde derives to structs/enums
     pub add_serde_derives: bool,
     /// Output directory (None = create temp dir)
     pub output_dir: Option<PathBuf>,
+    /// Entry-point attribute macros removed together with `fn main`
+    pub main_attributes: Vec<String>,
+    /// Strip derives, attribute macros and imports from crates that are not
+    /// part of the generated Cargo.toml
+    pub strip_unavailable_derives: bool,
+    /// Cargo features the debugged binary was built with
+    pub features: Vec<String>,
+    /// The debugged binary was built with `--no-default-features`
+    pub no_default_features: bool,
+    /// Member to generate from when the project is a virtual workspace root
+    /// (None = the default member)
+    pub workspace_member: Option<String>,
+    /// Resolve and transform in memory only, returning the would-be files in
+    /// [`GeneratedLib::files`] instead of writing them
+    pub dry_run: bool,
+    /// Also depend on the project's `[dev-dependencies]`, so test fixtures
+    /// and builders can be used in the REPL
+    pub include_dev_deps: bool,
+    /// Extra module files (e.g. `tests/common/mod.rs`) added to the lib as
+    /// `pub mod <name>;`, relative to the project directory
+    pub extra_modules: Vec<PathBuf>,
+    /// Size limit for the sources copied from one path dependency; the copy
+    /// is aborted once it is exceeded
+    pub max_copy_bytes: u64,
+    /// Pin registry dependencies to the exact versions in the project's
+    /// Cargo.lock, matching what the debugged binary was built with
+    pub pin_versions: bool,
+    /// Derive macros removed from every derive list, by name or path, for
+    /// derives that don't compile outside the project (`Debug`, `Clone` and
+    /// serde's derives are always kept)
+    pub strip_unknown_derives: Option<Vec<String>>,
+    /// Remove all inner attributes (`#![...]`) from copied module files
+    pub strip_module_inner_attributes: bool,
 }
 
 impl Default for LibGenConfig {
@@ -26,6 +81,21 @@ impl Default for LibGenConfig {
         Self {
             add_serde_derives: true,
             output_dir: None,
+            main_attributes: DEFAULT_MAIN_ATTRIBUTES
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            strip_unavailable_derives: true,
+            features: Vec::new(),
+            no_default_features: false,
+            workspace_member: None,
+            dry_run: false,
+            include_dev_deps: false,
+            extra_modules: Vec::new(),
+            max_copy_bytes: DEFAULT_MAX_COPY_BYTES,
+            pin_versions: true,
+            strip_unknown_derives: None,
+            strip_module_inner_attributes: false,
         }
     }
 }
@@ -36,75 +106,536 @@ pub struct GeneratedLib {
     pub path: PathBuf,
     /// Crate name (for use in dependencies)
     pub crate_name: String,
+    /// Decisions made while generating the lib
+    pub report: LibGenReport,
+    /// Files that would be written, relative to `path` (dry runs only)
+    pub files: Vec<(PathBuf, String)>,
+    /// Public items re-exported by the lib's `prelude` module
+    pub exported_items: Vec<ExportedItem>,
+    /// Which of the project's dependencies made it into the lib's manifest
+    pub resolution: ResolutionReport,
+}
+
+/// Outcome of resolving the project's dependencies for the lib's manifest
+///
+/// Types from a skipped crate can't be restored in the REPL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolutionReport {
+    /// Dependencies of the project kept in the manifest
+    pub resolved: Vec<String>,
+    /// Dependencies left out, with the reason, including those of resolved
+    /// path dependency copies
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Summary of decisions made while generating a lib, for the REPL to surface
+#[derive(Debug, Clone, Default)]
+pub struct LibGenReport {
+    /// Path dependencies re-exported under their crate name because some of
+    /// their items collide with another dependency's exports
+    pub reexport_aliases: Vec<ReexportAlias>,
+    /// `macro_rules!` macros exported from the lib's root, usable in the REPL
+    /// as `use ferrumpy_snapshot::name;`
+    pub exported_macros: Vec<String>,
+    /// Dev-dependencies added to the lib by `include_dev_deps`
+    pub dev_dependencies: Vec<String>,
+    /// `[lints]`, `[profile]` and `[workspace]` tables left out of resolved
+    /// path dependency copies, which build outside their workspace
+    pub stripped_sections: Vec<StrippedSection>,
+}
+
+impl LibGenReport {
+    /// Human-readable notes describing how to reach shadowed items
+    pub fn notes(&self) -> Vec<String> {
+        self.reexport_aliases
+            .iter()
+            .map(|alias| {
+                let paths: Vec<String> = alias
+                    .shadowed
+                    .iter()
+                    .map(|item| format!("{}::{}", alias.alias, item))
+                    .collect();
+                format!(
+                    "'{}' exports names shared with another dependency; use {}",
+                    alias.crate_ident,
+                    paths.join(", ")
+                )
+            })
+            .collect()
+    }
 }
 
 /// Generate a lib crate from a user's project
 pub fn generate_lib(project_path: &Path, config: LibGenConfig) -> Result<GeneratedLib> {
-    // 1. Create output directory
-    let output_dir = config.output_dir.unwrap_or_else(|| {
+    let output_dir = config.output_dir.clone().unwrap_or_else(|| {
         let tmp = std::env::temp_dir().join(format!("ferrumpy_lib_{}", std::process::id()));
         tmp
     });
-    fs::create_dir_all(&output_dir)?;
-    fs::create_dir_all(output_dir.join("src"))?;
+    let output = Output::new(output_dir, config.dry_run).with_max_copy_bytes(config.max_copy_bytes);
+    let mut generated = generate_into(project_path, &config, &output)?;
+    if config.dry_run {
+        generated.files = output.into_files();
+    }
+    Ok(generated)
+}
+
+/// Generate the lib crate's files into `output`
+fn generate_into(
+    project_path: &Path,
+    config: &LibGenConfig,
+    output: &Output,
+) -> Result<GeneratedLib> {
+    // A virtual workspace root has no sources: generate from one of its members
+    // (workspace dependencies are still resolved from the root)
+    let member_dir = match read_manifest(project_path) {
+        Some(root) if workspace::is_virtual_manifest(&root) => {
+            let member =
+                workspace::select_member(project_path, &root, config.workspace_member.as_deref())?;
+            if std::env::var("FERRUMPY_DEBUG").is_ok() {
+                eprintln!("[libgen] Using workspace member: {:?}", member.path);
+            }
+            Some(member.path)
+        }
+        _ => None,
+    };
+    let project_path = member_dir.as_deref().unwrap_or(project_path);
 
-    // 2. Determine source file (main.rs or lib.rs)
+    // 1. Create output directory
+    output.create_dir_all(output.dir())?;
+    output.create_dir_all(&output.dir().join("src"))?;
+
+    // 2. Determine source file (main.rs or the [lib] root, src/lib.rs by default)
+    let user_manifest = read_manifest(project_path);
+    let lib_target = user_manifest
+        .as_ref()
+        .map(LibTarget::from_manifest)
+        .unwrap_or_default();
+    let active_features = user_manifest
+        .as_ref()
+        .map(|m| FeatureSet::resolve(m, &config.features, config.no_default_features))
+        .unwrap_or_default();
     let main_rs = project_path.join("src/main.rs");
-    let lib_rs = project_path.join("src/lib.rs");
+    let lib_rs = lib_target.root_file(project_path);
 
     let (source_file, is_bin) = if main_rs.exists() {
         (main_rs, true)
     } else if lib_rs.exists() {
         (lib_rs, false)
     } else {
-        anyhow::bail!("No src/main.rs or src/lib.rs found in project");
+        anyhow::bail!(
+            "No src/main.rs or {} found in project",
+            lib_target.root_path().display()
+        );
     };
 
-    // 3. Transform main source file
+    // 3. Generate Cargo.toml (with path dependency resolution)
+    let manifest = generate_cargo_toml(
+        project_path,
+        &output,
+        config.add_serde_derives,
+        config.include_dev_deps,
+        config.pin_versions,
+        &active_features,
+    )?;
+    output.write(&output.dir().join("Cargo.toml"), &manifest.content)?;
+
+    // 4. Transform main source file
+    // Extra modules are declared from the lib root, wherever they lived before
+    let mut relocated_modules = Vec::new();
+    for extra in &config.extra_modules {
+        if let Some(from) = original_module_path(extra) {
+            let to = vec![extra_module_name(&project_path.join(extra))?];
+            relocated_modules.push(ModuleRelocation { from, to });
+        }
+    }
+    let options = TransformOptions {
+        remove_main: is_bin,
+        add_serde: config.add_serde_derives,
+        main_attributes: config.main_attributes.clone(),
+        available_crates: config
+            .strip_unavailable_derives
+            .then(|| manifest.crate_idents.clone()),
+        active_features: Some(active_features.clone()),
+        // A binary refers to its package's lib by the lib target name
+        self_crate: lib_target.name.clone().or_else(|| {
+            user_manifest
+                .as_ref()?
+                .get("package")?
+                .get("name")?
+                .as_str()
+                .map(String::from)
+        }),
+        relocated_modules,
+        strip_derives: config.strip_unknown_derives.clone().unwrap_or_default(),
+        strip_inner_attrs: false,
+        exported_macros: Vec::new(),
+    };
+    let module_options = TransformOptions {
+        strip_inner_attrs: config.strip_module_inner_attributes,
+        ..options.clone()
+    };
     let transformed =
-        transformer::transform_to_lib(&source_file, is_bin, config.add_serde_derives)?;
+        transformer::transform_with_output(&fs::read_to_string(&source_file)?, &options)?;
+    let mut exported_macros = transformed.exported_macros;
+    let mut exported_items = transformed.exported_items;
+    let mut cfg_gated_modules = transformed.cfg_gated_modules;
 
-    // 4. Resolve and copy module files
-    let modules = resolver::resolve_modules(&source_file)?;
+    // 5. Resolve and copy module files
+    let modules = resolver::resolve_modules_with_features(&source_file, Some(&active_features))?;
     for (rel_path, content) in modules {
-        let dest = output_dir.join("src").join(&rel_path);
+        let dest = output.dir().join("src").join(&rel_path);
         if let Some(parent) = dest.parent() {
-            fs::create_dir_all(parent)?;
+            output.create_dir_all(parent)?;
         }
-        let transformed_mod = transformer::transform_module(&content, config.add_serde_derives)?;
-        fs::write(&dest, transformed_mod)?;
+        let file_options = TransformOptions {
+            exported_macros: exported_macros.clone(),
+            ..module_options.clone()
+        };
+        let transformed_mod = transformer::transform_module_output(&content, &file_options)?;
+        collect_module_exports(
+            &rel_path,
+            &transformed_mod,
+            &mut exported_items,
+            &mut cfg_gated_modules,
+        );
+        exported_macros.extend(transformed_mod.exported_macros);
+        output.write(&dest, transformed_mod.code)?;
     }
 
-    // 5. Generate Cargo.toml (with path dependency resolution)
-    let (cargo_toml, path_deps) =
-        generate_cargo_toml(project_path, &output_dir, config.add_serde_derives)?;
-    fs::write(output_dir.join("Cargo.toml"), cargo_toml)?;
+    // 5b. Copy extra (test helper) modules, declared from lib.rs below
+    let mut extra_mod_names = Vec::new();
+    for extra in &config.extra_modules {
+        let extra_path = project_path.join(extra);
+        let name = extra_module_name(&extra_path)?;
+        let is_mod_rs = extra_path.file_name().is_some_and(|f| f == "mod.rs");
+        let (root_dest, sub_dir) = if is_mod_rs {
+            (PathBuf::from(&name).join("mod.rs"), PathBuf::from(&name))
+        } else {
+            (PathBuf::from(format!("{}.rs", name)), PathBuf::from(&name))
+        };
+        let src_dir = output.dir().join("src");
+        if output.exists(&src_dir.join(&root_dest)) || output.exists(&src_dir.join(&sub_dir)) {
+            anyhow::bail!(
+                "Extra module '{}' ({}) conflicts with a module of the project",
+                name,
+                extra.display()
+            );
+        }
+
+        let mut files = resolver::resolve_submodules(&extra_path, Some(&active_features))?
+            .into_iter()
+            .map(|(rel_path, content)| (sub_dir.join(rel_path), content))
+            .collect::<Vec<_>>();
+        files.push((root_dest, fs::read_to_string(&extra_path)?));
+        for (rel_path, content) in files {
+            let dest = src_dir.join(&rel_path);
+            if let Some(parent) = dest.parent() {
+                output.create_dir_all(parent)?;
+            }
+            let file_options = TransformOptions {
+                exported_macros: exported_macros.clone(),
+                ..module_options.clone()
+            };
+            let transformed_mod = transformer::transform_module_output(&content, &file_options)?;
+            collect_module_exports(
+                &rel_path,
+                &transformed_mod,
+                &mut exported_items,
+                &mut cfg_gated_modules,
+            );
+            exported_macros.extend(transformed_mod.exported_macros);
+            output.write(&dest, transformed_mod.code)?;
+        }
+        extra_mod_names.push(name);
+    }
 
     // 6. Add pub use statements for path dependencies to lib.rs
     // This makes types from those crates accessible by simple names
-    let mut lib_content = transformed;
-    if !path_deps.is_empty() {
-        lib_content.push_str("\n// Re-export types from path dependencies\n");
-        for dep in &path_deps {
-            // Convert hyphens to underscores for valid Rust identifiers
-            let crate_name = dep.replace('-', "_");
-            lib_content.push_str(&format!("pub use {}::*;\n", crate_name));
+    exported_macros.sort();
+    exported_macros.dedup();
+    let mut report = LibGenReport {
+        exported_macros,
+        dev_dependencies: manifest.dev_deps.clone(),
+        stripped_sections: output.take_stripped_sections(),
+        ..Default::default()
+    };
+    let mut lib_content = transformed.code;
+    if !extra_mod_names.is_empty() {
+        lib_content.push_str("\n// Extra modules\n");
+        for name in &extra_mod_names {
+            lib_content.push_str(&format!("pub mod {};\n", name));
         }
     }
-    fs::write(output_dir.join("src/lib.rs"), &lib_content)?;
+
+    // 6a. List every public item in a prelude, unless the project has its own
+    let exported_items = prelude::prelude_items(exported_items, &cfg_gated_modules);
+    let prelude_taken = extra_mod_names.iter().any(|n| n == prelude::PRELUDE_MODULE)
+        || syn::parse_file(&lib_content)
+            .map(|file| reexport::item_names(&file.items, false))
+            .unwrap_or_default()
+            .contains(prelude::PRELUDE_MODULE);
+    if prelude_taken {
+        eprintln!(
+            "[FerrumPy] Note: The project defines its own '{}' module; no prelude generated",
+            prelude::PRELUDE_MODULE
+        );
+    } else {
+        output.write(
+            &output
+                .dir()
+                .join("src")
+                .join(format!("{}.rs", prelude::PRELUDE_MODULE)),
+            prelude::render_prelude(&exported_items),
+        )?;
+        lib_content.push_str(&format!(
+            "\n// Explicit re-exports of the public items\npub mod {};\n",
+            prelude::PRELUDE_MODULE
+        ));
+    }
+    if !manifest.path_deps.is_empty() {
+        let local_names: HashSet<String> = syn::parse_file(&lib_content)
+            .map(|file| reexport::item_names(&file.items, false))
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        let deps: Vec<reexport::DepExports> = manifest
+            .path_deps
+            .iter()
+            .map(|dep| reexport::DepExports {
+                crate_ident: dep.crate_ident.clone(),
+                names: dep
+                    .lib_root
+                    .as_deref()
+                    .and_then(reexport::public_item_names),
+            })
+            .collect();
+        let (reexports, aliases) = reexport::plan_reexports(&deps, &local_names);
+
+        lib_content.push_str("\n// Re-export types from path dependencies\n");
+        lib_content.push_str(&reexports);
+        report.reexport_aliases = aliases;
+    }
+    output.write(&output.dir().join("src/lib.rs"), &lib_content)?;
+
+    for note in report.notes() {
+        eprintln!("[FerrumPy] Note: {}", note);
+    }
 
     Ok(GeneratedLib {
-        path: output_dir,
+        path: output.dir().to_path_buf(),
         crate_name: "ferrumpy_snapshot".to_string(),
+        report,
+        files: Vec::new(),
+        exported_items,
+        resolution: manifest.resolution,
     })
 }
 
-/// Returns (cargo_toml_content, path_dependency_names)
+/// Add a module file's exported items and gated modules, rebased from the
+/// file to the crate root
+fn collect_module_exports(
+    rel_path: &Path,
+    transformed: &TransformOutput,
+    exported_items: &mut Vec<ExportedItem>,
+    cfg_gated_modules: &mut Vec<Vec<String>>,
+) {
+    let base = module_path_of(rel_path);
+    let rebase = |path: &[String]| base.iter().chain(path).cloned().collect::<Vec<_>>();
+    exported_items.extend(transformed.exported_items.iter().map(|item| ExportedItem {
+        module_path: rebase(&item.module_path),
+        ..item.clone()
+    }));
+    cfg_gated_modules.extend(transformed.cfg_gated_modules.iter().map(|p| rebase(p)));
+}
+
+/// Module path of a file relative to the crate root's directory
+/// (`net/proto.rs` and `net/proto/mod.rs` are both `net::proto`)
+fn module_path_of(rel_path: &Path) -> Vec<String> {
+    let mut components: Vec<String> = rel_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().replace('-', "_"))
+        .collect();
+    if let Some(file) = components.pop() {
+        let stem = file.strip_suffix(".rs").unwrap_or(&file);
+        if stem != "mod" {
+            components.push(stem.to_string());
+        }
+    }
+    components
+}
+
+/// Module name for an extra module file: the directory name for `mod.rs`,
+/// the file stem otherwise
+fn extra_module_name(path: &Path) -> Result<String> {
+    let name = match path.file_stem().and_then(|s| s.to_str()) {
+        Some("mod") => path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|f| f.to_str()),
+        stem => stem,
+    };
+    let name = name
+        .map(|n| n.replace('-', "_"))
+        .ok_or_else(|| anyhow::anyhow!("Invalid extra module path: {}", path.display()))?;
+    if syn::parse_str::<syn::Ident>(&name).is_err() {
+        anyhow::bail!(
+            "Extra module {} is not a valid module name: '{}'",
+            path.display(),
+            name
+        );
+    }
+    Ok(name)
+}
+
+/// Module path of a source file in its original crate, relative to the crate
+/// root (`src/testing/fixtures.rs` is `testing::fixtures`). `None` when the
+/// file isn't under a target directory.
+fn original_module_path(path: &Path) -> Option<Vec<String>> {
+    let mut components = path.components();
+    let target_dir = components.next()?.as_os_str().to_str()?;
+    if !["src", "tests", "benches", "examples"].contains(&target_dir) {
+        return None;
+    }
+    let rel_path = components.as_path();
+    if rel_path.extension()? != "rs" {
+        return None;
+    }
+    Some(module_path_of(rel_path))
+}
+
+/// Library target settings from a manifest's `[lib]` table
+#[derive(Debug, Default, Clone, PartialEq)]
+struct LibTarget {
+    /// `[lib] name`, the identifier other crates use to refer to this one
+    name: Option<String>,
+    /// `[lib] path`, relative to the manifest directory
+    path: Option<PathBuf>,
+}
+
+impl LibTarget {
+    fn from_manifest(manifest: &toml::Value) -> Self {
+        let lib = manifest.get("lib");
+        Self {
+            name: lib
+                .and_then(|l| l.get("name"))
+                .and_then(|v| v.as_str())
+                .map(String::from),
+            path: lib
+                .and_then(|l| l.get("path"))
+                .and_then(|v| v.as_str())
+                .map(PathBuf::from),
+        }
+    }
+
+    /// Crate root relative to the manifest directory
+    fn root_path(&self) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("src/lib.rs"))
+    }
+
+    /// Absolute crate root for a crate located at `crate_dir`
+    fn root_file(&self, crate_dir: &Path) -> PathBuf {
+        crate_dir.join(self.root_path())
+    }
+
+    /// Whether the crate root lives outside `src/`, so copying `src/` alone misses it
+    fn root_outside_src(&self) -> bool {
+        self.path.as_ref().is_some_and(|p| !p.starts_with("src"))
+    }
+}
+
+/// Read and parse `Cargo.toml` in a crate directory
+fn read_manifest(crate_dir: &Path) -> Option<toml::Value> {
+    let content = fs::read_to_string(crate_dir.join("Cargo.toml")).ok()?;
+    content.parse().ok()
+}
+
+/// Name used to refer to a path dependency from Rust code
+///
+/// A renamed dependency (`package = "..."`) is referred to by its key. Otherwise
+/// the dependency's own `[lib] name` wins over its package name.
+fn path_dep_crate_ident(
+    name: &str,
+    value: &toml::Value,
+    workspace_deps: &Option<toml::value::Table>,
+    path_base: &Path,
+) -> String {
+    let table = dependency_table(name, value, workspace_deps);
+
+    let lib_name = table.and_then(|t| {
+        if t.contains_key("package") {
+            return None;
+        }
+        let manifest = read_manifest(&path_dep_dir(name, value, workspace_deps, path_base)?)?;
+        LibTarget::from_manifest(&manifest).name.or_else(|| {
+            manifest
+                .get("package")
+                .and_then(|p| p.get("name"))
+                .and_then(|v| v.as_str())
+                .map(String::from)
+        })
+    });
+
+    // Convert hyphens to underscores for valid Rust identifiers
+    lib_name.as_deref().unwrap_or(name).replace('-', "_")
+}
+
+/// Dependency spec table, following `workspace = true` to the workspace entry
+fn dependency_table<'a>(
+    name: &str,
+    value: &'a toml::Value,
+    workspace_deps: &'a Option<toml::value::Table>,
+) -> Option<&'a toml::value::Table> {
+    let table = value.as_table()?;
+    if table.get("workspace").and_then(|v| v.as_bool()) == Some(true) {
+        return workspace_deps.as_ref()?.get(name)?.as_table();
+    }
+    Some(table)
+}
+
+/// Source directory of a path dependency
+fn path_dep_dir(
+    name: &str,
+    value: &toml::Value,
+    workspace_deps: &Option<toml::value::Table>,
+    path_base: &Path,
+) -> Option<PathBuf> {
+    let path = dependency_table(name, value, workspace_deps)?
+        .get("path")?
+        .as_str()?;
+    Some(path_base.join(path))
+}
+
+/// A path dependency of the snapshot crate
+struct PathDep {
+    /// Identifier used to refer to the crate from Rust code
+    crate_ident: String,
+    /// Crate root file of the copy the snapshot crate depends on
+    lib_root: Option<PathBuf>,
+}
+
+/// Cargo.toml generated for the snapshot crate
+struct GeneratedManifest {
+    /// Manifest text
+    content: String,
+    /// Path dependencies (re-exported from lib.rs)
+    path_deps: Vec<PathDep>,
+    /// Crate identifiers of every dependency in the manifest
+    crate_idents: Vec<String>,
+    /// Dev-dependencies merged into `[dependencies]`
+    dev_deps: Vec<String>,
+    resolution: ResolutionReport,
+}
+
 fn generate_cargo_toml(
     project_path: &Path,
-    output_dir: &Path,
+    output: &Output,
     add_serde: bool,
-) -> Result<(String, Vec<String>)> {
+    include_dev_deps: bool,
+    pin_versions: bool,
+    features: &FeatureSet,
+) -> Result<GeneratedManifest> {
     let user_cargo = project_path.join("Cargo.toml");
     let user_content = fs::read_to_string(&user_cargo)?;
 
@@ -129,82 +660,165 @@ fn generate_cargo_toml(
         );
     }
 
-    let mut cargo = String::new();
-    cargo.push_str("[package]\n");
-    cargo.push_str("name = \"ferrumpy_snapshot\"\n");
-    cargo.push_str("version = \"0.1.5\"\n");
-    cargo.push_str("edition = \"2021\"\n\n");
+    let mut package = toml::Table::new();
+    package.insert("name".to_string(), "ferrumpy_snapshot".into());
+    package.insert("version".to_string(), "0.1.5".into());
+    package.insert("edition".to_string(), "2021".into());
 
-    cargo.push_str("[lib]\n");
-    cargo.push_str("crate-type = [\"rlib\"]\n\n");
+    let mut lib = toml::Table::new();
+    lib.insert(
+        "crate-type".to_string(),
+        toml::Value::Array(vec!["rlib".into()]),
+    );
 
-    cargo.push_str("[dependencies]\n");
+    let mut dependencies = toml::Table::new();
+
+    // Track path dependencies for re-export, and every crate the lib can refer to
+    let mut path_deps: Vec<PathDep> = Vec::new();
+    let mut crate_idents: Vec<String> = Vec::new();
+    let mut dev_deps: Vec<String> = Vec::new();
+    let mut resolved_names: Vec<String> = Vec::new();
 
     // Add serde if requested
     if add_serde {
-        cargo.push_str("serde = { version = \"1\", features = [\"derive\"] }\n");
-        cargo.push_str("serde_json = \"1\"\n");
+        dependencies.insert("serde".to_string(), serde_dependency());
+        dependencies.insert("serde_json".to_string(), "1".into());
+        crate_idents.push("serde".to_string());
+        crate_idents.push("serde_json".to_string());
     }
 
-    // Track path dependencies for re-export
-    let mut path_deps: Vec<String> = Vec::new();
-
-    // Copy user dependencies
-    if let Some(deps) = user_toml.get("dependencies") {
-        if let Some(table) = deps.as_table() {
+    // Copy user dependencies, then dev-dependencies the regular ones don't cover
+    let sections: &[&str] = if include_dev_deps {
+        &["dependencies", "dev-dependencies"]
+    } else {
+        &["dependencies"]
+    };
+    for section in sections {
+        if let Some(table) = user_toml.get(section).and_then(|d| d.as_table()) {
             for (name, value) in table {
                 // Skip if we already added serde
                 if add_serde && (name == "serde" || name == "serde_json") {
                     continue;
                 }
+                if dependencies.contains_key(name) {
+                    continue;
+                }
+
+                // Drop optional deps no active feature enables, forward features
+                let Some(value) = features.apply_to_dependency(name, value) else {
+                    continue;
+                };
+                let value = &value;
 
                 // Check if this is a path dependency (directly or via workspace)
-                let is_path_dep = is_path_dependency(value, &workspace_deps);
+                let is_path_dep = is_path_dependency(name, value, &workspace_deps);
 
                 // Resolve dependency (handles workspace deps and path deps)
                 if let Some(resolved) =
-                    resolve_dependency(name, value, &workspace_deps, path_base, output_dir)
+                    resolve_dependency(name, value, &workspace_deps, path_base, output)
                 {
-                    cargo.push_str(&resolved);
-                    cargo.push('\n');
+                    dependencies.insert(name.clone(), resolved);
+                    resolved_names.push(name.clone());
+                    crate_idents.push(name.replace('-', "_"));
+                    if *section == "dev-dependencies" {
+                        dev_deps.push(name.clone());
+                    }
 
                     // Track path deps for re-export
                     if is_path_dep {
-                        path_deps.push(name.clone());
+                        let ident = path_dep_crate_ident(name, value, &workspace_deps, path_base);
+                        // Prefer the resolved copy, which is what the snapshot crate builds
+                        // (a dry run has not written it, so read the original)
+                        let resolved_copy = output.dir().join("deps").join(name);
+                        let dep_dir = if !output.is_dry_run() && resolved_copy.exists() {
+                            Some(resolved_copy)
+                        } else {
+                            path_dep_dir(name, value, &workspace_deps, path_base)
+                        };
+                        let lib_root = dep_dir.map(|dir| {
+                            read_manifest(&dir)
+                                .map(|m| LibTarget::from_manifest(&m))
+                                .unwrap_or_default()
+                                .root_file(&dir)
+                        });
+                        crate_idents.push(ident.clone());
+                        path_deps.push(PathDep {
+                            crate_ident: ident,
+                            lib_root,
+                        });
                     }
                 }
             }
         }
     }
 
-    Ok((cargo, path_deps))
+    // Pin registry dependencies to the versions the binary was built with
+    let lockfile = pin_versions
+        .then(|| Lockfile::find(project_path, workspace_root.as_deref()))
+        .flatten();
+    if let Some(lockfile) = lockfile {
+        for (name, value) in dependencies.iter_mut() {
+            if let Some(pinned) = lockfile.pin(name, value) {
+                *value = pinned;
+            }
+        }
+    }
+
+    let mut cargo = toml::Table::new();
+    cargo.insert("package".to_string(), toml::Value::Table(package));
+    cargo.insert("lib".to_string(), toml::Value::Table(lib));
+    cargo.insert("dependencies".to_string(), toml::Value::Table(dependencies));
+
+    Ok(GeneratedManifest {
+        content: toml::to_string(&cargo)?,
+        path_deps,
+        crate_idents,
+        dev_deps,
+        resolution: ResolutionReport {
+            resolved: resolved_names,
+            skipped: output.take_skipped_dependencies(),
+        },
+    })
 }
 
 /// Check if a dependency is a path dependency (directly or via workspace)
-fn is_path_dependency(value: &toml::Value, workspace_deps: &Option<toml::value::Table>) -> bool {
-    match value {
-        toml::Value::Table(t) => {
-            // Direct path dependency
-            if t.get("path").is_some() {
-                return true;
-            }
-            // Workspace dependency - check if it resolves to path
-            if t.get("workspace").and_then(|v| v.as_bool()) == Some(true) {
-                if let Some(ws_deps) = workspace_deps {
-                    // Check all keys since we don't know the name here
-                    for (_, ws_val) in ws_deps {
-                        if let toml::Value::Table(ws_t) = ws_val {
-                            if ws_t.get("path").is_some() {
-                                return true;
-                            }
-                        }
-                    }
-                }
-            }
-            false
+fn is_path_dependency(
+    name: &str,
+    value: &toml::Value,
+    workspace_deps: &Option<toml::value::Table>,
+) -> bool {
+    dependency_table(name, value, workspace_deps).is_some_and(|t| t.contains_key("path"))
+}
+
+/// Workspace dependency spec combined with the member's `workspace = true` entry
+///
+/// The workspace entry provides the source, version and `package` rename; the
+/// member can only add features.
+fn merge_workspace_dependency(ws_dep: &toml::Value, member: &toml::value::Table) -> toml::Value {
+    let Some(extra) = member.get("features").and_then(|f| f.as_array()) else {
+        return ws_dep.clone();
+    };
+    let mut merged = match ws_dep {
+        toml::Value::String(version) => {
+            let mut t = toml::value::Table::new();
+            t.insert("version".to_string(), version.clone().into());
+            t
+        }
+        toml::Value::Table(t) => t.clone(),
+        other => return other.clone(),
+    };
+    let mut features: Vec<toml::Value> = merged
+        .get("features")
+        .and_then(|f| f.as_array())
+        .cloned()
+        .unwrap_or_default();
+    for feature in extra {
+        if !features.contains(feature) {
+            features.push(feature.clone());
         }
-        _ => false,
     }
+    merged.insert("features".to_string(), toml::Value::Array(features));
+    toml::Value::Table(merged)
 }
 
 /// Find workspace root and extract workspace.dependencies
@@ -258,17 +872,29 @@ fn find_workspace_dependencies(
     (None, None)
 }
 
+/// `serde` with the derive feature, needed by the added `Serialize`/`Deserialize` derives
+fn serde_dependency() -> toml::Value {
+    let mut serde = toml::Table::new();
+    serde.insert("version".to_string(), "1".into());
+    serde.insert(
+        "features".to_string(),
+        toml::Value::Array(vec!["derive".into()]),
+    );
+    toml::Value::Table(serde)
+}
+
 /// Resolve a dependency, handling workspace = true and path = "..." cases
 /// For path deps with workspace deps, creates a resolved copy in output_dir/deps/
+/// Returns the dependency's value for the generated manifest
 fn resolve_dependency(
     name: &str,
     value: &toml::Value,
     workspace_deps: &Option<toml::value::Table>,
     path_base: &Path,
-    output_dir: &Path,
-) -> Option<String> {
+    output: &Output,
+) -> Option<toml::Value> {
     match value {
-        toml::Value::String(version) => Some(format!("{} = \"{}\"", name, version)),
+        toml::Value::String(_) => Some(value.clone()),
         toml::Value::Table(t) => {
             // Check if this is a workspace dependency
             if t.get("workspace").and_then(|v| v.as_bool()) == Some(true) {
@@ -285,12 +911,13 @@ fn resolve_dependency(
                 if let Some(ws_deps) = workspace_deps {
                     if let Some(ws_dep) = ws_deps.get(name) {
                         // Recursively resolve (in case workspace dep is also a table)
+                        let merged = merge_workspace_dependency(ws_dep, t);
                         return resolve_dependency(
                             name,
-                            ws_dep,
+                            &merged,
                             workspace_deps,
                             path_base,
-                            output_dir,
+                            output,
                         );
                     }
                 }
@@ -299,97 +926,78 @@ fn resolve_dependency(
                     "[FerrumPy] Warning: Skipping workspace dependency '{}' (could not resolve from workspace root)",
                     name
                 );
+                output.record_skipped_dependency(name, "not found in workspace dependencies");
                 return None;
             }
 
             // Check if this is a path dependency
-            if let Some(path_val) = t.get("path") {
-                if let Some(path_str) = path_val.as_str() {
-                    let dep_path = Path::new(path_str);
-                    let absolute_path = if dep_path.is_relative() {
-                        path_base.join(dep_path)
-                    } else {
-                        dep_path.to_path_buf()
-                    };
-
-                    // Check if this crate uses workspace dependencies
-                    let dep_cargo_toml = absolute_path.join("Cargo.toml");
-                    let has_workspace_deps = if dep_cargo_toml.exists() {
-                        if let Ok(content) = fs::read_to_string(&dep_cargo_toml) {
-                            content.contains("workspace = true")
-                                || content.contains(".workspace = true")
-                        } else {
-                            false
-                        }
-                    } else {
-                        false
-                    };
-
-                    if has_workspace_deps {
-                        // Create a resolved copy of the path dependency
-                        if let Some(resolved_path) = create_resolved_path_dep(
-                            name,
-                            &absolute_path,
-                            workspace_deps,
-                            path_base,
-                            output_dir,
-                        ) {
-                            let mut parts = Vec::new();
-                            parts.push(format!("path = \"{}\"", resolved_path.display()));
-
-                            // Copy other keys (version, features, etc.)
-                            for (key, val) in t {
-                                if key == "path" {
-                                    continue;
-                                }
-                                let val_str = format_toml_value(val);
-                                parts.push(format!("{} = {}", key, val_str));
-                            }
-
-                            return Some(format!("{} = {{ {} }}", name, parts.join(", ")));
-                        } else {
+            if let Some(path_str) = t.get("path").and_then(|v| v.as_str()) {
+                let dep_path = Path::new(path_str);
+                let absolute_path = if dep_path.is_relative() {
+                    path_base.join(dep_path)
+                } else {
+                    dep_path.to_path_buf()
+                };
+
+                // Check if this crate uses workspace dependencies
+                let has_workspace_deps = fs::read_to_string(absolute_path.join("Cargo.toml"))
+                    .map(|content| {
+                        content.contains("workspace = true")
+                            || content.contains(".workspace = true")
+                    })
+                    .unwrap_or(false);
+
+                let resolved_path = if has_workspace_deps {
+                    // Create a resolved copy of the path dependency
+                    match create_resolved_path_dep(
+                        name,
+                        &absolute_path,
+                        workspace_deps,
+                        path_base,
+                        output,
+                    ) {
+                        Some(resolved_path) => resolved_path,
+                        None => {
                             eprintln!(
                                 "[FerrumPy] Warning: Failed to resolve path dependency '{}' with workspace deps",
                                 name
                             );
+                            output.record_skipped_dependency(
+                                name,
+                                "could not copy path dependency with workspace dependencies",
+                            );
                             return None;
                         }
-                    } else {
-                        // No workspace deps - just use absolute path
-                        let mut parts = Vec::new();
-                        parts.push(format!("path = \"{}\"", absolute_path.display()));
-
-                        for (key, val) in t {
-                            if key == "path" {
-                                continue;
-                            }
-                            let val_str = format_toml_value(val);
-                            parts.push(format!("{} = {}", key, val_str));
-                        }
-
-                        return Some(format!("{} = {{ {} }}", name, parts.join(", ")));
                     }
-                }
+                } else {
+                    // No workspace deps - just use absolute path
+                    absolute_path
+                };
+
+                // Keep other keys (version, features, etc.)
+                let mut resolved = t.clone();
+                resolved.insert(
+                    "path".to_string(),
+                    resolved_path.to_string_lossy().into_owned().into(),
+                );
+                return Some(toml::Value::Table(resolved));
             }
 
-            // Handle complex dependencies - serialize as inline table
-            let mut parts = Vec::new();
-            for (key, val) in t {
-                // Skip 'workspace' key if present
-                if key == "workspace" {
-                    continue;
-                }
-                let val_str = format_toml_value(val);
-                parts.push(format!("{} = {}", key, val_str));
-            }
+            // Complex dependency - keep the table without the 'workspace' key
+            let mut resolved = t.clone();
+            resolved.remove("workspace");
 
-            if parts.is_empty() {
+            if resolved.is_empty() {
+                output.record_skipped_dependency(name, "empty dependency table");
                 None
             } else {
-                Some(format!("{} = {{ {} }}", name, parts.join(", ")))
+                Some(toml::Value::Table(resolved))
             }
         }
-        _ => None,
+        _ => {
+            output.record_skipped_dependency(name, "unsupported dependency value");
+            None
+        }
     }
 }
 
@@ -400,21 +1008,24 @@ fn create_resolved_path_dep(
     source_path: &Path,
     workspace_deps: &Option<toml::value::Table>,
     path_base: &Path,
-    output_dir: &Path,
+    output: &Output,
 ) -> Option<PathBuf> {
     // Create deps directory in output
-    let deps_dir = output_dir.join("deps");
+    let deps_dir = output.dir().join("deps");
     let dest_dir = deps_dir.join(name);
 
-    if let Err(e) = fs::create_dir_all(&dest_dir) {
+    if let Err(e) = output.create_dir_all(&dest_dir) {
         eprintln!("[FerrumPy] Failed to create deps dir: {}", e);
         return None;
     }
 
     // Copy and transform src directory (add serde derives to types)
+    let mut guard = CopyGuard::new(output.max_copy_bytes());
     let src_dir = source_path.join("src");
     if src_dir.exists() {
-        if let Err(e) = copy_and_transform_src(&src_dir, &dest_dir.join("src"), true) {
+        if let Err(e) =
+            copy_and_transform_src(output, &mut guard, &src_dir, &dest_dir.join("src"), true)
+        {
             eprintln!("[FerrumPy] Failed to copy and transform src: {}", e);
             return None;
         }
@@ -438,11 +1049,40 @@ fn create_resolved_path_dep(
         }
     };
 
+    // A custom [lib] path outside src/ is not covered by the copy above
+    let lib_target = LibTarget::from_manifest(&toml_val);
+    if lib_target.root_outside_src() {
+        if let Err(e) =
+            copy_and_transform_lib_root(output, source_path, &dest_dir, &lib_target.root_path())
+        {
+            eprintln!("[FerrumPy] Failed to copy lib root: {}", e);
+            return None;
+        }
+    }
+
+    // Copy the build script and files pulled in by include_str!/include_bytes!
+    if let Err(e) = copy_build_script(output, source_path, &dest_dir, &toml_val) {
+        eprintln!("[FerrumPy] Failed to copy build script: {}", e);
+        return None;
+    }
+    if let Err(e) = copy_referenced_assets(output, &mut guard, source_path, &dest_dir) {
+        eprintln!(
+            "[FerrumPy] Warning: Failed to copy assets of '{}': {}",
+            name, e
+        );
+    }
+
     // Generate resolved Cargo.toml
     let resolved_cargo =
-        generate_resolved_cargo_toml(&toml_val, workspace_deps, path_base, output_dir);
+        match generate_resolved_cargo_toml(&toml_val, workspace_deps, path_base, output) {
+            Ok(cargo) => cargo,
+            Err(e) => {
+                eprintln!("[FerrumPy] Failed to generate resolved Cargo.toml: {}", e);
+                return None;
+            }
+        };
 
-    if let Err(e) = fs::write(dest_dir.join("Cargo.toml"), &resolved_cargo) {
+    if let Err(e) = output.write(&dest_dir.join("Cargo.toml"), &resolved_cargo) {
         eprintln!("[FerrumPy] Failed to write resolved Cargo.toml: {}", e);
         return None;
     }
@@ -462,92 +1102,311 @@ fn generate_resolved_cargo_toml(
     toml_val: &toml::Value,
     workspace_deps: &Option<toml::value::Table>,
     path_base: &Path,
-    output_dir: &Path,
-) -> String {
-    let mut result = String::new();
+    output: &Output,
+) -> Result<String> {
+    let mut result = toml::Table::new();
 
     // Copy [package] section, removing workspace inheritance
-    if let Some(package) = toml_val.get("package") {
-        result.push_str("[package]\n");
-        if let Some(table) = package.as_table() {
-            for (key, val) in table {
-                // Skip workspace inherited fields
-                if let toml::Value::Table(inner) = val {
-                    if inner.get("workspace").is_some() {
-                        continue;
-                    }
-                }
-                // Simple values
-                match val {
-                    toml::Value::String(s) => result.push_str(&format!("{} = \"{}\"\n", key, s)),
-                    toml::Value::Integer(i) => result.push_str(&format!("{} = {}\n", key, i)),
-                    toml::Value::Boolean(b) => result.push_str(&format!("{} = {}\n", key, b)),
-                    _ => {}
-                }
-            }
-        }
+    if let Some(table) = toml_val.get("package").and_then(|p| p.as_table()) {
+        let mut package: toml::Table = table
+            .iter()
+            .filter(|(_, val)| !val.as_table().is_some_and(|t| t.contains_key("workspace")))
+            .map(|(key, val)| (key.clone(), val.clone()))
+            .collect();
         // Add default edition if not present
-        if !result.contains("edition") {
-            result.push_str("edition = \"2021\"\n");
-        }
-        result.push('\n');
+        package.entry("edition").or_insert_with(|| "2021".into());
+        result.insert("package".to_string(), toml::Value::Table(package));
     }
 
     // Copy [lib] section if present
     if let Some(lib) = toml_val.get("lib") {
-        result.push_str("[lib]\n");
-        if let Some(table) = lib.as_table() {
-            for (key, val) in table {
-                result.push_str(&format!("{} = {}\n", key, format_toml_value(val)));
+        result.insert("lib".to_string(), lib.clone());
+    }
+
+    // Resolve [dependencies] - always add serde for derive macros
+    let mut dependencies = toml::Table::new();
+    dependencies.insert("serde".to_string(), serde_dependency());
+
+    if let Some(table) = toml_val.get("dependencies").and_then(|d| d.as_table()) {
+        for (dep_name, dep_val) in table {
+            // Skip serde if already in deps
+            if dep_name == "serde" || dep_name == "serde_json" {
+                continue;
+            }
+            if let Some(resolved) =
+                resolve_dependency(dep_name, dep_val, workspace_deps, path_base, output)
+            {
+                dependencies.insert(dep_name.clone(), resolved);
             }
         }
-        result.push('\n');
+    }
+    result.insert("dependencies".to_string(), toml::Value::Table(dependencies));
+
+    // Resolve [build-dependencies] for the copied build script
+    if let Some(table) = toml_val
+        .get("build-dependencies")
+        .and_then(|d| d.as_table())
+    {
+        let build_dependencies: toml::Table = table
+            .iter()
+            .filter_map(|(dep_name, dep_val)| {
+                resolve_dependency(dep_name, dep_val, workspace_deps, path_base, output)
+                    .map(|resolved| (dep_name.clone(), resolved))
+            })
+            .collect();
+        result.insert(
+            "build-dependencies".to_string(),
+            toml::Value::Table(build_dependencies),
+        );
     }
 
-    // Resolve [dependencies] - always add serde for derive macros
-    result.push_str("[dependencies]\n");
-    result.push_str("serde = { version = \"1\", features = [\"derive\"] }\n");
-
-    if let Some(deps) = toml_val.get("dependencies") {
-        if let Some(table) = deps.as_table() {
-            for (dep_name, dep_val) in table {
-                // Skip serde if already in deps
-                if dep_name == "serde" || dep_name == "serde_json" {
-                    continue;
-                }
-                if let Some(resolved) =
-                    resolve_dependency(dep_name, dep_val, workspace_deps, path_base, output_dir)
-                {
-                    result.push_str(&resolved);
-                    result.push('\n');
+    // Keep [lints], inherited ones included; [profile] and [workspace] only
+    // apply at a workspace root, which the copy is not part of
+    let crate_name = toml_val
+        .get("package")
+        .and_then(|p| p.get("name"))
+        .and_then(|n| n.as_str())
+        .unwrap_or("?");
+    if let Some(lints) = toml_val.get("lints").and_then(|l| l.as_table()) {
+        let inherited = lints.get("workspace").and_then(|w| w.as_bool()) == Some(true);
+        if !inherited {
+            result.insert("lints".to_string(), toml::Value::Table(lints.clone()));
+        } else if let Some(ws_lints) = workspace_lints(path_base) {
+            result.insert("lints".to_string(), ws_lints);
+        } else {
+            eprintln!(
+                "[FerrumPy] Warning: Dropping [lints] of '{}' (workspace lints not found)",
+                crate_name
+            );
+            output.record_stripped(crate_name, "lints");
+        }
+    }
+    for section in ["profile", "workspace"] {
+        if toml_val.get(section).is_some() {
+            output.record_stripped(crate_name, section);
+        }
+    }
+
+    validate_resolved_manifest(&result)?;
+    Ok(toml::to_string(&result)?)
+}
+
+/// `[workspace.lints]` of the workspace rooted at `root`
+fn workspace_lints(root: &Path) -> Option<toml::Value> {
+    read_manifest(root)?.get("workspace")?.get("lints").cloned()
+}
+
+/// Check a resolved manifest no longer needs a workspace to build
+fn validate_resolved_manifest(manifest: &toml::Table) -> Result<()> {
+    for (section, value) in manifest {
+        let Some(table) = value.as_table() else {
+            continue;
+        };
+        if section == "workspace" || section == "profile" {
+            anyhow::bail!("Resolved manifest still has a [{}] table", section);
+        }
+        if table.get("workspace").and_then(|w| w.as_bool()) == Some(true) {
+            anyhow::bail!(
+                "Resolved manifest still inherits [{}] from the workspace",
+                section
+            );
+        }
+        let inherited: Vec<&String> = table
+            .iter()
+            .filter(|(_, v)| v.get("workspace").and_then(|w| w.as_bool()) == Some(true))
+            .map(|(k, _)| k)
+            .collect();
+        if !inherited.is_empty() {
+            anyhow::bail!(
+                "Resolved manifest still inherits {} in [{}] from the workspace",
+                inherited
+                    .iter()
+                    .map(|k| format!("'{}'", k))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                section
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Asset directories copied when a crate's sources mention them
+/// (e.g. `sqlx::migrate!("./migrations")`, askama templates)
+const CONVENTIONAL_ASSET_DIRS: &[&str] = &["migrations", "templates"];
+
+/// Copy the crate's build script, if any, preserving its relative path
+fn copy_build_script(
+    output: &Output,
+    src_crate: &Path,
+    dst_crate: &Path,
+    manifest: &toml::Value,
+) -> Result<()> {
+    let build = manifest.get("package").and_then(|p| p.get("build"));
+    let script = match build {
+        Some(toml::Value::String(path)) => PathBuf::from(path),
+        Some(toml::Value::Boolean(false)) => return Ok(()),
+        _ => PathBuf::from("build.rs"),
+    };
+
+    let src = src_crate.join(&script);
+    if !src.is_file() {
+        return Ok(());
+    }
+    let dst = dst_crate.join(&script);
+    if let Some(parent) = dst.parent() {
+        output.create_dir_all(parent)?;
+    }
+    output.copy(&src, &dst)?;
+    Ok(())
+}
+
+/// Copy non-Rust files a crate's sources reference, preserving layout:
+/// `include_str!`/`include_bytes!` targets and conventional asset directories
+fn copy_referenced_assets(
+    output: &Output,
+    guard: &mut CopyGuard,
+    src_crate: &Path,
+    dst_crate: &Path,
+) -> Result<()> {
+    let crate_root = src_crate.canonicalize()?;
+    let mut sources = Vec::new();
+    collect_rust_files(&mut CopyGuard::new(u64::MAX), src_crate, &mut sources)?;
+
+    let mut mentioned_dirs = Vec::new();
+    for file in &sources {
+        let content = fs::read_to_string(file)?;
+        let file_dir = file.parent().unwrap_or(src_crate);
+
+        for include in collect_include_paths(&content) {
+            // Only copy files that live inside the crate
+            let Ok(target) = file_dir.join(&include).canonicalize() else {
+                continue;
+            };
+            let Ok(rel) = target.strip_prefix(&crate_root) else {
+                continue;
+            };
+            let dst = dst_crate.join(rel);
+            if target.is_file() && !output.exists(&dst) {
+                guard.charge(file_dir, target.metadata()?.len())?;
+                if let Some(parent) = dst.parent() {
+                    output.create_dir_all(parent)?;
                 }
+                output.copy(&target, &dst)?;
+            }
+        }
+
+        for dir in CONVENTIONAL_ASSET_DIRS {
+            if content.contains(dir) && !mentioned_dirs.contains(dir) {
+                mentioned_dirs.push(*dir);
+            }
+        }
+    }
+
+    for dir in mentioned_dirs {
+        let src = src_crate.join(dir);
+        if src.is_dir() {
+            copy_dir_all(output, guard, &src, &dst_crate.join(dir))?;
+        }
+    }
+    Ok(())
+}
+
+/// Collect `.rs` files under a crate directory, skipping `target/` and `.git/`
+fn collect_rust_files(guard: &mut CopyGuard, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !guard.enter(dir)? {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_rust_files(guard, &path, files)?;
+        } else if path.extension().and_then(|e| e.to_str()) == Some("rs") {
+            files.push(path);
+        }
+    }
+    guard.leave();
+    Ok(())
+}
+
+/// Extract the literal paths passed to `include_str!`/`include_bytes!`
+fn collect_include_paths(source: &str) -> Vec<String> {
+    let mut paths = Vec::new();
+    for macro_name in ["include_str!", "include_bytes!"] {
+        let mut rest = source;
+        while let Some(pos) = rest.find(macro_name) {
+            rest = &rest[pos + macro_name.len()..];
+            let args = rest.trim_start();
+            let Some(args) = args.strip_prefix('(') else {
+                continue;
+            };
+            // Only plain string literals; `concat!(env!(...))` paths are skipped
+            let Some(literal) = args.trim_start().strip_prefix('"') else {
+                continue;
+            };
+            if let Some(end) = literal.find('"') {
+                paths.push(literal[..end].to_string());
             }
         }
     }
-    result.push('\n');
+    paths
+}
 
-    result
+/// Recursively copy a directory verbatim
+fn copy_dir_all(output: &Output, guard: &mut CopyGuard, src: &Path, dst: &Path) -> Result<()> {
+    if !guard.enter(src)? {
+        return Ok(());
+    }
+    output.create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        if src_path.is_dir() {
+            copy_dir_all(output, guard, &src_path, &dst_path)?;
+        } else if let Ok(metadata) = src_path.metadata() {
+            guard.charge(src, metadata.len())?;
+            output.copy(&src_path, &dst_path)?;
+        }
+    }
+    guard.leave();
+    Ok(())
 }
 
 /// Copy src directory and transform Rust files (add serde derives)
-fn copy_and_transform_src(src: &Path, dst: &Path, add_serde: bool) -> anyhow::Result<()> {
-    fs::create_dir_all(dst)?;
+fn copy_and_transform_src(
+    output: &Output,
+    guard: &mut CopyGuard,
+    src: &Path,
+    dst: &Path,
+    add_serde: bool,
+) -> anyhow::Result<()> {
+    if !guard.enter(src)? {
+        return Ok(());
+    }
+    output.create_dir_all(dst)?;
     for entry in fs::read_dir(src)? {
         let entry = entry?;
-        let file_type = entry.file_type()?;
         let src_path = entry.path();
         let dst_path = dst.join(entry.file_name());
 
-        if file_type.is_dir() {
-            copy_and_transform_src(&src_path, &dst_path, add_serde)?;
+        // Follows symlinks; the guard stops cycles
+        if src_path.is_dir() {
+            copy_and_transform_src(output, guard, &src_path, &dst_path, add_serde)?;
         } else {
+            // Dangling symlinks have no metadata and are skipped
+            let Ok(metadata) = src_path.metadata() else {
+                continue;
+            };
+            guard.charge(src, metadata.len())?;
+
             // Check if it's a Rust file
             if src_path.extension().and_then(|e| e.to_str()) == Some("rs") {
                 // Read and transform the file
                 let content = fs::read_to_string(&src_path)?;
                 match transformer::transform_module(&content, add_serde) {
                     Ok(transformed) => {
-                        fs::write(&dst_path, transformed)?;
+                        output.write(&dst_path, transformed)?;
                     }
                     Err(e) => {
                         // If transformation fails, just copy the original
@@ -555,38 +1414,44 @@ fn copy_and_transform_src(src: &Path, dst: &Path, add_serde: bool) -> anyhow::Re
                             "[FerrumPy] Warning: Could not transform {:?}: {}",
                             src_path, e
                         );
-                        fs::copy(&src_path, &dst_path)?;
+                        output.copy(&src_path, &dst_path)?;
                     }
                 }
             } else {
                 // Non-Rust files, just copy
-                fs::copy(&src_path, &dst_path)?;
+                output.copy(&src_path, &dst_path)?;
             }
         }
     }
+    guard.leave();
     Ok(())
 }
 
-/// Format a TOML value for inline use
-fn format_toml_value(val: &toml::Value) -> String {
-    match val {
-        toml::Value::String(s) => format!("\"{}\"", s),
-        toml::Value::Array(arr) => {
-            let items: Vec<String> = arr.iter().map(format_toml_value).collect();
-            format!("[{}]", items.join(", "))
-        }
-        toml::Value::Boolean(b) => b.to_string(),
-        toml::Value::Integer(i) => i.to_string(),
-        toml::Value::Float(f) => f.to_string(),
-        toml::Value::Table(t) => {
-            let parts: Vec<String> = t
-                .iter()
-                .map(|(k, v)| format!("{} = {}", k, format_toml_value(v)))
-                .collect();
-            format!("{{ {} }}", parts.join(", "))
+/// Copy a crate root file and the modules it declares, relative to `dst_crate`
+fn copy_and_transform_lib_root(
+    output: &Output,
+    src_crate: &Path,
+    dst_crate: &Path,
+    root_path: &Path,
+) -> anyhow::Result<()> {
+    let root_file = src_crate.join(root_path);
+    let root_dir = root_path.parent().unwrap_or(Path::new(""));
+
+    let mut files = resolver::resolve_modules(&root_file)?;
+    files.insert(
+        PathBuf::from(root_path.file_name().unwrap_or_default()),
+        fs::read_to_string(&root_file)?,
+    );
+
+    for (rel_path, content) in files {
+        let dest = dst_crate.join(root_dir).join(rel_path);
+        if let Some(parent) = dest.parent() {
+            output.create_dir_all(parent)?;
         }
-        _ => toml::to_string(val).unwrap_or_default().trim().to_string(),
+        let transformed = transformer::transform_module(&content, true).unwrap_or(content);
+        output.write(&dest, transformed)?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -598,36 +1463,17 @@ mod tests {
         let config = LibGenConfig::default();
         assert!(config.add_serde_derives);
         assert!(config.output_dir.is_none());
+        assert!(config.strip_unavailable_derives);
+        assert!(config.main_attributes.iter().any(|a| a == "tokio::main"));
     }
 
     #[test]
-    fn test_format_toml_value_string() {
+    fn test_resolve_dependency_simple_version() {
         let val = toml::Value::String("1.0".to_string());
-        assert_eq!(format_toml_value(&val), "\"1.0\"");
-    }
-
-    #[test]
-    fn test_format_toml_value_array() {
-        let val = toml::Value::Array(vec![
-            toml::Value::String("derive".to_string()),
-            toml::Value::String("serde".to_string()),
-        ]);
-        assert_eq!(format_toml_value(&val), "[\"derive\", \"serde\"]");
-    }
-
-    #[test]
-    fn test_format_toml_value_bool() {
-        let val = toml::Value::Boolean(true);
-        assert_eq!(format_toml_value(&val), "true");
-    }
-
-    #[test]
-    fn test_resolve_dependency_simple_version() {
-        let val = toml::Value::String("1.0".to_string());
-        let dummy_path = Path::new("/tmp/test");
-        let dummy_output = Path::new("/tmp/output");
-        let result = resolve_dependency("serde", &val, &None, dummy_path, dummy_output);
-        assert_eq!(result, Some("serde = \"1.0\"".to_string()));
+        let dummy_path = Path::new("/tmp/test");
+        let dummy_output = &Output::new("/tmp/output", false);
+        let result = resolve_dependency("serde", &val, &None, dummy_path, dummy_output);
+        assert_eq!(result, Some(val));
     }
 
     #[test]
@@ -643,14 +1489,9 @@ mod tests {
         );
         let val = toml::Value::Table(table);
         let dummy_path = Path::new("/tmp/test");
-        let dummy_output = Path::new("/tmp/output");
-        let result = resolve_dependency("serde", &val, &None, dummy_path, dummy_output).unwrap();
-        // Order may vary, so check both possibilities
-        assert!(
-            result.contains("version = \"1.0\"") && result.contains("features = [\"derive\"]"),
-            "Got: {}",
-            result
-        );
+        let dummy_output = &Output::new("/tmp/output", false);
+        let result = resolve_dependency("serde", &val, &None, dummy_path, dummy_output);
+        assert_eq!(result, Some(val));
     }
 
     #[test]
@@ -668,7 +1509,7 @@ mod tests {
         );
 
         let dummy_path = Path::new("/tmp/test");
-        let dummy_output = Path::new("/tmp/output");
+        let dummy_output = &Output::new("/tmp/output", false);
         let result = resolve_dependency(
             "bitflags",
             &dep_val,
@@ -676,7 +1517,7 @@ mod tests {
             dummy_path,
             dummy_output,
         );
-        assert_eq!(result, Some("bitflags = \"2.4\"".to_string()));
+        assert_eq!(result, Some(toml::Value::String("2.4".to_string())));
     }
 
     #[test]
@@ -697,12 +1538,12 @@ mod tests {
         ws_deps.insert("tokio".to_string(), toml::Value::Table(tokio_table));
 
         let dummy_path = Path::new("/tmp/test");
-        let dummy_output = Path::new("/tmp/output");
+        let dummy_output = &Output::new("/tmp/output", false);
         let result =
             resolve_dependency("tokio", &dep_val, &Some(ws_deps), dummy_path, dummy_output)
                 .unwrap();
-        assert!(result.contains("version = \"1\""), "Got: {}", result);
-        assert!(result.contains("features = [\"full\"]"), "Got: {}", result);
+        assert_eq!(result["version"].as_str(), Some("1"));
+        assert_eq!(result["features"], toml::Value::Array(vec!["full".into()]));
     }
 
     #[test]
@@ -713,9 +1554,16 @@ mod tests {
         let dep_val = toml::Value::Table(dep_table);
 
         let dummy_path = Path::new("/tmp/test");
-        let dummy_output = Path::new("/tmp/output");
+        let dummy_output = &Output::new("/tmp/output", false);
         let result = resolve_dependency("unknown_dep", &dep_val, &None, dummy_path, dummy_output);
         assert_eq!(result, None); // Should skip with warning
+        assert_eq!(
+            dummy_output.take_skipped_dependencies(),
+            vec![(
+                "unknown_dep".to_string(),
+                "not found in workspace dependencies".to_string()
+            )]
+        );
     }
 
     #[test]
@@ -728,7 +1576,7 @@ mod tests {
         let ws_deps = toml::value::Table::new(); // Empty
 
         let dummy_path = Path::new("/tmp/test");
-        let dummy_output = Path::new("/tmp/output");
+        let dummy_output = &Output::new("/tmp/output", false);
         let result = resolve_dependency(
             "missing_dep",
             &dep_val,
@@ -750,15 +1598,14 @@ mod tests {
         let dep_val = toml::Value::Table(dep_table);
 
         let project_path = Path::new("/home/user/myproject");
-        let dummy_output = Path::new("/tmp/output");
+        let dummy_output = &Output::new("/tmp/output", false);
         let result =
             resolve_dependency("other_crate", &dep_val, &None, project_path, dummy_output).unwrap();
 
         // Should convert relative path to absolute
-        assert!(
-            result.contains("path = \"/home/user/myproject/crates/other_crate\""),
-            "Got: {}",
-            result
+        assert_eq!(
+            result["path"].as_str(),
+            Some("/home/user/myproject/crates/other_crate")
         );
     }
 
@@ -777,16 +1624,15 @@ mod tests {
         let dep_val = toml::Value::Table(dep_table);
 
         let project_path = Path::new("/workspace/project");
-        let dummy_output = Path::new("/tmp/output");
+        let dummy_output = &Output::new("/tmp/output", false);
         let result =
             resolve_dependency("my_lib", &dep_val, &None, project_path, dummy_output).unwrap();
 
-        assert!(
-            result.contains("path = \"/workspace/project/crates/my_lib\""),
-            "Got: {}",
-            result
+        assert_eq!(
+            result["path"].as_str(),
+            Some("/workspace/project/crates/my_lib")
         );
-        assert!(result.contains("features = [\"async\"]"), "Got: {}", result);
+        assert_eq!(result["features"], toml::Value::Array(vec!["async".into()]));
     }
 
     #[test]
@@ -809,7 +1655,7 @@ mod tests {
         // path_base should be workspace root, not project path
         // In real usage, this is the workspace root from find_workspace_dependencies
         let workspace_root = Path::new("/workspace/myproject");
-        let dummy_output = Path::new("/tmp/output");
+        let dummy_output = &Output::new("/tmp/output", false);
         let result = resolve_dependency(
             "common",
             &dep_val,
@@ -820,10 +1666,1214 @@ mod tests {
         .unwrap();
 
         // Path should be relative to workspace root, not some subdir
+        assert_eq!(
+            result["path"].as_str(),
+            Some("/workspace/myproject/crates/common"),
+            "Expected path relative to workspace root"
+        );
+    }
+
+    #[test]
+    fn test_lib_target_from_manifest() {
+        let manifest: toml::Value = r#"
+[package]
+name = "my-crate"
+
+[lib]
+name = "renamed_lib"
+path = "source/entry.rs"
+"#
+        .parse()
+        .unwrap();
+
+        let target = LibTarget::from_manifest(&manifest);
+        assert_eq!(target.name.as_deref(), Some("renamed_lib"));
+        assert_eq!(target.root_path(), PathBuf::from("source/entry.rs"));
+        assert!(target.root_outside_src());
+        assert_eq!(
+            LibTarget::default().root_path(),
+            PathBuf::from("src/lib.rs")
+        );
+    }
+
+    #[test]
+    fn test_generate_lib_with_custom_lib_path_and_name() {
+        let temp = tempfile::TempDir::new().unwrap();
+
+        // Sibling crate that renames its lib target
+        let helper = temp.path().join("helper-crate");
+        fs::create_dir_all(helper.join("src")).unwrap();
+        fs::write(
+            helper.join("Cargo.toml"),
+            "[package]\nname = \"helper-crate\"\nversion = \"0.1.0\"\n\n[lib]\nname = \"renamed_lib\"\n",
+        )
+        .unwrap();
+        fs::write(helper.join("src/lib.rs"), "pub fn help() {}\n").unwrap();
+
+        // Project whose crate root is moved out of src/lib.rs
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("source")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            r#"[package]
+name = "project"
+version = "0.1.0"
+
+[lib]
+name = "project_core"
+path = "source/entry.rs"
+
+[dependencies]
+helper-crate = { path = "../helper-crate" }
+"#,
+        )
+        .unwrap();
+        fs::write(
+            project.join("source/entry.rs"),
+            "mod util;\npub struct Config { pub port: u16 }\n",
+        )
+        .unwrap();
+        fs::write(project.join("source/util.rs"), "fn port() -> u16 { 80 }\n").unwrap();
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            add_serde_derives: false,
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        generate_lib(&project, config).unwrap();
+
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(lib_rs.contains("pub struct Config"), "Got: {}", lib_rs);
+        assert!(
+            lib_rs.contains("pub use renamed_lib::*;"),
+            "Got: {}",
+            lib_rs
+        );
+        assert!(!lib_rs.contains("helper_crate"), "Got: {}", lib_rs);
+        assert!(output.join("src/util.rs").exists());
+    }
+
+    #[test]
+    fn test_path_dep_crate_ident_renamed_dependency() {
+        let mut dep_table = toml::value::Table::new();
+        dep_table.insert(
+            "path".to_string(),
+            toml::Value::String("crates/does-not-exist".to_string()),
+        );
+        dep_table.insert(
+            "package".to_string(),
+            toml::Value::String("real-name".to_string()),
+        );
+        let dep_val = toml::Value::Table(dep_table);
+
+        let ident = path_dep_crate_ident("my-alias", &dep_val, &None, Path::new("/tmp"));
+        assert_eq!(ident, "my_alias");
+    }
+
+    #[test]
+    fn test_generate_cargo_toml_renamed_registry_dependency() {
+        let temp = tempfile::TempDir::new().unwrap();
+        fs::write(
+            temp.path().join("Cargo.toml"),
+            r#"[workspace]
+members = ["app"]
+
+[workspace.dependencies]
+json = { package = "serde_json", version = "1" }
+helper = { path = "helper" }
+"#,
+        )
+        .unwrap();
+        let helper = temp.path().join("helper");
+        fs::create_dir_all(helper.join("src")).unwrap();
+        fs::write(
+            helper.join("Cargo.toml"),
+            "[package]\nname = \"helper\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(helper.join("src/lib.rs"), "pub fn help() {}\n").unwrap();
+
+        let project = temp.path().join("app");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            r#"[package]
+name = "app"
+version = "0.1.0"
+
+[dependencies]
+json = { workspace = true, features = ["preserve_order"] }
+yaml = { package = "serde_yaml", version = "0.9" }
+helper = { workspace = true }
+"#,
+        )
+        .unwrap();
+
+        let manifest = generate_cargo_toml(
+            &project,
+            &Output::new(temp.path().join("out"), false),
+            false,
+            false,
+            false,
+            &FeatureSet::default(),
+        )
+        .unwrap();
+        let cargo: toml::Table = manifest.content.parse().unwrap();
+        let deps = &cargo["dependencies"];
+
+        assert_eq!(deps["json"]["package"].as_str(), Some("serde_json"));
+        assert_eq!(deps["json"]["version"].as_str(), Some("1"));
+        assert_eq!(
+            deps["json"]["features"].as_array().unwrap(),
+            &vec![toml::Value::from("preserve_order")]
+        );
+        assert_eq!(deps["yaml"]["package"].as_str(), Some("serde_yaml"));
+
+        // Only the real path dependency is re-exported
+        let idents: Vec<&str> = manifest
+            .path_deps
+            .iter()
+            .map(|d| d.crate_ident.as_str())
+            .collect();
+        assert_eq!(idents, vec!["helper"]);
+        assert!(manifest.crate_idents.contains(&"json".to_string()));
+        assert!(!manifest.crate_idents.contains(&"serde_json".to_string()));
+    }
+
+    #[test]
+    fn test_generate_lib_renamed_path_dependency() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let core = temp.path().join("core");
+        fs::create_dir_all(core.join("src")).unwrap();
+        fs::write(
+            core.join("Cargo.toml"),
+            "[package]\nname = \"helper-core\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(core.join("src/lib.rs"), "pub struct Widget;\n").unwrap();
+
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            r#"[package]
+name = "project"
+version = "0.1.0"
+
+[dependencies]
+helper = { package = "helper-core", path = "../core" }
+"#,
+        )
+        .unwrap();
+        fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            add_serde_derives: false,
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        generate_lib(&project, config).unwrap();
+
+        let cargo: toml::Table = fs::read_to_string(output.join("Cargo.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            cargo["dependencies"]["helper"]["package"].as_str(),
+            Some("helper-core")
+        );
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(lib_rs.contains("pub use helper::"), "Got: {}", lib_rs);
+        assert!(!lib_rs.contains("helper_core"), "Got: {}", lib_rs);
+    }
+
+    #[test]
+    fn test_generate_lib_aliases_colliding_reexports() {
+        let temp = tempfile::TempDir::new().unwrap();
+
+        for (name, source) in [
+            ("dep_a", "pub struct Config;\npub struct OnlyA;\n"),
+            ("dep_b", "pub struct Config;\npub fn only_b() {}\n"),
+        ] {
+            let dir = temp.path().join(name);
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::write(
+                dir.join("Cargo.toml"),
+                format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name),
+            )
+            .unwrap();
+            fs::write(dir.join("src/lib.rs"), source).unwrap();
+        }
+
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            r#"[package]
+name = "project"
+version = "0.1.0"
+
+[dependencies]
+dep_a = { path = "../dep_a" }
+dep_b = { path = "../dep_b" }
+"#,
+        )
+        .unwrap();
+        fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            add_serde_derives: false,
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        let generated = generate_lib(&project, config).unwrap();
+
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(
+            lib_rs.contains("pub use dep_a::{OnlyA};"),
+            "Got: {}",
+            lib_rs
+        );
         assert!(
-            result.contains("path = \"/workspace/myproject/crates/common\""),
-            "Expected path relative to workspace root. Got: {}",
-            result
+            lib_rs.contains("pub use dep_b::{only_b};"),
+            "Got: {}",
+            lib_rs
         );
+        assert!(lib_rs.contains("pub use dep_a;"), "Got: {}", lib_rs);
+        assert!(lib_rs.contains("pub use dep_b;"), "Got: {}", lib_rs);
+        assert!(!lib_rs.contains("::*"), "Got: {}", lib_rs);
+
+        let aliases = &generated.report.reexport_aliases;
+        assert_eq!(aliases.len(), 2);
+        assert!(aliases
+            .iter()
+            .all(|a| a.shadowed == vec!["Config".to_string()]));
+        assert_eq!(generated.report.notes().len(), 2);
+    }
+
+    #[test]
+    fn test_collect_include_paths() {
+        let source = r#"
+const SCHEMA: &str = include_str!("../queries/schema.sql");
+const LOGO: &[u8] = include_bytes!( "logo.png" );
+const GEN: &str = include_str!(concat!(env!("OUT_DIR"), "/gen.rs"));
+"#;
+        assert_eq!(
+            collect_include_paths(source),
+            vec!["../queries/schema.sql".to_string(), "logo.png".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_resolved_path_dep_copies_included_files() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let dep = temp.path().join("queries-crate");
+        fs::create_dir_all(dep.join("src")).unwrap();
+        fs::create_dir_all(dep.join("queries")).unwrap();
+        fs::create_dir_all(dep.join("migrations")).unwrap();
+        fs::write(
+            dep.join("Cargo.toml"),
+            "[package]\nname = \"queries-crate\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dep.join("src/lib.rs"),
+            "pub const SCHEMA: &str = include_str!(\"../queries/schema.sql\");\n\
+             pub const MIGRATIONS: &str = \"./migrations\";\n",
+        )
+        .unwrap();
+        fs::write(dep.join("queries/schema.sql"), "CREATE TABLE t ();").unwrap();
+        fs::write(dep.join("migrations/001_init.sql"), "-- init").unwrap();
+
+        let output = Output::new(temp.path().join("out"), false);
+        let dest =
+            create_resolved_path_dep("queries-crate", &dep, &None, temp.path(), &output).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("queries/schema.sql")).unwrap(),
+            "CREATE TABLE t ();"
+        );
+        assert!(dest.join("migrations/001_init.sql").exists());
+    }
+
+    #[test]
+    fn test_resolved_path_dep_copies_build_script() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let dep = temp.path().join("built");
+        fs::create_dir_all(dep.join("src")).unwrap();
+        fs::write(
+            dep.join("Cargo.toml"),
+            r#"[package]
+name = "built"
+version = "0.1.0"
+
+[build-dependencies]
+cc = "1.0"
+"#,
+        )
+        .unwrap();
+        fs::write(dep.join("build.rs"), "fn main() {}\n").unwrap();
+        fs::write(
+            dep.join("src/lib.rs"),
+            "include!(concat!(env!(\"OUT_DIR\"), \"/gen.rs\"));\n",
+        )
+        .unwrap();
+
+        let output = Output::new(temp.path().join("out"), false);
+        let dest = create_resolved_path_dep("built", &dep, &None, temp.path(), &output).unwrap();
+
+        assert!(dest.join("build.rs").exists());
+        let cargo: toml::Table = fs::read_to_string(dest.join("Cargo.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(cargo["build-dependencies"]["cc"].as_str(), Some("1.0"));
+    }
+
+    #[test]
+    fn test_generate_lib_respects_features() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            r#"[package]
+name = "project"
+version = "0.1.0"
+
+[dependencies]
+log = { version = "0.4", optional = true }
+
+[features]
+extra = ["dep:log"]
+"#,
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/lib.rs"),
+            "#[cfg(feature = \"extra\")]\nmod extra;\n\n#[cfg(feature = \"extra\")]\npub struct Extra;\n\npub struct Always;\n",
+        )
+        .unwrap();
+        fs::write(project.join("src/extra.rs"), "pub struct Detail;\n").unwrap();
+
+        for enabled in [false, true] {
+            let output = temp.path().join(format!("out_{}", enabled));
+            let config = LibGenConfig {
+                add_serde_derives: false,
+                output_dir: Some(output.clone()),
+                features: if enabled {
+                    vec!["extra".to_string()]
+                } else {
+                    Vec::new()
+                },
+                ..Default::default()
+            };
+            generate_lib(&project, config).unwrap();
+
+            let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+            let cargo: toml::Table = fs::read_to_string(output.join("Cargo.toml"))
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(lib_rs.contains("pub struct Always"), "Got: {}", lib_rs);
+            assert_eq!(
+                lib_rs.contains("pub struct Extra"),
+                enabled,
+                "Got: {}",
+                lib_rs
+            );
+            assert_eq!(output.join("src/extra.rs").exists(), enabled);
+            let log = cargo["dependencies"].get("log");
+            assert_eq!(log.is_some(), enabled, "Got: {}", cargo);
+            assert!(log.is_none_or(|log| log.get("optional").is_none()));
+        }
+    }
+
+    /// Quote a string as a TOML basic string
+    fn toml_str(s: &str) -> String {
+        toml::Value::String(s.to_string()).to_string()
+    }
+
+    #[test]
+    fn test_generate_cargo_toml_golden() {
+        let temp = tempfile::TempDir::new().unwrap();
+        // Backslashes and quotes must survive serialization (Windows paths)
+        let project = temp.path().join(r#"we"ird\project"#);
+        fs::create_dir_all(&project).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            r#"[package]
+name = "project"
+version = "0.1.0"
+
+[dependencies]
+"dotted.name" = "1.2"
+anyhow = { version = "1", default-features = false }
+local = { path = "crates\\local", features = ["x"] }
+"#,
+        )
+        .unwrap();
+
+        let manifest = generate_cargo_toml(
+            &project,
+            &Output::new(temp.path().join("out"), false),
+            true,
+            false,
+            false,
+            &FeatureSet::default(),
+        )
+        .unwrap();
+
+        let local_path = project.join(r"crates\local");
+        let expected: toml::Table = format!(
+            r#"[package]
+name = "ferrumpy_snapshot"
+version = "0.1.5"
+edition = "2021"
+
+[lib]
+crate-type = ["rlib"]
+
+[dependencies]
+serde = {{ version = "1", features = ["derive"] }}
+serde_json = "1"
+"dotted.name" = "1.2"
+anyhow = {{ version = "1", default-features = false }}
+local = {{ path = {}, features = ["x"] }}
+"#,
+            toml_str(&local_path.to_string_lossy())
+        )
+        .parse()
+        .unwrap();
+        let actual: toml::Table = manifest.content.parse().unwrap();
+        assert_eq!(actual, expected, "Got: {}", manifest.content);
+    }
+
+    #[test]
+    fn test_generate_resolved_cargo_toml_golden() {
+        let manifest: toml::Value = r#"
+[package]
+name = "dep"
+version = "0.1.0"
+authors = ["A <a@example.com>"]
+license.workspace = true
+
+[package.metadata.release]
+date = 1979-05-27T07:32:00Z
+
+[lib]
+name = "dep_lib"
+crate-type = ["rlib"]
+
+[dependencies]
+"dotted.name" = { workspace = true }
+shared = { workspace = true, features = ["extra"] }
+sibling = { path = "C:\\work\\sibling" }
+
+[build-dependencies]
+cc = "1.0"
+"#
+        .parse()
+        .unwrap();
+
+        let mut ws_deps = toml::Table::new();
+        ws_deps.insert("dotted.name".to_string(), "2".into());
+
+        let base = Path::new(r"C:\ws");
+        let sibling = base.join(r"C:\work\sibling");
+        let resolved = generate_resolved_cargo_toml(
+            &manifest,
+            &Some(ws_deps),
+            base,
+            &Output::new("/tmp/out", false),
+        )
+        .unwrap();
+
+        let expected: toml::Table = format!(
+            r#"[package]
+name = "dep"
+version = "0.1.0"
+authors = ["A <a@example.com>"]
+edition = "2021"
+
+[package.metadata.release]
+date = 1979-05-27T07:32:00Z
+
+[lib]
+name = "dep_lib"
+crate-type = ["rlib"]
+
+[dependencies]
+serde = {{ version = "1", features = ["derive"] }}
+"dotted.name" = "2"
+sibling = {{ path = {} }}
+
+[build-dependencies]
+cc = "1.0"
+"#,
+            toml_str(&sibling.to_string_lossy())
+        )
+        .parse()
+        .unwrap();
+        let actual: toml::Table = resolved.parse().unwrap();
+        assert_eq!(actual, expected, "Got: {}", resolved);
+    }
+
+    #[test]
+    fn test_resolved_path_dep_inherits_workspace_lints() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = temp.path().join("ws");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            r#"[workspace]
+members = ["app", "core"]
+
+[workspace.dependencies]
+core = { path = "core" }
+log = "0.4"
+
+[workspace.lints.rust]
+unsafe_code = "deny"
+"#,
+        )
+        .unwrap();
+        fs::create_dir_all(root.join("app/src")).unwrap();
+        fs::write(
+            root.join("app/Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\ncore.workspace = true\n",
+        )
+        .unwrap();
+        fs::write(root.join("app/src/main.rs"), "fn main() {}\n").unwrap();
+        fs::create_dir_all(root.join("core/src")).unwrap();
+        fs::write(
+            root.join("core/Cargo.toml"),
+            r#"[package]
+name = "core"
+version = "0.1.0"
+
+[dependencies]
+log.workspace = true
+
+[lints]
+workspace = true
+
+[profile.release]
+opt-level = 3
+"#,
+        )
+        .unwrap();
+        fs::write(root.join("core/src/lib.rs"), "pub struct Engine;\n").unwrap();
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        let generated = generate_lib(&root.join("app"), config).unwrap();
+
+        let cargo: toml::Table = fs::read_to_string(output.join("deps/core/Cargo.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(cargo["lints"]["rust"]["unsafe_code"].as_str(), Some("deny"));
+        assert!(!cargo.contains_key("profile"), "Got: {:?}", cargo);
+        assert_eq!(
+            generated.report.stripped_sections,
+            vec![StrippedSection {
+                crate_name: "core".to_string(),
+                section: "profile".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_validate_resolved_manifest() {
+        let ok: toml::Table = "[lints.rust]\nunsafe_code = \"deny\"\n".parse().unwrap();
+        assert!(validate_resolved_manifest(&ok).is_ok());
+        let lints: toml::Table = "[lints]\nworkspace = true\n".parse().unwrap();
+        assert!(validate_resolved_manifest(&lints).is_err());
+        let dep: toml::Table = "[dependencies]\nlog.workspace = true\n".parse().unwrap();
+        let err = validate_resolved_manifest(&dep).unwrap_err();
+        assert!(err.to_string().contains("'log'"), "Got: {}", err);
+    }
+
+    #[test]
+    fn test_generate_lib_from_virtual_workspace() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = temp.path().join("ws");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            r#"[workspace]
+members = ["app", "core"]
+
+[workspace.dependencies]
+log = "0.4"
+"#,
+        )
+        .unwrap();
+        for (member, file, source) in [
+            ("app", "main.rs", "pub struct App;\n\nfn main() {}\n"),
+            ("core", "lib.rs", "pub struct Engine;\n"),
+        ] {
+            let dir = root.join(member);
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::write(
+                dir.join("Cargo.toml"),
+                format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.1.0\"\n\n[dependencies]\nlog.workspace = true\n",
+                    member
+                ),
+            )
+            .unwrap();
+            fs::write(dir.join("src").join(file), source).unwrap();
+        }
+
+        // The binary member is the default
+        let output = temp.path().join("out_default");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        generate_lib(&root, config).unwrap();
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(lib_rs.contains("pub struct App"), "Got: {}", lib_rs);
+        let cargo: toml::Table = fs::read_to_string(output.join("Cargo.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(cargo["dependencies"]["log"].as_str(), Some("0.4"));
+
+        let output = temp.path().join("out_core");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            workspace_member: Some("core".to_string()),
+            ..Default::default()
+        };
+        generate_lib(&root, config).unwrap();
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(lib_rs.contains("pub struct Engine"), "Got: {}", lib_rs);
+
+        let config = LibGenConfig {
+            output_dir: Some(temp.path().join("out_missing")),
+            workspace_member: Some("cli".to_string()),
+            ..Default::default()
+        };
+        let err = generate_lib(&root, config).err().unwrap();
+        assert!(
+            err.to_string().contains("available members: app, core"),
+            "Got: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_generate_lib_reports_exported_macros() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"project\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/main.rs"),
+            "macro_rules! double {\n    ($x:expr) => { $x * 2 };\n}\n\nmod util;\n\nfn main() {\n    println!(\"{}\", double!(2));\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/util.rs"),
+            "macro_rules! triple {\n    ($x:expr) => { $x * 3 };\n}\n",
+        )
+        .unwrap();
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        let lib = generate_lib(&project, config).unwrap();
+
+        assert_eq!(lib.report.exported_macros, ["double", "triple"]);
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(
+            lib_rs.contains("#[macro_export]\nmacro_rules! double"),
+            "Got: {}",
+            lib_rs
+        );
+    }
+
+    /// Project with a module and a path dependency that inherits from a workspace
+    fn dry_run_project(temp: &Path) -> PathBuf {
+        let helper = temp.join("helper");
+        fs::create_dir_all(helper.join("src")).unwrap();
+        fs::write(
+            helper.join("Cargo.toml"),
+            "[package]\nname = \"helper\"\nversion.workspace = true\n",
+        )
+        .unwrap();
+        fs::write(helper.join("src/lib.rs"), "pub struct Helper;\n").unwrap();
+
+        let project = temp.join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"project\"\nversion = \"0.1.0\"\n\n[dependencies]\nhelper = { path = \"../helper\" }\n",
+        )
+        .unwrap();
+        fs::write(project.join("src/main.rs"), "mod util;\n\nfn main() {}\n").unwrap();
+        fs::write(project.join("src/util.rs"), "struct Port(u16);\n").unwrap();
+        project
+    }
+
+    #[test]
+    fn test_generate_lib_dry_run_writes_nothing() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = dry_run_project(temp.path());
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            dry_run: true,
+            ..Default::default()
+        };
+        let lib = generate_lib(&project, config).unwrap();
+
+        assert!(!output.exists());
+        assert_eq!(lib.path, output);
+        let paths: Vec<&Path> = lib.files.iter().map(|(p, _)| p.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("Cargo.toml"),
+                Path::new("deps/helper/Cargo.toml"),
+                Path::new("deps/helper/src/lib.rs"),
+                Path::new("src/lib.rs"),
+                Path::new("src/prelude.rs"),
+                Path::new("src/util.rs"),
+            ]
+        );
+        let lib_rs = &lib.files[3].1;
+        assert!(lib_rs.contains("pub use helper::*;"), "Got: {}", lib_rs);
+    }
+
+    #[test]
+    fn test_generate_lib_reports_skipped_dependencies() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"project\"\nversion = \"0.1.0\"\n\n[dependencies]\nanyhow = \"1\"\nlost = { workspace = true }\n",
+        )
+        .unwrap();
+        fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+
+        let config = LibGenConfig {
+            output_dir: Some(temp.path().join("out")),
+            dry_run: true,
+            pin_versions: false,
+            ..Default::default()
+        };
+        let lib = generate_lib(&project, config).unwrap();
+        assert_eq!(lib.resolution.resolved, vec!["anyhow"]);
+        assert_eq!(
+            lib.resolution.skipped,
+            vec![(
+                "lost".to_string(),
+                "not found in workspace dependencies".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_generate_lib_dry_run_diff() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = dry_run_project(temp.path());
+        let output = temp.path().join("out");
+        let config = || LibGenConfig {
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        let written = generate_lib(&project, config()).unwrap();
+        assert!(written.files.is_empty());
+
+        fs::write(project.join("src/util.rs"), "struct Port(u32);\n").unwrap();
+        let lib = generate_lib(
+            &project,
+            LibGenConfig {
+                dry_run: true,
+                ..config()
+            },
+        )
+        .unwrap();
+
+        let report = diff_against(&output, &lib.files);
+        assert!(
+            report.starts_with("--- a/src/util.rs\n+++ b/src/util.rs\n"),
+            "Got: {}",
+            report
+        );
+        assert!(
+            report.contains("+pub struct Port(pub u32);"),
+            "Got: {}",
+            report
+        );
+        assert_eq!(report.matches("+++ ").count(), 1, "Got: {}", report);
+    }
+
+    /// Project whose test helpers build values with a dev-dependency path crate
+    fn dev_deps_project(temp: &Path) -> PathBuf {
+        let fixtures = temp.join("fixtures");
+        fs::create_dir_all(fixtures.join("src")).unwrap();
+        fs::write(
+            fixtures.join("Cargo.toml"),
+            "[package]\nname = \"fixtures\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            fixtures.join("src/lib.rs"),
+            "pub struct UserBuilder { pub name: String }\n",
+        )
+        .unwrap();
+
+        let project = temp.join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::create_dir_all(project.join("tests/common")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"project\"\nversion = \"0.1.0\"\n\n[dev-dependencies]\nfixtures = { path = \"../fixtures\" }\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/main.rs"),
+            "pub struct User { pub name: String }\n\nfn main() {}\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("tests/common/mod.rs"),
+            "mod names;\n\npub fn builder() -> fixtures::UserBuilder {\n    fixtures::UserBuilder { name: names::DEFAULT.to_string() }\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("tests/common/names.rs"),
+            "pub const DEFAULT: &str = \"ada\";\n",
+        )
+        .unwrap();
+        project
+    }
+
+    #[test]
+    fn test_generate_lib_include_dev_deps() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = dev_deps_project(temp.path());
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            include_dev_deps: true,
+            extra_modules: vec![PathBuf::from("tests/common/mod.rs")],
+            ..Default::default()
+        };
+        let lib = generate_lib(&project, config).unwrap();
+
+        assert_eq!(lib.report.dev_dependencies, ["fixtures"]);
+        let cargo: toml::Table = fs::read_to_string(output.join("Cargo.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(cargo["dependencies"].get("fixtures").is_some());
+
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(lib_rs.contains("pub mod common;"), "Got: {}", lib_rs);
+        assert!(lib_rs.contains("pub use fixtures::*;"), "Got: {}", lib_rs);
+        let common = fs::read_to_string(output.join("src/common/mod.rs")).unwrap();
+        assert!(common.contains("pub fn builder()"), "Got: {}", common);
+        assert!(output.join("src/common/names.rs").exists());
+    }
+
+    #[test]
+    fn test_generate_lib_skips_dev_deps_by_default() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = dev_deps_project(temp.path());
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        let lib = generate_lib(&project, config).unwrap();
+
+        assert!(lib.report.dev_dependencies.is_empty());
+        let cargo = fs::read_to_string(output.join("Cargo.toml")).unwrap();
+        assert!(!cargo.contains("fixtures"), "Got: {}", cargo);
+        assert!(!output.join("src/common").exists());
+    }
+
+    #[test]
+    fn test_generate_lib_writes_prelude() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("src/net")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"project\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/main.rs"),
+            r#"mod net;
+#[cfg(test)]
+mod tests;
+
+struct Config;
+type Port = u16;
+
+fn parse_args() -> Config { Config }
+
+fn main() {
+    struct Local;
+}
+"#,
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/net/mod.rs"),
+            "pub mod proto {\n    pub enum Frame { Ping }\n}\n\n#[cfg(unix)]\npub struct UnixOnly;\n",
+        )
+        .unwrap();
+        fs::write(project.join("src/tests.rs"), "struct Fixture;\n").unwrap();
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            ..Default::default()
+        };
+        let generated = generate_lib(&project, config).unwrap();
+
+        let exported: Vec<(String, &str)> = generated
+            .exported_items
+            .iter()
+            .map(|item| (item.path(), item.kind.as_str()))
+            .collect();
+        assert_eq!(
+            exported,
+            vec![
+                ("Config".to_string(), "struct"),
+                ("Port".to_string(), "type"),
+                ("parse_args".to_string(), "fn"),
+                ("net::proto::Frame".to_string(), "enum"),
+            ]
+        );
+
+        let prelude = fs::read_to_string(output.join("src/prelude.rs")).unwrap();
+        assert!(syn::parse_file(&prelude).is_ok(), "Got: {}", prelude);
+        assert!(
+            prelude.contains("pub use crate::net::proto::Frame;"),
+            "Got: {}",
+            prelude
+        );
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(lib_rs.contains("pub mod prelude;"), "Got: {}", lib_rs);
+    }
+
+    #[test]
+    fn test_original_module_path() {
+        let path = |p: &str| original_module_path(Path::new(p));
+        assert_eq!(
+            path("src/testing/fixtures.rs"),
+            Some(vec!["testing".to_string(), "fixtures".to_string()])
+        );
+        assert_eq!(
+            path("tests/common/mod.rs"),
+            Some(vec!["common".to_string()])
+        );
+        assert_eq!(path("helpers/util.rs"), None);
+    }
+
+    #[test]
+    fn test_generate_lib_relocates_extra_module_uses() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        fs::create_dir_all(project.join("src/testing")).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"project\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/main.rs"),
+            "mod testing;\n\nmod report {\n    use crate::testing::fixtures::{sample, Fixture};\n}\n\nfn main() {}\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/testing/mod.rs"),
+            "#[cfg(test)]\npub mod fixtures;\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("src/testing/fixtures.rs"),
+            "pub struct Fixture;\npub fn sample() -> Fixture { Fixture }\n",
+        )
+        .unwrap();
+
+        let output = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(output.clone()),
+            extra_modules: vec![PathBuf::from("src/testing/fixtures.rs")],
+            ..Default::default()
+        };
+        generate_lib(&project, config).unwrap();
+
+        let lib_rs = fs::read_to_string(output.join("src/lib.rs")).unwrap();
+        assert!(
+            lib_rs.contains("pub use crate::fixtures::sample;"),
+            "Got: {}",
+            lib_rs
+        );
+        assert!(
+            lib_rs.contains("pub use crate::fixtures::Fixture;"),
+            "Got: {}",
+            lib_rs
+        );
+        assert!(lib_rs.contains("pub mod fixtures;"), "Got: {}", lib_rs);
+    }
+
+    #[test]
+    fn test_extra_module_name() {
+        assert_eq!(
+            extra_module_name(Path::new("tests/common/mod.rs")).unwrap(),
+            "common"
+        );
+        assert_eq!(
+            extra_module_name(Path::new("src/test-utils.rs")).unwrap(),
+            "test_utils"
+        );
+        assert!(extra_module_name(Path::new("tests/1fixtures.rs")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_src_skips_symlink_loops_and_ignored_dirs() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let src = temp.path().join("dep/src");
+        fs::create_dir_all(src.join("nested/target")).unwrap();
+        fs::create_dir_all(src.join(".git")).unwrap();
+        fs::write(src.join("lib.rs"), "pub mod nested;\n").unwrap();
+        fs::write(src.join("nested/mod.rs"), "pub struct Inner;\n").unwrap();
+        fs::write(src.join("nested/target/junk.bin"), "junk").unwrap();
+        fs::write(src.join(".git/HEAD"), "ref").unwrap();
+        // nested/back -> src: following it would recurse forever
+        std::os::unix::fs::symlink(&src, src.join("nested/back")).unwrap();
+
+        let dst = temp.path().join("out/src");
+        let output = Output::new(temp.path().join("out"), false);
+        let mut guard = CopyGuard::new(DEFAULT_MAX_COPY_BYTES);
+        copy_and_transform_src(&output, &mut guard, &src, &dst, true).unwrap();
+
+        assert!(dst.join("lib.rs").exists());
+        assert!(dst.join("nested/mod.rs").exists());
+        assert!(!dst.join("nested/back").exists());
+        assert!(!dst.join("nested/target").exists());
+        assert!(!dst.join(".git").exists());
+    }
+
+    #[test]
+    fn test_copy_src_aborts_over_size_limit() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let src = temp.path().join("dep/src");
+        fs::create_dir_all(src.join("fixtures")).unwrap();
+        fs::write(src.join("lib.rs"), "pub struct Small;\n").unwrap();
+        fs::write(src.join("fixtures/big.bin"), vec![0u8; 4096]).unwrap();
+
+        let output = Output::new(temp.path().join("out"), false);
+        let mut guard = CopyGuard::new(1024);
+        let err = copy_and_transform_src(
+            &output,
+            &mut guard,
+            &src,
+            &temp.path().join("out/src"),
+            true,
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(
+            err.contains(&format!("Copying {}", src.join("fixtures").display())),
+            "Got: {}",
+            err
+        );
+        assert!(err.contains("size limit of 1024 bytes"), "Got: {}", err);
+    }
+
+    #[test]
+    fn test_generate_cargo_toml_pins_locked_versions() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            r#"[package]
+name = "project"
+version = "0.1.0"
+
+[dependencies]
+anyhow = "1"
+log = { version = "0.4", features = ["std"] }
+unlocked = "2"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            project.join("Cargo.lock"),
+            r#"version = 3
+
+[[package]]
+name = "anyhow"
+version = "1.0.86"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "log"
+version = "0.4.22"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "serde"
+version = "1.0.203"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#,
+        )
+        .unwrap();
+
+        let generate = |pin_versions| {
+            let manifest = generate_cargo_toml(
+                &project,
+                &Output::new(temp.path().join("out"), false),
+                true,
+                false,
+                pin_versions,
+                &FeatureSet::default(),
+            )
+            .unwrap();
+            let manifest: toml::Table = manifest.content.parse().unwrap();
+            manifest["dependencies"].clone()
+        };
+
+        let deps = generate(true);
+        assert_eq!(deps["anyhow"].as_str(), Some("=1.0.86"));
+        assert_eq!(deps["log"]["version"].as_str(), Some("=0.4.22"));
+        assert_eq!(deps["log"]["features"][0].as_str(), Some("std"));
+        assert_eq!(deps["serde"]["version"].as_str(), Some("=1.0.203"));
+        assert_eq!(deps["unlocked"].as_str(), Some("2"));
+
+        let deps = generate(false);
+        assert_eq!(deps["anyhow"].as_str(), Some("1"));
+        assert_eq!(deps["serde"]["version"].as_str(), Some("1"));
     }
 }
@@ -0,0 +1,159 @@
+//! Destination for generated crate files
+//!
+//! Files are written to disk, or collected in memory for a dry run so the
+//! would-be output can be inspected without touching the filesystem.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::copy_guard::DEFAULT_MAX_COPY_BYTES;
+
+/// Output directory of a generated crate
+pub struct Output {
+    dir: PathBuf,
+    dry_run: bool,
+    /// Size limit for the sources copied from one path dependency
+    max_copy_bytes: u64,
+    /// Files produced by a dry run, keyed by absolute path
+    files: RefCell<BTreeMap<PathBuf, Vec<u8>>>,
+    /// Manifest sections dropped from resolved path dependency copies
+    stripped_sections: RefCell<Vec<StrippedSection>>,
+    /// Dependencies left out of generated manifests, with the reason
+    skipped_dependencies: RefCell<Vec<(String, String)>>,
+}
+
+/// A manifest table left out of a resolved path dependency copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrippedSection {
+    /// Package whose manifest contained the table
+    pub crate_name: String,
+    /// Top-level table name (`lints`, `profile`, `workspace`)
+    pub section: String,
+}
+
+impl Output {
+    pub fn new(dir: impl Into<PathBuf>, dry_run: bool) -> Self {
+        Self {
+            dir: dir.into(),
+            dry_run,
+            max_copy_bytes: DEFAULT_MAX_COPY_BYTES,
+            files: RefCell::new(BTreeMap::new()),
+            stripped_sections: RefCell::new(Vec::new()),
+            skipped_dependencies: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn with_max_copy_bytes(mut self, max_copy_bytes: u64) -> Self {
+        self.max_copy_bytes = max_copy_bytes;
+        self
+    }
+
+    pub fn max_copy_bytes(&self) -> u64 {
+        self.max_copy_bytes
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        fs::create_dir_all(path)
+    }
+
+    pub fn write(&self, path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        if self.dry_run {
+            self.record(path, contents.as_ref());
+            return Ok(());
+        }
+        fs::write(path, contents)
+    }
+
+    pub fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.dry_run {
+            self.record(to, &fs::read(from)?);
+            return Ok(());
+        }
+        fs::copy(from, to).map(|_| ())
+    }
+
+    /// Whether a file or directory has been produced at `path`
+    pub fn exists(&self, path: &Path) -> bool {
+        if self.dry_run {
+            return self.files.borrow().keys().any(|p| p.starts_with(path));
+        }
+        path.exists()
+    }
+
+    /// Note that a manifest section was dropped from a resolved copy
+    pub fn record_stripped(&self, crate_name: &str, section: &str) {
+        let stripped = StrippedSection {
+            crate_name: crate_name.to_string(),
+            section: section.to_string(),
+        };
+        let mut sections = self.stripped_sections.borrow_mut();
+        if !sections.contains(&stripped) {
+            sections.push(stripped);
+        }
+    }
+
+    /// Sections recorded by `record_stripped`, in the order they were dropped
+    pub fn take_stripped_sections(&self) -> Vec<StrippedSection> {
+        self.stripped_sections.take()
+    }
+
+    /// Note that a dependency was left out of a generated manifest
+    pub fn record_skipped_dependency(&self, name: &str, reason: &str) {
+        let skipped = (name.to_string(), reason.to_string());
+        let mut dependencies = self.skipped_dependencies.borrow_mut();
+        if !dependencies.contains(&skipped) {
+            dependencies.push(skipped);
+        }
+    }
+
+    /// Dependencies recorded by `record_skipped_dependency`, in the order
+    /// they were skipped
+    pub fn take_skipped_dependencies(&self) -> Vec<(String, String)> {
+        self.skipped_dependencies.take()
+    }
+
+    /// Files collected by a dry run, relative to the output directory
+    pub fn into_files(self) -> Vec<(PathBuf, String)> {
+        self.into_file_bytes()
+            .into_iter()
+            .map(|(rel, content)| (rel, String::from_utf8_lossy(&content).into_owned()))
+            .collect()
+    }
+
+    /// Files collected by a dry run with their exact contents, relative to
+    /// the output directory
+    pub fn into_file_bytes(self) -> Vec<(PathBuf, Vec<u8>)> {
+        let dir = self.dir;
+        self.files
+            .into_inner()
+            .into_iter()
+            .map(|(path, content)| {
+                let rel = path
+                    .strip_prefix(&dir)
+                    .map(Path::to_path_buf)
+                    .unwrap_or(path);
+                (rel, content)
+            })
+            .collect()
+    }
+
+    fn record(&self, path: &Path, contents: &[u8]) {
+        self.files
+            .borrow_mut()
+            .insert(path.to_path_buf(), contents.to_vec());
+    }
+}
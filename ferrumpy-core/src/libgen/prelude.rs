@@ -0,0 +1,68 @@
+//! Prelude module for the generated lib
+//!
+//! Lists every public struct, enum, type alias and function with an explicit
+//! `pub use`, so the REPL can import the user's items (and offer their names)
+//! without relying on glob re-exports.
+
+use std::collections::HashSet;
+
+use super::transformer::ExportedItem;
+
+/// Name of the generated prelude module
+pub const PRELUDE_MODULE: &str = "prelude";
+
+/// Items to list in the prelude: ungated ones, shallowest module first, one
+/// per name since two imports of the same name would conflict
+pub fn prelude_items(mut items: Vec<ExportedItem>, gated: &[Vec<String>]) -> Vec<ExportedItem> {
+    items.retain(|item| !gated.iter().any(|g| item.module_path.starts_with(g)));
+    items.sort_by(|a, b| {
+        (a.module_path.len(), &a.module_path, &a.name).cmp(&(
+            b.module_path.len(),
+            &b.module_path,
+            &b.name,
+        ))
+    });
+    let mut seen = HashSet::new();
+    items.retain(|item| seen.insert(item.name.clone()));
+    items
+}
+
+/// Source of the prelude module
+pub fn render_prelude(items: &[ExportedItem]) -> String {
+    let mut code = String::from("//! Public items of the snapshot crate\n\n");
+    for item in items {
+        code.push_str(&format!("pub use crate::{};\n", item.path()));
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libgen::transformer::ExportedItemKind;
+
+    fn item(path: &[&str], name: &str) -> ExportedItem {
+        ExportedItem {
+            name: name.to_string(),
+            kind: ExportedItemKind::Struct,
+            module_path: path.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_prelude_items_dedup_and_gating() {
+        let items = vec![
+            item(&["net", "proto"], "Frame"),
+            item(&["util"], "Config"),
+            item(&[], "Config"),
+            item(&["platform"], "Handle"),
+        ];
+        let kept = prelude_items(items, &[vec!["platform".to_string()]]);
+        let paths: Vec<String> = kept.iter().map(ExportedItem::path).collect();
+        assert_eq!(paths, vec!["Config", "net::proto::Frame"]);
+
+        let code = render_prelude(&kept);
+        assert!(syn::parse_file(&code).is_ok(), "Got: {}", code);
+        assert!(code.contains("pub use crate::net::proto::Frame;\n"));
+    }
+}
@@ -0,0 +1,200 @@
+//! Path dependency re-exports
+//!
+//! The generated lib.rs re-exports every path dependency with `pub use dep::*;`.
+//! Two globs exporting the same name make that name ambiguous (E0659), so
+//! colliding crates are re-exported item by item and reachable under their
+//! crate name for the shadowed items.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+
+use syn::{Item, UseTree, Visibility};
+
+/// A path dependency re-exported under its crate name because some of its
+/// items collide with another dependency's exports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReexportAlias {
+    /// Crate identifier of the dependency
+    pub crate_ident: String,
+    /// Name the crate is re-exported as
+    pub alias: String,
+    /// Items only reachable as `alias::Item`
+    pub shadowed: Vec<String>,
+}
+
+/// A path dependency with the names it exports from its crate root
+pub struct DepExports {
+    pub crate_ident: String,
+    /// `None` when the crate root could not be read; re-exported as a glob
+    pub names: Option<BTreeSet<String>>,
+}
+
+/// Collect the public item names exported from a crate root file
+pub fn public_item_names(lib_root: &Path) -> Option<BTreeSet<String>> {
+    let source = std::fs::read_to_string(lib_root).ok()?;
+    let file = syn::parse_file(&source).ok()?;
+    Some(item_names(&file.items, true))
+}
+
+/// Names defined or imported by a list of items, optionally only `pub` ones
+pub fn item_names(items: &[Item], public_only: bool) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    for item in items {
+        let (vis, ident) = match item {
+            Item::Struct(i) => (&i.vis, &i.ident),
+            Item::Enum(i) => (&i.vis, &i.ident),
+            Item::Union(i) => (&i.vis, &i.ident),
+            Item::Fn(i) => (&i.vis, &i.sig.ident),
+            Item::Type(i) => (&i.vis, &i.ident),
+            Item::Const(i) => (&i.vis, &i.ident),
+            Item::Static(i) => (&i.vis, &i.ident),
+            Item::Trait(i) => (&i.vis, &i.ident),
+            Item::Mod(i) => (&i.vis, &i.ident),
+            // `#[macro_export]` macros live at the crate root whatever their visibility
+            Item::Macro(i) => {
+                let exported = i.attrs.iter().any(|a| a.path().is_ident("macro_export"));
+                if let Some(ident) = &i.ident {
+                    if !public_only || exported {
+                        names.insert(ident.to_string());
+                    }
+                }
+                continue;
+            }
+            Item::Use(i) => {
+                if !public_only || matches!(i.vis, Visibility::Public(_)) {
+                    collect_use_names(&i.tree, &mut names);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if !public_only || matches!(vis, Visibility::Public(_)) {
+            names.insert(ident.to_string());
+        }
+    }
+    names
+}
+
+fn collect_use_names(tree: &UseTree, names: &mut BTreeSet<String>) {
+    match tree {
+        UseTree::Path(p) => collect_use_names(&p.tree, names),
+        UseTree::Name(n) if n.ident != "self" => {
+            names.insert(n.ident.to_string());
+        }
+        UseTree::Rename(r) if r.rename != "_" => {
+            names.insert(r.rename.to_string());
+        }
+        UseTree::Group(g) => {
+            for item in &g.items {
+                collect_use_names(item, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Build the re-export block for lib.rs.
+///
+/// `local_names` are items defined by the lib itself; they shadow glob imports
+/// but would conflict with explicit ones, so they are never listed explicitly.
+pub fn plan_reexports(
+    deps: &[DepExports],
+    local_names: &HashSet<String>,
+) -> (String, Vec<ReexportAlias>) {
+    let mut owners: BTreeMap<&str, usize> = BTreeMap::new();
+    for dep in deps {
+        for name in dep.names.iter().flatten() {
+            *owners.entry(name.as_str()).or_default() += 1;
+        }
+    }
+
+    let mut code = String::new();
+    let mut aliases = Vec::new();
+    for dep in deps {
+        let Some(names) = &dep.names else {
+            code.push_str(&format!("pub use {}::*;\n", dep.crate_ident));
+            continue;
+        };
+
+        let (shadowed, unambiguous): (Vec<&String>, Vec<&String>) =
+            names.iter().partition(|n| owners[n.as_str()] > 1);
+        if shadowed.is_empty() {
+            code.push_str(&format!("pub use {}::*;\n", dep.crate_ident));
+            continue;
+        }
+
+        let explicit: Vec<&str> = unambiguous
+            .iter()
+            .filter(|n| !local_names.contains(n.as_str()))
+            .map(|n| n.as_str())
+            .collect();
+        if !explicit.is_empty() {
+            code.push_str(&format!(
+                "pub use {}::{{{}}};\n",
+                dep.crate_ident,
+                explicit.join(", ")
+            ));
+        }
+        code.push_str(&format!("pub use {};\n", dep.crate_ident));
+
+        aliases.push(ReexportAlias {
+            crate_ident: dep.crate_ident.clone(),
+            alias: dep.crate_ident.clone(),
+            shadowed: shadowed.into_iter().cloned().collect(),
+        });
+    }
+
+    (code, aliases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dep(name: &str, names: &[&str]) -> DepExports {
+        DepExports {
+            crate_ident: name.to_string(),
+            names: Some(names.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn test_item_names_public_only() {
+        let file: syn::File = syn::parse_str(
+            "pub struct Config; struct Private; pub use inner::{Error, Result as Res}; pub mod util {}
+             #[macro_export] macro_rules! exported { () => {} } macro_rules! local { () => {} }",
+        )
+        .unwrap();
+        let names = item_names(&file.items, true);
+        let expected: BTreeSet<String> = ["Config", "Error", "Res", "exported", "util"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_plan_reexports_without_collisions() {
+        let deps = vec![dep("dep_a", &["A"]), dep("dep_b", &["B"])];
+        let (code, aliases) = plan_reexports(&deps, &HashSet::new());
+        assert_eq!(code, "pub use dep_a::*;\npub use dep_b::*;\n");
+        assert!(aliases.is_empty());
+    }
+
+    #[test]
+    fn test_plan_reexports_with_collisions() {
+        let deps = vec![
+            dep("dep_a", &["Config", "OnlyA", "Shared"]),
+            dep("dep_b", &["Config"]),
+        ];
+        let local: HashSet<String> = ["Shared".to_string()].into_iter().collect();
+        let (code, aliases) = plan_reexports(&deps, &local);
+        assert_eq!(
+            code,
+            "pub use dep_a::{OnlyA};\npub use dep_a;\npub use dep_b;\n"
+        );
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases[0].shadowed, vec!["Config".to_string()]);
+        assert_eq!(aliases[1].alias, "dep_b");
+    }
+}
@@ -5,12 +5,44 @@
 use anyhow::Result;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use syn::{parse_file, Item};
+use syn::{parse_file, Item, ItemMod};
+
+use super::features::FeatureSet;
 
 /// Resolve all module files referenced from a source file
 pub fn resolve_modules(source_path: &Path) -> Result<HashMap<PathBuf, String>> {
-    let mut modules = HashMap::new();
+    resolve_modules_with_features(source_path, None)
+}
+
+/// Resolve module files, skipping `mod` declarations gated on inactive features
+pub fn resolve_modules_with_features(
+    source_path: &Path,
+    features: Option<&FeatureSet>,
+) -> Result<HashMap<PathBuf, String>> {
     let source_dir = source_path.parent().unwrap_or(Path::new("."));
+    resolve_modules_in(source_path, source_dir, features)
+}
+
+/// Resolve the modules declared by a non-root module file, relative to the
+/// directory they live in (`foo/` for `foo.rs`, the file's own directory for `mod.rs`)
+pub fn resolve_submodules(
+    module_path: &Path,
+    features: Option<&FeatureSet>,
+) -> Result<HashMap<PathBuf, String>> {
+    let parent = module_path.parent().unwrap_or(Path::new("."));
+    let base_dir = match module_path.file_stem().and_then(|s| s.to_str()) {
+        Some("mod") | None => parent.to_path_buf(),
+        Some(stem) => parent.join(stem),
+    };
+    resolve_modules_in(module_path, &base_dir, features)
+}
+
+fn resolve_modules_in(
+    source_path: &Path,
+    source_dir: &Path,
+    features: Option<&FeatureSet>,
+) -> Result<HashMap<PathBuf, String>> {
+    let mut modules = HashMap::new();
 
     let source = std::fs::read_to_string(source_path)?;
     let ast = parse_file(&source)?;
@@ -18,8 +50,14 @@ pub fn resolve_modules(source_path: &Path) -> Result<HashMap<PathBuf, String>> {
     for item in &ast.items {
         if let Item::Mod(item_mod) = item {
             // Only process external modules (no content block)
-            if item_mod.content.is_none() {
-                resolve_module_recursive(source_dir, &item_mod.ident.to_string(), &mut modules)?;
+            if item_mod.content.is_none() && is_mod_enabled(item_mod, features) {
+                resolve_module_recursive(
+                    source_dir,
+                    Path::new(""),
+                    &item_mod.ident.to_string(),
+                    features,
+                    &mut modules,
+                )?;
             }
         }
     }
@@ -27,9 +65,24 @@ pub fn resolve_modules(source_path: &Path) -> Result<HashMap<PathBuf, String>> {
     Ok(modules)
 }
 
+/// Whether a `mod` declaration survives feature cfg evaluation
+fn is_mod_enabled(item_mod: &ItemMod, features: Option<&FeatureSet>) -> bool {
+    let Some(features) = features else {
+        return true;
+    };
+    !item_mod
+        .attrs
+        .iter()
+        .any(|attr| features.eval_cfg(attr) == Some(false))
+}
+
+/// Resolve `mod mod_name;` declared in `base_dir`, which is `rel_dir` relative
+/// to the root source directory
 fn resolve_module_recursive(
     base_dir: &Path,
+    rel_dir: &Path,
     mod_name: &str,
+    features: Option<&FeatureSet>,
     modules: &mut HashMap<PathBuf, String>,
 ) -> Result<()> {
     // Try to find the module file
@@ -39,10 +92,10 @@ fn resolve_module_recursive(
 
     let (actual_path, content) = if file_path.exists() {
         let content = std::fs::read_to_string(&file_path)?;
-        (PathBuf::from(format!("{}.rs", mod_name)), content)
+        (rel_dir.join(format!("{}.rs", mod_name)), content)
     } else if dir_path.exists() {
         let content = std::fs::read_to_string(&dir_path)?;
-        (PathBuf::from(format!("{}/mod.rs", mod_name)), content)
+        (rel_dir.join(mod_name).join("mod.rs"), content)
     } else {
         // Module not found, skip
         eprintln!(
@@ -57,18 +110,16 @@ fn resolve_module_recursive(
 
     // Parse and look for nested modules
     let ast = parse_file(&content)?;
-    let new_base = if file_path.exists() {
-        base_dir.join(mod_name)
-    } else {
-        base_dir.join(mod_name)
-    };
+    // Submodules of both `foo.rs` and `foo/mod.rs` live in `foo/`
+    let new_base = base_dir.join(mod_name);
+    let new_rel = rel_dir.join(mod_name);
 
     for item in &ast.items {
         if let Item::Mod(item_mod) = item {
-            if item_mod.content.is_none() {
+            if item_mod.content.is_none() && is_mod_enabled(item_mod, features) {
                 // Recursively resolve nested modules
                 let nested_name = item_mod.ident.to_string();
-                resolve_module_recursive(&new_base, &nested_name, modules)?;
+                resolve_module_recursive(&new_base, &new_rel, &nested_name, features, modules)?;
             }
         }
     }
@@ -114,4 +165,28 @@ pub fn helper() -> i32 { 42 }
         assert_eq!(modules.len(), 1);
         assert!(modules.contains_key(&PathBuf::from("utils.rs")));
     }
+
+    #[test]
+    fn test_resolve_nested_modules_keep_their_directory() {
+        let temp = TempDir::new().unwrap();
+        let src_dir = temp.path().join("src");
+        fs::create_dir_all(src_dir.join("net/proto")).unwrap();
+        fs::write(src_dir.join("main.rs"), "mod net;\nfn main() {}\n").unwrap();
+        fs::write(src_dir.join("net/mod.rs"), "mod proto;\n").unwrap();
+        fs::write(src_dir.join("net/proto.rs"), "mod frame;\n").unwrap();
+        fs::write(src_dir.join("net/proto/frame.rs"), "pub struct Frame;\n").unwrap();
+
+        let modules = resolve_modules(&src_dir.join("main.rs")).unwrap();
+
+        let mut paths: Vec<_> = modules.keys().cloned().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("net/mod.rs"),
+                PathBuf::from("net/proto/frame.rs"),
+                PathBuf::from("net/proto.rs"),
+            ]
+        );
+    }
 }
@@ -4,50 +4,307 @@
 //! - Make all items public
 //! - Remove fn main()
 //! - Add serde derives
+//! - Strip entry-point attributes and macros from crates the lib can't see
+//! - Strip derives named in the options
+//! - Export `macro_rules!` macros
+//! - Rewrite paths through the crate's own name to `crate::`
+//! - Point `use crate::...` at modules that moved in the generated lib
 
 use anyhow::Result;
 use quote::ToTokens;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+
+use super::features::{eval_cfg_without_test, FeatureSet};
 use syn::{
-    parse_file, visit_mut::VisitMut, Attribute, Fields, Item, ItemEnum, ItemFn, ItemMod,
-    ItemStruct, Type,
+    parse_file, punctuated::Punctuated, visit_mut::VisitMut, Attribute, Fields, Item, ItemEnum,
+    ItemFn, ItemMod, ItemStruct, Token, Type, UseTree,
 };
 
+/// Entry-point attribute macros stripped by default
+pub const DEFAULT_MAIN_ATTRIBUTES: &[&str] = &["tokio::main", "async_std::main", "actix_web::main"];
+
+/// Crates every generated lib can refer to without a dependency entry
+const BUILTIN_CRATES: &[&str] = &[
+    "std",
+    "core",
+    "alloc",
+    "proc_macro",
+    "crate",
+    "self",
+    "super",
+];
+
+/// Helper attributes registered by common derive macros, keyed by crate.
+/// When a crate's derive is stripped, these attributes must go with it.
+const DERIVE_HELPER_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("clap", &["arg", "command", "clap", "value", "group"]),
+    ("structopt", &["structopt"]),
+    ("strum", &["strum"]),
+    ("thiserror", &["error", "from", "source", "backtrace"]),
+];
+
+/// Derives kept even when listed in [`TransformOptions::strip_derives`]; the
+/// REPL relies on them to restore and display values
+const KEPT_DERIVES: &[&str] = &["Debug", "Clone", "Serialize", "Deserialize"];
+
+/// Options controlling source transformation
+#[derive(Debug, Clone)]
+pub struct TransformOptions {
+    /// Remove `fn main` (and any fn carrying a main attribute)
+    pub remove_main: bool,
+    /// Add serde derives to structs/enums
+    pub add_serde: bool,
+    /// Entry-point attribute macros such as `tokio::main`. Fns carrying one are
+    /// treated as `main`; if such a fn is kept, the attribute is dropped.
+    pub main_attributes: Vec<String>,
+    /// Crate identifiers available to the generated lib. When set, derives,
+    /// attribute macros and `use` items referring to other crates are stripped.
+    pub available_crates: Option<Vec<String>>,
+    /// Active cargo features. When set, `#[cfg(feature = ...)]` items are
+    /// dropped or kept (with the cfg removed) accordingly.
+    pub active_features: Option<FeatureSet>,
+    /// Identifier the source uses for its own crate (e.g. `my_app` in a
+    /// binary's `use my_app::Config;`). Such paths are rewritten to `crate::`.
+    pub self_crate: Option<String>,
+    /// Modules placed at a different path in the generated lib than in the
+    /// original crate. `use crate::...` items through them are rewritten.
+    pub relocated_modules: Vec<ModuleRelocation>,
+    /// Derive macros removed from `#[derive(...)]` lists, by name (`Builder`)
+    /// or path (`derive_builder::Builder`). `Debug`, `Clone` and serde's
+    /// derives are always kept.
+    pub strip_derives: Vec<String>,
+    /// Remove all of the file's inner attributes (`#![...]`)
+    pub strip_inner_attrs: bool,
+    /// Macros other files of the crate already export from its root.
+    /// Same-name macros of this file aren't given `#[macro_export]`, which
+    /// would define the name twice.
+    pub exported_macros: Vec<String>,
+}
+
+/// A module moved by lib generation, e.g. `testing::fixtures` to `fixtures`.
+/// Paths are relative to the crate root, without the leading `crate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleRelocation {
+    pub from: Vec<String>,
+    pub to: Vec<String>,
+}
+
+impl Default for TransformOptions {
+    fn default() -> Self {
+        Self {
+            remove_main: false,
+            add_serde: true,
+            main_attributes: DEFAULT_MAIN_ATTRIBUTES
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            available_crates: None,
+            active_features: None,
+            self_crate: None,
+            relocated_modules: Vec::new(),
+            strip_derives: Vec::new(),
+            strip_inner_attrs: false,
+            exported_macros: Vec::new(),
+        }
+    }
+}
+
+/// Result of transforming a source file
+#[derive(Debug, Clone, Default)]
+pub struct TransformOutput {
+    /// Transformed source code
+    pub code: String,
+    /// `macro_rules!` macros exported from the crate root by the transformed code
+    pub exported_macros: Vec<String>,
+    /// Public items of the transformed code, with module paths relative to it
+    pub exported_items: Vec<ExportedItem>,
+    /// `#[cfg]`-gated `mod name;` declarations, relative to the transformed
+    /// code; items of these modules may not exist
+    pub cfg_gated_modules: Vec<Vec<String>>,
+}
+
+/// Kind of an item listed in the generated prelude
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExportedItemKind {
+    Struct,
+    Enum,
+    TypeAlias,
+    Function,
+}
+
+impl ExportedItemKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportedItemKind::Struct => "struct",
+            ExportedItemKind::Enum => "enum",
+            ExportedItemKind::TypeAlias => "type",
+            ExportedItemKind::Function => "fn",
+        }
+    }
+}
+
+/// A public item of the generated lib
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExportedItem {
+    pub name: String,
+    pub kind: ExportedItemKind,
+    /// Modules between the crate root and the item (`["net", "proto"]`)
+    pub module_path: Vec<String>,
+}
+
+impl ExportedItem {
+    /// Path of the item below the crate root, e.g. `net::proto::Frame`
+    pub fn path(&self) -> String {
+        self.module_path
+            .iter()
+            .chain(std::iter::once(&self.name))
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("::")
+    }
+}
+
 /// Transform a source file to lib format
-pub fn transform_to_lib(path: &Path, remove_main: bool, add_serde: bool) -> Result<String> {
+pub fn transform_to_lib(path: &Path, options: &TransformOptions) -> Result<String> {
     let source = std::fs::read_to_string(path)?;
-    transform_source(&source, remove_main, add_serde)
+    transform_source(&source, options)
 }
 
 /// Transform a module file
 pub fn transform_module(source: &str, add_serde: bool) -> Result<String> {
-    transform_source(source, false, add_serde)
+    let options = TransformOptions {
+        add_serde,
+        ..Default::default()
+    };
+    transform_source(source, &options)
+}
+
+/// Transform a module file with explicit options (`remove_main` is ignored)
+pub fn transform_module_with(source: &str, options: &TransformOptions) -> Result<String> {
+    Ok(transform_module_output(source, options)?.code)
+}
+
+/// Like [`transform_module_with`], also reporting what the module exported
+pub fn transform_module_output(
+    source: &str,
+    options: &TransformOptions,
+) -> Result<TransformOutput> {
+    let options = TransformOptions {
+        remove_main: false,
+        ..options.clone()
+    };
+    transform_with_output(source, &options)
+}
+
+fn transform_source(source: &str, options: &TransformOptions) -> Result<String> {
+    Ok(transform_with_output(source, options)?.code)
 }
 
-fn transform_source(source: &str, remove_main: bool, add_serde: bool) -> Result<String> {
+/// Transform source code, also reporting what the transformation exported
+pub fn transform_with_output(source: &str, options: &TransformOptions) -> Result<TransformOutput> {
     let mut ast = parse_file(source)?;
 
     // Filter out problematic inner attributes that don't apply to the companion library
-    ast.attrs.retain(|attr| !is_problematic_inner_attr(attr));
+    ast.attrs
+        .retain(|attr| !options.strip_inner_attrs && !is_problematic_inner_attr(attr));
 
-    // Apply transformations
-    let mut transformer = PublicityTransformer { add_serde };
-    transformer.visit_file_mut(&mut ast);
+    // The original crate name means nothing inside the renamed lib
+    if let Some(self_crate) = &options.self_crate {
+        rewrite_self_crate_paths(&mut ast, &self_crate.replace('-', "_"));
+    }
 
-    // Remove fn main if requested
-    if remove_main {
-        ast.items.retain(|item| !is_main_fn(item));
+    if !options.relocated_modules.is_empty() {
+        UseRelocator {
+            relocations: &options.relocated_modules,
+        }
+        .visit_file_mut(&mut ast);
     }
 
+    // Drop entry points (or just their attributes, when kept)
+    if options.remove_main {
+        ast.items
+            .retain(|item| !is_main_fn(item) && !has_main_attr(item, &options.main_attributes));
+    } else {
+        for item in &mut ast.items {
+            if let Item::Fn(f) = item {
+                f.attrs
+                    .retain(|attr| !is_main_attr(attr, &options.main_attributes));
+            }
+        }
+    }
+
+    // Evaluate feature cfgs up front: the generated crate declares no features
+    if let Some(features) = &options.active_features {
+        apply_feature_cfgs(&mut ast.items, features);
+    }
+
+    // Strip references to crates missing from the generated dependency set
+    if let Some(available) = &options.available_crates {
+        strip_unavailable_crates(&mut ast, available);
+    }
+
+    if !options.strip_derives.is_empty() {
+        NamedDeriveStripper {
+            names: &options.strip_derives,
+        }
+        .visit_file_mut(&mut ast);
+    }
+
+    // Apply transformations
+    let mut taken_macros: HashSet<String> = options.exported_macros.iter().cloned().collect();
+    taken_macros.extend(macro_exports(&ast.items));
+    let mut transformer = PublicityTransformer {
+        add_serde: options.add_serde,
+        exported_macros: Vec::new(),
+        taken_macros,
+        in_block: 0,
+        in_cfg_test: false,
+        module_path: Vec::new(),
+        in_cfg: 0,
+        exported_items: Vec::new(),
+        cfg_gated_modules: Vec::new(),
+    };
+    transformer.visit_file_mut(&mut ast);
+
     // Generate output
-    let tokens = ast.to_token_stream();
-    let code = prettyplease::unparse(&syn::parse2(tokens)?);
+    let code = format_tokens(ast.to_token_stream());
 
     // Prepend allow attributes to suppress warnings in generated code
-    Ok(format!(
-        "#![allow(unused_imports, dead_code, unexpected_cfgs)]\n\n{}",
-        code
-    ))
+    Ok(TransformOutput {
+        code: format!(
+            "#![allow(unused_imports, dead_code, unexpected_cfgs)]\n\n{}",
+            code
+        ),
+        exported_macros: transformer.exported_macros,
+        exported_items: transformer.exported_items,
+        cfg_gated_modules: transformer.cfg_gated_modules,
+    })
+}
+
+/// Pretty-print the generated tokens, falling back to the unformatted tokens
+/// if they can't be re-parsed or pretty-printed
+///
+/// The generated lib compiling matters far more than its formatting.
+fn format_tokens(tokens: proc_macro2::TokenStream) -> String {
+    let formatted = syn::parse2::<syn::File>(tokens.clone())
+        .map_err(|e| e.to_string())
+        .and_then(|file| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                prettyplease::unparse(&file)
+            }))
+            .map_err(|_| "prettyplease panicked".to_string())
+        });
+    match formatted {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!(
+                "[FerrumPy] Warning: Could not format generated code ({}), writing it unformatted",
+                e
+            );
+            tokens.to_string()
+        }
+    }
 }
 
 /// Check if an inner attribute should be filtered out for the companion library
@@ -133,9 +390,560 @@ fn is_main_fn(item: &Item) -> bool {
     }
 }
 
+/// Drop items whose feature cfgs are inactive, recursing into inline modules,
+/// struct fields, enum variants and impl blocks
+fn apply_feature_cfgs(items: &mut Vec<Item>, features: &FeatureSet) {
+    items.retain_mut(|item| {
+        let keep = match item {
+            Item::Struct(i) => features.retain_attrs(&mut i.attrs),
+            Item::Enum(i) => features.retain_attrs(&mut i.attrs),
+            Item::Fn(i) => features.retain_attrs(&mut i.attrs),
+            Item::Mod(i) => features.retain_attrs(&mut i.attrs),
+            Item::Use(i) => features.retain_attrs(&mut i.attrs),
+            Item::Impl(i) => features.retain_attrs(&mut i.attrs),
+            Item::Type(i) => features.retain_attrs(&mut i.attrs),
+            Item::Const(i) => features.retain_attrs(&mut i.attrs),
+            Item::Static(i) => features.retain_attrs(&mut i.attrs),
+            Item::Trait(i) => features.retain_attrs(&mut i.attrs),
+            _ => true,
+        };
+        if !keep {
+            return false;
+        }
+
+        match item {
+            Item::Mod(m) => {
+                if let Some((_, items)) = &mut m.content {
+                    apply_feature_cfgs(items, features);
+                }
+            }
+            Item::Struct(s) => retain_fields(&mut s.fields, features),
+            Item::Enum(e) => {
+                e.variants = std::mem::take(&mut e.variants)
+                    .into_iter()
+                    .filter_map(|mut v| {
+                        features.retain_attrs(&mut v.attrs).then(|| {
+                            retain_fields(&mut v.fields, features);
+                            v
+                        })
+                    })
+                    .collect();
+            }
+            Item::Impl(i) => i.items.retain_mut(|impl_item| match impl_item {
+                syn::ImplItem::Fn(f) => features.retain_attrs(&mut f.attrs),
+                syn::ImplItem::Const(c) => features.retain_attrs(&mut c.attrs),
+                syn::ImplItem::Type(t) => features.retain_attrs(&mut t.attrs),
+                _ => true,
+            }),
+            _ => {}
+        }
+        true
+    });
+}
+
+fn retain_fields(fields: &mut Fields, features: &FeatureSet) {
+    let named = match fields {
+        Fields::Named(f) => &mut f.named,
+        Fields::Unnamed(f) => &mut f.unnamed,
+        Fields::Unit => return,
+    };
+    *named = std::mem::take(named)
+        .into_iter()
+        .filter_map(|mut field| features.retain_attrs(&mut field.attrs).then_some(field))
+        .collect();
+}
+
+/// Check if an attribute is one of the configured entry-point attributes
+fn is_main_attr(attr: &Attribute, main_attributes: &[String]) -> bool {
+    let path = path_to_string(attr.path());
+    main_attributes.iter().any(|m| *m == path)
+}
+
+fn has_main_attr(item: &Item, main_attributes: &[String]) -> bool {
+    match item {
+        Item::Fn(f) => f.attrs.iter().any(|a| is_main_attr(a, main_attributes)),
+        _ => false,
+    }
+}
+
+fn path_to_string(path: &syn::Path) -> String {
+    path.segments
+        .iter()
+        .map(|s| s.ident.to_string())
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+/// Root identifier of a `use` tree (`clap` for `use clap::Parser;`)
+fn use_tree_root(tree: &UseTree) -> Option<String> {
+    match tree {
+        UseTree::Path(p) => Some(p.ident.to_string()),
+        UseTree::Name(n) => Some(n.ident.to_string()),
+        UseTree::Rename(r) => Some(r.ident.to_string()),
+        _ => None,
+    }
+}
+
+/// Collect names brought into scope by a `use` tree, mapped to their root crate
+fn collect_use_names(tree: &UseTree, root: &str, names: &mut HashMap<String, String>) {
+    match tree {
+        UseTree::Path(p) => collect_use_names(&p.tree, root, names),
+        UseTree::Name(n) => {
+            names.insert(n.ident.to_string(), root.to_string());
+        }
+        UseTree::Rename(r) => {
+            names.insert(r.rename.to_string(), root.to_string());
+        }
+        UseTree::Group(g) => {
+            for item in &g.items {
+                collect_use_names(item, root, names);
+            }
+        }
+        UseTree::Glob(_) => {}
+    }
+}
+
+/// Resolves paths to the external crate they come from, if that crate is unavailable
+struct UnavailableCrates {
+    available: HashSet<String>,
+    local_items: HashSet<String>,
+    /// Imported name -> root crate
+    imports: HashMap<String, String>,
+}
+
+impl UnavailableCrates {
+    fn is_unavailable_root(&self, root: &str) -> bool {
+        !BUILTIN_CRATES.contains(&root)
+            && !self.available.contains(root)
+            && !self.local_items.contains(root)
+    }
+
+    /// Crate a path refers to, if it is one the generated lib can't see
+    fn unavailable_crate_of(&self, path: &syn::Path) -> Option<String> {
+        let first = path.segments.first()?.ident.to_string();
+        let root = if path.segments.len() > 1 {
+            first
+        } else {
+            self.imports.get(&first)?.clone()
+        };
+        self.is_unavailable_root(&root).then_some(root)
+    }
+
+    /// Strip derives and attribute macros from unavailable crates.
+    /// Returns the crates whose derives were removed.
+    fn strip_attrs(&self, attrs: &mut Vec<Attribute>) -> HashSet<String> {
+        let mut stripped = HashSet::new();
+        attrs.retain_mut(|attr| {
+            if attr.path().is_ident("derive") {
+                let Ok(paths) =
+                    attr.parse_args_with(Punctuated::<syn::Path, Token![,]>::parse_terminated)
+                else {
+                    return true;
+                };
+                let kept: Punctuated<syn::Path, Token![,]> = paths
+                    .into_iter()
+                    .filter(|p| match self.unavailable_crate_of(p) {
+                        Some(krate) => {
+                            stripped.insert(krate);
+                            false
+                        }
+                        None => true,
+                    })
+                    .collect();
+                if kept.is_empty() {
+                    return false;
+                }
+                *attr = syn::parse_quote!(#[derive(#kept)]);
+                return true;
+            }
+            self.unavailable_crate_of(attr.path()).is_none()
+        });
+        stripped
+    }
+}
+
+/// Remove helper attributes belonging to derives that were stripped
+fn strip_helper_attrs(attrs: &mut Vec<Attribute>, crates: &HashSet<String>) {
+    let helpers: Vec<&str> = DERIVE_HELPER_ATTRIBUTES
+        .iter()
+        .filter(|(krate, _)| crates.contains(*krate))
+        .flat_map(|(_, names)| names.iter().copied())
+        .collect();
+    if helpers.is_empty() {
+        return;
+    }
+    attrs.retain(|attr| !helpers.iter().any(|h| attr.path().is_ident(h)));
+}
+
+fn strip_unavailable_crates(ast: &mut syn::File, available: &[String]) {
+    let local_items = ast
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Mod(m) => Some(m.ident.to_string()),
+            Item::Struct(s) => Some(s.ident.to_string()),
+            Item::Enum(e) => Some(e.ident.to_string()),
+            Item::Trait(t) => Some(t.ident.to_string()),
+            Item::Type(t) => Some(t.ident.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    let mut imports = HashMap::new();
+    for item in &ast.items {
+        if let Item::Use(u) = item {
+            if let Some(root) = use_tree_root(&u.tree) {
+                collect_use_names(&u.tree, &root, &mut imports);
+            }
+        }
+    }
+
+    let resolver = UnavailableCrates {
+        available: available.iter().map(|c| c.replace('-', "_")).collect(),
+        local_items,
+        imports,
+    };
+
+    // Drop imports and extern crates that would dangle
+    ast.items.retain(|item| match item {
+        Item::Use(u) => use_tree_root(&u.tree).is_none_or(|r| !resolver.is_unavailable_root(&r)),
+        Item::ExternCrate(e) => !resolver.is_unavailable_root(&e.ident.to_string()),
+        _ => true,
+    });
+
+    let mut stripper = UnavailableAttrStripper { resolver };
+    stripper.visit_file_mut(ast);
+}
+
+/// Visitor that removes attributes referring to unavailable crates
+struct UnavailableAttrStripper {
+    resolver: UnavailableCrates,
+}
+
+impl VisitMut for UnavailableAttrStripper {
+    fn visit_item_struct_mut(&mut self, node: &mut ItemStruct) {
+        let stripped = self.resolver.strip_attrs(&mut node.attrs);
+        strip_helper_attrs(&mut node.attrs, &stripped);
+        for field in &mut node.fields {
+            let field_stripped = self.resolver.strip_attrs(&mut field.attrs);
+            strip_helper_attrs(&mut field.attrs, &stripped);
+            strip_helper_attrs(&mut field.attrs, &field_stripped);
+        }
+    }
+
+    fn visit_item_enum_mut(&mut self, node: &mut ItemEnum) {
+        let stripped = self.resolver.strip_attrs(&mut node.attrs);
+        strip_helper_attrs(&mut node.attrs, &stripped);
+        for variant in &mut node.variants {
+            self.resolver.strip_attrs(&mut variant.attrs);
+            strip_helper_attrs(&mut variant.attrs, &stripped);
+            for field in &mut variant.fields {
+                self.resolver.strip_attrs(&mut field.attrs);
+                strip_helper_attrs(&mut field.attrs, &stripped);
+            }
+        }
+    }
+
+    fn visit_item_fn_mut(&mut self, node: &mut ItemFn) {
+        self.resolver.strip_attrs(&mut node.attrs);
+    }
+
+    fn visit_impl_item_fn_mut(&mut self, node: &mut syn::ImplItemFn) {
+        self.resolver.strip_attrs(&mut node.attrs);
+    }
+
+    fn visit_item_impl_mut(&mut self, node: &mut syn::ItemImpl) {
+        self.resolver.strip_attrs(&mut node.attrs);
+        syn::visit_mut::visit_item_impl_mut(self, node);
+    }
+}
+
+/// Visitor that removes the derives listed in `strip_derives`
+struct NamedDeriveStripper<'a> {
+    names: &'a [String],
+}
+
+impl NamedDeriveStripper<'_> {
+    fn is_stripped(&self, path: &syn::Path) -> bool {
+        let Some(last) = path.segments.last().map(|s| s.ident.to_string()) else {
+            return false;
+        };
+        if KEPT_DERIVES.contains(&last.as_str()) {
+            return false;
+        }
+        let full = path_to_string(path);
+        self.names
+            .iter()
+            .any(|name| *name == full || (!name.contains("::") && *name == last))
+    }
+
+    fn strip(&self, attrs: &mut Vec<Attribute>) {
+        attrs.retain_mut(|attr| {
+            if !attr.path().is_ident("derive") {
+                return true;
+            }
+            let Ok(paths) =
+                attr.parse_args_with(Punctuated::<syn::Path, Token![,]>::parse_terminated)
+            else {
+                return true;
+            };
+            let kept: Punctuated<syn::Path, Token![,]> =
+                paths.into_iter().filter(|p| !self.is_stripped(p)).collect();
+            if kept.is_empty() {
+                return false;
+            }
+            *attr = syn::parse_quote!(#[derive(#kept)]);
+            true
+        });
+    }
+}
+
+impl VisitMut for NamedDeriveStripper<'_> {
+    fn visit_item_struct_mut(&mut self, node: &mut ItemStruct) {
+        self.strip(&mut node.attrs);
+    }
+
+    fn visit_item_enum_mut(&mut self, node: &mut ItemEnum) {
+        self.strip(&mut node.attrs);
+    }
+
+    fn visit_item_union_mut(&mut self, node: &mut syn::ItemUnion) {
+        self.strip(&mut node.attrs);
+    }
+}
+
+/// Rewrite `self_crate::...` paths to `crate::...`, unless the name is shadowed
+/// by a root item or import of the same name
+fn rewrite_self_crate_paths(ast: &mut syn::File, self_crate: &str) {
+    let shadowed = ast.items.iter().any(|item| match item {
+        Item::Mod(m) => m.ident == self_crate,
+        Item::Struct(s) => s.ident == self_crate,
+        Item::Enum(e) => e.ident == self_crate,
+        Item::Trait(t) => t.ident == self_crate,
+        Item::Type(t) => t.ident == self_crate,
+        Item::Use(u) => {
+            let mut names = HashMap::new();
+            if let Some(root) = use_tree_root(&u.tree) {
+                collect_use_names(&u.tree, &root, &mut names);
+            }
+            names.contains_key(self_crate) && use_tree_root(&u.tree).as_deref() != Some(self_crate)
+        }
+        _ => false,
+    });
+    if shadowed {
+        return;
+    }
+
+    // `extern crate my_app;` would refer to the lib itself
+    ast.items
+        .retain(|item| !matches!(item, Item::ExternCrate(e) if e.ident == self_crate));
+
+    let mut rewriter = SelfCrateRewriter {
+        ident: self_crate.to_string(),
+    };
+    rewriter.visit_file_mut(ast);
+}
+
+/// Visitor that replaces the leading segment of paths through the crate's own name
+struct SelfCrateRewriter {
+    ident: String,
+}
+
+impl VisitMut for SelfCrateRewriter {
+    fn visit_path_mut(&mut self, path: &mut syn::Path) {
+        if path.segments.len() > 1 {
+            let first = &mut path.segments[0];
+            if first.ident == self.ident && first.arguments.is_none() {
+                first.ident = syn::Ident::new("crate", first.ident.span());
+                path.leading_colon = None;
+            }
+        }
+        syn::visit_mut::visit_path_mut(self, path);
+    }
+
+    fn visit_item_use_mut(&mut self, node: &mut syn::ItemUse) {
+        if let UseTree::Path(p) = &mut node.tree {
+            if p.ident == self.ident {
+                p.ident = syn::Ident::new("crate", p.ident.span());
+                node.leading_colon = None;
+            }
+        }
+    }
+}
+
+/// Visitor that rewrites `use crate::...` items through relocated modules,
+/// in the crate root, inline modules and fn bodies alike
+struct UseRelocator<'a> {
+    relocations: &'a [ModuleRelocation],
+}
+
+impl UseRelocator<'_> {
+    /// Rewritten replacements for a `use` item, or `None` if it is unaffected
+    fn relocate(&self, node: &syn::ItemUse) -> Option<Vec<syn::ItemUse>> {
+        if use_tree_root(&node.tree).as_deref() != Some("crate") {
+            return None;
+        }
+        let mut leaves = Vec::new();
+        collect_use_leaves(&node.tree, &mut Vec::new(), &mut leaves);
+
+        let mut moved = false;
+        let mut paths = Vec::new();
+        for (mut path, leaf) in leaves.into_iter() {
+            // Path below `crate`, including the imported name
+            path.remove(0);
+            let suffix = match leaf {
+                UseLeaf::Name(name) if name == "self" => String::new(),
+                UseLeaf::Name(name) => {
+                    path.push(name);
+                    String::new()
+                }
+                UseLeaf::Rename(name, alias) => {
+                    if name != "self" {
+                        path.push(name);
+                    }
+                    format!(" as {}", alias)
+                }
+                UseLeaf::Glob => "::*".to_string(),
+            };
+            if let Some(reloc) = self
+                .relocations
+                .iter()
+                .find(|r| path.starts_with(&r.from) && r.from != r.to)
+            {
+                path.splice(..reloc.from.len(), reloc.to.iter().cloned());
+                moved = true;
+            }
+            paths.push(format!("crate::{}{}", path.join("::"), suffix));
+        }
+        if !moved {
+            return None;
+        }
+
+        // One `use` per imported path; a group can't span old and new locations
+        paths
+            .iter()
+            .map(|path| {
+                let mut item = node.clone();
+                item.leading_colon = None;
+                item.tree = syn::parse_str(path).ok()?;
+                Some(item)
+            })
+            .collect()
+    }
+
+    fn relocate_items(&self, items: &mut Vec<Item>) {
+        let mut relocated = Vec::with_capacity(items.len());
+        for item in items.drain(..) {
+            match &item {
+                Item::Use(u) => match self.relocate(u) {
+                    Some(uses) => relocated.extend(uses.into_iter().map(Item::Use)),
+                    None => relocated.push(item),
+                },
+                _ => relocated.push(item),
+            }
+        }
+        *items = relocated;
+    }
+}
+
+impl VisitMut for UseRelocator<'_> {
+    fn visit_file_mut(&mut self, node: &mut syn::File) {
+        self.relocate_items(&mut node.items);
+        syn::visit_mut::visit_file_mut(self, node);
+    }
+
+    fn visit_item_mod_mut(&mut self, node: &mut ItemMod) {
+        if let Some((_, items)) = &mut node.content {
+            self.relocate_items(items);
+        }
+        syn::visit_mut::visit_item_mod_mut(self, node);
+    }
+
+    fn visit_block_mut(&mut self, node: &mut syn::Block) {
+        let mut stmts = Vec::with_capacity(node.stmts.len());
+        for stmt in node.stmts.drain(..) {
+            match &stmt {
+                syn::Stmt::Item(Item::Use(u)) => match self.relocate(u) {
+                    Some(uses) => {
+                        stmts.extend(uses.into_iter().map(|u| syn::Stmt::Item(Item::Use(u))))
+                    }
+                    None => stmts.push(stmt),
+                },
+                _ => stmts.push(stmt),
+            }
+        }
+        node.stmts = stmts;
+        syn::visit_mut::visit_block_mut(self, node);
+    }
+}
+
+/// What a use tree imports at the end of one of its paths
+enum UseLeaf {
+    Name(String),
+    Rename(String, String),
+    Glob,
+}
+
+/// Flatten a use tree into (path, leaf) pairs
+fn collect_use_leaves(
+    tree: &UseTree,
+    prefix: &mut Vec<String>,
+    leaves: &mut Vec<(Vec<String>, UseLeaf)>,
+) {
+    match tree {
+        UseTree::Path(p) => {
+            prefix.push(p.ident.to_string());
+            collect_use_leaves(&p.tree, prefix, leaves);
+            prefix.pop();
+        }
+        UseTree::Group(g) => {
+            for item in &g.items {
+                collect_use_leaves(item, prefix, leaves);
+            }
+        }
+        UseTree::Name(n) => leaves.push((prefix.clone(), UseLeaf::Name(n.ident.to_string()))),
+        UseTree::Rename(r) => leaves.push((
+            prefix.clone(),
+            UseLeaf::Rename(r.ident.to_string(), r.rename.to_string()),
+        )),
+        UseTree::Glob(_) => leaves.push((prefix.clone(), UseLeaf::Glob)),
+    }
+}
+
 /// Visitor that makes all items public and optionally adds serde derives
 struct PublicityTransformer {
     add_serde: bool,
+    /// Names of the `macro_rules!` macros given `#[macro_export]`
+    exported_macros: Vec<String>,
+    /// Macro names exported from the crate root so far, or by the source
+    /// itself
+    taken_macros: HashSet<String>,
+    /// Depth of nested blocks (fn bodies); macros defined there stay local
+    in_block: usize,
+    /// Inside a `#[cfg(test)]` module, whose macros are not exported
+    in_cfg_test: bool,
+    /// Inline modules enclosing the visited item
+    module_path: Vec<String>,
+    /// Depth of `#[cfg]`-gated inline modules; their items may not exist
+    in_cfg: usize,
+    /// Public items reachable by path, for the prelude
+    exported_items: Vec<ExportedItem>,
+    cfg_gated_modules: Vec<Vec<String>>,
+}
+
+impl PublicityTransformer {
+    /// Record an item for the prelude unless it is local to a fn body or
+    /// only compiled under some cfg
+    fn record_item(&mut self, ident: &syn::Ident, kind: ExportedItemKind, attrs: &[Attribute]) {
+        let reachable = self.in_block == 0 && !self.in_cfg_test && self.in_cfg == 0;
+        if reachable && !attrs.iter().any(|a| a.path().is_ident("cfg")) {
+            self.exported_items.push(ExportedItem {
+                name: ident.to_string(),
+                kind,
+                module_path: self.module_path.clone(),
+            });
+        }
+    }
 }
 
 impl VisitMut for PublicityTransformer {
@@ -153,6 +961,7 @@ impl VisitMut for PublicityTransformer {
         if self.add_serde && !has_reference_fields(&node.fields) {
             add_serde_derive(&mut node.attrs);
         }
+        self.record_item(&node.ident, ExportedItemKind::Struct, &node.attrs);
 
         // Continue visiting
         syn::visit_mut::visit_item_struct_mut(self, node);
@@ -170,6 +979,7 @@ impl VisitMut for PublicityTransformer {
         if self.add_serde {
             add_serde_derive(&mut node.attrs);
         }
+        self.record_item(&node.ident, ExportedItemKind::Enum, &node.attrs);
 
         syn::visit_mut::visit_item_enum_mut(self, node);
     }
@@ -178,6 +988,7 @@ impl VisitMut for PublicityTransformer {
         // Make function public (except main, which will be removed)
         if node.sig.ident != "main" {
             node.vis = syn::parse_quote!(pub);
+            self.record_item(&node.sig.ident, ExportedItemKind::Function, &node.attrs);
         }
         syn::visit_mut::visit_item_fn_mut(self, node);
     }
@@ -186,18 +997,66 @@ impl VisitMut for PublicityTransformer {
         // Make module public
         node.vis = syn::parse_quote!(pub);
 
-        // If inline module, visit contents
-        if let Some((_, ref mut items)) = node.content {
-            for item in items {
-                self.visit_item_mut(item);
+        // Items of a gated module file are left out of the prelude
+        let gated = node.attrs.iter().any(|a| a.path().is_ident("cfg"));
+        if node.content.is_none() {
+            if gated && self.in_block == 0 {
+                let mut path = self.module_path.clone();
+                path.push(node.ident.to_string());
+                self.cfg_gated_modules.push(path);
             }
+            return;
         }
 
-        // Don't call default visit to avoid double-visiting
+        // Inline module contents, at any depth, get the same treatment as
+        // items at the root
+        let outer_cfg_test = self.in_cfg_test;
+        self.in_cfg_test |= has_cfg_test(&node.attrs);
+        self.in_cfg += usize::from(gated);
+        self.module_path.push(node.ident.to_string());
+        syn::visit_mut::visit_item_mod_mut(self, node);
+        self.module_path.pop();
+        self.in_cfg -= usize::from(gated);
+        self.in_cfg_test = outer_cfg_test;
+    }
+
+    fn visit_item_macro_mut(&mut self, node: &mut syn::ItemMacro) {
+        // Export macro_rules! so REPL code can use them via the companion crate
+        if let Some(ident) = &node.ident {
+            let exportable = node.mac.path.is_ident("macro_rules")
+                && self.in_block == 0
+                && !self.in_cfg_test
+                && !has_cfg_test(&node.attrs);
+            let name = ident.to_string();
+            if node.attrs.iter().any(|a| a.path().is_ident("macro_export")) {
+                if exportable {
+                    self.exported_macros.push(name);
+                }
+            } else if exportable && !self.taken_macros.insert(name.clone()) {
+                // Exporting moves the macro to the crate root, where a
+                // macro of another module may already have the name
+                eprintln!(
+                    "[FerrumPy] Warning: Not exporting macro '{}' of module '{}': another macro of that name is exported",
+                    name,
+                    self.module_path.join("::")
+                );
+            } else if exportable {
+                node.attrs.push(syn::parse_quote!(#[macro_export]));
+                self.exported_macros.push(name);
+            }
+        }
+        syn::visit_mut::visit_item_macro_mut(self, node);
+    }
+
+    fn visit_block_mut(&mut self, node: &mut syn::Block) {
+        self.in_block += 1;
+        syn::visit_mut::visit_block_mut(self, node);
+        self.in_block -= 1;
     }
 
     fn visit_item_type_mut(&mut self, node: &mut syn::ItemType) {
         node.vis = syn::parse_quote!(pub);
+        self.record_item(&node.ident, ExportedItemKind::TypeAlias, &node.attrs);
         syn::visit_mut::visit_item_type_mut(self, node);
     }
 
@@ -211,6 +1070,28 @@ impl VisitMut for PublicityTransformer {
         syn::visit_mut::visit_item_static_mut(self, node);
     }
 
+    fn visit_item_impl_mut(&mut self, node: &mut syn::ItemImpl) {
+        // Associated fns and consts of inherent impls become callable from
+        // the REPL; trait impl items can't take a visibility. Types local to
+        // a fn body aren't reachable, so their impls are left alone.
+        if node.trait_.is_none() && self.in_block == 0 {
+            for item in &mut node.items {
+                match item {
+                    syn::ImplItem::Fn(f) => f.vis = syn::parse_quote!(pub),
+                    syn::ImplItem::Const(c) => c.vis = syn::parse_quote!(pub),
+                    _ => {}
+                }
+            }
+        }
+        syn::visit_mut::visit_item_impl_mut(self, node);
+    }
+
+    fn visit_item_trait_mut(&mut self, node: &mut syn::ItemTrait) {
+        // Trait methods share the trait's visibility
+        node.vis = syn::parse_quote!(pub);
+        syn::visit_mut::visit_item_trait_mut(self, node);
+    }
+
     fn visit_item_use_mut(&mut self, node: &mut syn::ItemUse) {
         // Make use statements public (pub use) so they're re-exported
         // This is critical for user types to be accessible via `use ferrumpy_snapshot::*;`
@@ -219,6 +1100,32 @@ impl VisitMut for PublicityTransformer {
     }
 }
 
+/// Names of the macros the source exports itself, at any depth
+fn macro_exports(items: &[Item]) -> Vec<String> {
+    let mut names = Vec::new();
+    for item in items {
+        match item {
+            Item::Macro(m) if m.attrs.iter().any(|a| a.path().is_ident("macro_export")) => {
+                names.extend(m.ident.as_ref().map(ToString::to_string));
+            }
+            Item::Mod(m) => {
+                if let Some((_, items)) = &m.content {
+                    names.extend(macro_exports(items));
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Check for a `#[cfg(...)]` only true in test builds, e.g. `#[cfg(test)]`
+fn has_cfg_test(attrs: &[Attribute]) -> bool {
+    attrs
+        .iter()
+        .any(|attr| eval_cfg_without_test(attr) == Some(false))
+}
+
 /// Add serde derive attributes to a struct/enum
 fn add_serde_derive(attrs: &mut Vec<Attribute>) {
     // Check if serde derives already exist
@@ -257,12 +1164,197 @@ struct User {
     age: u32,
 }
 "#;
-        let result = transform_source(source, false, true).unwrap();
+        let options = TransformOptions::default();
+        let result = transform_source(source, &options).unwrap();
         assert!(result.contains("pub struct User"));
         assert!(result.contains("pub name"));
         assert!(result.contains("Serialize"));
     }
 
+    #[test]
+    fn test_format_tokens_falls_back_to_raw_tokens() {
+        let file = format_tokens(quote::quote!(
+            struct A;
+        ));
+        assert_eq!(file, "struct A;\n");
+
+        // Not a file: re-parsing fails, the tokens are kept as they are
+        let raw = format_tokens(quote::quote!(1 + 2));
+        assert_eq!(raw, "1 + 2");
+    }
+
+    #[test]
+    fn test_transform_macro_heavy_source() {
+        let source = r#"
+macro_rules! make {
+    ($($name:ident => $ty:ty),* $(,)?) => {
+        $(struct $name { value: $ty })*
+    };
+}
+make!(A => u8, B => Vec<Option<String>>,);
+const TABLE: [fn(u8) -> u8; 2] = [|x| x, |x| x.wrapping_mul(2)];
+"#;
+        let result = transform_source(source, &TransformOptions::default()).unwrap();
+        assert!(result.contains("make!"), "Got: {}", result);
+        syn::parse_file(&result).unwrap();
+    }
+
+    #[test]
+    fn test_transform_impl_items_public() {
+        let source = r#"
+struct User {
+    name: String,
+}
+
+impl User {
+    const MAX_NAME: usize = 32;
+
+    fn private_helper() {}
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Clone for User {
+    fn clone(&self) -> Self {
+        User { name: self.name.clone() }
+    }
+}
+
+trait Greet {
+    fn greet(&self) -> String;
+}
+
+fn local() {
+    struct Local;
+    impl Local {
+        fn hidden() {}
+    }
+}
+"#;
+        let result = transform_source(source, &TransformOptions::default()).unwrap();
+        assert!(
+            result.contains("pub const MAX_NAME: usize"),
+            "Got: {}",
+            result
+        );
+        assert!(
+            result.contains("pub fn private_helper()"),
+            "Got: {}",
+            result
+        );
+        assert!(result.contains("pub fn name(&self)"), "Got: {}", result);
+        assert!(!result.contains("pub(crate)"), "Got: {}", result);
+        assert!(result.contains("    fn clone(&self)"), "Got: {}", result);
+        assert!(!result.contains("pub fn clone"), "Got: {}", result);
+        assert!(result.contains("pub trait Greet"), "Got: {}", result);
+        assert!(!result.contains("pub fn hidden"), "Got: {}", result);
+        syn::parse_file(&result).unwrap();
+    }
+
+    #[test]
+    fn test_transform_nested_inline_modules() {
+        let source = r#"
+mod a {
+    mod b {
+        struct C;
+    }
+}
+
+#[cfg(unix)]
+mod gated {
+    mod inner {
+        struct D;
+    }
+}
+"#;
+        let expected_c = "pub mod a {\n    pub mod b {\n        #[derive(serde::Serialize, serde::Deserialize)]\n        pub struct C;\n    }\n}";
+        let expected_d = "#[cfg(unix)]\npub mod gated {\n    pub mod inner {\n        #[derive(serde::Serialize, serde::Deserialize)]\n        pub struct D;\n    }\n}";
+
+        // Lib roots and copied module files get the same treatment
+        let results = [
+            transform_source(source, &TransformOptions::default()).unwrap(),
+            transform_module(source, true).unwrap(),
+        ];
+        for result in results {
+            assert!(result.contains(expected_c), "Got: {}", result);
+            assert!(result.contains(expected_d), "Got: {}", result);
+        }
+    }
+
+    #[test]
+    fn test_nested_inline_modules_keep_crate_uses() {
+        let source = r#"
+mod types {
+    pub struct User;
+}
+
+mod a {
+    mod b {
+        use crate::types::User;
+
+        struct Session {
+            user: User,
+        }
+    }
+}
+"#;
+        let result = transform_source(source, &TransformOptions::default()).unwrap();
+        assert!(
+            result.contains("    pub mod b {\n        pub use crate::types::User;"),
+            "Got: {}",
+            result
+        );
+        assert!(
+            result.contains("#[derive(serde::Serialize, serde::Deserialize)]\n        pub struct Session {\n            pub user: User,"),
+            "Got: {}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_relocated_module_uses_rewritten() {
+        let source = r#"
+use crate::types::{self, User as Account, admin::*};
+use crate::other::Thing;
+
+mod a {
+    mod b {
+        use crate::types::User;
+
+        fn load() {
+            use crate::types::Id;
+        }
+    }
+}
+"#;
+        let options = TransformOptions {
+            relocated_modules: vec![ModuleRelocation {
+                from: vec!["types".to_string()],
+                to: vec!["model".to_string(), "types".to_string()],
+            }],
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
+        for expected in [
+            "pub use crate::model::types;",
+            "pub use crate::model::types::User as Account;",
+            "pub use crate::model::types::admin::*;",
+            "pub use crate::other::Thing;",
+            "        pub use crate::model::types::User;",
+            "            pub use crate::model::types::Id;",
+        ] {
+            assert!(
+                result.contains(expected),
+                "Missing {}: {}",
+                expected,
+                result
+            );
+        }
+        assert!(!result.contains("crate::types"), "Got: {}", result);
+    }
+
     #[test]
     fn test_remove_main() {
         let source = r#"
@@ -274,8 +1366,363 @@ fn helper() -> i32 {
     42
 }
 "#;
-        let result = transform_source(source, true, false).unwrap();
+        let options = TransformOptions {
+            remove_main: true,
+            add_serde: false,
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
         assert!(!result.contains("fn main"));
         assert!(result.contains("pub fn helper"));
     }
+
+    #[test]
+    fn test_remove_tokio_main() {
+        let source = r#"
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    tokio::time::sleep(Duration::from_millis(1)).await;
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn entry() {}
+
+fn helper() -> i32 {
+    42
+}
+"#;
+        let options = TransformOptions {
+            remove_main: true,
+            add_serde: false,
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
+        assert!(!result.contains("tokio"), "Got: {}", result);
+        assert!(!result.contains("fn main"), "Got: {}", result);
+        assert!(!result.contains("fn entry"), "Got: {}", result);
+        assert!(result.contains("pub fn helper"), "Got: {}", result);
+
+        // Kept as a lib function, the async fn loses only its entry-point attribute
+        let options = TransformOptions {
+            add_serde: false,
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
+        assert!(result.contains("async fn main"), "Got: {}", result);
+        assert!(!result.contains("tokio::main"), "Got: {}", result);
+    }
+
+    #[test]
+    fn test_strip_named_derives() {
+        let source = r#"
+#[derive(Debug, Clone, Builder, serde::Serialize)]
+struct Config {
+    name: String,
+}
+
+#[derive(Getters)]
+struct Inner {
+    value: i32,
+}
+
+mod nested {
+    #[derive(Debug, derive_more::Display, PartialEq)]
+    pub enum Mode {
+        Fast,
+    }
+}
+"#;
+        let options = TransformOptions {
+            add_serde: false,
+            strip_derives: vec![
+                "Builder".to_string(),
+                "Getters".to_string(),
+                "derive_more::Display".to_string(),
+                "Debug".to_string(),
+            ],
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
+        assert!(!result.contains("Builder"), "Got: {}", result);
+        assert!(!result.contains("Getters"), "Got: {}", result);
+        assert!(!result.contains("Display"), "Got: {}", result);
+        assert!(
+            result.contains("#[derive(Debug, Clone, serde::Serialize)]"),
+            "Got: {}",
+            result
+        );
+        assert!(
+            result.contains("#[derive(Debug, PartialEq)]"),
+            "Got: {}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_strip_module_inner_attributes() {
+        let source =
+            "#![allow(dead_code)]\n#![cfg_attr(docsrs, feature(doc_cfg))]\npub fn f() {}\n";
+        let kept = transform_module_output(source, &TransformOptions::default()).unwrap();
+        assert!(
+            kept.code.contains("#![allow(dead_code)]"),
+            "Got: {}",
+            kept.code
+        );
+
+        let options = TransformOptions {
+            strip_inner_attrs: true,
+            ..Default::default()
+        };
+        let stripped = transform_module_output(source, &options).unwrap();
+        assert!(
+            !stripped.code.contains("allow(dead_code)"),
+            "Got: {}",
+            stripped.code
+        );
+        assert!(!stripped.code.contains("doc_cfg"), "Got: {}", stripped.code);
+        assert!(stripped.code.contains("pub fn f"), "Got: {}", stripped.code);
+    }
+
+    #[test]
+    fn test_strip_derives_from_unavailable_crates() {
+        let source = r#"
+use clap::Parser;
+use serde::Serialize;
+
+#[derive(Debug, Parser, Serialize)]
+#[command(version)]
+struct Args {
+    #[arg(short, long)]
+    name: String,
+}
+
+#[derive(Clone, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+enum Mode {
+    Fast,
+}
+
+#[tracing::instrument]
+fn run() {}
+"#;
+        let options = TransformOptions {
+            add_serde: false,
+            available_crates: Some(vec!["serde".to_string()]),
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
+        assert!(!result.contains("clap"), "Got: {}", result);
+        assert!(!result.contains("Parser"), "Got: {}", result);
+        assert!(!result.contains("#[command"), "Got: {}", result);
+        assert!(!result.contains("#[arg"), "Got: {}", result);
+        assert!(!result.contains("strum"), "Got: {}", result);
+        assert!(!result.contains("tracing"), "Got: {}", result);
+        assert!(
+            result.contains("#[derive(Debug, Serialize)]"),
+            "Got: {}",
+            result
+        );
+        assert!(result.contains("#[derive(Clone)]"), "Got: {}", result);
+        assert!(
+            result.contains("pub use serde::Serialize"),
+            "Got: {}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_feature_cfgs_applied() {
+        let source = r#"
+#[cfg(feature = "extra")]
+struct Extra;
+
+#[cfg(not(feature = "extra"))]
+struct Basic;
+
+struct Config {
+    #[cfg(feature = "extra")]
+    level: u8,
+    #[cfg(unix)]
+    socket: String,
+}
+"#;
+        let manifest: toml::Value = "[features]\nextra = []\n".parse().unwrap();
+        let options = TransformOptions {
+            add_serde: false,
+            active_features: Some(FeatureSet::resolve(
+                &manifest,
+                &["extra".to_string()],
+                false,
+            )),
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
+        assert!(result.contains("pub struct Extra"), "Got: {}", result);
+        assert!(!result.contains("Basic"), "Got: {}", result);
+        assert!(result.contains("pub level: u8"), "Got: {}", result);
+        assert!(!result.contains("feature"), "Got: {}", result);
+        assert!(result.contains("#[cfg(unix)]"), "Got: {}", result);
+
+        let options = TransformOptions {
+            active_features: Some(FeatureSet::resolve(&manifest, &[], false)),
+            ..options
+        };
+        let result = transform_source(source, &options).unwrap();
+        assert!(!result.contains("Extra"), "Got: {}", result);
+        assert!(!result.contains("level"), "Got: {}", result);
+        assert!(result.contains("pub struct Basic"), "Got: {}", result);
+    }
+
+    #[test]
+    fn test_export_macro_rules() {
+        let source = r#"
+macro_rules! square {
+    ($x:expr) => { $x * $x };
+}
+
+#[macro_export]
+macro_rules! already {
+    () => {};
+}
+
+mod helpers {
+    macro_rules! nested {
+        () => {};
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    macro_rules! test_only {
+        () => {};
+    }
+}
+
+#[cfg(not(test))]
+mod runtime {
+    macro_rules! not_in_tests {
+        () => {};
+    }
+}
+
+fn area(side: u32) -> u32 {
+    macro_rules! local {
+        () => {};
+    }
+    square!(side)
+}
+"#;
+        let output = transform_with_output(source, &TransformOptions::default()).unwrap();
+        assert_eq!(
+            output.exported_macros,
+            ["square", "already", "nested", "not_in_tests"]
+        );
+        assert_eq!(
+            output.code.matches("#[macro_export]").count(),
+            4,
+            "Got: {}",
+            output.code
+        );
+        assert!(output.code.contains("square!(side)"));
+    }
+
+    #[test]
+    fn test_export_macro_rules_skips_name_clashes() {
+        let source = r#"
+mod first {
+    macro_rules! helper {
+        () => {};
+    }
+}
+
+mod second {
+    macro_rules! helper {
+        () => {};
+    }
+    macro_rules! shared {
+        () => {};
+    }
+}
+
+#[macro_export]
+macro_rules! own {
+    () => {};
+}
+
+mod third {
+    macro_rules! own {
+        () => {};
+    }
+}
+"#;
+        let options = TransformOptions {
+            exported_macros: vec!["shared".to_string()],
+            ..Default::default()
+        };
+        let output = transform_with_output(source, &options).unwrap();
+        assert_eq!(output.exported_macros, ["helper", "own"]);
+        assert_eq!(
+            output.code.matches("#[macro_export]").count(),
+            2,
+            "Got: {}",
+            output.code
+        );
+    }
+
+    #[test]
+    fn test_transform_rewrites_self_crate_paths() {
+        let source = r#"
+extern crate my_app;
+use my_app::config::Config;
+use ::my_app::{db, Error};
+
+fn load() -> Result<my_app::Config, my_app::Error> {
+    let path = my_app::paths::default_path();
+    other::my_app::ignored();
+    db::open(path)
+}
+
+fn main() {}
+"#;
+        let options = TransformOptions {
+            remove_main: true,
+            self_crate: Some("my-app".to_string()),
+            ..Default::default()
+        };
+        let result = transform_source(source, &options).unwrap();
+
+        assert!(!result.contains("extern crate"), "Got: {}", result);
+        assert!(
+            result.contains("pub use crate::config::Config;"),
+            "Got: {}",
+            result
+        );
+        assert!(
+            result.contains("pub use crate::{db, Error};"),
+            "Got: {}",
+            result
+        );
+        assert!(
+            result.contains("Result<crate::Config, crate::Error>"),
+            "Got: {}",
+            result
+        );
+        assert!(
+            result.contains("crate::paths::default_path()"),
+            "Got: {}",
+            result
+        );
+        assert!(
+            result.contains("other::my_app::ignored()"),
+            "Got: {}",
+            result
+        );
+
+        // A local module of the same name is left alone
+        let shadowed = "mod my_app { pub fn f() {} }\nfn g() { my_app::f() }\n";
+        let result = transform_source(shadowed, &options).unwrap();
+        assert!(result.contains("my_app::f()"), "Got: {}", result);
+    }
 }
@@ -0,0 +1,229 @@
+//! Incremental regeneration of an existing lib crate
+//!
+//! Regenerating into a cached output directory rewrites every file, which
+//! throws away cargo's incremental state for the snapshot crate. Updating
+//! writes only the files whose content changed.
+
+use anyhow::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use super::output::Output;
+use super::{generate_into, GeneratedLib, LibGenConfig};
+
+/// Directories of a generated crate whose files all come from the generator
+const GENERATED_DIRS: &[&str] = &["src", "deps"];
+
+/// Files touched by [`update_lib`], relative to the crate root
+#[derive(Debug, Clone, Default)]
+pub struct UpdateReport {
+    /// Files created or rewritten
+    pub written: Vec<PathBuf>,
+    /// Files deleted because their source module disappeared
+    pub removed: Vec<PathBuf>,
+    /// Files left as they were
+    pub unchanged: Vec<PathBuf>,
+}
+
+/// Bring a previously generated lib up to date with the project's sources
+///
+/// Only files whose content changed are rewritten, and Cargo.toml only when
+/// the dependency set changed. `config.output_dir` is ignored in favour of
+/// `existing.path`; with `config.dry_run` the report is computed without
+/// touching the crate.
+pub fn update_lib(
+    existing: &GeneratedLib,
+    project_path: &Path,
+    config: LibGenConfig,
+) -> Result<UpdateReport> {
+    let output = Output::new(&existing.path, true).with_max_copy_bytes(config.max_copy_bytes);
+    generate_into(project_path, &config, &output)?;
+    let files = output.into_file_bytes();
+    let generated: HashSet<PathBuf> = files.iter().map(|(rel, _)| rel.clone()).collect();
+
+    let mut report = UpdateReport::default();
+    for (rel, content) in files {
+        let path = existing.path.join(&rel);
+        let unchanged = match fs::read(&path) {
+            Ok(old) if rel == Path::new("Cargo.toml") => same_dependencies(&old, &content),
+            Ok(old) => content_hash(&old) == content_hash(&content),
+            Err(_) => false,
+        };
+        if unchanged {
+            report.unchanged.push(rel);
+            continue;
+        }
+        if !config.dry_run {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, &content)?;
+        }
+        report.written.push(rel);
+    }
+
+    for dir in GENERATED_DIRS {
+        let mut existing_files = Vec::new();
+        collect_files(&existing.path, Path::new(dir), &mut existing_files)?;
+        for rel in existing_files {
+            if generated.contains(&rel) {
+                continue;
+            }
+            if !config.dry_run {
+                fs::remove_file(existing.path.join(&rel))?;
+                remove_empty_parents(&existing.path, &rel);
+            }
+            report.removed.push(rel);
+        }
+    }
+
+    Ok(report)
+}
+
+fn content_hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether two manifests declare the same dependencies
+fn same_dependencies(old: &[u8], new: &[u8]) -> bool {
+    let parse =
+        |content: &[u8]| -> Option<toml::Table> { std::str::from_utf8(content).ok()?.parse().ok() };
+    match (parse(old), parse(new)) {
+        (Some(old), Some(new)) => old.get("dependencies") == new.get("dependencies"),
+        _ => false,
+    }
+}
+
+/// Collect the files under `root/rel_dir`, relative to `root`, skipping
+/// build output
+fn collect_files(root: &Path, rel_dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let Ok(entries) = fs::read_dir(root.join(rel_dir)) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        let rel = rel_dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if entry.file_name() != "target" {
+                collect_files(root, &rel, files)?;
+            }
+        } else {
+            files.push(rel);
+        }
+    }
+    Ok(())
+}
+
+/// Remove the directories left empty by deleting `rel`, up to `root`
+fn remove_empty_parents(root: &Path, rel: &Path) {
+    let mut dir = rel.parent();
+    while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        // Fails on the first non-empty directory
+        if fs::remove_dir(root.join(d)).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libgen::generate_lib;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    fn write_project(dir: &Path, main_rs: &str) {
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\n",
+        )
+        .unwrap();
+        fs::write(dir.join("src/main.rs"), main_rs).unwrap();
+    }
+
+    fn set_old_mtime(path: &Path) -> SystemTime {
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        old
+    }
+
+    fn mtime(path: &Path) -> SystemTime {
+        fs::metadata(path).unwrap().modified().unwrap()
+    }
+
+    #[test]
+    fn test_update_lib_rewrites_only_changed_modules() {
+        let temp = TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        write_project(&project, "mod a;\nmod b;\n\nfn main() {}\n");
+        fs::write(project.join("src/a.rs"), "pub struct A;\n").unwrap();
+        fs::write(project.join("src/b.rs"), "pub struct B;\n").unwrap();
+
+        let out = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(out.clone()),
+            ..Default::default()
+        };
+        let existing = generate_lib(&project, config).unwrap();
+        let old = set_old_mtime(&out.join("src/a.rs"));
+        set_old_mtime(&out.join("src/b.rs"));
+        set_old_mtime(&out.join("Cargo.toml"));
+
+        fs::write(project.join("src/a.rs"), "pub struct A(pub u8);\n").unwrap();
+        let report = update_lib(&existing, &project, LibGenConfig::default()).unwrap();
+
+        assert_eq!(report.written, vec![PathBuf::from("src/a.rs")]);
+        assert!(report.removed.is_empty(), "Got: {:?}", report.removed);
+        assert!(report.unchanged.contains(&PathBuf::from("Cargo.toml")));
+        assert_ne!(mtime(&out.join("src/a.rs")), old);
+        assert_eq!(mtime(&out.join("src/b.rs")), old);
+        assert_eq!(mtime(&out.join("Cargo.toml")), old);
+        let a_rs = fs::read_to_string(out.join("src/a.rs")).unwrap();
+        assert!(a_rs.contains("pub struct A(pub u8)"), "Got: {}", a_rs);
+    }
+
+    #[test]
+    fn test_update_lib_removes_deleted_modules() {
+        let temp = TempDir::new().unwrap();
+        let project = temp.path().join("project");
+        write_project(&project, "mod a;\nmod net;\n\nfn main() {}\n");
+        fs::write(project.join("src/a.rs"), "pub struct A;\n").unwrap();
+        fs::create_dir_all(project.join("src/net")).unwrap();
+        fs::write(project.join("src/net/mod.rs"), "pub struct Socket;\n").unwrap();
+
+        let out = temp.path().join("out");
+        let config = LibGenConfig {
+            output_dir: Some(out.clone()),
+            ..Default::default()
+        };
+        let existing = generate_lib(&project, config).unwrap();
+
+        fs::write(project.join("src/main.rs"), "mod a;\n\nfn main() {}\n").unwrap();
+        let config = LibGenConfig {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = update_lib(&existing, &project, config).unwrap();
+        assert_eq!(report.removed, vec![PathBuf::from("src/net/mod.rs")]);
+        assert!(out.join("src/net/mod.rs").exists());
+
+        let report = update_lib(&existing, &project, LibGenConfig::default()).unwrap();
+        assert_eq!(report.removed, vec![PathBuf::from("src/net/mod.rs")]);
+        assert!(report.written.contains(&PathBuf::from("src/lib.rs")));
+        assert!(!out.join("src/net").exists());
+        assert!(out.join("src/a.rs").exists());
+    }
+}
@@ -0,0 +1,247 @@
+//! Virtual workspace support
+//!
+//! A workspace root whose manifest only has a `[workspace]` table has no
+//! sources of its own. Lib generation runs against one of its members instead,
+//! while workspace dependencies keep resolving from the root.
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::read_manifest;
+
+/// A member crate of a workspace
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkspaceMember {
+    /// Package name (the directory name if the manifest has none)
+    pub name: String,
+    /// Member directory
+    pub path: PathBuf,
+}
+
+/// Check for a virtual manifest: `[workspace]` without `[package]`
+pub(super) fn is_virtual_manifest(manifest: &toml::Value) -> bool {
+    manifest.get("workspace").is_some() && manifest.get("package").is_none()
+}
+
+/// Members listed in `workspace.members`, with `*` globs in the last path
+/// component expanded and `workspace.exclude` applied
+pub(super) fn workspace_members(root: &Path, manifest: &toml::Value) -> Vec<WorkspaceMember> {
+    member_dirs(root, manifest, "members")
+        .into_iter()
+        .map(|path| WorkspaceMember {
+            name: member_name(&path),
+            path,
+        })
+        .collect()
+}
+
+/// Pick the member to generate the lib from
+///
+/// An explicitly requested member (by package or directory name) wins. Otherwise
+/// the candidates are `workspace.default-members` (all members if unset): a single
+/// candidate, or the single candidate with a `src/main.rs`, is chosen.
+pub(super) fn select_member(
+    root: &Path,
+    manifest: &toml::Value,
+    requested: Option<&str>,
+) -> Result<WorkspaceMember> {
+    let members = workspace_members(root, manifest);
+    let available = || {
+        members
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    if let Some(requested) = requested {
+        return members
+            .iter()
+            .find(|m| m.name == requested || m.path.file_name().is_some_and(|f| f == requested))
+            .cloned()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Workspace member '{}' not found in {}; available members: {}",
+                    requested,
+                    root.display(),
+                    available()
+                )
+            });
+    }
+
+    let default_dirs = member_dirs(root, manifest, "default-members");
+    let candidates: Vec<&WorkspaceMember> = if default_dirs.is_empty() {
+        members.iter().collect()
+    } else {
+        members
+            .iter()
+            .filter(|m| default_dirs.contains(&m.path))
+            .collect()
+    };
+
+    let binaries: Vec<&WorkspaceMember> = candidates
+        .iter()
+        .copied()
+        .filter(|m| m.path.join("src/main.rs").exists())
+        .collect();
+
+    match (candidates.as_slice(), binaries.as_slice()) {
+        ([only], _) | (_, [only]) => Ok((*only).clone()),
+        _ => anyhow::bail!(
+            "{} is a virtual workspace; set workspace_member to one of: {}",
+            root.display(),
+            available()
+        ),
+    }
+}
+
+/// Directories listed under `workspace.<key>`
+fn member_dirs(root: &Path, manifest: &toml::Value, key: &str) -> Vec<PathBuf> {
+    let workspace = manifest.get("workspace");
+    let patterns = |key: &str| -> Vec<String> {
+        workspace
+            .and_then(|w| w.get(key))
+            .and_then(|m| m.as_array())
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|v| v.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let excluded: Vec<PathBuf> = patterns("exclude").iter().map(|e| root.join(e)).collect();
+
+    let mut dirs = Vec::new();
+    for pattern in patterns(key) {
+        for dir in expand_member_pattern(root, &pattern) {
+            if !excluded.contains(&dir) && !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    dirs
+}
+
+/// Expand a member entry such as `crates/*` to the crate directories it matches
+fn expand_member_pattern(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let path = Path::new(pattern);
+    let last = path.file_name().and_then(|f| f.to_str()).unwrap_or("");
+    let Some((prefix, suffix)) = last.split_once('*') else {
+        return vec![root.join(path)];
+    };
+
+    let parent = root.join(path.parent().unwrap_or(Path::new("")));
+    let Ok(entries) = fs::read_dir(&parent) else {
+        return Vec::new();
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.join("Cargo.toml").is_file())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|f| f.to_str())
+                .is_some_and(|f| f.starts_with(prefix) && f.ends_with(suffix))
+        })
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+fn member_name(dir: &Path) -> String {
+    read_manifest(dir)
+        .and_then(|m| {
+            m.get("package")
+                .and_then(|p| p.get("name"))
+                .and_then(|n| n.as_str())
+                .map(String::from)
+        })
+        .unwrap_or_else(|| {
+            dir.file_name()
+                .map(|f| f.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Virtual workspace with two library members under `crates/`
+    fn two_member_workspace(extra: &str) -> (tempfile::TempDir, toml::Value) {
+        let temp = tempfile::TempDir::new().unwrap();
+        let manifest = format!("[workspace]\nmembers = [\"crates/*\"]\n{}", extra);
+        fs::write(temp.path().join("Cargo.toml"), &manifest).unwrap();
+        for (dir, name) in [("alpha", "alpha-core"), ("beta", "beta")] {
+            let crate_dir = temp.path().join("crates").join(dir);
+            fs::create_dir_all(crate_dir.join("src")).unwrap();
+            fs::write(
+                crate_dir.join("Cargo.toml"),
+                format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name),
+            )
+            .unwrap();
+            fs::write(crate_dir.join("src/lib.rs"), "").unwrap();
+        }
+        (temp, manifest.parse().unwrap())
+    }
+
+    #[test]
+    fn test_is_virtual_manifest() {
+        let virtual_manifest: toml::Value = "[workspace]\nmembers = []\n".parse().unwrap();
+        let root_package: toml::Value = "[package]\nname = \"a\"\n[workspace]\n".parse().unwrap();
+        assert!(is_virtual_manifest(&virtual_manifest));
+        assert!(!is_virtual_manifest(&root_package));
+    }
+
+    #[test]
+    fn test_workspace_members_expands_globs() {
+        let (temp, manifest) = two_member_workspace("");
+        let names: Vec<String> = workspace_members(temp.path(), &manifest)
+            .into_iter()
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(names, ["alpha-core", "beta"]);
+
+        let (temp, manifest) = two_member_workspace("exclude = [\"crates/beta\"]\n");
+        assert_eq!(workspace_members(temp.path(), &manifest).len(), 1);
+    }
+
+    #[test]
+    fn test_select_member_explicit() {
+        let (temp, manifest) = two_member_workspace("");
+        let by_name = select_member(temp.path(), &manifest, Some("alpha-core")).unwrap();
+        let by_dir = select_member(temp.path(), &manifest, Some("alpha")).unwrap();
+        assert_eq!(by_name, by_dir);
+        assert_eq!(by_name.path, temp.path().join("crates/alpha"));
+
+        let err = select_member(temp.path(), &manifest, Some("gamma")).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("available members: alpha-core, beta"),
+            "Got: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_select_member_default() {
+        let (temp, manifest) = two_member_workspace("default-members = [\"crates/beta\"]\n");
+        let member = select_member(temp.path(), &manifest, None).unwrap();
+        assert_eq!(member.name, "beta");
+
+        // Without default-members, two libraries are ambiguous
+        let (temp, manifest) = two_member_workspace("");
+        let err = select_member(temp.path(), &manifest, None).unwrap_err();
+        assert!(err.to_string().contains("alpha-core, beta"), "Got: {}", err);
+
+        // ... unless exactly one of them is a binary
+        fs::write(
+            temp.path().join("crates/beta/src/main.rs"),
+            "fn main() {}\n",
+        )
+        .unwrap();
+        let member = select_member(temp.path(), &manifest, None).unwrap();
+        assert_eq!(member.name, "beta");
+    }
+}
@@ -1,17 +1,23 @@
 //! rust-analyzer LSP client
 //!
 //! Communicates with rust-analyzer subprocess using JSON-RPC over stdio.
+//! A reader thread forwards every message from rust-analyzer; responses are
+//! matched to requests by id, and progress notifications are tracked so
+//! callers can wait for indexing to finish.
 
+use std::collections::HashSet;
 use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use super::types::{CompletionItem, CompletionKind};
+use super::types::CompletionItem;
 
 /// JSON-RPC request
 #[derive(Debug, Serialize)]
@@ -40,12 +46,85 @@ struct JsonRpcError {
     message: String,
 }
 
+/// Indexing state, from rust-analyzer's `$/progress` notifications
+#[derive(Debug, Default)]
+struct IndexingProgress {
+    /// Indexing progress tokens that have begun but not ended
+    active: HashSet<String>,
+    /// Some indexing progress has run to completion
+    finished: bool,
+}
+
+impl IndexingProgress {
+    /// Update from the params of a `$/progress` notification
+    fn update(&mut self, params: &Value) {
+        let token = match &params["token"] {
+            Value::String(token) => token.clone(),
+            Value::Number(token) => token.to_string(),
+            _ => return,
+        };
+        if !is_indexing_token(&token) {
+            return;
+        }
+        match params["value"]["kind"].as_str() {
+            Some("begin") => {
+                self.active.insert(token);
+            }
+            Some("end") => {
+                if self.active.remove(&token) && self.active.is_empty() {
+                    self.finished = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.finished && self.active.is_empty()
+    }
+}
+
+/// Progress tokens rust-analyzer uses while indexing, e.g. `rustAnalyzer/Indexing`
+/// and `rustAnalyzer/cachePriming`
+fn is_indexing_token(token: &str) -> bool {
+    token.contains("Indexing") || token.contains("cachePriming")
+}
+
+/// Read one `Content-Length` framed message. Returns `None` at end of stream.
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut content_length = None;
+    let mut line = String::new();
+
+    // Read headers
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("Content-Length:") {
+            content_length = Some(length.trim().parse::<usize>()?);
+        }
+    }
+
+    // Read body
+    let length = content_length.context("Message without Content-Length header")?;
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
 /// rust-analyzer client
 pub struct RustAnalyzerClient {
     project_root: PathBuf,
     process: Option<Child>,
+    /// Messages from rust-analyzer, forwarded by the reader thread
+    incoming: Option<Receiver<Value>>,
     request_id: AtomicU64,
     initialized: bool,
+    indexing: IndexingProgress,
 }
 
 impl RustAnalyzerClient {
@@ -53,8 +132,10 @@ impl RustAnalyzerClient {
         Self {
             project_root: project_root.into(),
             process: None,
+            incoming: None,
             request_id: AtomicU64::new(1),
             initialized: false,
+            indexing: IndexingProgress::default(),
         }
     }
 
@@ -68,14 +149,27 @@ impl RustAnalyzerClient {
         let ra_path = Self::find_rust_analyzer()?;
 
         // Start process
-        let child = Command::new(&ra_path)
+        let mut child = Command::new(&ra_path)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
             .spawn()
             .with_context(|| format!("Failed to start rust-analyzer at {:?}", ra_path))?;
 
+        // Forward messages until rust-analyzer closes its output
+        let stdout = child.stdout.take().context("No stdout")?;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
         self.process = Some(child);
+        self.incoming = Some(receiver);
 
         // Send initialize request
         self.send_initialize()?;
@@ -123,6 +217,9 @@ impl RustAnalyzerClient {
             "processId": std::process::id(),
             "rootUri": format!("file://{}", self.project_root.display()),
             "capabilities": {
+                "window": {
+                    "workDoneProgress": true
+                },
                 "textDocument": {
                     "completion": {
                         "completionItem": {
@@ -148,21 +245,6 @@ impl RustAnalyzerClient {
 
     /// Send a JSON-RPC request and wait for response
     fn send_request(&mut self, method: &str, params: Option<Value>) -> Result<JsonRpcResponse> {
-        let process = self
-            .process
-            .as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Process not started"))?;
-
-        let stdin = process
-            .stdin
-            .as_mut()
-            .ok_or_else(|| anyhow::anyhow!("No stdin"))?;
-
-        let stdout = process
-            .stdout
-            .as_mut()
-            .ok_or_else(|| anyhow::anyhow!("No stdout"))?;
-
         let id = self.request_id.fetch_add(1, Ordering::SeqCst);
 
         let request = JsonRpcRequest {
@@ -171,47 +253,34 @@ impl RustAnalyzerClient {
             method: method.to_string(),
             params,
         };
+        self.write_message(&request)?;
 
-        let content = serde_json::to_string(&request)?;
-        let header = format!("Content-Length: {}\r\n\r\n", content.len());
-
-        stdin.write_all(header.as_bytes())?;
-        stdin.write_all(content.as_bytes())?;
-        stdin.flush()?;
-
-        // Read response
-        let mut reader = BufReader::new(stdout);
-        let mut headers = String::new();
-        let mut content_length = 0usize;
-
-        // Read headers
+        // Handle notifications and server requests until our response arrives
         loop {
-            headers.clear();
-            reader.read_line(&mut headers)?;
-
-            if headers == "\r\n" {
-                break;
-            }
-
-            if headers.starts_with("Content-Length:") {
-                content_length = headers
-                    .trim_start_matches("Content-Length:")
-                    .trim()
-                    .parse()?;
+            let message = self
+                .recv_message(None)?
+                .context("rust-analyzer closed the connection")?;
+            let is_response = message.get("method").is_none()
+                && message.get("id").and_then(|i| i.as_u64()) == Some(id);
+            if is_response {
+                return Ok(serde_json::from_value(message)?);
             }
+            self.handle_server_message(&message)?;
         }
-
-        // Read body
-        let mut body = vec![0u8; content_length];
-        std::io::Read::read_exact(&mut reader, &mut body)?;
-
-        let response: JsonRpcResponse = serde_json::from_slice(&body)?;
-
-        Ok(response)
     }
 
     /// Send a notification (no response expected)
     fn send_notification(&mut self, method: &str, params: Option<Value>) -> Result<()> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        });
+        self.write_message(&notification)
+    }
+
+    /// Write a framed message to rust-analyzer's stdin
+    fn write_message(&mut self, message: &impl Serialize) -> Result<()> {
         let process = self
             .process
             .as_mut()
@@ -222,13 +291,7 @@ impl RustAnalyzerClient {
             .as_mut()
             .ok_or_else(|| anyhow::anyhow!("No stdin"))?;
 
-        let notification = json!({
-            "jsonrpc": "2.0",
-            "method": method,
-            "params": params
-        });
-
-        let content = serde_json::to_string(&notification)?;
+        let content = serde_json::to_string(message)?;
         let header = format!("Content-Length: {}\r\n\r\n", content.len());
 
         stdin.write_all(header.as_bytes())?;
@@ -238,6 +301,72 @@ impl RustAnalyzerClient {
         Ok(())
     }
 
+    /// Next message from rust-analyzer, or `None` if `timeout` elapses first
+    fn recv_message(&mut self, timeout: Option<Duration>) -> Result<Option<Value>> {
+        let incoming = self
+            .incoming
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Process not started"))?;
+
+        match timeout {
+            None => Ok(incoming.recv().ok()),
+            Some(timeout) => match incoming.recv_timeout(timeout) {
+                Ok(message) => Ok(Some(message)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    anyhow::bail!("rust-analyzer closed the connection")
+                }
+            },
+        }
+    }
+
+    /// Handle a notification or request sent by rust-analyzer
+    fn handle_server_message(&mut self, message: &Value) -> Result<()> {
+        let Some(method) = message.get("method").and_then(|m| m.as_str()) else {
+            // A response nobody is waiting for
+            return Ok(());
+        };
+
+        match message.get("id") {
+            // Requests such as `window/workDoneProgress/create` expect a reply
+            Some(id) => self.write_message(&json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": null
+            })),
+            None => {
+                if method == "$/progress" {
+                    self.indexing.update(&message["params"]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Block until rust-analyzer reports that indexing finished, or `timeout`
+    /// elapses. Returns whether indexing finished.
+    ///
+    /// Completions requested while indexing are often empty, so call this
+    /// after [`start`](Self::start) before the first completion.
+    pub fn wait_for_indexing(&mut self, timeout: Duration) -> Result<bool> {
+        if !self.initialized {
+            self.start()?;
+        }
+
+        let deadline = Instant::now() + timeout;
+        while !self.indexing.is_done() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            match self.recv_message(Some(remaining))? {
+                Some(message) => self.handle_server_message(&message)?,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
     /// Open a virtual document for completion analysis
     pub fn open_virtual_document(&mut self, uri: &str, content: &str) -> Result<()> {
         if !self.initialized {
@@ -265,6 +394,21 @@ impl RustAnalyzerClient {
         uri: &str,
         line: u32,
         character: u32,
+    ) -> Result<Vec<CompletionItem>> {
+        self.completions_with_resolve(uri, line, character, 0)
+    }
+
+    /// Request completions at a position, eagerly resolving documentation
+    /// for the first `resolve_top` items via `completionItem/resolve`.
+    ///
+    /// Each resolved item costs an extra round-trip, so callers should keep
+    /// `resolve_top` small. Items that fail to resolve keep their original data.
+    pub fn completions_with_resolve(
+        &mut self,
+        uri: &str,
+        line: u32,
+        character: u32,
+        resolve_top: usize,
     ) -> Result<Vec<CompletionItem>> {
         if !self.initialized {
             self.start()?;
@@ -300,26 +444,47 @@ impl RustAnalyzerClient {
             Vec::new()
         };
 
-        // Convert to our types
-        let completions = items
-            .into_iter()
-            .map(|item| CompletionItem {
-                label: item.label,
-                kind: item
-                    .kind
-                    .map(CompletionKind::from)
-                    .unwrap_or(CompletionKind::Other),
-                detail: item.detail,
-                documentation: item.documentation.map(|doc| match doc {
-                    lsp_types::Documentation::String(s) => s,
-                    lsp_types::Documentation::MarkupContent(m) => m.value,
-                }),
-            })
-            .collect();
+        // Convert to our types, resolving the top items first if requested
+        let mut completions = Vec::with_capacity(items.len());
+        for (index, item) in items.into_iter().enumerate() {
+            let item = if index < resolve_top && item.documentation.is_none() {
+                let fallback = item.clone();
+                self.resolve_completion(item).unwrap_or(fallback)
+            } else {
+                item
+            };
+            completions.push(CompletionItem::from(item));
+        }
 
         Ok(completions)
     }
 
+    /// Resolve additional details (documentation, detail) for a completion item
+    pub fn resolve_completion(
+        &mut self,
+        item: lsp_types::CompletionItem,
+    ) -> Result<lsp_types::CompletionItem> {
+        if !self.initialized {
+            self.start()?;
+        }
+
+        let response =
+            self.send_request("completionItem/resolve", Some(serde_json::to_value(&item)?))?;
+
+        if let Some(error) = response.error {
+            anyhow::bail!(
+                "Completion resolve failed: {} ({})",
+                error.message,
+                error.code
+            );
+        }
+
+        match response.result {
+            Some(result) if !result.is_null() => Ok(serde_json::from_value(result)?),
+            _ => Ok(item),
+        }
+    }
+
     pub fn project_root(&self) -> &Path {
         &self.project_root
     }
@@ -337,3 +502,57 @@ impl Drop for RustAnalyzerClient {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(token: &str, kind: &str) -> Value {
+        json!({ "token": token, "value": { "kind": kind } })
+    }
+
+    #[test]
+    fn test_read_message_frames() {
+        let first = r#"{"jsonrpc":"2.0","method":"$/progress"}"#;
+        let second = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        let stream = format!(
+            "Content-Length: {}\r\n\r\n{}Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc\r\n\r\n{}",
+            first.len(),
+            first,
+            second.len(),
+            second
+        );
+        let mut reader = std::io::Cursor::new(stream);
+
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message["method"], "$/progress");
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message["id"], 1);
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_indexing_progress() {
+        let mut indexing = IndexingProgress::default();
+        assert!(!indexing.is_done());
+
+        // Unrelated progress is ignored
+        indexing.update(&progress("rustAnalyzer/Fetching", "begin"));
+        indexing.update(&progress("rustAnalyzer/Fetching", "end"));
+        assert!(!indexing.is_done());
+
+        indexing.update(&progress("rustAnalyzer/Indexing", "begin"));
+        indexing.update(&progress("rustAnalyzer/cachePriming", "begin"));
+        indexing.update(&progress("rustAnalyzer/Indexing", "end"));
+        assert!(!indexing.is_done());
+
+        indexing.update(&json!({
+            "token": "rustAnalyzer/cachePriming",
+            "value": { "kind": "report", "percentage": 50 }
+        }));
+        assert!(!indexing.is_done());
+
+        indexing.update(&progress("rustAnalyzer/cachePriming", "end"));
+        assert!(indexing.is_done());
+    }
+}
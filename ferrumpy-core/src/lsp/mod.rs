@@ -6,4 +6,4 @@ mod client;
 pub mod types;
 
 pub use client::RustAnalyzerClient;
-pub use types::{CompletionItem, CompletionKind};
+pub use types::{fuzzy_score, CompletionItem, CompletionKind};
@@ -9,6 +9,9 @@ pub struct CompletionItem {
     pub kind: CompletionKind,
     pub detail: Option<String>,
     pub documentation: Option<String>,
+    /// How well the label matches what was typed (see [`fuzzy_score`])
+    #[serde(default)]
+    pub score: f32,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -28,6 +31,26 @@ pub enum CompletionKind {
     Other,
 }
 
+impl CompletionKind {
+    /// Name of the kind, as serialized
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompletionKind::Field => "field",
+            CompletionKind::Method => "method",
+            CompletionKind::Function => "function",
+            CompletionKind::Variable => "variable",
+            CompletionKind::Struct => "struct",
+            CompletionKind::Enum => "enum",
+            CompletionKind::Module => "module",
+            CompletionKind::Keyword => "keyword",
+            CompletionKind::Snippet => "snippet",
+            CompletionKind::Property => "property",
+            CompletionKind::Constant => "constant",
+            CompletionKind::Other => "other",
+        }
+    }
+}
+
 impl From<lsp_types::CompletionItemKind> for CompletionKind {
     fn from(kind: lsp_types::CompletionItemKind) -> Self {
         match kind {
@@ -47,6 +70,72 @@ impl From<lsp_types::CompletionItemKind> for CompletionKind {
     }
 }
 
+impl From<lsp_types::CompletionItem> for CompletionItem {
+    fn from(item: lsp_types::CompletionItem) -> Self {
+        CompletionItem {
+            label: item.label,
+            kind: item
+                .kind
+                .map(CompletionKind::from)
+                .unwrap_or(CompletionKind::Other),
+            detail: item.detail,
+            documentation: item.documentation.map(|doc| match doc {
+                lsp_types::Documentation::String(s) => s,
+                lsp_types::Documentation::MarkupContent(m) => m.value,
+            }),
+            score: 0.0,
+        }
+    }
+}
+
+/// Score how well `candidate` matches the typed `query`, higher is better
+///
+/// The query's characters must appear in order in the candidate, ignoring
+/// case. Matches at the start of the candidate or of a `_`-separated word,
+/// and runs of consecutive matches, score higher; extra characters cost a
+/// little so shorter candidates win ties. An empty query matches everything
+/// with a score of 0.
+pub fn fuzzy_score(candidate: &str, query: &str) -> Option<f32> {
+    if query.is_empty() {
+        return Some(0.0);
+    }
+    let mut score = 0.0;
+    let mut query_chars = query.chars().map(|c| c.to_ascii_lowercase()).peekable();
+    let mut prev: Option<char> = None;
+    let mut prev_matched = false;
+    let mut run = 0.0;
+
+    for (i, c) in candidate.chars().enumerate() {
+        let Some(&want) = query_chars.peek() else {
+            break;
+        };
+        if c.to_ascii_lowercase() == want {
+            query_chars.next();
+            score += 1.0;
+            if i == 0 {
+                score += 2.0;
+            } else if prev == Some('_') {
+                score += 1.0;
+            }
+            run = if prev_matched { run + 1.0 } else { 0.0 };
+            score += run;
+            prev_matched = true;
+        } else {
+            prev_matched = false;
+        }
+        prev = Some(c);
+    }
+
+    if query_chars.peek().is_some() {
+        return None;
+    }
+    let extra = candidate
+        .chars()
+        .count()
+        .saturating_sub(query.chars().count());
+    Some(score - 0.01 * extra as f32)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,5 +145,53 @@ mod tests {
         let kind = CompletionKind::Field;
         let json = serde_json::to_string(&kind).unwrap();
         assert_eq!(json, "\"field\"");
+        assert_eq!(json, format!("\"{}\"", kind.as_str()));
+    }
+
+    #[test]
+    fn test_fuzzy_score_matches_subsequences() {
+        assert_eq!(fuzzy_score("anything", ""), Some(0.0));
+        assert!(fuzzy_score("user_name", "usrnm").is_some());
+        assert!(fuzzy_score("UserName", "un").is_some());
+        assert_eq!(fuzzy_score("user", "usx"), None);
+        assert_eq!(fuzzy_score("us", "user"), None);
+        // Order matters
+        assert_eq!(fuzzy_score("name", "en"), None);
+    }
+
+    #[test]
+    fn test_fuzzy_score_ranking() {
+        let score = |candidate: &str, query: &str| fuzzy_score(candidate, query).unwrap();
+        // Prefix beats a match in the middle
+        assert!(score("count", "co") > score("discount", "co"));
+        // Contiguous beats scattered
+        assert!(score("user_id", "use") > score("u_s_e", "use"));
+        // Word starts beat arbitrary positions
+        assert!(score("max_len", "ml") > score("formula", "ml"));
+        // Shorter wins ties
+        assert!(score("len", "len") > score("length", "len"));
+    }
+
+    #[test]
+    fn test_completion_item_from_lsp() {
+        let item = lsp_types::CompletionItem {
+            label: "len".to_string(),
+            kind: Some(lsp_types::CompletionItemKind::METHOD),
+            detail: Some("fn(&self) -> usize".to_string()),
+            documentation: Some(lsp_types::Documentation::MarkupContent(
+                lsp_types::MarkupContent {
+                    kind: lsp_types::MarkupKind::PlainText,
+                    value: "Returns the length.".to_string(),
+                },
+            )),
+            ..Default::default()
+        };
+
+        let converted = CompletionItem::from(item);
+        assert_eq!(converted.kind, CompletionKind::Method);
+        assert_eq!(
+            converted.documentation.as_deref(),
+            Some("Returns the length.")
+        );
     }
 }
@@ -2,8 +2,9 @@
 //!
 //! Defines the communication protocol between Python bridge and ferrumpy-server.
 
-use crate::dwarf::VariableInfo;
-use crate::lsp::CompletionItem;
+use crate::dwarf::{dwarf_type_to_rust, VariableInfo};
+use crate::lsp::{CompletionItem, CompletionKind};
+use crate::repl::OutputStream;
 use serde::{Deserialize, Serialize};
 
 /// Frame information from LLDB
@@ -19,13 +20,59 @@ pub struct FrameInfo {
     pub locals: Vec<VariableInfo>,
 }
 
+/// A local variable as shown in a variables pane
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalSummary {
+    pub name: String,
+    /// Type with standard library paths simplified
+    pub rust_type: String,
+    /// One-line, length-limited value
+    pub value_preview: String,
+}
+
+impl From<&VariableInfo> for LocalSummary {
+    fn from(var: &VariableInfo) -> Self {
+        Self {
+            name: var.name.clone(),
+            rust_type: dwarf_type_to_rust(&var.type_name).unwrap_or_else(|_| var.rust_type.clone()),
+            value_preview: var.pretty_value(),
+        }
+    }
+}
+
+/// Outcome of evaluating an expression in one frame
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FrameEvalOutcome {
+    Value { value: String, value_type: String },
+    Error { error: String },
+}
+
+/// Companion lib crate generated from the user's project (see `libgen`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompanionLib {
+    /// Path to the generated crate
+    pub path: String,
+    /// Crate name, as used in `use <crate>::*;`
+    pub crate_name: String,
+}
+
 /// Request from Python to ferrumpy-server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "method", content = "params")]
 pub enum Request {
     /// Initialize the server for a project
     #[serde(rename = "initialize")]
-    Initialize { project_root: String },
+    Initialize {
+        project_root: String,
+        /// Companion lib that gives rust-analyzer access to the user's types
+        #[serde(default)]
+        companion: Option<CompanionLib>,
+        /// When rust-analyzer returns no completions, ask once more after
+        /// this many milliseconds (150 when absent, 0 disables the retry)
+        #[serde(default)]
+        max_completion_wait_ms: Option<u64>,
+    },
 
     /// Request completions
     #[serde(rename = "complete")]
@@ -33,6 +80,12 @@ pub enum Request {
         frame: FrameInfo,
         input: String,
         cursor: usize,
+        /// Eagerly resolve documentation for this many top items (0 = off)
+        #[serde(default)]
+        resolve_top: usize,
+        /// Only return completions of these kinds (all kinds when absent)
+        #[serde(default)]
+        kinds: Option<Vec<CompletionKind>>,
     },
 
     /// Request type information
@@ -43,10 +96,32 @@ pub enum Request {
     #[serde(rename = "eval")]
     Eval { frame: FrameInfo, expr: String },
 
+    /// Run code in a REPL holding the frame's locals, answered with a
+    /// `Response::Output` per line it prints, then the final
+    /// `Response::EvalResult` (or `Response::Error`), all with the
+    /// request's id
+    #[serde(rename = "eval_stream")]
+    EvalStream { frame: FrameInfo, expr: String },
+
+    /// Evaluate an expression in each frame of a backtrace
+    #[serde(rename = "eval_frames")]
+    EvalAcrossFrames {
+        frames: Vec<FrameInfo>,
+        expr: String,
+    },
+
     /// Request hover documentation
     #[serde(rename = "hover")]
     Hover { frame: FrameInfo, path: String },
 
+    /// List the frame's locals with simplified types and value previews
+    #[serde(rename = "locals")]
+    ListLocals { frame: FrameInfo },
+
+    /// Check that the server is alive, without side effects
+    #[serde(rename = "ping")]
+    Ping,
+
     /// Shutdown the server
     #[serde(rename = "shutdown")]
     Shutdown,
@@ -56,12 +131,59 @@ pub enum Request {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Response {
-    Completions { completions: Vec<CompletionItem> },
-    TypeInfo { type_name: String },
-    EvalResult { value: String, value_type: String },
-    Hover { content: Option<String> },
-    Success { ok: bool },
-    Error { error: String },
+    Completions {
+        completions: Vec<CompletionItem>,
+    },
+    TypeInfo {
+        type_name: String,
+    },
+    EvalResult {
+        value: String,
+        value_type: String,
+    },
+    /// A line printed by code run for `Request::EvalStream`, sent before
+    /// its result
+    Output {
+        stream: OutputStream,
+        /// The line, without its newline
+        chunk: String,
+    },
+    Hover {
+        content: Option<String>,
+    },
+    Locals {
+        locals: Vec<LocalSummary>,
+    },
+    /// Per-frame outcomes, keyed by the frame's index in the request
+    FrameEvalResults {
+        results: Vec<(usize, FrameEvalOutcome)>,
+    },
+    Success {
+        ok: bool,
+    },
+    Error {
+        error: String,
+        /// One of [`error_codes`], for clients to tell failures apart
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error_code: Option<i32>,
+    },
+}
+
+/// Codes of `Response::Error`, following JSON-RPC: the standard codes,
+/// and server-defined ones in -32000..=-32099
+pub mod error_codes {
+    /// The request isn't valid JSON
+    pub const PARSE_ERROR: i32 = -32700;
+    /// The request is JSON but not a known request
+    pub const INVALID_REQUEST: i32 = -32600;
+    /// The expression doesn't parse
+    pub const EXPRESSION_PARSE_ERROR: i32 = -32001;
+    /// The expression names a variable the frame doesn't have
+    pub const UNKNOWN_VARIABLE: i32 = -32002;
+    /// The expression uses something the evaluator doesn't support
+    pub const UNSUPPORTED_EXPRESSION: i32 = -32003;
+    /// Evaluating the expression failed (type mismatch, overflow, ...)
+    pub const EVAL_ERROR: i32 = -32004;
 }
 
 impl Response {
@@ -70,7 +192,18 @@ impl Response {
     }
 
     pub fn error(msg: impl Into<String>) -> Self {
-        Response::Error { error: msg.into() }
+        Response::Error {
+            error: msg.into(),
+            error_code: None,
+        }
+    }
+
+    /// An error with one of the [`error_codes`]
+    pub fn error_with_code(code: i32, msg: impl Into<String>) -> Self {
+        Response::Error {
+            error: msg.into(),
+            error_code: Some(code),
+        }
     }
 
     pub fn completions(items: Vec<CompletionItem>) -> Self {
@@ -119,6 +252,8 @@ mod tests {
             },
             input: "user.".to_string(),
             cursor: 5,
+            resolve_top: 0,
+            kinds: None,
         };
 
         let json = serde_json::to_string(&req).unwrap();
@@ -132,9 +267,80 @@ mod tests {
             kind: crate::lsp::CompletionKind::Field,
             detail: Some("String".to_string()),
             documentation: None,
+            score: 0.0,
         }]);
 
         let json = serde_json::to_string(&resp).unwrap();
         assert!(json.contains("\"label\":\"name\""));
     }
+
+    #[test]
+    fn test_output_round_trip() {
+        let message = RpcMessage::new(
+            7,
+            Response::Output {
+                stream: OutputStream::Stderr,
+                chunk: "warning".to_string(),
+            },
+        );
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains("\"stream\":\"stderr\""), "Got: {}", json);
+        let parsed: RpcMessage<Response> = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            parsed.content,
+            Response::Output {
+                stream: OutputStream::Stderr,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_complete_resolve_top_defaults_off() {
+        let json = r#"{"method":"complete","params":{"frame":{"function":"main","file":null,"line":null,"locals":[]},"input":"x.","cursor":2}}"#;
+        let req: Request = serde_json::from_str(json).unwrap();
+        match req {
+            Request::Complete {
+                resolve_top, kinds, ..
+            } => {
+                assert_eq!(resolve_top, 0);
+                assert!(kinds.is_none());
+            }
+            other => panic!("unexpected request: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_initialize_with_companion() {
+        let json = r#"{"method":"initialize","params":{"project_root":"/p","companion":{"path":"/tmp/lib","crate_name":"ferrumpy_snapshot"}}}"#;
+        let req: Request = serde_json::from_str(json).unwrap();
+        match req {
+            Request::Initialize { companion, .. } => {
+                assert_eq!(companion.unwrap().crate_name, "ferrumpy_snapshot");
+            }
+            other => panic!("unexpected request: {:?}", other),
+        }
+
+        let json = r#"{"method":"initialize","params":{"project_root":"/p"}}"#;
+        let req: Request = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            req,
+            Request::Initialize {
+                companion: None,
+                max_completion_wait_ms: None,
+                ..
+            }
+        ));
+
+        let json =
+            r#"{"method":"initialize","params":{"project_root":"/p","max_completion_wait_ms":0}}"#;
+        let req: Request = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            req,
+            Request::Initialize {
+                max_completion_wait_ms: Some(0),
+                ..
+            }
+        ));
+    }
 }
@@ -5,9 +5,13 @@
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
 
-use crate::expr::{parse_expr, Evaluator, Value};
+use crate::expr::{format_expr, parse_expr, parse_expr_with_notes, Evaluator, Value};
+use crate::repl::OutputStream;
 
 /// Parse and evaluate a Rust expression
+///
+/// Returns {"value", "type"}, plus "notes" on input accepted although it
+/// isn't quite Rust, e.g. a unary `+`.
 #[pyfunction]
 fn eval_expression(
     py: Python<'_>,
@@ -15,8 +19,8 @@ fn eval_expression(
     variables: &Bound<'_, PyDict>,
 ) -> PyResult<PyObject> {
     // Parse expression
-    let ast =
-        parse_expr(expr).map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+    let (ast, notes) = parse_expr_with_notes(expr)
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
 
     // Build evaluator with variables
     let mut evaluator = Evaluator::new();
@@ -49,6 +53,9 @@ fn eval_expression(
             let result = PyDict::new_bound(py);
             result.set_item("value", value.to_string())?;
             result.set_item("type", value.type_name())?;
+            if !notes.is_empty() {
+                result.set_item("notes", notes)?;
+            }
             Ok(result.into())
         }
         Err(e) => Err(pyo3::exceptions::PyRuntimeError::new_err(e.to_string())),
@@ -82,7 +89,7 @@ fn parse_value(type_name: &str, value_str: &str) -> Option<Value> {
 
 /// Parse a Rust expression and return AST as JSON
 #[pyfunction]
-fn parse_expression(expr: &str) -> PyResult<String> {
+fn parse_expression_ast(expr: &str) -> PyResult<String> {
     let ast =
         parse_expr(expr).map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
 
@@ -90,40 +97,233 @@ fn parse_expression(expr: &str) -> PyResult<String> {
         .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
 }
 
+/// Parse a Rust expression and return it reformatted as canonical source,
+/// e.g. `a+b` as `a + b`
+#[pyfunction]
+fn parse_expression(expr: &str) -> PyResult<String> {
+    let ast =
+        parse_expr(expr).map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+
+    Ok(format_expr(&ast))
+}
+
+/// Read the `config` dict of `PyReplSession`'s constructor
+fn repl_config_from_dict(dict: &Bound<'_, PyDict>) -> PyResult<crate::repl::ReplConfig> {
+    let mut config = crate::repl::ReplConfig::default();
+    for (key, value) in dict.iter() {
+        let key: String = key.extract()?;
+        match key.as_str() {
+            "opt_level" => config.opt_level = value.extract()?,
+            "offline" => config.offline = value.extract()?,
+            "toolchain" => config.toolchain = value.extract()?,
+            "linker" => config.linker = value.extract()?,
+            "cache_mb" => config.cache_mb = value.extract()?,
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Unknown REPL config key: {}",
+                    key
+                )))
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// RuntimeError for a failed evaluation
+///
+/// Compile errors are also attached as its `errors` attribute: a list of
+/// dicts with keys "level", "code", "message", "section" ({"kind", "name"}
+/// or None), "line", "source_lines" and "label".
+fn eval_err(py: Python<'_>, e: anyhow::Error) -> PyErr {
+    let err = pyo3::exceptions::PyRuntimeError::new_err(e.to_string());
+    if let Some(errors) = e.downcast_ref::<crate::repl::CompileErrors>() {
+        let attach = || -> PyResult<()> {
+            let value = serde_json::to_string(&errors.0)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+            let list = py.import_bound("json")?.call_method1("loads", (value,))?;
+            err.value_bound(py).setattr("errors", list)
+        };
+        if let Err(attach_err) = attach() {
+            return attach_err;
+        }
+    }
+    err
+}
+
 /// Python wrapper for ReplSession
 #[pyclass]
 struct PyReplSession {
     inner: Option<crate::repl::ReplSession>,
+    /// Evaluation started by `eval_async`, which holds the session meanwhile
+    pending: Option<crate::repl::EvalHandle>,
 }
 
 #[pymethods]
 impl PyReplSession {
     /// Create a new REPL session
+    ///
+    /// `max_array_elements` limits how many elements of each snapshot array
+    /// are loaded; longer arrays are truncated. `default_prelude=False` skips
+    /// the built-in `HashMap`/`Arc`/`Rc`/serde imports. `fast_path=True`
+    /// answers simple expressions like `1 + 1` without compiling them, see
+    /// `ReplSession::with_fast_path`. `config` sets the
+    /// compiler: a dict with any of the keys "opt_level" (int), "offline"
+    /// (bool), "toolchain", "linker" (str) and "cache_mb" (int). `cwd`,
+    /// `env` (a dict of str) and `args` (a list of str) set the working
+    /// directory, extra environment variables and `std::env::args()` of the
+    /// process running the evaluated code.
     #[new]
-    fn new() -> PyResult<Self> {
+    #[pyo3(signature = (max_array_elements=None, default_prelude=true, config=None, cwd=None, env=None, args=None, fast_path=false))]
+    fn new(
+        max_array_elements: Option<usize>,
+        default_prelude: bool,
+        config: Option<&Bound<'_, PyDict>>,
+        cwd: Option<std::path::PathBuf>,
+        env: Option<std::collections::HashMap<String, String>>,
+        args: Option<Vec<String>>,
+        fast_path: bool,
+    ) -> PyResult<Self> {
+        let config = match config {
+            Some(dict) => repl_config_from_dict(dict)?,
+            None => crate::repl::ReplConfig::default(),
+        };
+        let options = crate::repl::ReplWorkerOptions {
+            cwd,
+            env: env.unwrap_or_default(),
+            args: args.unwrap_or_default(),
+        };
         // Note: evcxr requires runtime_hook() to be called first
         // We'll try to create the session and handle errors gracefully
-        match crate::repl::ReplSession::new() {
-            Ok(session) => Ok(Self {
-                inner: Some(session),
-            }),
+        match crate::repl::ReplSession::with_config_and_options(config, options) {
+            Ok(session) => {
+                let mut session = session
+                    .with_default_prelude(default_prelude)
+                    .with_fast_path(fast_path);
+                if let Some(max) = max_array_elements {
+                    session.set_max_array_elements(max);
+                }
+                Ok(Self {
+                    inner: Some(session),
+                    pending: None,
+                })
+            }
             Err(e) => Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
-                "Failed to create REPL session: {}",
+                "Failed to create REPL session: {:#}",
                 e
             ))),
         }
     }
 
     /// Evaluate a Rust expression
-    fn eval(&mut self, code: &str) -> PyResult<String> {
+    ///
+    /// Returns a dict with keys "value" (None if the code didn't end with an
+    /// expression), "stdout" and "stderr" (lists of lines printed by this
+    /// evaluation), "opt_level" (the optimization level it was compiled at)
+    /// and "text" (the value followed by the stdout lines).
+    /// Compile errors raise a RuntimeError with an `errors` attribute.
+    fn eval(&mut self, py: Python<'_>, code: &str) -> PyResult<PyObject> {
         let session = self
             .inner
             .as_mut()
             .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
 
-        session
-            .eval(code)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+        let output = py
+            .allow_threads(|| session.eval_output(code))
+            .map_err(|e| eval_err(py, e))?;
+        let mut value = serde_json::to_value(&output)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        value["text"] = serde_json::Value::String(output.to_string());
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
+    }
+
+    /// Evaluate Rust code, raising TimeoutError after `timeout_secs`
+    ///
+    /// On timeout the session is restarted, and its snapshot restored at the
+    /// next evaluation.
+    fn eval_with_timeout(
+        &mut self,
+        py: Python<'_>,
+        code: &str,
+        timeout_secs: f64,
+    ) -> PyResult<String> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+        let timeout = std::time::Duration::try_from_secs_f64(timeout_secs)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+
+        py.allow_threads(|| session.eval_with_timeout(code, timeout))
+            .map_err(|e| {
+                if e.downcast_ref::<crate::repl::EvalTimedOut>().is_some() {
+                    pyo3::exceptions::PyTimeoutError::new_err(e.to_string())
+                } else {
+                    pyo3::exceptions::PyRuntimeError::new_err(e.to_string())
+                }
+            })
+    }
+
+    /// Start evaluating Rust code in the background
+    ///
+    /// Drive it with `poll_eval()`, `wait_eval()` and `cancel_eval()`; other
+    /// methods fail until `wait_eval()` returns.
+    fn eval_async(&mut self, code: &str) -> PyResult<()> {
+        if self.pending.is_some() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "An evaluation is already in progress",
+            ));
+        }
+        let session = self
+            .inner
+            .take()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+        self.pending = Some(session.eval_async(code));
+        Ok(())
+    }
+
+    /// Whether the evaluation started by `eval_async` has finished
+    fn poll_eval(&self) -> PyResult<bool> {
+        self.pending
+            .as_ref()
+            .map(|handle| handle.poll())
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("No evaluation in progress"))
+    }
+
+    /// Kill the evaluation started by `eval_async`; `wait_eval()` then
+    /// raises after restoring the snapshot
+    fn cancel_eval(&self) -> PyResult<()> {
+        let handle = self.pending.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("No evaluation in progress")
+        })?;
+        handle.cancel();
+        Ok(())
+    }
+
+    /// Wait for the evaluation started by `eval_async` and return its result
+    fn wait_eval(&mut self, py: Python<'_>) -> PyResult<String> {
+        let handle = self.pending.take().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("No evaluation in progress")
+        })?;
+        let (session, result) = py.allow_threads(|| handle.wait());
+        self.inner = Some(session);
+        result.map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    }
+
+    /// Evaluate an expression and return its value as a JSON-compatible object
+    ///
+    /// Values whose type isn't `Serialize` come back as {"__display__": "<text>"}.
+    fn eval_json(&mut self, py: Python<'_>, expr: &str) -> PyResult<PyObject> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        let value = session
+            .eval_json(expr)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
     }
 
     /// Add a crate dependency
@@ -138,18 +338,275 @@ impl PyReplSession {
             .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
     }
 
+    /// Add a `use` statement run before the next evaluation
+    fn add_prelude(&mut self, stmt: &str) -> PyResult<()> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        session
+            .add_prelude(stmt)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+    }
+
     /// Load variables from JSON snapshot
-    fn load_snapshot(&mut self, json_data: &str, type_hints: &str) -> PyResult<String> {
+    ///
+    /// `type_hints` is a JSON object mapping variable names to Rust types,
+    /// preferred over the snapshot's own `types`; each loaded variable's
+    /// "type_source" tells which was used.
+    ///
+    /// `max_collection_len` and `max_string_len` (bytes) override the
+    /// snapshot policy for this and later loads; values over them are
+    /// truncated, or left out when `skip_truncated` is set. `as_struct`
+    /// also bundles the variables in `ctx: &__FerrumpyCtx`.
+    ///
+    /// Returns the load report as a dict with keys "loaded", "skipped",
+    /// "fallback_to_json", "adjusted", "truncated", "eval_output",
+    /// "as_struct" and a one-line "summary".
+    #[pyo3(signature = (json_data, type_hints, max_collection_len=None, max_string_len=None, skip_truncated=None, as_struct=None))]
+    fn load_snapshot(
+        &mut self,
+        py: Python<'_>,
+        json_data: &str,
+        type_hints: &str,
+        max_collection_len: Option<usize>,
+        max_string_len: Option<usize>,
+        skip_truncated: Option<bool>,
+        as_struct: Option<bool>,
+    ) -> PyResult<PyObject> {
         let session = self
             .inner
             .as_mut()
             .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
 
+        let mut policy = session.snapshot_policy().clone();
+        if let Some(max) = max_collection_len {
+            policy.max_collection_len = max;
+        }
+        if let Some(max) = max_string_len {
+            policy.max_string_len = max;
+        }
+        if let Some(skip) = skip_truncated {
+            policy.on_truncate = if skip {
+                crate::repl::OnTruncate::Skip
+            } else {
+                crate::repl::OnTruncate::Placeholder
+            };
+        }
+        if let Some(as_struct) = as_struct {
+            policy.as_struct = as_struct;
+        }
+        let report = session
+            .load_snapshot_with_policy(json_data, type_hints, policy)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let mut value = serde_json::to_value(&report)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        value["summary"] = serde_json::Value::String(report.to_string());
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
+    }
+
+    /// Load variables from a binary snapshot
+    ///
+    /// `payload_path` holds the bincode encoding of each variable, and the
+    /// manifest's "variables" map names to {"type", "offset", "len"} in it.
+    /// Returns the load report like `load_snapshot`.
+    fn load_snapshot_binary(
+        &mut self,
+        py: Python<'_>,
+        payload_path: std::path::PathBuf,
+        manifest_json: &str,
+    ) -> PyResult<PyObject> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        let report = session
+            .load_snapshot_binary(&payload_path, manifest_json)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let mut value = serde_json::to_value(&report)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        value["summary"] = serde_json::Value::String(report.to_string());
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
+    }
+
+    /// Replace a snapshot variable loaded truncated by its full value
+    ///
+    /// `full_json` is the variable's value in snapshot form.
+    fn hydrate_variable(&mut self, name: &str, full_json: &str) -> PyResult<String> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        session
+            .hydrate_variable(name, full_json)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    }
+
+    /// Reload a snapshot, redeclaring only new and changed variables
+    ///
+    /// Variables are compared with the values they were last restored from:
+    /// only added and changed ones are compiled, and changed ones shadow
+    /// their previous binding. Returns a dict with keys "added", "changed",
+    /// "unchanged", "removed" (no longer in the snapshot), "report" (the
+    /// load report of the redeclared variables) and a one-line "summary".
+    fn update_snapshot(
+        &mut self,
+        py: Python<'_>,
+        json_data: &str,
+        type_hints: &str,
+    ) -> PyResult<PyObject> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        let update = session
+            .update_snapshot(json_data, type_hints)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let mut value = serde_json::to_value(&update)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        value["summary"] = serde_json::Value::String(update.to_string());
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
+    }
+
+    /// Restart the subprocess, e.g. when it is wedged
+    ///
+    /// With `preserve_state`, dependencies, the snapshot and the code that
+    /// defined variables are replayed. Returns a dict with keys "deps",
+    /// "snapshot" (load report or None), "replayed", "failed" (list of
+    /// [code, error]) and a one-line "summary".
+    #[pyo3(signature = (preserve_state=true))]
+    fn restart(&mut self, py: Python<'_>, preserve_state: bool) -> PyResult<PyObject> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        let report = session
+            .restart(preserve_state)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let mut value = serde_json::to_value(&report)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        value["summary"] = serde_json::Value::String(report.to_string());
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
+    }
+
+    /// Code evaluated in this session, oldest first
+    ///
+    /// Returns:
+    ///     List of dicts with keys: "code", "timestamp" (seconds since the
+    ///     epoch), "success" and "summary" (first line of output or error)
+    fn history(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let list = pyo3::types::PyList::empty_bound(py);
+        let Some(session) = self.inner.as_ref() else {
+            return Ok(list.into());
+        };
+        for entry in session.history() {
+            let timestamp = entry
+                .timestamp
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default();
+            let dict = PyDict::new_bound(py);
+            dict.set_item("code", &entry.code)?;
+            dict.set_item("timestamp", timestamp)?;
+            dict.set_item("success", entry.success)?;
+            dict.set_item("summary", &entry.summary)?;
+            list.append(dict)?;
+        }
+        Ok(list.into())
+    }
+
+    /// Evaluate history entries `start..end` again (to the end if `end` is
+    /// None), returning the text of each evaluation
+    #[pyo3(signature = (start, end=None))]
+    fn replay(
+        &mut self,
+        py: Python<'_>,
+        start: usize,
+        end: Option<usize>,
+    ) -> PyResult<Vec<String>> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        let end = end.unwrap_or(session.history().len());
+        let outputs = py
+            .allow_threads(|| session.replay(start..end))
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("{:#}", e)))?;
+        Ok(outputs.iter().map(|output| output.to_string()).collect())
+    }
+
+    /// Write the history to `path` as a Rust program running it in `main`
+    #[pyo3(signature = (path, only_successful=false))]
+    fn export_history(&self, path: &str, only_successful: bool) -> PyResult<()> {
+        let session = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
         session
-            .load_snapshot(json_data, type_hints)
+            .export_history(std::path::Path::new(path), only_successful)
             .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
     }
 
+    /// Set the optimization level (0 to 3) of later evaluations
+    ///
+    /// 0, the default, compiles fastest, e.g. for snapshot loads; 2 or 3
+    /// run hot loops faster.
+    fn set_opt_level(&mut self, level: u8) -> PyResult<()> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        session
+            .set_opt_level(level)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    /// Optimization level of later evaluations, None for evcxr's default
+    fn opt_level(&self) -> Option<u8> {
+        self.inner.as_ref().and_then(|s| s.opt_level())
+    }
+
+    /// Build the snapshot dependencies in the background
+    ///
+    /// Call it early, e.g. when the debugger attaches, so the first
+    /// `load_snapshot` or `eval` doesn't pay for the build; they wait for
+    /// the warmup instead. `project_lib` is the path of the snapshot crate.
+    #[pyo3(signature = (project_lib=None))]
+    fn warmup(&mut self, project_lib: Option<std::path::PathBuf>) -> PyResult<()> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        session.warmup(project_lib.as_deref());
+        Ok(())
+    }
+
+    /// Status of the last warmup: a dict with key "status" ("pending",
+    /// "ready" or "failed") and, when it failed, "error"; None if there was
+    /// no warmup
+    fn warmup_status(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let Some(status) = self.inner.as_ref().and_then(|s| s.warmup_status()) else {
+            return Ok(py.None());
+        };
+        let value = serde_json::to_string(&status)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (value,))?.unbind())
+    }
+
     /// Check if session is initialized
     fn is_initialized(&self) -> bool {
         self.inner
@@ -158,10 +615,51 @@ impl PyReplSession {
             .unwrap_or(false)
     }
 
+    /// Serialize the named variables back into a snapshot dict
+    ///
+    /// Returns:
+    ///     Dict with keys: "variables", "types" and "skipped" (list of
+    ///     {"name", "reason"} for undefined or non-Serialize variables)
+    fn export_snapshot(&mut self, py: Python<'_>, names: Vec<String>) -> PyResult<PyObject> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        let exported = session
+            .export_snapshot(&names)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (exported,))?.unbind())
+    }
+
+    /// List the session's variables, sorted by name
+    ///
+    /// Returns:
+    ///     List of dicts with keys: "name", "type", "origin" ("snapshot" or
+    ///     "user") and "truncated" (loaded cut to the snapshot policy)
+    fn list_variables(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let list = pyo3::types::PyList::empty_bound(py);
+        let Some(session) = self.inner.as_ref() else {
+            return Ok(list.into());
+        };
+        let mut vars: Vec<_> = session.variables().iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, var) in vars {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("name", name)?;
+            dict.set_item("type", &var.declared_type)?;
+            dict.set_item("origin", var.origin.as_str())?;
+            dict.set_item("truncated", var.truncated)?;
+            list.append(dict)?;
+        }
+        Ok(list.into())
+    }
+
     /// Get any stderr output
-    fn get_stderr(&self) -> Vec<String> {
+    fn get_stderr(&mut self) -> Vec<String> {
         self.inner
-            .as_ref()
+            .as_mut()
             .map(|s| s.get_stderr())
             .unwrap_or_default()
     }
@@ -197,7 +695,8 @@ impl PyReplSession {
     ///     position: Cursor position (byte offset) in the source
     ///
     /// Returns:
-    ///     Dict with keys: "completions" (list of strings), "start_offset", "end_offset"
+    ///     Dict with keys: "completions" (list of dicts with "code", "label",
+    ///     "kind" and "detail", best match first), "start_offset", "end_offset"
     fn completions(&mut self, py: Python<'_>, src: &str, position: usize) -> PyResult<PyObject> {
         let session = self
             .inner
@@ -212,24 +711,7 @@ impl PyReplSession {
                     let dict = PyDict::new_bound(py);
                     dict.set_item("code", c.code)?;
                     dict.set_item("label", c.label)?;
-
-                    // Normalize kind: strip "SymbolKind(...)" wrapper to extract semantic name
-                    // Example: "SymbolKind(Local)" -> "Local", "Field" -> "Field"
-                    let normalized_kind = c
-                        .kind
-                        .strip_prefix("SymbolKind(")
-                        .and_then(|s| s.strip_suffix(')'))
-                        .map(|inner| {
-                            // Map common rust-analyzer kinds to user-friendly names
-                            match inner {
-                                "Local" => "Variable",
-                                "Const" => "Constant",
-                                other => other,
-                            }
-                        })
-                        .unwrap_or(c.kind.as_str());
-
-                    dict.set_item("kind", normalized_kind)?;
+                    dict.set_item("kind", c.kind.as_str())?;
                     dict.set_item("detail", c.detail)?;
                     list.append(dict)?;
                 }
@@ -268,10 +750,35 @@ impl PyReplSession {
             .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
     }
 
-    /// Drain all pending stdout lines from the subprocess
+    /// Handle whose `interrupt()` stops an evaluation in flight from another
+    /// thread; the evaluation then raises RuntimeError("Evaluation
+    /// interrupted") once the snapshot is restored
+    fn interrupt_handle(&self) -> PyResult<PyInterruptHandle> {
+        let session = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        Ok(PyInterruptHandle {
+            inner: session.interrupt_handle(),
+        })
+    }
+
+    /// Whether the session was interrupted since the last evaluation
+    /// started, losing the variables it had bound
+    fn was_interrupted(&self) -> PyResult<bool> {
+        let session = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        Ok(session.was_interrupted())
+    }
+
+    /// Take the stdout lines no evaluation returned
     ///
-    /// Returns a list of output lines. Call this periodically to prevent
-    /// the subprocess from blocking on stdout writes.
+    /// Output is buffered up to the output cap, past which the oldest lines
+    /// are dropped.
     fn drain_stdout(&mut self) -> PyResult<Vec<String>> {
         let session = self
             .inner
@@ -281,10 +788,7 @@ impl PyReplSession {
         Ok(session.drain_stdout())
     }
 
-    /// Drain all pending stderr lines from the subprocess
-    ///
-    /// Returns a list of error lines. Call this periodically to prevent
-    /// the subprocess from blocking on stderr writes.
+    /// Take the stderr lines no evaluation returned
     fn drain_stderr(&mut self) -> PyResult<Vec<String>> {
         let session = self
             .inner
@@ -293,6 +797,57 @@ impl PyReplSession {
 
         Ok(session.drain_stderr())
     }
+
+    /// Keep at most `max_bytes` of each stream's unread output
+    fn set_output_cap(&mut self, max_bytes: usize) -> PyResult<()> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        session.set_output_cap(max_bytes);
+        Ok(())
+    }
+
+    /// Lines dropped to stay under the output cap, as a dict with keys
+    /// "stdout" and "stderr"
+    fn dropped_lines(&self) -> PyResult<std::collections::HashMap<&'static str, u64>> {
+        let session = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        Ok([OutputStream::Stdout, OutputStream::Stderr]
+            .into_iter()
+            .map(|stream| (stream.as_str(), session.dropped_lines(stream)))
+            .collect())
+    }
+
+    /// Call `callback(stream, line)` with each output line as it arrives,
+    /// `stream` being "stdout" or "stderr"; None stops streaming
+    ///
+    /// The callback runs on a background thread. Lines are still returned
+    /// by `eval` and `drain_stdout`/`drain_stderr`.
+    #[pyo3(signature = (callback=None))]
+    fn set_output_callback(&mut self, py: Python<'_>, callback: Option<PyObject>) -> PyResult<()> {
+        let session = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Session not initialized"))?;
+
+        // The callback threads take the GIL while holding the callback
+        py.allow_threads(|| match callback {
+            Some(callback) => session.set_output_callback(move |stream, line| {
+                Python::with_gil(|py| {
+                    if let Err(e) = callback.call1(py, (stream.as_str(), line)) {
+                        e.print(py);
+                    }
+                })
+            }),
+            None => session.clear_output_callback(),
+        });
+        Ok(())
+    }
 }
 
 /// Generate a companion lib crate from a user's project
@@ -300,23 +855,182 @@ impl PyReplSession {
 /// Args:
 ///     project_path: Path to the user's Rust project (containing Cargo.toml)
 ///     output_dir: Optional output directory (None = use temp dir)
+///     features: Cargo features the debugged binary was built with
+///     no_default_features: Whether it was built with --no-default-features
+///     workspace_member: Member to use when project_path is a virtual workspace root
+///     dry_run: Only compute the generated files, without writing anything
+///     include_dev_deps: Also depend on the project's dev-dependencies
+///     extra_modules: Extra module files (relative to project_path) to add to the lib
 ///
 /// Returns:
-///     Tuple of (lib_path, crate_name)
+///     Tuple of (lib_path, crate_name, skipped_deps), where skipped_deps lists
+///     {"name", "reason"} for dependencies left out of the lib (their types
+///     can't be restored), or with dry_run a dict mapping each file path
+///     (relative to lib_path) to its contents
 #[pyfunction]
-#[pyo3(signature = (project_path, output_dir=None))]
-fn generate_lib(project_path: &str, output_dir: Option<&str>) -> PyResult<(String, String)> {
+#[pyo3(signature = (project_path, output_dir=None, features=None, no_default_features=false, workspace_member=None, dry_run=false, include_dev_deps=false, extra_modules=None))]
+fn generate_lib(
+    py: Python<'_>,
+    project_path: &str,
+    output_dir: Option<&str>,
+    features: Option<Vec<String>>,
+    no_default_features: bool,
+    workspace_member: Option<String>,
+    dry_run: bool,
+    include_dev_deps: bool,
+    extra_modules: Option<Vec<String>>,
+) -> PyResult<PyObject> {
     use crate::libgen::{generate_lib as rust_generate_lib, LibGenConfig};
 
     let config = LibGenConfig {
         add_serde_derives: true,
         output_dir: output_dir.map(std::path::PathBuf::from),
+        features: features.unwrap_or_default(),
+        no_default_features,
+        workspace_member,
+        dry_run,
+        include_dev_deps,
+        extra_modules: extra_modules
+            .unwrap_or_default()
+            .into_iter()
+            .map(std::path::PathBuf::from)
+            .collect(),
+        ..Default::default()
     };
 
     let result = rust_generate_lib(std::path::Path::new(project_path), config)
         .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
 
-    Ok((result.path.to_string_lossy().to_string(), result.crate_name))
+    if dry_run {
+        let files = PyDict::new_bound(py);
+        for (path, content) in &result.files {
+            files.set_item(path.to_string_lossy(), content)?;
+        }
+        return Ok(files.into());
+    }
+
+    let skipped = pyo3::types::PyList::empty_bound(py);
+    for (name, reason) in &result.resolution.skipped {
+        let dict = PyDict::new_bound(py);
+        dict.set_item("name", name)?;
+        dict.set_item("reason", reason)?;
+        skipped.append(dict)?;
+    }
+    Ok((
+        result.path.to_string_lossy().to_string(),
+        result.crate_name,
+        skipped,
+    )
+        .into_py(py))
+}
+
+/// Interrupts a `PyReplSession`'s evaluations, see its `interrupt_handle()`
+#[pyclass(frozen)]
+struct PyInterruptHandle {
+    inner: crate::repl::InterruptHandle,
+}
+
+#[pymethods]
+impl PyInterruptHandle {
+    fn interrupt(&self) {
+        self.inner.interrupt();
+    }
+}
+
+/// Python counterpart of `SessionManager`: named sessions, each a
+/// `PyReplSession` of its own
+///
+/// The Python objects are handed out, so sessions stay usable with the full
+/// `PyReplSession` API; closing one stops its worker, after which the
+/// object raises "Session not initialized".
+#[pyclass]
+#[derive(Default)]
+struct PySessionManager {
+    sessions: std::collections::HashMap<String, Py<PyReplSession>>,
+}
+
+#[pymethods]
+impl PySessionManager {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a session named `name`, with `config` as in `PyReplSession`
+    ///
+    /// Raises ValueError if a session of that name is open.
+    #[pyo3(signature = (name, config=None))]
+    fn create(
+        &mut self,
+        py: Python<'_>,
+        name: &str,
+        config: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyReplSession>> {
+        if self.sessions.contains_key(name) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Session `{}` already exists",
+                name
+            )));
+        }
+        let session = PyReplSession::new(None, true, config, None, None, None, false)?;
+        let session = Py::new(py, session)?;
+        self.sessions
+            .insert(name.to_string(), session.clone_ref(py));
+        Ok(session)
+    }
+
+    /// The session named `name`, or None
+    fn get(&self, py: Python<'_>, name: &str) -> Option<Py<PyReplSession>> {
+        self.sessions.get(name).map(|session| session.clone_ref(py))
+    }
+
+    /// Names of the open sessions, sorted
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.sessions.keys().cloned().collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Close the session named `name`; False if there was none
+    ///
+    /// Raises RuntimeError if the session is in use, e.g. evaluating on
+    /// another thread, leaving it open.
+    fn close(&mut self, py: Python<'_>, name: &str) -> PyResult<bool> {
+        let Some(session) = self.sessions.get(name) else {
+            return Ok(false);
+        };
+        close_session(py, session)?;
+        self.sessions.remove(name);
+        Ok(true)
+    }
+
+    /// Close every session that isn't in use, raising RuntimeError if one
+    /// is, which stays open
+    fn close_all(&mut self, py: Python<'_>) -> PyResult<()> {
+        let mut busy = None;
+        self.sessions
+            .retain(|_, session| match close_session(py, session) {
+                Ok(()) => false,
+                Err(e) => {
+                    busy.get_or_insert(e);
+                    true
+                }
+            });
+        busy.map_or(Ok(()), Err)
+    }
+}
+
+/// Drop a session's `ReplSession`, stopping its worker
+///
+/// Fails if the session is borrowed, e.g. by a method running on another
+/// thread, rather than panicking.
+fn close_session(py: Python<'_>, session: &Py<PyReplSession>) -> PyResult<()> {
+    let mut session = session
+        .try_borrow_mut(py)
+        .map_err(|_| pyo3::exceptions::PyRuntimeError::new_err("session is busy"))?;
+    session.pending = None;
+    session.inner = None;
+    Ok(())
 }
 
 /// FerrumPy Python module
@@ -324,7 +1038,10 @@ fn generate_lib(project_path: &str, output_dir: Option<&str>) -> PyResult<(Strin
 fn ferrumpy_core(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(eval_expression, m)?)?;
     m.add_function(wrap_pyfunction!(parse_expression, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_expression_ast, m)?)?;
     m.add_function(wrap_pyfunction!(generate_lib, m)?)?;
     m.add_class::<PyReplSession>()?;
+    m.add_class::<PySessionManager>()?;
+    m.add_class::<PyInterruptHandle>()?;
     Ok(())
 }
@@ -0,0 +1,3169 @@
+//! Snapshot code generation
+//!
+//! Turns the variables of a JSON snapshot into Rust items that restore them
+//! inside the REPL (see `ReplSession::load_snapshot`).
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+use super::diagnostics::{CodeSection, SourceMap};
+use super::report::{RestoreStrategy, TypeSource};
+use crate::dwarf::{split_generic, split_top_level_commas};
+
+/// Generates the `ferrumpy_vars` module from snapshot variables
+pub(super) struct SnapshotCodegen;
+
+/// A variable of a binary snapshot: its bincode encoding is at
+/// `offset..offset + len` of the payload file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct BinaryVar {
+    pub(super) name: String,
+    pub(super) type_hint: String,
+    pub(super) offset: u64,
+    pub(super) len: u64,
+}
+
+impl SnapshotCodegen {
+    /// Extract variables from snapshot JSON for item-level module generation
+    ///
+    /// Variables restored with a different type than they were declared with
+    /// (`&str` and tuples holding it, values whose JSON doesn't fit their type) are
+    /// recorded in `adjusted` as `(name, declared type, restored type)`.
+    ///
+    /// Variables come sorted by name, so the same snapshot always generates
+    /// the same code and evcxr can reuse what it compiled.
+    pub(super) fn extract_variables(
+        &self,
+        snapshot: &serde_json::Value,
+        adjusted: &mut Vec<(String, String, String)>,
+    ) -> Result<Vec<(String, serde_json::Value, String)>> {
+        let mut vars = Vec::new();
+
+        let variables = snapshot
+            .get("variables")
+            .and_then(|v| v.as_object())
+            .ok_or_else(|| anyhow::anyhow!("No variables in snapshot"))?;
+
+        let types_map = snapshot.get("types").and_then(|v| v.as_object());
+
+        let mut variables: Vec<_> = variables.iter().collect();
+        variables.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, value) in variables {
+            let declared: &str = types_map
+                .and_then(|m| m.get(name))
+                .and_then(|v| v.as_str())
+                .unwrap_or("serde_json::Value");
+
+            // Borrowed strings can't be restored, so they become `String`
+            let owned_type = owned_str_type(declared);
+            let type_hint = owned_type.as_deref().unwrap_or(declared);
+
+            let actual_type = if self.is_supported_type(type_hint) {
+                self.normalize_rust_type(type_hint)
+            } else {
+                "serde_json::Value".to_string()
+            };
+
+            if !self.is_valid_for_deserialization(value, &actual_type) {
+                // Keep the raw JSON rather than panic when first accessed
+                let fallback = "serde_json::Value".to_string();
+                if actual_type != fallback {
+                    adjusted.push((name.clone(), declared.to_string(), fallback.clone()));
+                }
+                vars.push((name.clone(), value.clone(), fallback));
+            } else {
+                if let Some(owned_type) = &owned_type {
+                    adjusted.push((name.clone(), declared.to_string(), owned_type.clone()));
+                }
+                vars.push((name.clone(), value.clone(), actual_type));
+            }
+        }
+
+        Ok(vars)
+    }
+
+    /// Type to try at runtime for a variable `extract_variables` fell back to
+    /// `serde_json::Value` for, given its declared type
+    ///
+    /// Some values only fail checks made ahead of time, e.g. an empty string
+    /// or a field named like a serializer marker, and may still deserialize
+    /// as their type. `None` when the type isn't supported, or the value is
+    /// known not to fit it: data the serializer lost, an integer out of
+    /// range, a null float.
+    pub(super) fn typed_fallback_type(
+        &self,
+        value: &serde_json::Value,
+        declared: &str,
+    ) -> Option<String> {
+        let owned_type = owned_str_type(declared);
+        let type_hint = owned_type.as_deref().unwrap_or(declared);
+        if !self.is_supported_type(type_hint) || type_hint.contains('&') {
+            return None;
+        }
+        let ty = self.normalize_rust_type(type_hint);
+        let fits = ty != "serde_json::Value"
+            && !has_lost_data(value)
+            && !has_null_float(value, &ty)
+            && integers_fit(value, &ty)
+            && !has_malformed_enum(value)
+            && (ty != "char" || char_literal(value).is_some())
+            && (os_string_type(&ty).is_none() || value.is_string());
+        fits.then_some(ty)
+    }
+
+    /// Merge `type_hints`, a JSON object mapping variable names to Rust
+    /// types, into the snapshot's `types`, returning where each variable's
+    /// type came from
+    ///
+    /// A variable's hint wins over its snapshot type unless only the latter
+    /// is supported; a variable with neither gets a type inferred from its
+    /// value. An empty `type_hints` means no hints.
+    pub(super) fn merge_type_hints(
+        &self,
+        snapshot: &mut serde_json::Value,
+        type_hints: &str,
+    ) -> Result<HashMap<String, TypeSource>> {
+        let hints = parse_type_hints(type_hints)?;
+        let Some(variables) = snapshot.get("variables").and_then(|v| v.as_object()) else {
+            return Ok(HashMap::new());
+        };
+        let snapshot_types = snapshot.get("types").and_then(|v| v.as_object());
+
+        let mut types = serde_json::Map::new();
+        let mut sources = HashMap::new();
+        for (name, value) in variables {
+            let snapshot_type = snapshot_types
+                .and_then(|m| m.get(name))
+                .and_then(|v| v.as_str());
+            let declared: Vec<(&str, TypeSource)> = [
+                (hints.get(name).map(String::as_str), TypeSource::Hint),
+                (snapshot_type, TypeSource::Snapshot),
+            ]
+            .into_iter()
+            .filter_map(|(ty, source)| Some((ty?, source)))
+            .collect();
+            let supported = declared.iter().find(|(ty, _)| {
+                let owned = owned_str_type(ty);
+                self.is_supported_type(owned.as_deref().unwrap_or(ty))
+            });
+            let (ty, source) = match supported.or(declared.first()) {
+                Some((ty, source)) => (ty.to_string(), *source),
+                None => (infer_type(value), TypeSource::Inferred),
+            };
+            types.insert(name.clone(), serde_json::Value::String(ty));
+            sources.insert(name.clone(), source);
+        }
+        snapshot["types"] = serde_json::Value::Object(types);
+        Ok(sources)
+    }
+
+    /// Extract the variables of a binary snapshot manifest
+    ///
+    /// The manifest's `variables` maps names to `{"type", "offset", "len"}`.
+    pub(super) fn extract_binary_variables(
+        &self,
+        manifest: &serde_json::Value,
+    ) -> Result<Vec<BinaryVar>> {
+        let variables = manifest
+            .get("variables")
+            .and_then(|v| v.as_object())
+            .ok_or_else(|| anyhow::anyhow!("No variables in snapshot manifest"))?;
+
+        variables
+            .iter()
+            .map(|(name, entry)| {
+                let field = |key: &str| {
+                    entry.get(key).ok_or_else(|| {
+                        anyhow::anyhow!("Variable {} has no {} in manifest", name, key)
+                    })
+                };
+                let type_hint = field("type")?
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Type of variable {} is not a string", name))?;
+                let offset = field("offset")?
+                    .as_u64()
+                    .ok_or_else(|| anyhow::anyhow!("Offset of variable {} is not a u64", name))?;
+                let len = field("len")?
+                    .as_u64()
+                    .ok_or_else(|| anyhow::anyhow!("Length of variable {} is not a u64", name))?;
+                Ok(BinaryVar {
+                    name: name.clone(),
+                    type_hint: type_hint.to_string(),
+                    offset,
+                    len,
+                })
+            })
+            .collect()
+    }
+
+    /// Generate the `ferrumpy_vars` module for a binary snapshot, reading
+    /// each value from the payload file when first accessed
+    pub(super) fn generate_binary_module(
+        &self,
+        payload_path: &str,
+        vars: &[BinaryVar],
+    ) -> Result<SourceMap> {
+        let mut module_code =
+            SourceMap::single(CodeSection::Generated, &module_header("ferrumpy_vars"));
+
+        for var in vars {
+            let init_expr = format!(
+                r#"{{
+            let mut file = std::fs::File::open({})?;
+            std::io::Seek::seek(&mut file, std::io::SeekFrom::Start({}))?;
+            let mut buf = vec![0u8; {}];
+            std::io::Read::read_exact(&mut file, &mut buf)?;
+            bincode::deserialize::<{}>(&buf)?
+        }}"#,
+                str_literal(payload_path),
+                var.offset,
+                var.len,
+                var.type_hint
+            );
+            let item = self.static_item(&var.name, &var.type_hint, &init_expr);
+            module_code.push(CodeSection::Variable(var.name.clone()), &item);
+        }
+
+        module_code.push(CodeSection::Generated, "}\n");
+        Ok(module_code)
+    }
+
+    /// Generate module containing snapshot variables as static items, each
+    /// mapped to the variable it restores
+    pub(super) fn generate_snapshot_module(
+        &self,
+        vars: &[(String, serde_json::Value, String)],
+    ) -> Result<SourceMap> {
+        // Use different name to avoid conflict with companion lib
+        let mut module_code =
+            SourceMap::single(CodeSection::Generated, &module_header("ferrumpy_vars"));
+
+        for (name, value, ty) in vars {
+            let item_code = self.generate_static_item(name, value, ty)?;
+            module_code.push(CodeSection::Variable(name.clone()), &item_code);
+        }
+
+        module_code.push(CodeSection::Generated, "}\n");
+        Ok(module_code)
+    }
+
+    /// Generate code redeclaring variables of an already loaded snapshot
+    ///
+    /// Each variable gets a module of its own, imported by name so it shadows
+    /// the `ferrumpy_vars` glob import and replaces earlier updates.
+    pub(super) fn generate_update_modules(
+        &self,
+        vars: &[(String, serde_json::Value, String)],
+    ) -> Result<SourceMap> {
+        let mut code = SourceMap::new();
+        for (name, value, ty) in vars {
+            let module_name = format!("ferrumpy_var_{}", name);
+            let mut module_code = module_header(&module_name);
+            module_code.push_str(&self.generate_static_item(name, value, ty)?);
+            module_code.push_str(&format!("}}\nuse {}::{};\n", module_name, name));
+            code.push(CodeSection::Variable(name.clone()), &module_code);
+        }
+        Ok(code)
+    }
+
+    /// Generate code restoring one variable in a module of its own, for an
+    /// evaluation separate from the other variables
+    ///
+    /// The code ends in an expression evaluating to `None` if the value
+    /// restores, or to `Some(error)`; see [`parse_restore_error`].
+    pub(super) fn generate_isolated_module(
+        &self,
+        name: &str,
+        value: &serde_json::Value,
+        type_hint: &str,
+    ) -> Result<String> {
+        let module_name = format!("ferrumpy_var_{}", name);
+        let mut module_code = module_header(&module_name);
+        module_code.push_str(&self.generate_static_item(name, value, type_hint)?);
+        module_code.push_str(&restore_error_tail(name, &module_name));
+        Ok(module_code)
+    }
+
+    /// Generate code restoring one variable as `type_hint` by deserializing
+    /// its JSON, for a value [`typed_fallback_type`](Self::typed_fallback_type)
+    /// suggests trying
+    ///
+    /// Like [`generate_isolated_module`](Self::generate_isolated_module), the
+    /// code evaluates to the error, if any, after which the caller restores
+    /// the raw JSON instead.
+    pub(super) fn generate_typed_attempt_module(
+        &self,
+        name: &str,
+        value: &serde_json::Value,
+        type_hint: &str,
+    ) -> Result<String> {
+        let init_expr = format!(
+            "serde_json::from_str::<{}>({})?",
+            type_hint,
+            str_literal(&serde_json::to_string(value)?)
+        );
+        let module_name = format!("ferrumpy_var_{}", name);
+        let mut module_code = module_header(&module_name);
+        module_code.push_str(&self.static_item(name, type_hint, &init_expr));
+        module_code.push_str(&restore_error_tail(name, &module_name));
+        Ok(module_code)
+    }
+
+    /// Generate the `__FerrumpyCtx` struct bundling loaded variables, a
+    /// `ctx` binding of it and a `let` alias per field, for
+    /// `SnapshotPolicy::as_struct`
+    ///
+    /// `vars` are names and restored types. Fields are cloned from the
+    /// accessors, and the struct is leaked so the aliases can borrow from it
+    /// across evaluations. A variable named `ctx` keeps only its field.
+    pub(super) fn generate_ctx_struct(&self, vars: &[(String, String)]) -> String {
+        let mut code = format!("pub struct {} {{\n", CTX_STRUCT_NAME);
+        for (name, ty) in vars {
+            code.push_str(&format!("    pub {}: {},\n", name, ty));
+        }
+        code.push_str("}\n");
+        code.push_str(&format!(
+            "let ctx: &'static {0} = Box::leak(Box::new({0} {{\n",
+            CTX_STRUCT_NAME
+        ));
+        for (name, _) in vars {
+            code.push_str(&format!("    {}: {}().clone(),\n", name, name));
+        }
+        code.push_str("}));\n");
+        for (name, ty) in vars.iter().filter(|(name, _)| name != "ctx") {
+            code.push_str(&format!("let {}: &'static {} = &ctx.{};\n", name, ty, name));
+        }
+        code
+    }
+
+    /// Generate a single static item with accessor function
+    ///
+    /// The value is built by a fallible init fn. If it fails (the JSON doesn't
+    /// fit the type), `Default` types report the error and fall back to the
+    /// default value; others panic when first accessed, so they should be
+    /// restored by [`generate_isolated_module`](Self::generate_isolated_module).
+    fn generate_static_item(
+        &self,
+        name: &str,
+        value: &serde_json::Value,
+        type_hint: &str,
+    ) -> Result<String> {
+        let init_expr = self.generate_value_init_expr(value, type_hint)?;
+        Ok(self.static_item(name, type_hint, &init_expr))
+    }
+
+    /// Static item and accessor fn for a value built by `init_expr`, which
+    /// may use `?` on errors convertible to `Box<dyn Error>`
+    fn static_item(&self, name: &str, type_hint: &str, init_expr: &str) -> String {
+        let cell_name = format!("{}_CELL", name.to_uppercase());
+        let init_fn = init_fn_name(name);
+        let on_error = if self.is_default_type(type_hint) {
+            format!(
+                r#"{{
+                eprintln!("[FerrumPy] Warning: failed to restore {{}}: {{}}", "{}", e);
+                Default::default()
+            }}"#,
+                name
+            )
+        } else {
+            format!(
+                r#"panic!("[FerrumPy] failed to restore {{}}: {{}}", "{}", e)"#,
+                name
+            )
+        };
+        let restore_expr = format!(
+            r#"match {}() {{
+            Ok(v) => v,
+            Err(e) => {},
+        }}"#,
+            init_fn, on_error
+        );
+        let init_item = format!(
+            r#"    fn {}() -> Result<{}, Box<dyn std::error::Error>> {{
+        Ok({})
+    }}
+"#,
+            init_fn, type_hint, init_expr
+        );
+
+        let is_sync = self.is_likely_sync_type(type_hint);
+
+        if is_sync {
+            format!(
+                r#"{}
+    static {}: OnceLock<{}> = OnceLock::new();
+    
+    pub fn {}() -> &'static {} {{
+        {}.get_or_init(|| {{
+            {}
+        }})
+    }}
+"#,
+                init_item, cell_name, type_hint, name, type_hint, cell_name, restore_expr
+            )
+        } else {
+            format!(
+                r#"{}
+    thread_local! {{
+        static {}: std::cell::RefCell<Option<{}>> = 
+            std::cell::RefCell::new(None);
+    }}
+    
+    pub fn {}() -> {} {{
+        {}.with(|cell| {{
+            let mut opt = cell.borrow_mut();
+            if opt.is_none() {{
+                *opt = Some({});
+            }}
+            opt.as_ref().unwrap().clone()
+        }})
+    }}
+"#,
+                init_item, cell_name, type_hint, name, type_hint, cell_name, restore_expr
+            )
+        }
+    }
+
+    /// Whether a type is known to implement `Default`, so a variable that
+    /// fails to restore can fall back to its default value
+    ///
+    /// User types are assumed not to.
+    pub(super) fn is_default_type(&self, type_hint: &str) -> bool {
+        let type_hint = type_hint.trim();
+        match type_hint {
+            "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64"
+            | "u128" | "usize" | "f32" | "f64" | "bool" | "char" | "String"
+            | "serde_json::Value" => return true,
+            _ => {}
+        }
+        if let Some(types) = split_tuple_types(type_hint) {
+            return types.len() <= 12 && types.iter().all(|ty| self.is_default_type(ty));
+        }
+        if let Some((_, args)) = split_collection_type(type_hint) {
+            return args.iter().all(|ty| self.is_default_type(ty));
+        }
+        if let Some((_, inner_type)) = split_wrapper_type(type_hint) {
+            return self.is_default_type(inner_type);
+        }
+        if let Some((path, _)) = split_single_generic(type_hint) {
+            // Empty vec / `None` whatever the element type
+            return matches!(path, "Vec" | "Option");
+        }
+        false
+    }
+
+    /// Whether a type is a `HashMap` or `BTreeMap`, restored from a JSON object
+    pub(super) fn is_map_type(&self, type_hint: &str) -> bool {
+        split_collection_type(type_hint.trim()).is_some_and(|(collection, _)| collection.is_map())
+    }
+
+    /// Heuristic to detect if a type is likely Sync
+    fn is_likely_sync_type(&self, type_hint: &str) -> bool {
+        let non_sync_markers = ["Rc<", "RefCell<", "Cell<", "*const", "*mut"];
+
+        for marker in &non_sync_markers {
+            if type_hint.contains(marker) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Check if a type is likely to be available in the generated code scope
+    /// Types that are NOT likely available:
+    /// - Iterator adapters like Skip, Take, Map, Filter, etc. (from std::iter)
+    /// - Environment types like Args (from std::env)
+    /// - File/IO types that aren't commonly re-exported
+    /// - Raw pointers and references
+    pub(super) fn is_type_likely_available(&self, type_hint: &str) -> bool {
+        // List of type patterns that are unlikely to be in scope
+        let unsupported_patterns = [
+            // Iterator adapters (std::iter)
+            "Skip<",
+            "Take<",
+            "Map<",
+            "Filter<",
+            "FilterMap<",
+            "Enumerate<",
+            "Peekable<",
+            "Chain<",
+            "Zip<",
+            "Cycle<",
+            "Fuse<",
+            "Rev<",
+            "Flatten<",
+            "FlatMap<",
+            "Scan<",
+            "TakeWhile<",
+            "SkipWhile<",
+            "StepBy<",
+            "Inspect<",
+            "Cloned<",
+            "Copied<",
+            // Environment types (std::env)
+            "Args",
+            "ArgsOs",
+            "Vars",
+            "VarsOs",
+            // IO types that may not be in scope
+            "Stdin",
+            "Stdout",
+            "Stderr",
+            "BufReader<",
+            "BufWriter<",
+            "Lines<",
+            "Bytes<",
+            "Chars<",
+            // Raw pointers and references (can't be deserialized)
+            "*const",
+            "*mut",
+            "&'", // Any reference with explicit lifetime
+        ];
+
+        for pattern in &unsupported_patterns {
+            if type_hint.contains(pattern) {
+                return false;
+            }
+        }
+
+        // Check for types that look like they're from external crates but not imported
+        // Heuristic: if it contains "::" it should either:
+        // 1. Start with "std::", "core::", "alloc::" (std library - usually available via prelude)
+        // 2. Or be from ferrumpy_snapshot (companion lib)
+        // Otherwise it might not be in scope
+
+        // For now, allow all other types - the list above catches the most common issues
+        true
+    }
+
+    /// How `generate_value_init_expr` rebuilds a value, for the load report
+    pub(super) fn restore_strategy(
+        &self,
+        value: &serde_json::Value,
+        type_hint: &str,
+    ) -> RestoreStrategy {
+        if type_hint == "serde_json::Value" {
+            return RestoreStrategy::Serde;
+        }
+        if let Some(kind) = value.get("__ferrumpy_kind__").and_then(|v| v.as_str()) {
+            return match kind {
+                "option" | "result" | "tuple" | "array" => RestoreStrategy::Literal,
+                "arc" | "rc" | "box" => RestoreStrategy::Wrapper,
+                _ => RestoreStrategy::Serde,
+            };
+        }
+        match type_hint {
+            "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64"
+            | "u128" | "usize" | "f32" | "f64" | "bool" | "char" | "String" => {
+                RestoreStrategy::Literal
+            }
+            t if os_string_type(t).is_some() => RestoreStrategy::Literal,
+            t if split_wrapper_type(t).is_some() => RestoreStrategy::Wrapper,
+            t if t.starts_with('(') && value.is_array() => RestoreStrategy::Literal,
+            t if split_collection_type(t).is_some() && (value.is_object() || value.is_array()) => {
+                RestoreStrategy::Literal
+            }
+            t if t.starts_with("Vec<") || t.starts_with("Option<") => RestoreStrategy::Literal,
+            _ => RestoreStrategy::Serde,
+        }
+    }
+
+    /// Generate initialization expression for a value (for let bindings)
+    fn generate_value_init_expr(
+        &self,
+        value: &serde_json::Value,
+        type_hint: &str,
+    ) -> Result<String> {
+        // Check for __ferrumpy_kind__ metadata (special type handling)
+        // BUT: Skip if type_hint is serde_json::Value (fallback case for unsupported types)
+        if type_hint != "serde_json::Value" {
+            if let Some(kind) = value.get("__ferrumpy_kind__").and_then(|v| v.as_str()) {
+                return match kind {
+                    "option" => self.generate_option_code(value, type_hint),
+                    "result" => self.generate_result_code(value, type_hint),
+                    "tuple" => self.generate_tuple_code(value, type_hint),
+                    "array" => self.generate_array_code(value, type_hint),
+                    "arc" => {
+                        self.generate_pointer_kind_code(value, type_hint, "std::sync::Arc::new")
+                    }
+                    "rc" => self.generate_pointer_kind_code(value, type_hint, "std::rc::Rc::new"),
+                    "box" => self.generate_pointer_kind_code(value, type_hint, "Box::new"),
+                    "enum" => self.generate_enum_code(value, type_hint),
+                    "hashmap" => self.generate_collection_code(value, type_hint),
+                    _ => {
+                        // Unknown kind, fall through to default handling
+                        let json_str = serde_json::to_string(value)?;
+                        Ok(format!(
+                            "serde_json::from_str::<serde_json::Value>({})?",
+                            str_literal(&json_str)
+                        ))
+                    }
+                };
+            }
+        }
+
+        // Handle primitive types with literal generation
+        match type_hint {
+            // Integer types - generate literal
+            t if integer_range(t).is_some() => {
+                if let Some(literal) = integer_literal(value, t)? {
+                    return Ok(format!("{}{}", literal, t));
+                }
+            }
+
+            // Float types
+            "f32" | "f64" => {
+                if let Some(literal) = float_literal(value, type_hint) {
+                    return Ok(literal);
+                }
+            }
+
+            // Boolean
+            "bool" => {
+                if let Some(b) = value.as_bool() {
+                    return Ok(b.to_string());
+                }
+            }
+
+            "char" => {
+                if let Some(c) = char_literal(value) {
+                    return Ok(c);
+                }
+            }
+
+            // String - generate with to_string()
+            "String" => {
+                if let Some(s) = value.as_str() {
+                    let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
+                    return Ok(format!("\"{}\".to_string()", escaped));
+                }
+            }
+
+            // PathBuf and OsString - build from the path's text
+            t if os_string_type(t).is_some() => {
+                if let (Some(ty), Some(s)) = (os_string_type(t), value.as_str()) {
+                    return Ok(format!("{}::from({})", ty, str_literal(s)));
+                }
+            }
+
+            // Vec<u8> encoded as base64 by the Python layer - decode at runtime
+            // instead of emitting one literal per byte
+            "Vec<u8>" if is_base64_bytes(value, type_hint) => {
+                return Ok(base64_decode_expr(value.as_str().unwrap_or_default()));
+            }
+
+            // Smart pointers and cells - restore the inner value and wrap it
+            t if split_wrapper_type(t).is_some() => {
+                return self.generate_pointer_code(value, t);
+            }
+
+            // Tuples serialized as plain arrays - one literal per element
+            t if t.starts_with('(') && value.is_array() => {
+                return self.generate_plain_tuple_code(value, t);
+            }
+
+            // Maps, sets and deques - insert each element
+            t if split_collection_type(t).is_some() && (value.is_object() || value.is_array()) => {
+                return self.generate_collection_code(value, t);
+            }
+
+            // Vec types - generate vec![] macro
+            t if t.starts_with("Vec<") => {
+                if let (Some(arr), Some(inner_type)) = (value.as_array(), single_type_arg(t, "Vec"))
+                {
+                    let elements = self.generate_vec_elements(arr, inner_type)?;
+                    return Ok(format!("vec![{}]", elements));
+                }
+            }
+
+            // Option types (legacy path - now handled by __ferrumpy_kind__)
+            t if t.starts_with("Option<") => {
+                if value.is_null() {
+                    return Ok("None".to_string());
+                }
+                if let Some(inner_type) = single_type_arg(t, "Option") {
+                    let inner_code = self.generate_value_expr(value, inner_type)?;
+                    return Ok(format!("Some({})", inner_code));
+                }
+            }
+
+            _ => {}
+        }
+
+        // Fallback: use serde_json for complex/user types
+        let json_str = serde_json::to_string(value)?;
+        // Use type annotation for proper deserialization
+        if type_hint != "serde_json::Value" && type_hint != "?" {
+            Ok(format!(
+                "serde_json::from_str::<{}>({})?",
+                type_hint,
+                str_literal(&json_str)
+            ))
+        } else {
+            Ok(format!(
+                "serde_json::from_str::<serde_json::Value>({})?",
+                str_literal(&json_str)
+            ))
+        }
+    }
+
+    /// Generate code for Option<T> from __ferrumpy_kind__ metadata
+    fn generate_option_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
+        let variant = value
+            .get("__variant__")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+
+        match variant {
+            "None" => Ok("None".to_string()),
+            "Some" => {
+                // Extract inner type from Option<T>
+                let inner_type =
+                    single_type_arg(type_hint, "Option").unwrap_or("serde_json::Value");
+
+                if let Some(inner) = value.get("__inner__") {
+                    let inner_code = self.generate_value_init_expr(inner, inner_type)?;
+                    Ok(format!("Some({})", inner_code))
+                } else {
+                    Ok("None".to_string())
+                }
+            }
+            _ => Ok("None".to_string()), // Unknown variant, default to None
+        }
+    }
+
+    /// Generate code for Result<T, E> from __ferrumpy_kind__ metadata
+    fn generate_result_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
+        let variant = value
+            .get("__variant__")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+
+        // Parse Result<T, E> to extract T and E types
+        let (ok_type, err_type) = self.parse_result_types(type_hint);
+
+        match variant {
+            "Ok" => {
+                if let Some(inner) = value.get("__inner__") {
+                    let inner_code = self.generate_value_init_expr(inner, &ok_type)?;
+                    Ok(format!("Ok({})", inner_code))
+                } else {
+                    Ok("Ok(())".to_string())
+                }
+            }
+            "Err" => {
+                if let Some(inner) = value.get("__inner__") {
+                    let inner_code = self.generate_value_init_expr(inner, &err_type)?;
+                    Ok(format!("Err({})", inner_code))
+                } else {
+                    Ok("Err(\"unknown\".to_string())".to_string())
+                }
+            }
+            _ => Ok("Err(\"unknown variant\".to_string())".to_string()),
+        }
+    }
+
+    /// Parse Result<T, E> to extract T and E
+    fn parse_result_types(&self, type_hint: &str) -> (String, String) {
+        match split_generic(type_hint) {
+            Some((name, args)) if name == "Result" => {
+                let mut args = args.into_iter();
+                match (args.next(), args.next()) {
+                    (Some(ok_type), Some(err_type)) => (ok_type.to_string(), err_type.to_string()),
+                    (Some(ok_type), None) => (ok_type.to_string(), "String".to_string()),
+                    _ => ("serde_json::Value".to_string(), "String".to_string()),
+                }
+            }
+            _ => ("serde_json::Value".to_string(), "String".to_string()),
+        }
+    }
+
+    /// Generate code for tuple from __ferrumpy_kind__ metadata
+    fn generate_tuple_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
+        let elements = value.get("__elements__").and_then(|v| v.as_array());
+
+        if let Some(elems) = elements {
+            // Parse tuple types from type_hint like "(i32, String, f64)"
+            let elem_types = self.parse_tuple_types(type_hint);
+
+            let mut parts = Vec::new();
+            for (i, elem) in elems.iter().enumerate() {
+                let elem_type = elem_types
+                    .get(i)
+                    .map(|s| s.as_str())
+                    .unwrap_or("serde_json::Value");
+                let part = self.generate_value_init_expr(elem, elem_type)?;
+                parts.push(part);
+            }
+
+            Ok(tuple_literal(&parts))
+        } else {
+            Ok("()".to_string())
+        }
+    }
+
+    /// Generate a tuple literal from a JSON array, each element restored
+    /// with its own type from "(T1, T2, T3)"
+    fn generate_plain_tuple_code(
+        &self,
+        value: &serde_json::Value,
+        type_hint: &str,
+    ) -> Result<String> {
+        let elems = value.as_array().map(Vec::as_slice).unwrap_or_default();
+        let elem_types = self.parse_tuple_types(type_hint);
+        if elems.len() != elem_types.len() {
+            anyhow::bail!(
+                "Tuple type {} has {} elements, snapshot has {}",
+                type_hint,
+                elem_types.len(),
+                elems.len()
+            );
+        }
+
+        let parts = elems
+            .iter()
+            .zip(&elem_types)
+            .map(|(elem, elem_type)| self.generate_value_expr(elem, elem_type))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(tuple_literal(&parts))
+    }
+
+    /// Generate a block building a std map, set or deque element by element
+    ///
+    /// Maps come from a JSON object (or an array of `[key, value]` pairs, bare
+    /// or as the serializer's `__pairs__` for keys JSON can't hold), the
+    /// others from a JSON array. A `HashMap` or `HashSet` was serialized in
+    /// whatever order it iterated, so its inserts are sorted to generate the
+    /// same code for the same contents.
+    fn generate_collection_code(
+        &self,
+        value: &serde_json::Value,
+        type_hint: &str,
+    ) -> Result<String> {
+        let Some((collection, args)) = split_collection_type(type_hint) else {
+            anyhow::bail!("Not a std collection type: {}", type_hint);
+        };
+
+        let mut inserts = Vec::new();
+        if collection.is_map() {
+            let (key_type, value_type) = (args[0], args[1]);
+            let mut entries = Vec::new();
+            let pairs = match value {
+                serde_json::Value::Object(obj) if obj.contains_key("__ferrumpy_kind__") => {
+                    match obj.get("__pairs__").and_then(|p| p.as_array()) {
+                        Some(pairs) => pairs,
+                        None => anyhow::bail!("Malformed {} value: {}", type_hint, value),
+                    }
+                }
+                serde_json::Value::Object(obj) => {
+                    for (key, v) in obj {
+                        entries.push((map_key_value(key, key_type), v));
+                    }
+                    &Vec::new()
+                }
+                serde_json::Value::Array(pairs) => pairs,
+                _ => anyhow::bail!("Expected an object for {}, got {}", type_hint, value),
+            };
+            for pair in pairs {
+                match pair.as_array().map(Vec::as_slice) {
+                    Some([key, v]) => entries.push((key.clone(), v)),
+                    _ => anyhow::bail!("Expected a [key, value] pair, got {}", pair),
+                }
+            }
+            let mut entries = entries
+                .into_iter()
+                .map(|(key, v)| {
+                    Ok((
+                        self.generate_value_init_expr(&key, key_type)?,
+                        self.generate_value_init_expr(v, value_type)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if collection == Collection::HashMap {
+                // Stable, so a duplicate key still ends with its last value
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+            for (key, v) in entries {
+                inserts.push(format!("m.insert({}, {}); ", key, v));
+            }
+        } else {
+            let Some(elems) = value.as_array() else {
+                anyhow::bail!("Expected an array for {}, got {}", type_hint, value);
+            };
+            let method = if collection == Collection::VecDeque {
+                "push_back"
+            } else {
+                "insert"
+            };
+            let mut elems = elems
+                .iter()
+                .map(|elem| self.generate_value_init_expr(elem, args[0]))
+                .collect::<Result<Vec<_>>>()?;
+            if collection == Collection::HashSet {
+                elems.sort();
+            }
+            for elem in elems {
+                inserts.push(format!("m.{}({}); ", method, elem));
+            }
+        }
+
+        Ok(format!(
+            "{{ let mut m = {}(); {}m }}",
+            collection.constructor(),
+            inserts.concat()
+        ))
+    }
+
+    /// Parse tuple type "(T1, T2, T3)" into vec of types
+    fn parse_tuple_types(&self, type_hint: &str) -> Vec<String> {
+        split_tuple_types(type_hint)
+            .map(|types| types.into_iter().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    /// Generate code for fixed array from __ferrumpy_kind__ metadata
+    fn generate_array_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
+        let elements = value.get("__elements__").and_then(|v| v.as_array());
+
+        if let Some(elems) = elements {
+            // Parse array type like "[i32; 5]" to get element type
+            let elem_type = self.parse_array_elem_type(type_hint);
+
+            let mut parts = Vec::new();
+            for elem in elems {
+                let part = self.generate_value_init_expr(elem, &elem_type)?;
+                parts.push(part);
+            }
+
+            Ok(format!("[{}]", parts.join(", ")))
+        } else {
+            Ok("[]".to_string())
+        }
+    }
+
+    /// Parse array type "[T; N]" to get element type T
+    fn parse_array_elem_type(&self, type_hint: &str) -> String {
+        // Handle "[i32; 5]" format
+        if type_hint.starts_with("[") && type_hint.contains(";") {
+            if let Some(semi_pos) = type_hint.find(';') {
+                return type_hint[1..semi_pos].trim().to_string();
+            }
+        }
+        // Handle "int[5]" C-style format
+        if let Some(bracket_pos) = type_hint.find('[') {
+            return type_hint[..bracket_pos].to_string();
+        }
+        "serde_json::Value".to_string()
+    }
+
+    /// Generate code for Arc<T>, Rc<T> and Box<T> from __ferrumpy_kind__
+    /// metadata; `ctor` wraps the inner value when the type hint is no pointer
+    fn generate_pointer_kind_code(
+        &self,
+        value: &serde_json::Value,
+        type_hint: &str,
+        ctor: &str,
+    ) -> Result<String> {
+        match value.get("__inner__") {
+            Some(inner) if split_wrapper_type(type_hint).is_some() => {
+                self.generate_pointer_code(inner, type_hint)
+            }
+            Some(inner) => {
+                let inner_code = self.generate_value_init_expr(inner, "serde_json::Value")?;
+                Ok(format!("{}({})", ctor, inner_code))
+            }
+            None => {
+                // Fallback to serde_json::Value
+                let json_str = serde_json::to_string(value)?;
+                Ok(format!(
+                    "{}(serde_json::from_str::<serde_json::Value>({})?)",
+                    ctor,
+                    str_literal(&json_str)
+                ))
+            }
+        }
+    }
+
+    /// Generate code for a smart pointer (`Arc<T>`, `Rc<T>`, `Box<T>`) or a
+    /// cell (`RefCell<T>`, `Cell<T>`, `Mutex<T>`, `RwLock<T>`)
+    ///
+    /// The snapshot holds the inner value, so it is restored as `T` and
+    /// wrapped, recursing for nested wrappers like `Arc<Mutex<T>>`. Unsized
+    /// `str` and `[T]` pointees are built from a string or `Vec` with `.into()`.
+    fn generate_pointer_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
+        let Some((ctor, inner_type)) = split_wrapper_type(type_hint) else {
+            anyhow::bail!("Not a wrapper type: {}", type_hint);
+        };
+        if split_smart_pointer(type_hint).is_none() {
+            let inner_code = self.generate_value_init_expr(value, inner_type)?;
+            return Ok(format!("{}({})", ctor, inner_code));
+        }
+        if inner_type == "str" {
+            let string_code = match value.as_str() {
+                Some(s) => format!("{:?}", s),
+                None => format!(
+                    "serde_json::from_str::<String>({})?",
+                    str_literal(&serde_json::to_string(value)?)
+                ),
+            };
+            return Ok(format!("{}.into()", string_code));
+        }
+        if let Some(elem_type) = inner_type
+            .strip_prefix('[')
+            .and_then(|t| t.strip_suffix(']'))
+            .filter(|t| !t.contains(';'))
+        {
+            let vec_code = self.generate_value_init_expr(value, &format!("Vec<{}>", elem_type))?;
+            return Ok(format!("{}.into()", vec_code));
+        }
+        let inner_code = self.generate_value_init_expr(value, inner_type)?;
+        Ok(format!("{}({})", ctor, inner_code))
+    }
+
+    /// Generate code for user-defined enum from __ferrumpy_kind__: enum metadata
+    fn generate_enum_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
+        let enum_type = value
+            .get("__enum_type__")
+            .and_then(|v| v.as_str())
+            .unwrap_or("Unknown");
+
+        // Use the type_hint if it includes the full path, otherwise construct from enum_type
+        let base_type = if type_hint.contains("::") {
+            // Remove the variant part if present in type_hint
+            if let Some(pos) = type_hint.rfind("::") {
+                let before_last = &type_hint[..pos];
+                if let Some(pos2) = before_last.rfind("::") {
+                    // Check if last part looks like a variant (capitalized)
+                    let last = &before_last[pos2 + 2..];
+                    if !last.is_empty() && last.chars().next().unwrap().is_uppercase() {
+                        before_last.to_string()
+                    } else {
+                        type_hint.to_string()
+                    }
+                } else {
+                    before_last.to_string()
+                }
+            } else {
+                type_hint.to_string()
+            }
+        } else {
+            enum_type.to_string()
+        };
+
+        // Deserialize through serde, whose derives were added by libgen, so
+        // payloads of any type restore the same way as other user types
+        let Some(encoded) = externally_tagged_enum(value) else {
+            anyhow::bail!("Malformed {} value: {}", base_type, value);
+        };
+        Ok(format!(
+            "serde_json::from_str::<{}>({})?",
+            base_type,
+            str_literal(&serde_json::to_string(&encoded)?)
+        ))
+    }
+
+    /// Generate vec elements as comma-separated expressions
+    fn generate_vec_elements(&self, arr: &[serde_json::Value], inner_type: &str) -> Result<String> {
+        let elements: Result<Vec<String>> = arr
+            .iter()
+            .map(|v| self.generate_value_expr(v, inner_type))
+            .collect();
+        Ok(elements?.join(", "))
+    }
+
+    /// Generate a value expression for a given type
+    fn generate_value_expr(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
+        match type_hint {
+            t if integer_range(t).is_some() => {
+                Ok(integer_literal(value, t)?.unwrap_or_else(|| "0".to_string()))
+            }
+            "f32" | "f64" => Ok(float_literal(value, type_hint).unwrap_or("0.0".into())),
+            "bool" => Ok(value
+                .as_bool()
+                .map(|b| b.to_string())
+                .unwrap_or("false".into())),
+            "char" => Ok(char_literal(value).unwrap_or("'\\0'".into())),
+            "String" => {
+                let s = value.as_str().unwrap_or("");
+                let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
+                Ok(format!("\"{}\".to_string()", escaped))
+            }
+            t if split_wrapper_type(t).is_some() => self.generate_pointer_code(value, t),
+            t if split_collection_type(t).is_some() && (value.is_object() || value.is_array()) => {
+                self.generate_collection_code(value, t)
+            }
+            _ => {
+                // For complex types, use serde
+                let json_str = serde_json::to_string(value)?;
+                Ok(format!(
+                    "serde_json::from_str::<{}>({})?",
+                    type_hint,
+                    str_literal(&json_str)
+                ))
+            }
+        }
+    }
+
+    /// Fix user type paths: remove original crate name prefix
+    /// e.g., "Arc<rust_sample::User>" -> "Arc<User>"
+    ///
+    /// Each path in the type, including every generic argument, is rewritten
+    /// on its own: user types keep only their name (they are re-exported by
+    /// the companion lib), std types only when that name is in scope in the
+    /// snapshot module, e.g. "Result<i32, std::io::Error>" is kept as is.
+    fn fix_user_type_path(&self, type_hint: &str) -> String {
+        let ty = type_hint.trim();
+        if let Some(elements) = split_tuple_types(ty) {
+            let elements: Vec<String> = elements
+                .iter()
+                .map(|t| self.fix_user_type_path(t))
+                .collect();
+            return match elements.as_slice() {
+                [single] => format!("({},)", single),
+                _ => format!("({})", elements.join(", ")),
+            };
+        }
+        if let Some(inner) = ty.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            return match inner.rsplit_once(';') {
+                Some((elem, len)) => {
+                    format!("[{}; {}]", self.fix_user_type_path(elem), len.trim())
+                }
+                None => format!("[{}]", self.fix_user_type_path(inner)),
+            };
+        }
+        if let Some(rest) = ty.strip_prefix('&') {
+            // Keep the lifetime and `mut` of the reference
+            let (lifetime, rest) = match rest.strip_prefix('\'') {
+                Some(lifetime) => {
+                    let len = lifetime.find(char::is_whitespace).unwrap_or(lifetime.len());
+                    (&rest[..len + 1], lifetime[len..].trim_start())
+                }
+                None => ("", rest),
+            };
+            let (mutability, rest) = match rest.strip_prefix("mut ") {
+                Some(rest) => ("mut ", rest),
+                None => ("", rest),
+            };
+            let lifetime = if lifetime.is_empty() {
+                String::new()
+            } else {
+                format!("{} ", lifetime)
+            };
+            return format!(
+                "&{}{}{}",
+                lifetime,
+                mutability,
+                self.fix_user_type_path(rest)
+            );
+        }
+        for prefix in ["*const ", "*mut ", "dyn "] {
+            if let Some(rest) = ty.strip_prefix(prefix) {
+                return format!("{}{}", prefix, self.fix_user_type_path(rest));
+            }
+        }
+        match split_generic(ty) {
+            Some((base, args)) => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|arg| self.fix_user_type_path(arg))
+                    .collect();
+                format!("{}<{}>", strip_type_path(&base), args.join(", "))
+            }
+            // Not a plain path (`fn(A) -> B`, `Trait<A>::Assoc`): keep as is
+            None if !ty.chars().all(|c| is_ident_char(c) || c == ':') => ty.to_string(),
+            None => strip_type_path(ty),
+        }
+    }
+
+    /// Check if a type is supported for snapshot restoration
+    /// With improved type normalization from Python, we can now support more types
+    fn is_supported_type(&self, type_hint: &str) -> bool {
+        if type_hint == "char" || os_string_type(type_hint).is_some() {
+            return true;
+        }
+
+        // Skip pointer types (raw pointers)
+        if type_hint.contains(" *") || type_hint.contains("*const") || type_hint.contains("*mut") {
+            return false;
+        }
+
+        // Skip references (can't deserialize references)
+        if type_hint.starts_with("&") {
+            return false;
+        }
+
+        // Smart pointers are restored through their pointee
+        if let Some((_, inner_type)) = split_smart_pointer(type_hint) {
+            return inner_type == "str" || self.is_supported_type(inner_type);
+        }
+
+        // Cells are restored through their inner value
+        if let Some((_, inner_type)) = split_cell_type(type_hint) {
+            return self.is_supported_type(inner_type);
+        }
+
+        // Skip nested smart pointers if they don't have a type hint or are malformed
+        if type_hint.contains("Arc<") || type_hint.contains("Rc<") || type_hint.contains("Box<") {
+            return type_hint.contains('<') && type_hint.ends_with('>');
+        }
+
+        // Skip cells nested in other types (serde can't deserialize RefCell/Cell)
+        if type_hint.contains("RefCell<") || type_hint.contains("Cell<") {
+            return false;
+        }
+
+        // Skip allocator types (should be normalized away by Python, but double-check)
+        if type_hint.contains("Global") || type_hint.contains("alloc::") {
+            return false;
+        }
+
+        // NOW SUPPORTED: C-style arrays and Rust arrays via __ferrumpy_kind__ metadata
+        // (Removed the array exclusion)
+
+        // Skip tuples containing references (e.g., (&str, i32))
+        if type_hint.starts_with("(") && type_hint.contains("&") {
+            return false;
+        }
+
+        // Skip unknown types
+        if type_hint == "?" || type_hint.is_empty() {
+            return false;
+        }
+
+        // NOW ALLOW:
+        // - Result<T, E> (serde can deserialize)
+        // - HashMap<K, V> (serde can deserialize)
+        // - Nested generics Vec<Vec<T>> (now properly normalized)
+        // - User-defined types (Config, User etc. from companion lib)
+        // - Tuples without references (T1, T2, T3)
+
+        true
+    }
+
+    /// Check if a serialized value is valid for deserialization to the target type
+    /// Returns false for values that would cause serde::from_str to panic
+    fn is_valid_for_deserialization(&self, value: &serde_json::Value, type_hint: &str) -> bool {
+        // Empty strings cannot be deserialized to most types
+        // (an empty base64 string is just an empty byte buffer)
+        if let Some(s) = value.as_str() {
+            if s.is_empty() && type_hint != "Vec<u8>" {
+                return false;
+            }
+        }
+
+        // A null float is a non-finite value serialized without its type
+        if has_null_float(value, type_hint) {
+            return false;
+        }
+
+        // An integer out of its type's range would not compile
+        if !integers_fit(value, type_hint) {
+            return false;
+        }
+
+        // A char must be a single character, and a path its text
+        if type_hint == "char" && char_literal(value).is_none() {
+            return false;
+        }
+        if os_string_type(type_hint).is_some() && !value.is_string() {
+            return false;
+        }
+
+        // Check for error marker objects from Python serializer
+        if let Some(obj) = value.as_object() {
+            // Allow __ferrumpy_kind__ metadata - but only if the type is actually supported
+            if obj.contains_key("__ferrumpy_kind__") {
+                // For types with references, we still can't deserialize
+                // is_supported_type already checked for this, so trust the type_hint
+                if type_hint.contains("&") {
+                    return false; // Contains reference, can't restore
+                }
+                // A map the serializer couldn't read has no pairs
+                if obj.get("__ferrumpy_kind__").and_then(|v| v.as_str()) == Some("hashmap") {
+                    return obj.get("__pairs__").is_some_and(|p| p.is_array());
+                }
+                // An enum serde can't read would panic when first accessed
+                return !has_malformed_enum(value);
+            }
+
+            for key in obj.keys() {
+                // These markers indicate serialization failed in Python
+                if key.starts_with("__") && key.ends_with("__") {
+                    return false;
+                }
+            }
+
+            // For Result<T, E>, need {"Ok": ...} or {"Err": ...} format
+            // (Legacy path - now handled by __ferrumpy_kind__)
+            if type_hint.starts_with("Result<") {
+                if !obj.contains_key("Ok") && !obj.contains_key("Err") {
+                    return false;
+                }
+            }
+
+            // For HashMap<K, V>, the object should be a simple key-value map
+            // (not containing error markers, which we already checked)
+        }
+
+        // serde_json::Value can always be deserialized
+        if type_hint == "serde_json::Value" {
+            return true;
+        }
+
+        true
+    }
+
+    /// Normalize Rust type: remove allocator, convert C types, strip crate prefixes
+    fn normalize_rust_type(&self, type_hint: &str) -> String {
+        let mut result = type_hint.to_string();
+
+        // Remove Global allocator from generics
+        // Vec<i32, alloc::alloc::Global> -> Vec<i32>
+        for allocator in [
+            ", alloc::alloc::Global",
+            ",alloc::alloc::Global",
+            ", Global",
+            ",Global",
+        ] {
+            result = remove_whole_token(&result, allocator);
+        }
+
+        // Convert C types to Rust types
+        result = convert_c_types(&result);
+
+        // Strip crate prefixes (rust_sample::User -> User)
+        result = self.fix_user_type_path(&result);
+
+        // PathBuf and OsString aren't in scope in the snapshot module
+        if let Some(ty) = os_string_type(&result) {
+            result = ty.to_string();
+        }
+
+        result
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Remove every occurrence of `pattern` that isn't followed by more of the
+/// same identifier (`, Global` but not `, GlobalConfig`)
+fn remove_whole_token(type_hint: &str, pattern: &str) -> String {
+    let mut result = String::with_capacity(type_hint.len());
+    let mut rest = type_hint;
+    while let Some(pos) = rest.find(pattern) {
+        let after = &rest[pos + pattern.len()..];
+        result.push_str(&rest[..pos]);
+        if after.starts_with(is_ident_char) {
+            result.push_str(pattern);
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Std type names in scope in the snapshot module, through the prelude or
+/// its imports
+const SCOPE_TYPE_NAMES: &[&str] = &[
+    "String", "Vec", "Box", "Option", "Result", "HashMap", "BTreeMap", "HashSet", "BTreeSet",
+    "VecDeque", "Arc", "Rc", "Cell", "RefCell", "Mutex", "RwLock", "OnceLock",
+];
+
+/// Strip the module prefix of a single type path, keeping std paths whose
+/// name isn't in scope (see [`SCOPE_TYPE_NAMES`]) and paths into the serde
+/// crates, which the REPL depends on but doesn't import
+fn strip_type_path(path: &str) -> String {
+    let segments: Vec<&str> = path.trim_start_matches("::").split("::").collect();
+    let (Some(first), Some(name)) = (segments.first(), segments.last()) else {
+        return path.to_string();
+    };
+    match *first {
+        _ if segments.len() == 1 => path.to_string(),
+        "serde" | "serde_json" => path.to_string(),
+        "std" | "core" | "alloc" if !SCOPE_TYPE_NAMES.contains(name) => {
+            // `alloc` isn't an extern crate in the REPL, but std re-exports it
+            if *first == "alloc" {
+                format!("std::{}", segments[1..].join("::"))
+            } else {
+                path.to_string()
+            }
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Words that make up C primitive type names
+const C_TYPE_WORDS: &[&str] = &[
+    "unsigned", "signed", "long", "short", "int", "char", "float", "double",
+];
+
+/// Rust primitive for a run of C type words (`unsigned long` -> `u64`)
+///
+/// A lone `char` is left alone, as it is also a Rust type.
+fn c_type_to_rust(words: &[&str]) -> Option<&'static str> {
+    let has = |word: &str| words.contains(&word);
+    if has("double") {
+        return Some("f64");
+    }
+    if has("float") {
+        return Some("f32");
+    }
+    if words.is_empty() || words == ["char"] {
+        return None;
+    }
+    let unsigned = has("unsigned");
+    let ty = if has("char") {
+        if unsigned {
+            "u8"
+        } else {
+            "i8"
+        }
+    } else if has("short") {
+        if unsigned {
+            "u16"
+        } else {
+            "i16"
+        }
+    } else if has("long") {
+        if unsigned {
+            "u64"
+        } else {
+            "i64"
+        }
+    } else if unsigned {
+        "u32"
+    } else {
+        "i32"
+    };
+    Some(ty)
+}
+
+/// Convert C primitive type names to Rust ones, rewriting only whole words
+///
+/// Identifiers that merely contain a C type name (`Container`, `Printer`)
+/// and path segments (`ffi::int`) are kept as they are.
+fn convert_c_types(type_hint: &str) -> String {
+    let mut result = String::with_capacity(type_hint.len());
+    let mut rest = type_hint;
+    while let Some(start) = rest.find(is_ident_char) {
+        result.push_str(&rest[..start]);
+        let after_path = result.ends_with("::");
+        rest = &rest[start..];
+
+        // Collect a run of C type words separated by whitespace
+        let mut words = Vec::new();
+        let mut run_end = 0;
+        let mut cursor = rest;
+        loop {
+            let word_len = cursor.find(|c| !is_ident_char(c)).unwrap_or(cursor.len());
+            let word = &cursor[..word_len];
+            if after_path || !C_TYPE_WORDS.contains(&word) {
+                break;
+            }
+            words.push(word);
+            run_end = rest.len() - cursor.len() + word_len;
+            let spaced = cursor[word_len..].trim_start();
+            if spaced.len() == cursor[word_len..].len() || !spaced.starts_with(is_ident_char) {
+                break;
+            }
+            cursor = spaced;
+        }
+
+        match c_type_to_rust(&words) {
+            Some(ty) => {
+                result.push_str(ty);
+                rest = &rest[run_end..];
+            }
+            None => {
+                let word_len = rest.find(|c| !is_ident_char(c)).unwrap_or(rest.len());
+                result.push_str(&rest[..word_len]);
+                rest = &rest[word_len..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Split a tuple type "(T1, T2, T3)" into its element types, on top-level
+/// commas only
+fn split_tuple_types(type_hint: &str) -> Option<Vec<&str>> {
+    let inner = type_hint.trim().strip_prefix('(')?.strip_suffix(')')?;
+    split_top_level_commas(inner)
+}
+
+/// The single type argument of `type_hint` if its base is `base`, e.g.
+/// `Vec<i32>` for `Vec<Vec<i32>>` and `Vec`
+fn single_type_arg<'a>(type_hint: &'a str, base: &str) -> Option<&'a str> {
+    split_single_generic(type_hint)
+        .filter(|(path, _)| *path == base)
+        .map(|(_, arg)| arg)
+}
+
+/// Std collections restored by inserting their elements one by one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Collection {
+    HashMap,
+    BTreeMap,
+    HashSet,
+    BTreeSet,
+    VecDeque,
+}
+
+impl Collection {
+    fn constructor(self) -> &'static str {
+        match self {
+            Collection::HashMap => "std::collections::HashMap::new",
+            Collection::BTreeMap => "std::collections::BTreeMap::new",
+            Collection::HashSet => "std::collections::HashSet::new",
+            Collection::BTreeSet => "std::collections::BTreeSet::new",
+            Collection::VecDeque => "std::collections::VecDeque::new",
+        }
+    }
+
+    fn is_map(self) -> bool {
+        matches!(self, Collection::HashMap | Collection::BTreeMap)
+    }
+}
+
+/// Split a std collection type into its kind and type arguments
+///
+/// Maps with a custom hasher aren't recognized, as they can't be built with
+/// `new()`.
+fn split_collection_type(type_hint: &str) -> Option<(Collection, Vec<&str>)> {
+    let (path, args) = split_generic(type_hint)?;
+    let name = ["std::collections::", "alloc::collections::"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .unwrap_or(path);
+    let (collection, arity) = match name {
+        "HashMap" | "hash_map::HashMap" => (Collection::HashMap, 2),
+        "BTreeMap" | "btree_map::BTreeMap" => (Collection::BTreeMap, 2),
+        "HashSet" | "hash_set::HashSet" => (Collection::HashSet, 1),
+        "BTreeSet" | "btree_set::BTreeSet" => (Collection::BTreeSet, 1),
+        "VecDeque" | "vec_deque::VecDeque" => (Collection::VecDeque, 1),
+        _ => return None,
+    };
+    (args.len() == arity).then_some((collection, args))
+}
+
+/// Snapshot value of a map key, which JSON stores as a string
+///
+/// Keys of non-string types are parsed back (`"1"` -> `1`); those that don't
+/// parse are left to serde as strings (enum variants, chars).
+fn map_key_value(key: &str, key_type: &str) -> serde_json::Value {
+    if key_type != "String" {
+        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(key) {
+            if !parsed.is_string() {
+                return parsed;
+            }
+        }
+    }
+    serde_json::Value::String(key.to_string())
+}
+
+/// Tuple expression from its element expressions
+fn tuple_literal(parts: &[String]) -> String {
+    match parts {
+        [single] => format!("({},)", single),
+        _ => format!("({})", parts.join(", ")),
+    }
+}
+
+/// Whether a type is `&str`, with or without a lifetime
+fn is_str_ref(type_hint: &str) -> bool {
+    type_hint.trim().strip_prefix('&').is_some_and(|rest| {
+        let rest = match rest.strip_prefix('\'') {
+            Some(lifetime) => lifetime.trim_start_matches(is_ident_char),
+            None => rest,
+        };
+        rest.trim() == "str"
+    })
+}
+
+/// The owned type restoring `&str`, or a tuple with `&str` elements
+/// replaced by `String`; `None` if there are none
+pub(super) fn owned_str_type(type_hint: &str) -> Option<String> {
+    if is_str_ref(type_hint) {
+        return Some("String".to_string());
+    }
+    let types = split_tuple_types(type_hint)?;
+    if !types.iter().any(|ty| is_str_ref(ty)) {
+        return None;
+    }
+    let owned: Vec<String> = types
+        .iter()
+        .map(|ty| {
+            if is_str_ref(ty) {
+                "String".to_string()
+            } else {
+                ty.to_string()
+            }
+        })
+        .collect();
+    Some(tuple_literal(&owned))
+}
+
+/// Serde's externally tagged encoding of an `enum` snapshot value
+///
+/// Unit variants become `"Active"`, others `{"Pending": 3}` or
+/// `{"Inactive": {"reason": "..."}}`, the default representation of the
+/// `Deserialize` derives added by libgen. `None` if the variant is unknown or
+/// the payload can't be encoded.
+fn externally_tagged_enum(value: &serde_json::Value) -> Option<serde_json::Value> {
+    let variant = value.get("__variant__")?.as_str()?;
+    // "Unknown" is the serializer's placeholder for an undetected variant
+    let is_ident = variant.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && variant.chars().all(is_ident_char);
+    if !is_ident || variant == "Unknown" {
+        return None;
+    }
+    match value.get("__payload__") {
+        None | Some(serde_json::Value::Null) => Some(serde_json::Value::String(variant.into())),
+        Some(payload) => {
+            let mut tagged = serde_json::Map::new();
+            tagged.insert(variant.to_string(), serde_encoding(payload)?);
+            Some(serde_json::Value::Object(tagged))
+        }
+    }
+}
+
+/// The JSON serde produces for a snapshot value, with the serializer's
+/// `__ferrumpy_kind__` metadata resolved
+fn serde_encoding(value: &serde_json::Value) -> Option<serde_json::Value> {
+    let kind = value.get("__ferrumpy_kind__").and_then(|v| v.as_str());
+    let variant = value.get("__variant__").and_then(|v| v.as_str());
+    let inner = || match value.get("__inner__") {
+        Some(inner) => serde_encoding(inner),
+        None => Some(serde_json::Value::Null),
+    };
+    let elements = || {
+        value
+            .get("__elements__")?
+            .as_array()?
+            .iter()
+            .map(serde_encoding)
+            .collect::<Option<Vec<_>>>()
+            .map(serde_json::Value::Array)
+    };
+    match (kind, value) {
+        (Some("enum"), _) => externally_tagged_enum(value),
+        (Some("option"), _) if variant == Some("Some") => inner(),
+        (Some("option"), _) => Some(serde_json::Value::Null),
+        (Some("result"), _) => {
+            let variant = variant.filter(|v| matches!(*v, "Ok" | "Err"))?;
+            let mut tagged = serde_json::Map::new();
+            tagged.insert(variant.to_string(), inner()?);
+            Some(serde_json::Value::Object(tagged))
+        }
+        (Some("tuple" | "array"), _) => elements(),
+        (Some("arc" | "rc" | "box"), _) => serde_encoding(value.get("__inner__")?),
+        (Some(_), _) => None,
+        (None, serde_json::Value::Array(arr)) => arr
+            .iter()
+            .map(serde_encoding)
+            .collect::<Option<Vec<_>>>()
+            .map(serde_json::Value::Array),
+        (None, serde_json::Value::Object(obj)) => obj
+            .iter()
+            .map(|(k, v)| Some((k.clone(), serde_encoding(v)?)))
+            .collect::<Option<serde_json::Map<_, _>>>()
+            .map(serde_json::Value::Object),
+        (None, _) => Some(value.clone()),
+    }
+}
+
+/// Check whether a snapshot value holds the serializer's markers of data it
+/// couldn't read, e.g. a map only known by its summary
+fn has_lost_data(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(obj) => {
+            obj.contains_key("__error__")
+                || obj.contains_key("__summary__")
+                || obj.values().any(has_lost_data)
+        }
+        serde_json::Value::Array(arr) => arr.iter().any(has_lost_data),
+        _ => false,
+    }
+}
+
+/// Check whether a snapshot value holds an enum serde can't deserialize
+fn has_malformed_enum(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(obj) => {
+            if obj.get("__ferrumpy_kind__").and_then(|v| v.as_str()) == Some("enum") {
+                externally_tagged_enum(value).is_none()
+            } else {
+                obj.values().any(has_malformed_enum)
+            }
+        }
+        serde_json::Value::Array(arr) => arr.iter().any(has_malformed_enum),
+        _ => false,
+    }
+}
+
+/// Parse `type_hints` as a JSON object of variable name to Rust type
+fn parse_type_hints(type_hints: &str) -> Result<HashMap<String, String>> {
+    if type_hints.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let expected = "expected a JSON object mapping variable names to Rust types";
+    let hints: serde_json::Value = serde_json::from_str(type_hints)
+        .map_err(|e| anyhow::anyhow!("Invalid type hints ({}): {}", expected, e))?;
+    let hints = hints
+        .as_object()
+        .ok_or_else(|| anyhow::anyhow!("Invalid type hints ({}), got: {}", expected, hints))?;
+    hints
+        .iter()
+        .map(|(name, ty)| match ty.as_str() {
+            Some(ty) => Ok((name.clone(), ty.to_string())),
+            None => anyhow::bail!(
+                "Invalid type hint for `{}`: expected a string, got {}",
+                name,
+                ty
+            ),
+        })
+        .collect()
+}
+
+/// Rust type of a JSON value restored without a declared type
+///
+/// Values with no obvious type, like objects and mixed arrays, stay JSON.
+fn infer_type(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Bool(_) => "bool".to_string(),
+        serde_json::Value::Number(n) if n.is_i64() => "i64".to_string(),
+        serde_json::Value::Number(n) if n.is_u64() => "u64".to_string(),
+        serde_json::Value::Number(_) => "f64".to_string(),
+        serde_json::Value::String(_) => "String".to_string(),
+        serde_json::Value::Array(elements) if !elements.is_empty() => {
+            let element_type = infer_type(&elements[0]);
+            if element_type != "serde_json::Value"
+                && elements[1..].iter().all(|e| infer_type(e) == element_type)
+            {
+                format!("Vec<{}>", element_type)
+            } else {
+                "serde_json::Value".to_string()
+            }
+        }
+        _ => "serde_json::Value".to_string(),
+    }
+}
+
+/// Range of an integer type, `None` for other types
+///
+/// `i128` and `u128` bounds are capped to `i128`; `integer_literal` checks
+/// them by parsing.
+fn integer_range(type_hint: &str) -> Option<(i128, i128)> {
+    Some(match type_hint {
+        "i8" => (i8::MIN.into(), i8::MAX.into()),
+        "i16" => (i16::MIN.into(), i16::MAX.into()),
+        "i32" => (i32::MIN.into(), i32::MAX.into()),
+        "i64" | "isize" => (i64::MIN.into(), i64::MAX.into()),
+        "u8" => (0, u8::MAX.into()),
+        "u16" => (0, u16::MAX.into()),
+        "u32" => (0, u32::MAX.into()),
+        "u64" | "usize" => (0, u64::MAX.into()),
+        "i128" | "u128" => (i128::MIN, i128::MAX),
+        _ => return None,
+    })
+}
+
+/// Literal (without suffix) of an integer of type `type_hint`, from a JSON
+/// number or a decimal string, which is how integers beyond 64 bits are
+/// encoded
+///
+/// Digits are grouped in threes past four of them (`1_000_000`), for
+/// generated code someone may read. `Ok(None)` when the value isn't an
+/// integer at all; an error when it doesn't fit the type, rather than a
+/// literal that wraps or won't compile.
+fn integer_literal(value: &serde_json::Value, type_hint: &str) -> Result<Option<String>> {
+    let text = match value {
+        serde_json::Value::Number(n) if n.is_i64() || n.is_u64() => n.to_string(),
+        serde_json::Value::Number(n) => anyhow::bail!(
+            "{} is not a {}; integers beyond 64 bits must be encoded as strings",
+            n,
+            type_hint
+        ),
+        serde_json::Value::String(s) if is_decimal_integer(s) => s.clone(),
+        _ => return Ok(None),
+    };
+    let fits = match type_hint {
+        "u128" => text.parse::<u128>().is_ok(),
+        "i128" => text.parse::<i128>().is_ok(),
+        _ => match (text.parse::<i128>(), integer_range(type_hint)) {
+            (Ok(n), Some((min, max))) => (min..=max).contains(&n),
+            _ => false,
+        },
+    };
+    if !fits {
+        anyhow::bail!("{} is out of range for {}", text, type_hint);
+    }
+    Ok(Some(group_digits(&text)))
+}
+
+/// `-1234567` as `-1_234_567`; four digits or fewer stay as they are
+fn group_digits(text: &str) -> String {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", text),
+    };
+    if digits.len() <= 4 {
+        return text.to_string();
+    }
+    let mut grouped = String::from(sign);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push('_');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+fn is_decimal_integer(s: &str) -> bool {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Whether the integers of `value`, directly or as `Vec` elements, fit
+/// their type
+fn integers_fit(value: &serde_json::Value, type_hint: &str) -> bool {
+    if integer_range(type_hint).is_some() {
+        return integer_literal(value, type_hint).is_ok();
+    }
+    match (split_single_generic(type_hint), value.as_array()) {
+        (Some(("Vec", inner)), Some(elements)) => elements.iter().all(|e| integers_fit(e, inner)),
+        _ => true,
+    }
+}
+
+/// Opening of a snapshot module: its imports, with types of the parent
+/// scope (user-defined types, companion lib types)
+fn module_header(module_name: &str) -> String {
+    let mut module_code = format!("mod {} {{\n", module_name);
+    module_code.push_str("    use std::sync::OnceLock;\n");
+    module_code.push_str("    use std::collections::HashMap;\n");
+    module_code.push_str("    use std::sync::Arc;\n");
+    module_code.push_str("    use std::rc::Rc;\n");
+    module_code.push_str("    use std::cell::{Cell, RefCell};\n");
+    module_code.push_str("    use std::sync::{Mutex, RwLock};\n");
+    module_code.push_str("    use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};\n");
+    module_code.push_str("    use super::*;\n\n");
+    module_code
+}
+
+/// Struct generated by [`SnapshotCodegen::generate_ctx_struct`]
+pub(super) const CTX_STRUCT_NAME: &str = "__FerrumpyCtx";
+
+/// Name of the fn building a variable's value
+fn init_fn_name(name: &str) -> String {
+    format!("__init_{}", name)
+}
+
+/// End of an isolated variable module: a fn reporting the init fn's error,
+/// the import of the variable, and the expression evaluating to the error
+fn restore_error_tail(name: &str, module_name: &str) -> String {
+    format!(
+        r#"
+    pub fn __restore_error() -> Option<String> {{
+        {}().err().map(|e| e.to_string())
+    }}
+}}
+use {}::{};
+{}::__restore_error()
+"#,
+        init_fn_name(name),
+        module_name,
+        name,
+        module_name
+    )
+}
+
+/// Error reported by the code of [`SnapshotCodegen::generate_isolated_module`],
+/// given the evaluation's output (`None` or `Some("...")`)
+pub(super) fn parse_restore_error(output: &str) -> Option<String> {
+    let message = output.trim().strip_prefix("Some(")?.strip_suffix(')')?;
+    // The message is printed with Debug, which escapes like JSON in practice
+    Some(serde_json::from_str(message).unwrap_or_else(|_| message.to_string()))
+}
+
+/// Split a single-parameter generic type into its path and argument
+fn split_single_generic(type_hint: &str) -> Option<(&str, &str)> {
+    match split_generic(type_hint)? {
+        (path, args) if args.len() == 1 => Some((path, args[0])),
+        _ => None,
+    }
+}
+
+/// Split a smart pointer type (`Arc<T>`, `Rc<T>`, `Box<T>`, bare or
+/// path-qualified) into its constructor and pointee type
+fn split_smart_pointer(type_hint: &str) -> Option<(&'static str, &str)> {
+    let (path, inner_type) = split_single_generic(type_hint)?;
+    let ctor = match path {
+        "Arc" | "std::sync::Arc" | "alloc::sync::Arc" => "std::sync::Arc::new",
+        "Rc" | "std::rc::Rc" | "alloc::rc::Rc" => "std::rc::Rc::new",
+        "Box" | "std::boxed::Box" | "alloc::boxed::Box" => "Box::new",
+        _ => return None,
+    };
+    Some((ctor, inner_type))
+}
+
+/// Split a cell type (`RefCell<T>`, `Cell<T>`, `Mutex<T>`, `RwLock<T>`, bare
+/// or path-qualified) into its constructor and inner type
+fn split_cell_type(type_hint: &str) -> Option<(&'static str, &str)> {
+    let (path, inner_type) = split_single_generic(type_hint)?;
+    let ctor = match path {
+        "RefCell" | "std::cell::RefCell" | "core::cell::RefCell" => "std::cell::RefCell::new",
+        "Cell" | "std::cell::Cell" | "core::cell::Cell" => "std::cell::Cell::new",
+        "Mutex" | "std::sync::Mutex" => "std::sync::Mutex::new",
+        "RwLock" | "std::sync::RwLock" => "std::sync::RwLock::new",
+        _ => return None,
+    };
+    Some((ctor, inner_type))
+}
+
+/// Split any type restored by wrapping its inner value
+fn split_wrapper_type(type_hint: &str) -> Option<(&'static str, &str)> {
+    split_smart_pointer(type_hint).or_else(|| split_cell_type(type_hint))
+}
+
+/// Rust expression for a snapshot float of type `ty` (`f32` or `f64`)
+///
+/// Finite values get the shortest literal reading back as the same bits,
+/// `Debug`'s: `0.1`, `1e-300`, `-0.0`. An `f32` is formatted as the `f32`
+/// the JSON number was widened from, not as that `f64`. Non-finite values
+/// arrive as strings (`"NaN"`, `"inf"`, `"-Infinity"`) and become the type's
+/// constants, as they have no literal form.
+fn float_literal(value: &serde_json::Value, ty: &str) -> Option<String> {
+    if let Some(f) = value.as_f64() {
+        let text = if ty == "f32" {
+            format!("{:?}", f as f32)
+        } else {
+            format!("{:?}", f)
+        };
+        return Some(format!("{}{}", text, ty));
+    }
+    let constant = match value.as_str()?.trim().to_ascii_lowercase().as_str() {
+        "nan" | "+nan" | "-nan" => "NAN",
+        "inf" | "+inf" | "infinity" | "+infinity" => "INFINITY",
+        "-inf" | "-infinity" => "NEG_INFINITY",
+        _ => return None,
+    };
+    Some(format!("{}::{}", ty, constant))
+}
+
+/// Check whether a float, or an element of a float `Vec`, is `null`
+///
+/// serde_json writes non-finite floats as `null`, which can't be restored as
+/// the original type.
+fn has_null_float(value: &serde_json::Value, type_hint: &str) -> bool {
+    match type_hint {
+        "f32" | "f64" => value.is_null(),
+        "Vec<f32>" | "Vec<f64>" => value
+            .as_array()
+            .is_some_and(|arr| arr.iter().any(|v| v.is_null())),
+        _ => false,
+    }
+}
+
+/// Full path of a `PathBuf` or `OsString` type, however it was written
+fn os_string_type(type_hint: &str) -> Option<&'static str> {
+    match type_hint.trim() {
+        "PathBuf" | "std::path::PathBuf" => Some("std::path::PathBuf"),
+        "OsString" | "std::ffi::OsString" | "std::ffi::os_str::OsString" => {
+            Some("std::ffi::OsString")
+        }
+        _ => None,
+    }
+}
+
+/// A `char` literal for a one-character string or a code point
+fn char_literal(value: &serde_json::Value) -> Option<String> {
+    let c = match value {
+        serde_json::Value::String(s) => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return None,
+            }
+        }
+        serde_json::Value::Number(n) => char::from_u32(u32::try_from(n.as_u64()?).ok()?)?,
+        _ => return None,
+    };
+    Some(format!("'{}'", c.escape_default()))
+}
+
+/// Rust string literal for `s`, used to embed JSON in generated code
+///
+/// Usually `r#"..."#`. A raw string gets just enough `#`s that no `"#...`
+/// inside ends it early; when more than one is needed, a plain escaped
+/// literal is used instead if that's shorter.
+fn str_literal(s: &str) -> String {
+    let hashes = s
+        .match_indices('"')
+        .map(|(i, _)| s[i + 1..].bytes().take_while(|&b| b == b'#').count() + 1)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let fence = "#".repeat(hashes);
+    let raw = format!("r{}\"{}\"{}", fence, s, fence);
+    let escaped = format!("{:?}", s);
+    if hashes > 1 && escaped.len() < raw.len() {
+        escaped
+    } else {
+        raw
+    }
+}
+
+/// Check whether a snapshot value is a base64-encoded `Vec<u8>`
+pub(super) fn is_base64_bytes(value: &serde_json::Value, type_hint: &str) -> bool {
+    type_hint == "Vec<u8>"
+        && value.as_str().is_some_and(|s| {
+            s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+        })
+}
+
+/// Generate an expression that decodes a base64 string into a `Vec<u8>`
+fn base64_decode_expr(encoded: &str) -> String {
+    format!(
+        "base64::Engine::decode(&base64::engine::general_purpose::STANDARD, \"{}\")?",
+        encoded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_module_reads_payload_file() {
+        let len = 1_000_000u64;
+        let manifest = serde_json::json!({
+            "variables": {
+                "samples": {"type": "Vec<f64>", "offset": 16, "len": 8 + len * 8},
+                "count": {"type": "usize", "offset": 0, "len": 8}
+            }
+        });
+        let codegen = SnapshotCodegen;
+        let vars = codegen.extract_binary_variables(&manifest).unwrap();
+        assert_eq!(vars.len(), 2);
+        assert_eq!(vars[1].name, "samples");
+        assert_eq!(vars[1].len, 8_000_008);
+
+        let code = codegen
+            .generate_binary_module("/tmp/payload.bin", &vars)
+            .unwrap()
+            .into_code();
+        assert!(
+            code.contains("File::open(r#\"/tmp/payload.bin\"#)"),
+            "Got: {}",
+            code
+        );
+        assert!(code.contains("SeekFrom::Start(16)"), "Got: {}", code);
+        assert!(code.contains("vec![0u8; 8000008]"), "Got: {}", code);
+        assert!(
+            code.contains("bincode::deserialize::<Vec<f64>>"),
+            "Got: {}",
+            code
+        );
+        // Nothing is inlined, whatever the size of the value
+        assert!(code.len() < 4096, "Got {} bytes", code.len());
+
+        let missing = serde_json::json!({"variables": {"x": {"type": "i32", "offset": 0}}});
+        let err = codegen.extract_binary_variables(&missing).unwrap_err();
+        assert!(err.to_string().contains("no len"), "Got: {}", err);
+    }
+
+    #[test]
+    fn test_ctx_struct_fields_match_variables() {
+        let vars = vec![
+            ("config".to_string(), "Config".to_string()),
+            ("ids".to_string(), "Vec<u32>".to_string()),
+            ("ctx".to_string(), "String".to_string()),
+        ];
+        let code = SnapshotCodegen.generate_ctx_struct(&vars);
+
+        let struct_def = code.split("let ctx").next().unwrap();
+        let fields: Vec<&str> = struct_def
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("pub "))
+            .filter(|line| !line.starts_with("struct"))
+            .collect();
+        assert_eq!(
+            fields,
+            vec!["config: Config,", "ids: Vec<u32>,", "ctx: String,"]
+        );
+        assert!(code.contains("    ids: ids().clone(),"), "Got: {}", code);
+        assert!(
+            code.contains("let config: &'static Config = &ctx.config;"),
+            "Got: {}",
+            code
+        );
+        // An alias named `ctx` would shadow the struct
+        assert!(!code.contains("&ctx.ctx"), "Got: {}", code);
+    }
+
+    #[test]
+    fn test_base64_bytes_detection() {
+        let encoded = serde_json::json!("AAEC/w==");
+        assert!(is_base64_bytes(&encoded, "Vec<u8>"));
+        assert!(is_base64_bytes(&serde_json::json!(""), "Vec<u8>"));
+
+        // Plain arrays keep the literal path
+        assert!(!is_base64_bytes(&serde_json::json!([0, 1, 2]), "Vec<u8>"));
+        // Only byte vectors are decoded
+        assert!(!is_base64_bytes(&encoded, "Vec<i32>"));
+        assert!(!is_base64_bytes(&encoded, "String"));
+        // Strings that are not base64 are left alone
+        assert!(!is_base64_bytes(
+            &serde_json::json!("not base64!"),
+            "Vec<u8>"
+        ));
+    }
+
+    #[test]
+    fn test_float_literal() {
+        let literal = |value: serde_json::Value, ty: &str| {
+            let code = float_literal(&value, ty).unwrap();
+            syn::parse_str::<syn::Expr>(&code).unwrap();
+            code
+        };
+        assert_eq!(literal(serde_json::json!(1.5), "f64"), "1.5f64");
+        assert_eq!(literal(serde_json::json!(-2), "f32"), "-2.0f32");
+        assert_eq!(literal(serde_json::json!(3.14), "f64"), "3.14f64");
+        // The f64 JSON holds for an f32 0.1
+        assert_eq!(literal(serde_json::json!(0.1f32 as f64), "f32"), "0.1f32");
+        assert_eq!(literal(serde_json::json!("NaN"), "f64"), "f64::NAN");
+        assert_eq!(literal(serde_json::json!("inf"), "f32"), "f32::INFINITY");
+        assert_eq!(
+            literal(serde_json::json!("Infinity"), "f64"),
+            "f64::INFINITY"
+        );
+        assert_eq!(
+            literal(serde_json::json!("-Infinity"), "f64"),
+            "f64::NEG_INFINITY"
+        );
+        assert!(float_literal(&serde_json::json!("1.5x"), "f64").is_none());
+        assert!(float_literal(&serde_json::Value::Null, "f64").is_none());
+    }
+
+    #[test]
+    fn test_float_literal_round_trips() {
+        let f64_values = [
+            0.1,
+            1e-300,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            5e-324,
+            f64::MAX,
+            1.0 / 3.0,
+            123456789.125,
+        ];
+        for f in f64_values {
+            let code = float_literal(&serde_json::json!(f), "f64").unwrap();
+            syn::parse_str::<syn::Expr>(&code).unwrap();
+            let parsed: f64 = code.strip_suffix("f64").unwrap().parse().unwrap();
+            assert_eq!(parsed.to_bits(), f.to_bits(), "Got: {}", code);
+        }
+
+        let f32_values = [0.1f32, -0.0, f32::MIN_POSITIVE, 1e-45, f32::MAX, 16777217.0];
+        for f in f32_values {
+            let code = float_literal(&serde_json::json!(f as f64), "f32").unwrap();
+            syn::parse_str::<syn::Expr>(&code).unwrap();
+            let parsed: f32 = code.strip_suffix("f32").unwrap().parse().unwrap();
+            assert_eq!(parsed.to_bits(), f.to_bits(), "Got: {}", code);
+        }
+        assert_eq!(
+            float_literal(&serde_json::json!(-0.0), "f64").as_deref(),
+            Some("-0.0f64")
+        );
+        assert_eq!(
+            float_literal(&serde_json::json!(1e-300), "f64").as_deref(),
+            Some("1e-300f64")
+        );
+    }
+
+    #[test]
+    fn test_big_integer_literals() {
+        let gen = |value: serde_json::Value, ty: &str| {
+            SnapshotCodegen
+                .generate_value_init_expr(&value, ty)
+                .unwrap()
+        };
+        assert_eq!(
+            gen(serde_json::json!(u64::MAX), "u64"),
+            "18_446_744_073_709_551_615u64"
+        );
+        assert_eq!(
+            gen(serde_json::json!("18446744073709551615"), "u64"),
+            "18_446_744_073_709_551_615u64"
+        );
+        assert_eq!(
+            gen(serde_json::json!(u128::MAX.to_string()), "u128"),
+            "340_282_366_920_938_463_463_374_607_431_768_211_455u128"
+        );
+        assert_eq!(
+            gen(serde_json::json!(i128::MIN.to_string()), "i128"),
+            "-170_141_183_460_469_231_731_687_303_715_884_105_728i128"
+        );
+        assert_eq!(gen(serde_json::json!(-5), "i8"), "-5i8");
+        assert_eq!(gen(serde_json::json!(8080), "u16"), "8080u16");
+        assert_eq!(gen(serde_json::json!(-12345), "i32"), "-12_345i32");
+        assert_eq!(
+            gen(serde_json::json!([u128::MAX.to_string(), 2]), "Vec<u128>"),
+            "vec![340_282_366_920_938_463_463_374_607_431_768_211_455, 2]"
+        );
+
+        // A u128 beyond 64 bits sent as a JSON number has lost precision
+        let err = SnapshotCodegen
+            .generate_value_init_expr(&serde_json::json!(3.4e38), "u128")
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("encoded as strings"),
+            "Got: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_integer_is_reported() {
+        let err = integer_literal(&serde_json::json!(300), "u8").unwrap_err();
+        assert_eq!(err.to_string(), "300 is out of range for u8");
+        assert!(integer_literal(&serde_json::json!(-1), "u64").is_err());
+        assert!(integer_literal(&serde_json::json!(u128::MAX.to_string()), "i128").is_err());
+        assert!(integer_literal(&serde_json::json!("12ab"), "u64")
+            .unwrap()
+            .is_none());
+
+        let snapshot = serde_json::json!({
+            "variables": {"small": 300, "hash": "18446744073709551615", "ids": [1, -1]},
+            "types": {"small": "u8", "hash": "u64", "ids": "Vec<u32>"}
+        });
+        let mut adjusted = Vec::new();
+        let vars = SnapshotCodegen
+            .extract_variables(&snapshot, &mut adjusted)
+            .unwrap();
+        let types: Vec<_> = vars
+            .iter()
+            .map(|(n, _, t)| (n.as_str(), t.as_str()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("hash", "u64"),
+                ("ids", "serde_json::Value"),
+                ("small", "serde_json::Value")
+            ]
+        );
+        let adjusted: Vec<_> = adjusted
+            .iter()
+            .map(|(n, d, _)| (n.as_str(), d.as_str()))
+            .collect();
+        assert_eq!(adjusted, vec![("ids", "Vec<u32>"), ("small", "u8")]);
+    }
+
+    #[test]
+    fn test_has_null_float() {
+        assert!(has_null_float(&serde_json::Value::Null, "f64"));
+        assert!(has_null_float(&serde_json::json!([1.0, null]), "Vec<f32>"));
+        assert!(!has_null_float(
+            &serde_json::json!([1.0, "NaN"]),
+            "Vec<f64>"
+        ));
+        assert!(!has_null_float(&serde_json::Value::Null, "Option<f64>"));
+    }
+
+    #[test]
+    fn test_str_literal_hashes() {
+        assert_eq!(str_literal(r#"{"a":1}"#), r##"r#"{"a":1}"#"##);
+        assert_eq!(str_literal("3"), r##"r#"3"#"##);
+        let json = r###"{"src":"r#\"x\"#","re":"\"##"}"###;
+        assert!(str_literal(json).starts_with(r#"r###"{"#));
+        // Escaping wins when a longer fence would cost more than it saves
+        assert_eq!(str_literal(r##"a"#b"##), r##""a\"#b""##);
+
+        for s in [json, r##"a"#b"##, "\\", ""] {
+            let lit: syn::LitStr = syn::parse_str(&str_literal(s)).unwrap();
+            assert_eq!(lit.value(), s);
+        }
+    }
+
+    #[test]
+    fn test_json_with_raw_string_delimiters() {
+        let value = serde_json::json!({"src": "let s = r#\"hi\"#;", "re": "\"##"});
+        let code = SnapshotCodegen
+            .generate_value_init_expr(&value, "Snippet")
+            .unwrap();
+        let literal = code
+            .strip_prefix("serde_json::from_str::<Snippet>(")
+            .and_then(|rest| rest.strip_suffix(")?"))
+            .unwrap();
+        let expr: syn::LitStr = syn::parse_str(literal).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&expr.value()).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn test_base64_decode_expr() {
+        assert_eq!(
+            base64_decode_expr("AAEC/w=="),
+            "base64::Engine::decode(&base64::engine::general_purpose::STANDARD, \"AAEC/w==\")?"
+        );
+    }
+
+    #[test]
+    fn test_split_smart_pointer() {
+        assert_eq!(
+            split_smart_pointer("Arc<Vec<User>>"),
+            Some(("std::sync::Arc::new", "Vec<User>"))
+        );
+        assert_eq!(
+            split_smart_pointer("alloc::rc::Rc<str>"),
+            Some(("std::rc::Rc::new", "str"))
+        );
+        assert_eq!(split_smart_pointer("Vec<Box<i32>>"), None);
+        assert_eq!(split_smart_pointer("Box"), None);
+    }
+
+    #[test]
+    fn test_generate_smart_pointer_code() {
+        let gen = |value: serde_json::Value, ty: &str| {
+            SnapshotCodegen
+                .generate_value_init_expr(&value, ty)
+                .unwrap()
+        };
+        assert_eq!(
+            gen(serde_json::json!({"name": "ann"}), "Arc<User>"),
+            r##"std::sync::Arc::new(serde_json::from_str::<User>(r#"{"name":"ann"}"#)?)"##
+        );
+        assert_eq!(
+            gen(serde_json::json!("hi"), "Box<String>"),
+            "Box::new(\"hi\".to_string())"
+        );
+        assert_eq!(
+            gen(serde_json::json!([1, 2]), "Rc<Vec<i32>>"),
+            "std::rc::Rc::new(vec![1, 2])"
+        );
+        assert_eq!(gen(serde_json::json!("hi"), "Box<str>"), "\"hi\".into()");
+        assert_eq!(
+            gen(serde_json::json!([1, 2]), "Arc<[u8]>"),
+            "vec![1, 2].into()"
+        );
+        assert_eq!(
+            gen(serde_json::json!(7), "Arc<Box<u8>>"),
+            "std::sync::Arc::new(Box::new(7u8))"
+        );
+        // Same code through the __ferrumpy_kind__ metadata
+        assert_eq!(
+            gen(
+                serde_json::json!({"__ferrumpy_kind__": "rc", "__inner__": [1, 2]}),
+                "Rc<Vec<i32>>"
+            ),
+            "std::rc::Rc::new(vec![1, 2])"
+        );
+    }
+
+    #[test]
+    fn test_generate_cell_code() {
+        let gen = |value: serde_json::Value, ty: &str| {
+            SnapshotCodegen
+                .generate_value_init_expr(&value, ty)
+                .unwrap()
+        };
+        assert_eq!(
+            gen(serde_json::json!([1, 2]), "RefCell<Vec<i32>>"),
+            "std::cell::RefCell::new(vec![1, 2])"
+        );
+        assert_eq!(
+            gen(serde_json::json!(3), "Cell<u8>"),
+            "std::cell::Cell::new(3u8)"
+        );
+        assert_eq!(
+            gen(serde_json::json!("hi"), "std::sync::RwLock<String>"),
+            "std::sync::RwLock::new(\"hi\".to_string())"
+        );
+        assert_eq!(
+            gen(serde_json::json!({"debug": true}), "Arc<Mutex<Config>>"),
+            r##"std::sync::Arc::new(std::sync::Mutex::new(serde_json::from_str::<Config>(r#"{"debug":true}"#)?))"##
+        );
+    }
+
+    #[test]
+    fn test_cell_types_are_supported() {
+        assert!(SnapshotCodegen.is_supported_type("RefCell<Vec<i32>>"));
+        assert!(SnapshotCodegen.is_supported_type("Arc<Mutex<Config>>"));
+        assert!(SnapshotCodegen.is_supported_type("Rc<RefCell<String>>"));
+        assert!(!SnapshotCodegen.is_supported_type("Mutex<&str>"));
+        assert!(!SnapshotCodegen.is_supported_type("Vec<RefCell<i32>>"));
+    }
+
+    fn enum_value(variant: &str, payload: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "__ferrumpy_kind__": "enum",
+            "__enum_type__": "Status",
+            "__variant__": variant,
+            "__payload__": payload,
+        })
+    }
+
+    #[test]
+    fn test_generate_enum_code() {
+        let gen = |value: serde_json::Value, ty: &str| {
+            SnapshotCodegen
+                .generate_value_init_expr(&value, ty)
+                .unwrap()
+        };
+        assert_eq!(
+            gen(enum_value("Active", serde_json::Value::Null), "Status"),
+            r##"serde_json::from_str::<Status>(r#""Active""#)?"##
+        );
+        assert_eq!(
+            gen(enum_value("Pending", serde_json::json!(3)), "Status"),
+            r##"serde_json::from_str::<Status>(r#"{"Pending":3}"#)?"##
+        );
+        assert_eq!(
+            gen(enum_value("Moved", serde_json::json!([1, 2])), "Status"),
+            r##"serde_json::from_str::<Status>(r#"{"Moved":[1,2]}"#)?"##
+        );
+        assert_eq!(
+            gen(
+                enum_value("Inactive", serde_json::json!({"reason": "idle"})),
+                "Status"
+            ),
+            r##"serde_json::from_str::<Status>(r#"{"Inactive":{"reason":"idle"}}"#)?"##
+        );
+        // Payload metadata is resolved to serde's encoding too
+        let payload = serde_json::json!({
+            "__ferrumpy_kind__": "option", "__variant__": "Some", "__inner__": "x"
+        });
+        assert_eq!(
+            gen(enum_value("Named", payload), "Status"),
+            r##"serde_json::from_str::<Status>(r#"{"Named":"x"}"#)?"##
+        );
+        let option = serde_json::json!({
+            "__ferrumpy_kind__": "option",
+            "__variant__": "Some",
+            "__inner__": enum_value("Pending", serde_json::json!(3)),
+        });
+        assert_eq!(
+            gen(option, "Option<Status>"),
+            r##"Some(serde_json::from_str::<Status>(r#"{"Pending":3}"#)?)"##
+        );
+    }
+
+    #[test]
+    fn test_malformed_enum_falls_back_to_value() {
+        let malformed = enum_value("Unknown", serde_json::json!(3));
+        assert!(SnapshotCodegen
+            .generate_value_init_expr(&malformed, "Status")
+            .is_err());
+        let nested = serde_json::json!({
+            "__ferrumpy_kind__": "option", "__variant__": "Some", "__inner__": malformed,
+        });
+        assert!(!SnapshotCodegen.is_valid_for_deserialization(&nested, "Option<Status>"));
+
+        let snapshot = serde_json::json!({
+            "variables": {
+                "ok": enum_value("Active", serde_json::Value::Null),
+                "bad": nested,
+            },
+            "types": {"ok": "app::Status", "bad": "Option<app::Status>"}
+        });
+        let mut adjusted = Vec::new();
+        let vars = SnapshotCodegen
+            .extract_variables(&snapshot, &mut adjusted)
+            .unwrap();
+        let types: Vec<_> = vars
+            .iter()
+            .map(|(n, _, t)| (n.as_str(), t.as_str()))
+            .collect();
+        assert_eq!(types, vec![("bad", "serde_json::Value"), ("ok", "Status")]);
+        assert_eq!(
+            adjusted,
+            vec![(
+                "bad".to_string(),
+                "Option<app::Status>".to_string(),
+                "serde_json::Value".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_type_hint_overrides_snapshot_type() {
+        let mut snapshot = serde_json::json!({
+            "variables": {"port": 8080, "name": "db", "ptr": "0x10", "ids": [1, 2], "raw": {"a": 1}},
+            "types": {"port": "i8", "name": "String", "ptr": "*const u8"}
+        });
+        let hints = r#"{"port": "u16", "ptr": "&u8", "missing": "bool"}"#;
+        let sources = SnapshotCodegen
+            .merge_type_hints(&mut snapshot, hints)
+            .unwrap();
+
+        assert_eq!(snapshot["types"]["port"], "u16");
+        assert_eq!(sources["port"], TypeSource::Hint);
+        assert_eq!(snapshot["types"]["name"], "String");
+        assert_eq!(sources["name"], TypeSource::Snapshot);
+        // Neither type is supported, so the hint is kept for the JSON fallback
+        assert_eq!(snapshot["types"]["ptr"], "&u8");
+        assert_eq!(sources["ptr"], TypeSource::Hint);
+        assert_eq!(snapshot["types"]["ids"], "Vec<i64>");
+        assert_eq!(snapshot["types"]["raw"], "serde_json::Value");
+        assert_eq!(sources["raw"], TypeSource::Inferred);
+        assert!(!sources.contains_key("missing"));
+
+        let vars = SnapshotCodegen
+            .extract_variables(&snapshot, &mut Vec::new())
+            .unwrap();
+        assert!(vars.iter().any(|(n, _, t)| n == "port" && t == "u16"));
+
+        // An unsupported hint gives way to a supported snapshot type
+        let mut snapshot = serde_json::json!({
+            "variables": {"port": 8080},
+            "types": {"port": "u16"}
+        });
+        let sources = SnapshotCodegen
+            .merge_type_hints(&mut snapshot, r#"{"port": "*const u16"}"#)
+            .unwrap();
+        assert_eq!(snapshot["types"]["port"], "u16");
+        assert_eq!(sources["port"], TypeSource::Snapshot);
+    }
+
+    #[test]
+    fn test_malformed_type_hints() {
+        let mut snapshot = serde_json::json!({"variables": {"port": 1}});
+        for (hints, expected) in [
+            ("port:u16", "Invalid type hints (expected a JSON object"),
+            ("[\"u16\"]", "Invalid type hints (expected a JSON object"),
+            (
+                r#"{"port": 16}"#,
+                "Invalid type hint for `port`: expected a string",
+            ),
+        ] {
+            let err = SnapshotCodegen
+                .merge_type_hints(&mut snapshot, hints)
+                .unwrap_err()
+                .to_string();
+            assert!(err.starts_with(expected), "Got: {}", err);
+        }
+        // No hints at all
+        assert!(SnapshotCodegen.merge_type_hints(&mut snapshot, "").is_ok());
+        assert_eq!(snapshot["types"]["port"], "i64");
+    }
+
+    #[test]
+    fn test_is_default_type() {
+        let codegen = SnapshotCodegen;
+        assert!(codegen.is_default_type("i32"));
+        assert!(codegen.is_default_type("(String, Vec<User>)"));
+        assert!(codegen.is_default_type("HashMap<String, Option<User>>"));
+        assert!(codegen.is_default_type("Arc<Mutex<String>>"));
+        assert!(!codegen.is_default_type("User"));
+        assert!(!codegen.is_default_type("Box<User>"));
+        assert!(!codegen.is_default_type("HashMap<String, User>"));
+        assert!(!codegen.is_default_type("Result<i32, String>"));
+    }
+
+    #[test]
+    fn test_restore_strategy_for_mixed_snapshot() {
+        let snapshot = serde_json::json!({
+            "variables": {
+                "count": 3,
+                "shared": "hi",
+                "user": {"name": "ann"},
+                "ptr": "0x7ffee4b0",
+            },
+            "types": {
+                "count": "i32",
+                "shared": "Arc<String>",
+                "user": "app::User",
+                "ptr": "*const u8",
+            },
+        });
+        let vars = SnapshotCodegen
+            .extract_variables(&snapshot, &mut Vec::new())
+            .unwrap();
+        let strategies: Vec<(&str, &str, RestoreStrategy)> = vars
+            .iter()
+            .map(|(name, value, ty)| {
+                (
+                    name.as_str(),
+                    ty.as_str(),
+                    SnapshotCodegen.restore_strategy(value, ty),
+                )
+            })
+            .collect();
+        assert_eq!(
+            strategies,
+            vec![
+                ("count", "i32", RestoreStrategy::Literal),
+                ("ptr", "serde_json::Value", RestoreStrategy::Serde),
+                ("shared", "Arc<String>", RestoreStrategy::Wrapper),
+                ("user", "User", RestoreStrategy::Serde),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generated_items_recover_from_bad_json() {
+        let codegen = SnapshotCodegen;
+        let vars = vec![
+            ("count".to_string(), serde_json::json!(3), "i32".to_string()),
+            (
+                "user".to_string(),
+                serde_json::json!({"name": 1}),
+                "User".to_string(),
+            ),
+            (
+                "name".to_string(),
+                serde_json::json!("ann"),
+                "String".to_string(),
+            ),
+        ];
+        let (batch, isolated): (Vec<_>, Vec<_>) = vars
+            .into_iter()
+            .partition(|(_, _, ty)| codegen.is_default_type(ty));
+        assert_eq!(isolated.len(), 1);
+
+        let module = codegen
+            .generate_snapshot_module(&batch)
+            .unwrap()
+            .into_code();
+        syn::parse_file(&module).unwrap();
+        assert!(!module.contains(".unwrap()"), "Got: {}", module);
+        assert!(
+            module.contains("fn __init_name() -> Result<String, Box<dyn std::error::Error>>"),
+            "Got: {}",
+            module
+        );
+        assert!(module.contains("Default::default()"), "Got: {}", module);
+
+        let (name, value, ty) = &isolated[0];
+        let code = codegen.generate_isolated_module(name, value, ty).unwrap();
+        let (items, check) = code.trim_end().rsplit_once('\n').unwrap();
+        syn::parse_file(items).unwrap();
+        assert_eq!(check, "ferrumpy_var_user::__restore_error()");
+        assert!(
+            items.contains("use ferrumpy_var_user::user;"),
+            "Got: {}",
+            items
+        );
+        assert!(items.contains("panic!("), "Got: {}", items);
+        assert!(
+            items.contains(r##"Ok(serde_json::from_str::<User>(r#"{"name":1}"#)?)"##),
+            "Got: {}",
+            items
+        );
+    }
+
+    #[test]
+    fn test_typed_fallback_for_borderline_values() {
+        let codegen = SnapshotCodegen;
+        let snapshot = serde_json::json!({
+            "variables": {
+                "name": "",
+                "meta": {"__version__": 2, "label": "x"},
+                "lost": {"__type__": "Meta", "__error__": "Could not read"},
+                "big": 300,
+            },
+            "types": {
+                "name": "&str",
+                "meta": "app::Meta",
+                "lost": "app::Meta",
+                "big": "u8",
+            },
+        });
+        let vars = codegen
+            .extract_variables(&snapshot, &mut Vec::new())
+            .unwrap();
+        let typed: Vec<(&str, &str, Option<String>)> = vars
+            .iter()
+            .map(|(name, value, ty)| {
+                let declared = snapshot["types"][name.as_str()].as_str().unwrap();
+                (
+                    name.as_str(),
+                    ty.as_str(),
+                    codegen.typed_fallback_type(value, declared),
+                )
+            })
+            .collect();
+        // Empty strings and dunder fields only fail the checks made ahead of
+        // time, and are worth deserializing as their type
+        assert_eq!(
+            typed,
+            vec![
+                ("big", "serde_json::Value", None),
+                ("lost", "serde_json::Value", None),
+                ("meta", "serde_json::Value", Some("Meta".to_string())),
+                ("name", "serde_json::Value", Some("String".to_string())),
+            ]
+        );
+
+        let code = codegen
+            .generate_typed_attempt_module("name", &serde_json::json!(""), "String")
+            .unwrap();
+        assert!(
+            code.contains("serde_json::from_str::<String>(r#\"\"\"\"#)?"),
+            "Got: {}",
+            code
+        );
+        assert!(
+            code.trim_end()
+                .ends_with("ferrumpy_var_name::__restore_error()"),
+            "Got: {}",
+            code
+        );
+    }
+
+    #[test]
+    fn test_parse_restore_error() {
+        assert_eq!(parse_restore_error("None"), None);
+        assert_eq!(
+            parse_restore_error("Some(\"invalid type: \\\"x\\\"\")\n"),
+            Some("invalid type: \"x\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_split_collection_type() {
+        assert_eq!(
+            split_collection_type("std::collections::HashMap<u32, Vec<(i32, i32)>>"),
+            Some((Collection::HashMap, vec!["u32", "Vec<(i32, i32)>"]))
+        );
+        assert_eq!(
+            split_collection_type("HashSet<String>"),
+            Some((Collection::HashSet, vec!["String"]))
+        );
+        assert_eq!(
+            split_collection_type("HashMap<String, i32, MyHasher>"),
+            None
+        );
+        assert_eq!(split_collection_type("Vec<i32>"), None);
+    }
+
+    #[test]
+    fn test_generate_collection_code() {
+        let gen = |value: serde_json::Value, ty: &str| {
+            let code = SnapshotCodegen
+                .generate_value_init_expr(&value, ty)
+                .unwrap();
+            syn::parse_str::<syn::Expr>(&code).unwrap();
+            code
+        };
+        assert_eq!(
+            gen(
+                serde_json::json!({"1": "one", "2": "two"}),
+                "HashMap<u32, String>"
+            ),
+            "{ let mut m = std::collections::HashMap::new(); \
+             m.insert(1u32, \"one\".to_string()); m.insert(2u32, \"two\".to_string()); m }"
+        );
+        assert_eq!(
+            gen(serde_json::json!({"1": 1}), "BTreeMap<String, i64>"),
+            "{ let mut m = std::collections::BTreeMap::new(); m.insert(\"1\".to_string(), 1i64); m }"
+        );
+        assert_eq!(
+            gen(serde_json::json!([[-1, true]]), "HashMap<i8, bool>"),
+            "{ let mut m = std::collections::HashMap::new(); m.insert(-1i8, true); m }"
+        );
+        assert_eq!(
+            gen(serde_json::json!(["a", "b"]), "HashSet<String>"),
+            "{ let mut m = std::collections::HashSet::new(); \
+             m.insert(\"a\".to_string()); m.insert(\"b\".to_string()); m }"
+        );
+        assert_eq!(
+            gen(serde_json::json!([1, 2]), "VecDeque<u8>"),
+            "{ let mut m = std::collections::VecDeque::new(); m.push_back(1u8); m.push_back(2u8); m }"
+        );
+        assert_eq!(
+            gen(serde_json::json!([]), "BTreeSet<i32>"),
+            "{ let mut m = std::collections::BTreeSet::new(); m }"
+        );
+        assert_eq!(
+            gen(
+                serde_json::json!({"__ferrumpy_kind__": "hashmap", "__pairs__": [[[1, 2], "b"]]}),
+                "BTreeMap<(u8, u8), String>"
+            ),
+            "{ let mut m = std::collections::BTreeMap::new(); \
+             m.insert((1, 2), \"b\".to_string()); m }"
+        );
+    }
+
+    #[test]
+    fn test_btree_map_and_set_variables() {
+        let snapshot = serde_json::json!({
+            "variables": {
+                "scores": {"bob": 7, "ann": 9},
+                "ids": [3, 1, 2],
+                "unread": {"__ferrumpy_kind__": "hashmap", "__error__": "Could not extract HashMap contents"}
+            },
+            "types": {
+                "scores": "BTreeMap<String, i32>",
+                "ids": "HashSet<i32>",
+                "unread": "BTreeMap<u32, bool>"
+            }
+        });
+        let vars = SnapshotCodegen
+            .extract_variables(&snapshot, &mut Vec::new())
+            .unwrap();
+        let types: Vec<(&str, &str)> = vars
+            .iter()
+            .map(|(name, _, ty)| (name.as_str(), ty.as_str()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("ids", "HashSet<i32>"),
+                ("scores", "BTreeMap<String, i32>"),
+                ("unread", "serde_json::Value"),
+            ]
+        );
+        let code = SnapshotCodegen
+            .generate_snapshot_module(&vars)
+            .unwrap()
+            .into_code();
+        assert!(
+            code.contains(
+                "{ let mut m = std::collections::HashSet::new(); \
+                 m.insert(1i32); m.insert(2i32); m.insert(3i32); m }"
+            ),
+            "Got: {}",
+            code
+        );
+        assert!(
+            code.contains(
+                "m.insert(\"ann\".to_string(), 9i32); m.insert(\"bob\".to_string(), 7i32);"
+            ),
+            "Got: {}",
+            code
+        );
+    }
+
+    #[test]
+    fn test_map_key_value() {
+        assert_eq!(map_key_value("42", "u32"), serde_json::json!(42));
+        assert_eq!(map_key_value("42", "String"), serde_json::json!("42"));
+        assert_eq!(map_key_value("Red", "Color"), serde_json::json!("Red"));
+    }
+
+    #[test]
+    fn test_split_tuple_types() {
+        assert_eq!(
+            split_tuple_types("(HashMap<String, i32>, (u8, u8), [f64; 2])"),
+            Some(vec!["HashMap<String, i32>", "(u8, u8)", "[f64; 2]"])
+        );
+        assert_eq!(split_tuple_types("(i32,)"), Some(vec!["i32"]));
+        assert_eq!(split_tuple_types("()"), Some(vec![]));
+        assert_eq!(split_tuple_types("Vec<(i32, i32)>"), None);
+    }
+
+    #[test]
+    fn test_generate_plain_tuple_code() {
+        let gen = |value: serde_json::Value, ty: &str| {
+            SnapshotCodegen
+                .generate_value_init_expr(&value, ty)
+                .unwrap()
+        };
+        assert_eq!(
+            gen(serde_json::json!([1, true]), "(i32, bool)"),
+            "(1, true)"
+        );
+        let code = gen(serde_json::json!(["first", 2, 3.14]), "(String, i32, f64)");
+        assert_eq!(code, "(\"first\".to_string(), 2, 3.14f64)");
+        let float = code.trim_end_matches(')').rsplit(", ").next().unwrap();
+        assert_eq!(float.trim_end_matches("f64").parse::<f64>().unwrap(), 3.14);
+        assert_eq!(gen(serde_json::json!([7]), "(u8,)"), "(7,)");
+        assert!(SnapshotCodegen
+            .generate_value_init_expr(&serde_json::json!([1]), "(i32, i32)")
+            .is_err());
+    }
+
+    #[test]
+    fn test_tuple_str_refs_become_owned() {
+        assert_eq!(
+            owned_str_type("(&str, i32, f64)").as_deref(),
+            Some("(String, i32, f64)")
+        );
+        assert_eq!(
+            owned_str_type("(&'static str,)").as_deref(),
+            Some("(String,)")
+        );
+        assert_eq!(owned_str_type("(String, i32)"), None);
+        assert_eq!(owned_str_type("&'a str").as_deref(), Some("String"));
+
+        let snapshot = serde_json::json!({
+            "variables": {"tuple": ["first", 2, 3.14], "pair": [1, 2]},
+            "types": {"tuple": "(&str, i32, f64)", "pair": "(i32, i32)"}
+        });
+        let mut adjusted = Vec::new();
+        let vars = SnapshotCodegen
+            .extract_variables(&snapshot, &mut adjusted)
+            .unwrap();
+        let tuple = vars.iter().find(|(name, _, _)| name == "tuple").unwrap();
+        assert_eq!(tuple.2, "(String, i32, f64)");
+        assert_eq!(
+            adjusted,
+            vec![(
+                "tuple".to_string(),
+                "(&str, i32, f64)".to_string(),
+                "(String, i32, f64)".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_nested_generic_literals() {
+        let gen = |value: serde_json::Value, ty: &str| {
+            SnapshotCodegen
+                .generate_value_init_expr(&value, ty)
+                .unwrap()
+        };
+        let code = gen(serde_json::json!([[1], [2, 3]]), "Vec<Vec<i32>>");
+        assert!(code.starts_with("vec!["), "Got: {}", code);
+        assert!(code.contains("::<Vec<i32>>(r#\"[2,3]\"#)"), "Got: {}", code);
+        let result = serde_json::json!({
+            "__ferrumpy_kind__": "result",
+            "__variant__": "Ok",
+            "__inner__": {"a": [1]}
+        });
+        assert_eq!(
+            SnapshotCodegen.parse_result_types("Result<HashMap<String, Vec<i32>>, String>"),
+            (
+                "HashMap<String, Vec<i32>>".to_string(),
+                "String".to_string()
+            )
+        );
+        let code = gen(result, "Result<HashMap<String, Vec<i32>>, String>");
+        assert!(
+            code.starts_with("Ok({ let mut m = std::collections::HashMap::new(); m.insert("),
+            "Got: {}",
+            code
+        );
+        assert!(
+            code.contains("m.insert(\"a\".to_string(), vec![1])"),
+            "Got: {}",
+            code
+        );
+    }
+
+    #[test]
+    fn test_same_snapshot_generates_same_code() {
+        // The same contents, as two runs may serialize them
+        let snapshot = |ids: serde_json::Value, tags: serde_json::Value| {
+            serde_json::json!({
+                "variables": {"ids": ids, "tags": tags, "count": 3},
+                "types": {
+                    "ids": "HashMap<u32, String>",
+                    "tags": "HashSet<String>",
+                    "count": "i32"
+                }
+            })
+        };
+        let first = snapshot(
+            serde_json::json!([[1, "a"], [2, "b"]]),
+            serde_json::json!(["x", "y"]),
+        );
+        let second = snapshot(
+            serde_json::json!([[2, "b"], [1, "a"]]),
+            serde_json::json!(["y", "x"]),
+        );
+        let code = |snapshot: &serde_json::Value| {
+            let vars = SnapshotCodegen
+                .extract_variables(snapshot, &mut Vec::new())
+                .unwrap();
+            let names: Vec<&str> = vars.iter().map(|(name, _, _)| name.as_str()).collect();
+            assert_eq!(names, vec!["count", "ids", "tags"]);
+            SnapshotCodegen
+                .generate_snapshot_module(&vars)
+                .unwrap()
+                .into_code()
+        };
+
+        let code = [code(&first), code(&second)];
+        assert_eq!(code[0], code[1]);
+        assert!(
+            code[0]
+                .contains("m.insert(1u32, \"a\".to_string()); m.insert(2u32, \"b\".to_string());"),
+            "Got: {}",
+            code[0]
+        );
+    }
+
+    #[test]
+    fn test_char_str_and_path_locals() {
+        let snapshot = serde_json::json!({
+            "variables": {
+                "initial": "é",
+                "newline": "\n",
+                "code": 65,
+                "name": "boxed",
+                "dir": "/tmp/it's \"here\"",
+                "word": "two chars"
+            },
+            "types": {
+                "initial": "char",
+                "newline": "char",
+                "code": "char",
+                "name": "&str",
+                "dir": "PathBuf",
+                "word": "char"
+            }
+        });
+        let mut adjusted = Vec::new();
+        let vars = SnapshotCodegen
+            .extract_variables(&snapshot, &mut adjusted)
+            .unwrap();
+        let code: HashMap<_, _> = vars
+            .iter()
+            .map(|(name, value, ty)| {
+                let init = SnapshotCodegen.generate_value_init_expr(value, ty).unwrap();
+                (name.as_str(), (ty.as_str(), init))
+            })
+            .collect();
+
+        assert_eq!(code["initial"], ("char", "'\\u{e9}'".to_string()));
+        assert_eq!(code["newline"], ("char", "'\\n'".to_string()));
+        assert_eq!(code["code"], ("char", "'A'".to_string()));
+        assert_eq!(
+            code["name"],
+            ("String", "\"boxed\".to_string()".to_string())
+        );
+        assert_eq!(code["dir"].0, "std::path::PathBuf");
+        assert!(
+            code["dir"].1.starts_with("std::path::PathBuf::from(r"),
+            "Got: {}",
+            code["dir"].1
+        );
+        assert!(syn::parse_str::<syn::Expr>(&code["dir"].1).is_ok());
+        assert_eq!(code["word"].0, "serde_json::Value");
+
+        assert_eq!(
+            adjusted,
+            vec![
+                ("name".to_string(), "&str".to_string(), "String".to_string()),
+                (
+                    "word".to_string(),
+                    "char".to_string(),
+                    "serde_json::Value".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fix_user_type_path() {
+        let fix = |ty: &str| SnapshotCodegen.fix_user_type_path(ty);
+        assert_eq!(
+            fix("std::collections::HashMap<String, i32>"),
+            "HashMap<String, i32>"
+        );
+        assert_eq!(
+            fix("Result<i32, std::io::Error>"),
+            "Result<i32, std::io::Error>"
+        );
+        assert_eq!(fix("my_crate::Inner<other_crate::Thing>"), "Inner<Thing>");
+        assert_eq!(
+            fix("std::collections::HashMap<app::model::UserId, std::collections::LinkedList<app::Tag>>"),
+            "HashMap<UserId, std::collections::LinkedList<Tag>>"
+        );
+        assert_eq!(
+            fix("core::result::Result<alloc::vec::Vec<app::User>, alloc::ffi::CString>"),
+            "Result<Vec<User>, std::ffi::CString>"
+        );
+        assert_eq!(fix("(app::A, [app::B; 2])"), "(A, [B; 2])");
+        assert_eq!(fix("::app::User"), "User");
+        assert_eq!(fix("std::time::Duration"), "std::time::Duration");
+        assert_eq!(
+            fix("Option<serde_json::Value>"),
+            "Option<serde_json::Value>"
+        );
+        assert_eq!(fix("Vec<i32>"), "Vec<i32>");
+        assert_eq!(fix("std::collections::BTreeSet<app::Tag>"), "BTreeSet<Tag>");
+        assert_eq!(
+            fix("alloc::collections::btree::map::BTreeMap<u8, alloc::string::String>"),
+            "BTreeMap<u8, String>"
+        );
+        assert_eq!(
+            fix("std::collections::hash::set::HashSet<i32>"),
+            "HashSet<i32>"
+        );
+        assert_eq!(
+            fix("alloc::collections::vec_deque::VecDeque<(app::A,)>"),
+            "VecDeque<(A,)>"
+        );
+        assert_eq!(fix("&'static [app::Item]"), "&'static [Item]");
+    }
+
+    #[test]
+    fn test_normalize_c_types() {
+        let normalize = |ty: &str| SnapshotCodegen.normalize_rust_type(ty);
+        assert_eq!(normalize("int"), "i32");
+        assert_eq!(normalize("unsigned int"), "u32");
+        assert_eq!(normalize("unsigned long"), "u64");
+        assert_eq!(normalize("long long"), "i64");
+        assert_eq!(normalize("unsigned short"), "u16");
+        assert_eq!(normalize("short"), "i16");
+        assert_eq!(normalize("unsigned char"), "u8");
+        assert_eq!(normalize("signed char"), "i8");
+        assert_eq!(normalize("double"), "f64");
+        assert_eq!(normalize("float"), "f32");
+        assert_eq!(normalize("int[5]"), "i32[5]");
+        assert_eq!(normalize("Vec<unsigned long>"), "Vec<u64>");
+        assert_eq!(normalize("(int, double)"), "(i32, f64)");
+    }
+
+    #[test]
+    fn test_normalize_keeps_identifiers_containing_c_types() {
+        let normalize = |ty: &str| SnapshotCodegen.normalize_rust_type(ty);
+        assert_eq!(normalize("Container"), "Container");
+        assert_eq!(normalize("Printer"), "Printer");
+        assert_eq!(normalize("Point"), "Point");
+        assert_eq!(normalize("Vec<Point>"), "Vec<Point>");
+        assert_eq!(normalize("jobs::PrintJob"), "PrintJob");
+        assert_eq!(normalize("std::time::Duration"), "std::time::Duration");
+        assert_eq!(normalize("serde_json::Value"), "serde_json::Value");
+        assert_eq!(
+            normalize("HashMap<String, Container>"),
+            "HashMap<String, Container>"
+        );
+        assert_eq!(normalize("app::Interval<Longitude>"), "Interval<Longitude>");
+        assert_eq!(
+            normalize("ShortList<DoubleBuffer>"),
+            "ShortList<DoubleBuffer>"
+        );
+        assert_eq!(normalize("uint"), "uint");
+        assert_eq!(normalize("char"), "char");
+        assert_eq!(normalize("Vec<char>"), "Vec<char>");
+        assert_eq!(normalize("float_ext::Floaty"), "Floaty");
+    }
+
+    #[test]
+    fn test_normalize_removes_only_global_allocator() {
+        let normalize = |ty: &str| SnapshotCodegen.normalize_rust_type(ty);
+        assert_eq!(normalize("Vec<i32, alloc::alloc::Global>"), "Vec<i32>");
+        assert_eq!(normalize("Vec<int,Global>"), "Vec<i32>");
+        assert_eq!(
+            normalize("HashMap<String, GlobalConfig>"),
+            "HashMap<String, GlobalConfig>"
+        );
+    }
+
+    #[test]
+    fn test_smart_pointer_types_are_supported() {
+        assert!(SnapshotCodegen.is_supported_type("Arc<User>"));
+        assert!(SnapshotCodegen.is_supported_type("alloc::sync::Arc<rust_sample::User>"));
+        assert!(SnapshotCodegen.is_supported_type("Box<str>"));
+        assert!(!SnapshotCodegen.is_supported_type("Rc<*const u8>"));
+        assert_eq!(
+            SnapshotCodegen.normalize_rust_type("alloc::sync::Arc<rust_sample::User>"),
+            "Arc<User>"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Completions offered by a REPL session
+//!
+//! evcxr passes on rust-analyzer's items as they come: duplicated, in no
+//! particular order, and with kinds like `SymbolKind(Local)`. Snapshot
+//! variables are functions to rust-analyzer, so their types are missing.
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use super::tracking::{TrackedVar, VarOrigin};
+use crate::lsp::{fuzzy_score, CompletionKind};
+
+/// One completion at the cursor, see `ReplSession::completions`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplCompletion {
+    /// Text replacing the word at the cursor
+    pub code: String,
+    /// Text shown in the completion menu
+    pub label: String,
+    pub kind: CompletionKind,
+    /// Type or signature, when known
+    pub detail: Option<String>,
+}
+
+impl ReplCompletion {
+    fn from_evcxr(completion: evcxr::Completion) -> Self {
+        Self {
+            kind: completion_kind(&completion.kind),
+            code: completion.code,
+            label: completion.label,
+            detail: completion.detail.filter(|d| !d.is_empty()),
+        }
+    }
+
+    /// A variable tracked by the session, typed with its declared type
+    fn from_variable(name: &str, var: &TrackedVar) -> Self {
+        let code = match var.origin {
+            VarOrigin::Snapshot => format!("{}()", name),
+            VarOrigin::UserDefined => name.to_string(),
+        };
+        Self {
+            code,
+            label: name.to_string(),
+            kind: CompletionKind::Variable,
+            detail: Some(var.declared_type.clone()).filter(|ty| ty != "_"),
+        }
+    }
+}
+
+/// Map an evcxr kind (rust-analyzer's, in `Debug` form) to a `CompletionKind`
+fn completion_kind(kind: &str) -> CompletionKind {
+    let inner = kind
+        .strip_prefix("SymbolKind(")
+        .and_then(|s| s.strip_suffix(')'))
+        .unwrap_or(kind);
+    match inner {
+        "Local" | "ValueParam" | "SelfParam" | "Binding" => CompletionKind::Variable,
+        "Function" | "Macro" => CompletionKind::Function,
+        "Method" => CompletionKind::Method,
+        "Field" => CompletionKind::Field,
+        "Struct" | "Union" | "TypeAlias" | "SelfType" | "BuiltinType" => CompletionKind::Struct,
+        "Enum" | "Variant" => CompletionKind::Enum,
+        "Module" | "ToolModule" | "Crate" => CompletionKind::Module,
+        "Const" | "Static" | "ConstParam" => CompletionKind::Constant,
+        "Keyword" => CompletionKind::Keyword,
+        "Snippet" => CompletionKind::Snippet,
+        _ => CompletionKind::Other,
+    }
+}
+
+/// How well `label` matches the typed `word`, lower is better; `None` when
+/// it doesn't match at all
+///
+/// A case-sensitive prefix match ranks first, then a prefix match ignoring
+/// case, then any fuzzy match.
+fn match_rank(label: &str, word: &str) -> Option<(u8, f32)> {
+    if label.starts_with(word) {
+        Some((0, 0.0))
+    } else if label.to_lowercase().starts_with(&word.to_lowercase()) {
+        Some((1, 0.0))
+    } else {
+        fuzzy_score(label, word).map(|score| (2, -score))
+    }
+}
+
+/// Whether the word starting at `start` is a bare identifier, rather than
+/// a field, method or path segment
+fn is_bare_identifier(src: &str, start: usize) -> bool {
+    let before = src.get(..start).unwrap_or("").trim_end();
+    !(before.ends_with('.') || before.ends_with("::"))
+}
+
+/// Dedupe, filter and rank `raw` completions of the word at
+/// `start..position` in `src`
+///
+/// When the word is a bare identifier, the session's `variables` are merged
+/// in, taking the place of rust-analyzer's items for the same code. Ties
+/// keep rust-analyzer's order.
+pub(super) fn normalize_completions(
+    src: &str,
+    start: usize,
+    position: usize,
+    raw: Vec<evcxr::Completion>,
+    variables: &HashMap<String, TrackedVar>,
+) -> Vec<ReplCompletion> {
+    let start = start.min(position).min(src.len());
+    let word = src.get(start..position.min(src.len())).unwrap_or("");
+
+    let mut candidates = Vec::new();
+    if is_bare_identifier(src, start) {
+        let mut names: Vec<_> = variables.iter().collect();
+        names.sort_by(|a, b| a.0.cmp(b.0));
+        candidates.extend(
+            names
+                .into_iter()
+                .map(|(name, var)| ReplCompletion::from_variable(name, var)),
+        );
+    }
+    candidates.extend(raw.into_iter().map(ReplCompletion::from_evcxr));
+
+    let mut seen = HashSet::new();
+    let mut ranked: Vec<_> = candidates
+        .into_iter()
+        .filter(|c| seen.insert(c.code.clone()))
+        .filter_map(|c| match_rank(&c.label, word).map(|rank| (rank, c)))
+        .collect();
+    ranked.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    ranked.into_iter().map(|(_, c)| c).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(code: &str, kind: &str, detail: Option<&str>) -> evcxr::Completion {
+        evcxr::Completion {
+            code: code.to_string(),
+            label: code.to_string(),
+            kind: kind.to_string(),
+            detail: detail.map(str::to_string),
+        }
+    }
+
+    fn codes(completions: &[ReplCompletion]) -> Vec<&str> {
+        completions.iter().map(|c| c.code.as_str()).collect()
+    }
+
+    #[test]
+    fn test_completions_ranked_by_prefix() {
+        let items = vec![
+            raw("is_counted", "SymbolKind(Function)", None),
+            raw("Count", "SymbolKind(Struct)", None),
+            raw("count", "SymbolKind(Local)", Some("i32")),
+            raw("recount", "SymbolKind(Function)", None),
+            raw("count", "SymbolKind(Local)", Some("i32")),
+            raw("len", "Method", None),
+        ];
+        let src = "let x = cou";
+        let completions = normalize_completions(src, 8, src.len(), items, &HashMap::new());
+
+        assert_eq!(
+            codes(&completions),
+            vec!["count", "Count", "is_counted", "recount"]
+        );
+        assert_eq!(completions[0].kind, CompletionKind::Variable);
+        assert_eq!(completions[0].detail.as_deref(), Some("i32"));
+        assert_eq!(completions[1].kind, CompletionKind::Struct);
+    }
+
+    #[test]
+    fn test_completions_merge_snapshot_variables() {
+        let mut variables = HashMap::new();
+        variables.insert(
+            "config".to_string(),
+            TrackedVar::new("Config", VarOrigin::Snapshot),
+        );
+        variables.insert(
+            "conn".to_string(),
+            TrackedVar::new("_", VarOrigin::UserDefined),
+        );
+        variables.insert(
+            "other".to_string(),
+            TrackedVar::new("u8", VarOrigin::Snapshot),
+        );
+        let items = || {
+            vec![
+                raw("config()", "SymbolKind(Function)", Some("fn() -> Config")),
+                raw("const", "Keyword", None),
+            ]
+        };
+
+        let src = "con";
+        let completions = normalize_completions(src, 0, 3, items(), &variables);
+        assert_eq!(codes(&completions), vec!["config()", "conn", "const"]);
+        assert_eq!(completions[0].kind, CompletionKind::Variable);
+        assert_eq!(completions[0].detail.as_deref(), Some("Config"));
+        assert_eq!(completions[1].detail, None);
+        assert_eq!(completions[2].kind, CompletionKind::Keyword);
+
+        // Not merged after a `.`
+        let src = "value.con";
+        let completions = normalize_completions(src, 6, src.len(), items(), &variables);
+        assert_eq!(codes(&completions), vec!["config()", "const"]);
+        assert_eq!(completions[0].kind, CompletionKind::Function);
+    }
+}
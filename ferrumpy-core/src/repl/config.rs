@@ -0,0 +1,346 @@
+//! evcxr settings applied when a session starts
+
+use anyhow::Result;
+use evcxr::CommandContext;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Default for `ReplConfig::cache_mb`
+pub const DEFAULT_CACHE_MB: u64 = 512;
+
+/// Default for `ReplConfig::opt_level`: restoring a snapshot is mostly
+/// compiling, which optimizations slow down
+pub const DEFAULT_OPT_LEVEL: u8 = 0;
+
+/// Highest rustc optimization level
+pub(super) const MAX_OPT_LEVEL: u8 = 3;
+
+/// Compiler settings of a REPL session, see `ReplSession::with_config`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplConfig {
+    /// rustc optimization level, 0 to 3; evcxr's default when `None`
+    pub opt_level: Option<u8>,
+    /// Don't let cargo access the network
+    pub offline: bool,
+    /// rustup toolchain compiling the evaluated code, e.g. `nightly`
+    pub toolchain: Option<String>,
+    /// Linker: `system`, `lld` or `mold`
+    pub linker: Option<String>,
+    /// Size of evcxr's dependency cache in MiB, 0 to disable it
+    pub cache_mb: u64,
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        Self {
+            opt_level: Some(DEFAULT_OPT_LEVEL),
+            offline: false,
+            toolchain: None,
+            linker: None,
+            cache_mb: DEFAULT_CACHE_MB,
+        }
+    }
+}
+
+impl ReplConfig {
+    /// evcxr commands applying the settings, in the order they are issued
+    ///
+    /// The toolchain comes first, since it decides which rustc the other
+    /// settings apply to.
+    pub fn commands(&self) -> Vec<String> {
+        let mut commands = Vec::new();
+        if let Some(toolchain) = &self.toolchain {
+            commands.push(format!(":toolchain {}", toolchain));
+        }
+        if let Some(linker) = &self.linker {
+            commands.push(format!(":linker {}", linker));
+        }
+        if let Some(level) = self.opt_level {
+            commands.push(format!(":opt {}", level));
+        }
+        if self.offline {
+            commands.push(":offline 1".to_string());
+        }
+        commands.push(format!(":cache {}", self.cache_mb));
+        commands
+    }
+
+    /// Issue the commands to `sink`, stopping at the first one it rejects
+    pub(super) fn apply(&self, sink: &mut impl CommandSink) -> Result<()> {
+        if let Some(level) = self.opt_level {
+            check_opt_level(level)?;
+        }
+        for command in self.commands() {
+            sink.run_command(&command)
+                .map_err(|e| e.context(format!("Invalid REPL setting `{}`", command)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Locks of the dependency cache directories, held by a session compiling
+/// with the cache on
+///
+/// A cache directory is shared by every session of the process using it,
+/// and a build trimming it must not run while another one copies from it.
+/// Sessions whose caches differ, e.g. with `XDG_CACHE_HOME` changed in
+/// between, compile at the same time.
+static CACHE_LOCKS: Mutex<BTreeMap<PathBuf, Arc<CacheLock>>> = Mutex::new(BTreeMap::new());
+
+/// A lock whose holder can be released from another thread, unlike a
+/// `MutexGuard`
+#[derive(Default)]
+struct CacheLock {
+    held: Mutex<bool>,
+    released: Condvar,
+}
+
+impl CacheLock {
+    fn acquire(self: Arc<Self>) -> CacheLease {
+        {
+            let held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+            let mut held = self
+                .released
+                .wait_while(held, |held| *held)
+                .unwrap_or_else(|e| e.into_inner());
+            *held = true;
+        }
+        CacheLease(CacheRelease(Arc::new(LeaseState {
+            lock: self,
+            released: AtomicBool::new(false),
+        })))
+    }
+}
+
+struct LeaseState {
+    lock: Arc<CacheLock>,
+    released: AtomicBool,
+}
+
+/// Hold on the shared cache, given up when dropped
+pub(super) struct CacheLease(CacheRelease);
+
+impl CacheLease {
+    /// Handle giving up the lease early, e.g. from a thread abandoning the
+    /// compile holding it
+    pub(super) fn releaser(&self) -> CacheRelease {
+        self.0.clone()
+    }
+}
+
+impl Drop for CacheLease {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Gives up a [`CacheLease`] before it is dropped, see `CacheLease::releaser`
+#[derive(Clone)]
+pub(super) struct CacheRelease(Arc<LeaseState>);
+
+impl CacheRelease {
+    /// Let the next session take the cache; later calls, and dropping the
+    /// lease, do nothing
+    pub(super) fn release(&self) {
+        if self.0.released.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let lock = &self.0.lock;
+        *lock.held.lock().unwrap_or_else(|e| e.into_inner()) = false;
+        lock.released.notify_one();
+    }
+}
+
+/// Wait for other sessions to finish compiling against the cache directory
+/// evcxr uses now
+pub(super) fn lock_shared_cache() -> CacheLease {
+    let directory = evcxr::cache_dir().unwrap_or_default();
+    let lock = CACHE_LOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(directory)
+        .or_default()
+        .clone();
+    lock.acquire()
+}
+
+/// Fail unless `level` is a rustc optimization level
+pub(super) fn check_opt_level(level: u8) -> Result<()> {
+    if level > MAX_OPT_LEVEL {
+        anyhow::bail!(
+            "Invalid optimization level {} (expected 0 to {})",
+            level,
+            MAX_OPT_LEVEL
+        );
+    }
+    Ok(())
+}
+
+/// How the worker process running evaluated code is started, see
+/// `ReplSession::with_options`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplWorkerOptions {
+    /// Working directory of the worker; the caller's when `None`
+    pub cwd: Option<PathBuf>,
+    /// Environment variables set on top of the caller's
+    pub env: HashMap<String, String>,
+    /// Arguments the evaluated code sees in `std::env::args()`
+    pub args: Vec<String>,
+}
+
+impl ReplWorkerOptions {
+    /// Command starting the worker at `program`
+    pub(super) fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        command.envs(&self.env).args(&self.args);
+        command
+    }
+}
+
+/// Where `ReplConfig::apply` sends its commands
+pub(super) trait CommandSink {
+    fn run_command(&mut self, command: &str) -> Result<()>;
+}
+
+impl CommandSink for CommandContext {
+    fn run_command(&mut self, command: &str) -> Result<()> {
+        self.execute(command)
+            .map(|_| ())
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records commands, rejecting those starting with `fail_on`
+    #[derive(Default)]
+    struct RecordingSink {
+        commands: Vec<String>,
+        fail_on: Option<&'static str>,
+    }
+
+    impl CommandSink for RecordingSink {
+        fn run_command(&mut self, command: &str) -> Result<()> {
+            self.commands.push(command.to_string());
+            match self.fail_on {
+                Some(prefix) if command.starts_with(prefix) => {
+                    anyhow::bail!("Unknown toolchain")
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_config_sets_opt_level_and_cache() {
+        let mut sink = RecordingSink::default();
+        ReplConfig::default().apply(&mut sink).unwrap();
+        assert_eq!(sink.commands, vec![":opt 0", ":cache 512"]);
+
+        let config = ReplConfig {
+            opt_level: None,
+            ..Default::default()
+        };
+        let mut sink = RecordingSink::default();
+        config.apply(&mut sink).unwrap();
+        assert_eq!(sink.commands, vec![":cache 512"]);
+    }
+
+    #[test]
+    fn test_invalid_opt_level_is_rejected() {
+        let config = ReplConfig {
+            opt_level: Some(4),
+            ..Default::default()
+        };
+        let mut sink = RecordingSink::default();
+        let err = config.apply(&mut sink).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid optimization level 4 (expected 0 to 3)"
+        );
+        assert!(sink.commands.is_empty());
+    }
+
+    #[test]
+    fn test_config_commands_in_order() {
+        let config = ReplConfig {
+            opt_level: Some(0),
+            offline: true,
+            toolchain: Some("nightly".to_string()),
+            linker: Some("mold".to_string()),
+            cache_mb: 0,
+        };
+        let mut sink = RecordingSink::default();
+        config.apply(&mut sink).unwrap();
+        assert_eq!(
+            sink.commands,
+            vec![
+                ":toolchain nightly",
+                ":linker mold",
+                ":opt 0",
+                ":offline 1",
+                ":cache 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_worker_options_command() {
+        let mut env = HashMap::new();
+        env.insert("APP_MODE".to_string(), "debug".to_string());
+        let options = ReplWorkerOptions {
+            cwd: Some(PathBuf::from("/tmp/project")),
+            env,
+            args: vec!["--port".to_string(), "8080".to_string()],
+        };
+        let command = options.command("/opt/ferrumpy-repl-worker");
+
+        assert_eq!(command.get_program(), "/opt/ferrumpy-repl-worker");
+        assert_eq!(
+            command.get_current_dir(),
+            Some(std::path::Path::new("/tmp/project"))
+        );
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec!["--port", "8080"]
+        );
+        let envs: Vec<_> = command.get_envs().collect();
+        assert_eq!(
+            envs,
+            vec![(
+                std::ffi::OsStr::new("APP_MODE"),
+                Some(std::ffi::OsStr::new("debug"))
+            )]
+        );
+
+        let command = ReplWorkerOptions::default().command("worker");
+        assert_eq!(command.get_current_dir(), None);
+        assert_eq!(command.get_args().count(), 0);
+    }
+
+    #[test]
+    fn test_config_surfaces_rejected_command() {
+        let config = ReplConfig {
+            toolchain: Some("no-such-toolchain".to_string()),
+            opt_level: Some(1),
+            ..Default::default()
+        };
+        let mut sink = RecordingSink {
+            fail_on: Some(":toolchain"),
+            ..Default::default()
+        };
+        let err = config.apply(&mut sink).unwrap_err();
+        assert_eq!(sink.commands, vec![":toolchain no-such-toolchain"]);
+        let message = format!("{:#}", err);
+        assert!(message.contains("Unknown toolchain"), "Got: {}", message);
+        assert!(message.contains(":toolchain"), "Got: {}", message);
+    }
+}
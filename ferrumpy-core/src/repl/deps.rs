@@ -0,0 +1,514 @@
+//! Dependencies registered on a session
+//!
+//! evcxr keeps one spec per crate name, so a later `:dep serde = "1"`
+//! silently replaces the `derive` feature the snapshot code relies on, and a
+//! spec the snapshot crate's manifest can't agree with only fails once cargo
+//! resolves the build, mid-evaluation. The registry sees every dependency
+//! before evcxr does: equivalent ones are skipped, features are merged, and
+//! specs that can't go together are reported as a [`DepConflict`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use thiserror::Error;
+
+/// Where a dependency came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepOrigin {
+    /// Added with `add_dep` or a `:dep` command
+    User,
+    /// Added by the session for the code it generates, e.g. serde
+    Session,
+    /// Declared in the snapshot crate's Cargo.toml
+    SnapshotCrate,
+}
+
+impl DepOrigin {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DepOrigin::User => "user",
+            DepOrigin::Session => "session",
+            DepOrigin::SnapshotCrate => "snapshot crate",
+        }
+    }
+}
+
+/// A dependency spec as written, and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeclaredDep {
+    /// The part after `=` in a `:dep` command or Cargo.toml line
+    pub spec: String,
+    pub origin: DepOrigin,
+}
+
+/// Two specs of a crate cargo can't build together, found before compiling
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error(
+    "Dependency `{name}` conflicts: {} ({}) and {} ({}): {reason}",
+    requested.spec,
+    requested.origin.as_str(),
+    existing.spec,
+    existing.origin.as_str()
+)]
+pub struct DepConflict {
+    pub name: String,
+    /// The spec registered first
+    pub existing: DeclaredDep,
+    /// The spec that conflicts with it
+    pub requested: DeclaredDep,
+    pub reason: String,
+}
+
+/// What to do with a dependency that doesn't conflict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Registration {
+    /// Register it with evcxr with this spec, the requested one merged with
+    /// the features already registered
+    Add(String),
+    /// An equivalent spec is registered already
+    Redundant,
+}
+
+/// A dependency spec, as far as comparing specs goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DepSpec {
+    version: Option<String>,
+    path: Option<String>,
+    git: Option<String>,
+    features: BTreeSet<String>,
+    default_features: bool,
+}
+
+impl DepSpec {
+    fn parse(spec: &str) -> Option<Self> {
+        let mut table: toml::Table = toml::from_str(&format!("dep = {}", spec)).ok()?;
+        Self::from_toml(&table.remove("dep")?)
+    }
+
+    fn from_toml(value: &toml::Value) -> Option<Self> {
+        let text = |key: &str| value.get(key).and_then(|v| v.as_str()).map(str::to_string);
+        match value {
+            toml::Value::String(version) => Some(Self {
+                version: Some(version.clone()),
+                path: None,
+                git: None,
+                features: BTreeSet::new(),
+                default_features: true,
+            }),
+            toml::Value::Table(table) => Some(Self {
+                version: text("version"),
+                path: text("path"),
+                git: text("git"),
+                features: table
+                    .get("features")
+                    .and_then(|f| f.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|f| f.as_str().map(str::to_string))
+                    .collect(),
+                default_features: table
+                    .get("default-features")
+                    .or_else(|| table.get("default_features"))
+                    .and_then(|d| d.as_bool())
+                    .unwrap_or(true),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Why cargo can't build with both `self` and `other`, if it can't
+    fn conflict_with(&self, other: &Self) -> Option<String> {
+        if self.path != other.path {
+            return Some(match (&self.path, &other.path) {
+                (Some(_), Some(_)) => "different paths".to_string(),
+                _ => "a path and a non-path dependency".to_string(),
+            });
+        }
+        if self.git != other.git {
+            return Some("different sources".to_string());
+        }
+        if let (Some(a), Some(b)) = (&self.version, &other.version) {
+            if let (Some(a_range), Some(b_range)) = (version_range(a), version_range(b)) {
+                if a_range.0 >= b_range.1 || b_range.0 >= a_range.1 {
+                    return Some(format!("no version matches both `{}` and `{}`", a, b));
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether registering `self` after `other` would change nothing
+    fn covered_by(&self, other: &Self) -> bool {
+        self.path == other.path
+            && self.git == other.git
+            && (self.version.is_none() || self.version == other.version)
+            && self.features.is_subset(&other.features)
+            && (other.default_features || !self.default_features)
+    }
+
+    /// `self` with the features of `other` as well; the version of whichever
+    /// pins one exactly, else that of `self`
+    fn merged_with(&self, other: &Self) -> Self {
+        let pinned = |spec: &Self| spec.version.as_ref().is_some_and(|v| v.starts_with('='));
+        let version = if pinned(other) && !pinned(self) {
+            other.version.clone()
+        } else {
+            self.version.clone().or_else(|| other.version.clone())
+        };
+        Self {
+            version,
+            path: self.path.clone(),
+            git: self.git.clone(),
+            features: self.features.union(&other.features).cloned().collect(),
+            default_features: self.default_features || other.default_features,
+        }
+    }
+
+    fn to_spec(&self) -> String {
+        let quote = |text: &str| toml::Value::String(text.to_string()).to_string();
+        if self.path.is_none()
+            && self.git.is_none()
+            && self.features.is_empty()
+            && self.default_features
+        {
+            if let Some(version) = &self.version {
+                return quote(version);
+            }
+        }
+        let mut fields = Vec::new();
+        for (key, value) in [
+            ("version", &self.version),
+            ("path", &self.path),
+            ("git", &self.git),
+        ] {
+            if let Some(value) = value {
+                fields.push(format!("{} = {}", key, quote(value)));
+            }
+        }
+        if !self.features.is_empty() {
+            let features: Vec<String> = self.features.iter().map(|f| quote(f)).collect();
+            fields.push(format!("features = [{}]", features.join(", ")));
+        }
+        if !self.default_features {
+            fields.push("default-features = false".to_string());
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+/// Versions a requirement like `1.2`, `^0.8`, `~1.2.3` or `=1.0.100`
+/// accepts, from the first up to the second, exclusive
+///
+/// `None` for requirements with other operators or several parts, which
+/// aren't compared.
+fn version_range(req: &str) -> Option<([u64; 3], [u64; 3])> {
+    let req = req.trim();
+    let (op, version) = match req.find(|c: char| c.is_ascii_digit()) {
+        Some(start) => req.split_at(start),
+        None => return None,
+    };
+    let op = op.trim();
+    if !matches!(op, "" | "^" | "~" | "=") || version.contains(',') {
+        return None;
+    }
+    let version = version.split(['-', '+']).next()?;
+    let parts = version
+        .split('.')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    let (major, minor, patch) = match parts[..] {
+        [major] => (major, None, None),
+        [major, minor] => (major, Some(minor), None),
+        [major, minor, patch] => (major, Some(minor), Some(patch)),
+        _ => return None,
+    };
+    let low = [major, minor.unwrap_or(0), patch.unwrap_or(0)];
+    let high = match (op, minor, patch) {
+        ("=", Some(minor), Some(patch)) => [major, minor, patch + 1],
+        ("=" | "~", Some(minor), _) => [major, minor + 1, 0],
+        ("=" | "~", None, _) => [major + 1, 0, 0],
+        // Caret: up to the next change of the leftmost nonzero part
+        _ => match (major, minor, patch) {
+            (0, Some(0), Some(patch)) => [0, 0, patch + 1],
+            (0, Some(minor), _) => [0, minor + 1, 0],
+            _ => [major + 1, 0, 0],
+        },
+    };
+    Some((low, high))
+}
+
+/// A registered dependency
+#[derive(Debug, Clone)]
+struct Entry {
+    declared: DeclaredDep,
+    /// `None` for specs that don't parse, which evcxr validates itself
+    spec: Option<DepSpec>,
+}
+
+impl Entry {
+    fn new(spec: &str, origin: DepOrigin) -> Self {
+        Self {
+            declared: DeclaredDep {
+                spec: spec.to_string(),
+                origin,
+            },
+            spec: DepSpec::parse(spec),
+        }
+    }
+
+    /// The conflict of registering `requested` after this entry, if any
+    fn conflict(&self, name: &str, requested: &Entry) -> Option<DepConflict> {
+        let reason = requested
+            .spec
+            .as_ref()?
+            .conflict_with(self.spec.as_ref()?)?;
+        Some(DepConflict {
+            name: name.to_string(),
+            existing: self.declared.clone(),
+            requested: requested.declared.clone(),
+            reason,
+        })
+    }
+}
+
+/// The dependencies of a session's evcxr context, and those of the snapshot
+/// crate it was given
+#[derive(Debug, Default)]
+pub(super) struct DepRegistry {
+    /// Registered with evcxr, by crate name
+    context: BTreeMap<String, Entry>,
+    /// The snapshot crate's `[dependencies]`, built along with the context's
+    snapshot_crate: BTreeMap<String, Entry>,
+}
+
+impl DepRegistry {
+    /// Check `spec` for crate `name` against the registered dependencies
+    ///
+    /// A user spec replacing one the user registered before is no conflict;
+    /// evcxr takes the latest. Record the dependency once evcxr has it.
+    pub(super) fn check(
+        &self,
+        name: &str,
+        spec: &str,
+        origin: DepOrigin,
+    ) -> Result<Registration, DepConflict> {
+        let requested = Entry::new(spec, origin);
+        if let Some(conflict) = self
+            .snapshot_crate
+            .get(name)
+            .and_then(|existing| existing.conflict(name, &requested))
+        {
+            return Err(conflict);
+        }
+        let Some(existing) = self.context.get(name) else {
+            return Ok(Registration::Add(spec.to_string()));
+        };
+        if origin == DepOrigin::User && existing.declared.origin == DepOrigin::User {
+            return Ok(Registration::Add(spec.to_string()));
+        }
+        if let Some(conflict) = existing.conflict(name, &requested) {
+            return Err(conflict);
+        }
+        match (&requested.spec, &existing.spec) {
+            (Some(new), Some(old)) if new.covered_by(old) => Ok(Registration::Redundant),
+            (Some(new), Some(old)) => Ok(Registration::Add(new.merged_with(old).to_spec())),
+            _ if requested.declared.spec == existing.declared.spec => Ok(Registration::Redundant),
+            _ => Ok(Registration::Add(spec.to_string())),
+        }
+    }
+
+    /// Record that evcxr has `spec` for crate `name`
+    pub(super) fn record(&mut self, name: &str, spec: &str, origin: DepOrigin) {
+        self.context
+            .insert(name.to_string(), Entry::new(spec, origin));
+    }
+
+    /// Take the `[dependencies]` of the snapshot crate's `manifest`, in
+    /// directory `dir`, failing if one conflicts with a registered spec
+    pub(super) fn set_snapshot_crate(
+        &mut self,
+        manifest: &toml::Value,
+        dir: &Path,
+    ) -> Result<(), DepConflict> {
+        let mut snapshot_crate = BTreeMap::new();
+        let dependencies = manifest.get("dependencies").and_then(|d| d.as_table());
+        for (name, value) in dependencies.into_iter().flatten() {
+            let mut value = value.clone();
+            // Relative paths are relative to the snapshot crate
+            if let Some(path) = value.get_mut("path") {
+                if let Some(relative) = path.as_str().filter(|p| Path::new(p).is_relative()) {
+                    *path = dir.join(relative).to_string_lossy().into_owned().into();
+                }
+            }
+            let entry = Entry::new(&value.to_string(), DepOrigin::SnapshotCrate);
+            if let Some(conflict) = self
+                .context
+                .get(name)
+                .and_then(|existing| existing.conflict(name, &entry))
+            {
+                return Err(conflict);
+            }
+            snapshot_crate.insert(name.clone(), entry);
+        }
+        self.snapshot_crate = snapshot_crate;
+        Ok(())
+    }
+
+    /// Forget the context's dependencies, e.g. after `:clear` dropped them
+    pub(super) fn clear_context(&mut self) {
+        self.context.clear();
+    }
+
+    /// Dependencies registered with evcxr, sorted by crate name
+    pub(super) fn registered(&self) -> Vec<(String, DeclaredDep)> {
+        self.context
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.declared.clone()))
+            .collect()
+    }
+}
+
+/// Crate name and spec of a `:dep name = spec` command
+pub(super) fn parse_dep_command(code: &str) -> Option<(&str, &str)> {
+    let (name, spec) = code.trim().strip_prefix(":dep ")?.split_once('=')?;
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then(|| (name, spec.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERDE: &str = r#"{ version = "1", features = ["derive"] }"#;
+
+    fn registry_with_serde() -> DepRegistry {
+        let mut registry = DepRegistry::default();
+        assert_eq!(
+            registry.check("serde", SERDE, DepOrigin::Session),
+            Ok(Registration::Add(SERDE.to_string()))
+        );
+        registry.record("serde", SERDE, DepOrigin::Session);
+        registry
+    }
+
+    #[test]
+    fn test_equivalent_deps_are_skipped_and_features_merged() {
+        let registry = registry_with_serde();
+        let check = |spec: &str| registry.check("serde", spec, DepOrigin::User);
+
+        assert_eq!(check(SERDE), Ok(Registration::Redundant));
+        assert_eq!(check(r#""1""#), Ok(Registration::Redundant));
+        assert_eq!(
+            check(r#"{ version = "1", features = ["rc"] }"#),
+            Ok(Registration::Add(
+                r#"{ version = "1", features = ["derive", "rc"] }"#.to_string()
+            ))
+        );
+        // A pin keeps the session's features
+        assert_eq!(
+            check(r#""=1.0.190""#),
+            Ok(Registration::Add(
+                r#"{ version = "=1.0.190", features = ["derive"] }"#.to_string()
+            ))
+        );
+        assert_eq!(
+            registry.check("rand", r#""0.8""#, DepOrigin::User),
+            Ok(Registration::Add(r#""0.8""#.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_project_pinning_incompatible_serde() {
+        // The snapshot crate of a project stuck on serde 0.9
+        let manifest: toml::Value = toml::from_str(
+            r#"
+            [dependencies]
+            serde = "0.9"
+            helper = { path = "deps/helper" }
+            "#,
+        )
+        .unwrap();
+        let mut registry = registry_with_serde();
+        let conflict = registry
+            .set_snapshot_crate(&manifest, Path::new("/tmp/snap"))
+            .unwrap_err();
+        assert_eq!(conflict.name, "serde");
+        assert_eq!(conflict.existing.origin, DepOrigin::Session);
+        assert_eq!(conflict.requested.origin, DepOrigin::SnapshotCrate);
+        assert_eq!(conflict.requested.spec, r#""0.9""#);
+        assert_eq!(
+            conflict.to_string(),
+            "Dependency `serde` conflicts: \"0.9\" (snapshot crate) and \
+             { version = \"1\", features = [\"derive\"] } (session): \
+             no version matches both `0.9` and `1`"
+        );
+
+        // Registered in the other order, the session's serde is the conflict
+        let mut registry = DepRegistry::default();
+        registry
+            .set_snapshot_crate(&manifest, Path::new("/tmp/snap"))
+            .unwrap();
+        let conflict = registry
+            .check("serde", SERDE, DepOrigin::Session)
+            .unwrap_err();
+        assert_eq!(conflict.existing.origin, DepOrigin::SnapshotCrate);
+        assert_eq!(
+            registry.check(
+                "helper",
+                r#"{ path = "/tmp/snap/deps/helper" }"#,
+                DepOrigin::User
+            ),
+            Ok(Registration::Add(
+                r#"{ path = "/tmp/snap/deps/helper" }"#.to_string()
+            ))
+        );
+        let conflict = registry
+            .check("helper", r#""1""#, DepOrigin::User)
+            .unwrap_err();
+        assert_eq!(conflict.reason, "a path and a non-path dependency");
+    }
+
+    #[test]
+    fn test_pinned_versions_conflict() {
+        let mut registry = DepRegistry::default();
+        registry.record("serde", r#""=1.0.150""#, DepOrigin::Session);
+        let conflict = registry
+            .check("serde", r#""=1.0.100""#, DepOrigin::User)
+            .unwrap_err();
+        assert_eq!(
+            conflict.reason,
+            "no version matches both `=1.0.100` and `=1.0.150`"
+        );
+        assert!(registry
+            .check("serde", r#""^1.0.120""#, DepOrigin::User)
+            .is_ok());
+
+        // The user may replace their own dependency
+        registry.record("rand", r#""0.7""#, DepOrigin::User);
+        assert_eq!(
+            registry.check("rand", r#""0.8""#, DepOrigin::User),
+            Ok(Registration::Add(r#""0.8""#.to_string()))
+        );
+        registry.clear_context();
+        assert!(registry.registered().is_empty());
+    }
+
+    #[test]
+    fn test_version_range() {
+        assert_eq!(version_range("1"), Some(([1, 0, 0], [2, 0, 0])));
+        assert_eq!(version_range("0.8.3"), Some(([0, 8, 3], [0, 9, 0])));
+        assert_eq!(version_range("^0.0.4"), Some(([0, 0, 4], [0, 0, 5])));
+        assert_eq!(version_range("~1.2"), Some(([1, 2, 0], [1, 3, 0])));
+        assert_eq!(version_range("=1.0.100"), Some(([1, 0, 100], [1, 0, 101])));
+        assert_eq!(version_range(">=1, <2"), None);
+        assert_eq!(version_range("*"), None);
+        assert_eq!(
+            parse_dep_command(":dep serde_json = \"1\""),
+            Some(("serde_json", "\"1\""))
+        );
+        assert_eq!(parse_dep_command(":dep ./local/crate"), None);
+    }
+}
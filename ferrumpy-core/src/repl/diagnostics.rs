@@ -0,0 +1,281 @@
+//! Compile errors located in the code the user knows about
+//!
+//! The session evaluates the user's input together with code it generates
+//! (prelude imports, snapshot variables), so evcxr's line numbers refer to
+//! the assembled code. A `SourceMap` records which lines came from where.
+
+use serde::Serialize;
+use std::fmt;
+use thiserror::Error;
+
+/// Where a line of evaluated code came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum CodeSection {
+    /// `use` statements added with `add_prelude`
+    Prelude,
+    /// The item restoring a snapshot variable
+    Variable(String),
+    /// Code the user evaluated
+    Input,
+    /// Other code generated by the session
+    Generated,
+}
+
+/// Code assembled from sections, with the lines each one spans
+#[derive(Debug, Clone, Default)]
+pub(super) struct SourceMap {
+    code: String,
+    /// Section and its first line (1-based), in order
+    sections: Vec<(CodeSection, usize)>,
+}
+
+impl SourceMap {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// A map of `code` as a single section
+    pub(super) fn single(section: CodeSection, code: &str) -> Self {
+        let mut map = Self::new();
+        map.push(section, code);
+        map
+    }
+
+    /// Append `code` as lines of `section`, ending it with a newline
+    pub(super) fn push(&mut self, section: CodeSection, code: &str) {
+        if code.is_empty() {
+            return;
+        }
+        let start_line = self.code.lines().count() + 1;
+        if self.sections.last().map(|(last, _)| last) != Some(&section) {
+            self.sections.push((section, start_line));
+        }
+        self.code.push_str(code);
+        if !code.ends_with('\n') {
+            self.code.push('\n');
+        }
+    }
+
+    /// Append the sections of `other`
+    pub(super) fn append(&mut self, other: SourceMap) {
+        let offset = self.code.lines().count();
+        for (section, start_line) in other.sections {
+            if self.sections.last().map(|(last, _)| last) != Some(&section) {
+                self.sections.push((section, start_line + offset));
+            }
+        }
+        self.code.push_str(&other.code);
+    }
+
+    pub(super) fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub(super) fn into_code(self) -> String {
+        self.code
+    }
+
+    /// Section holding `line` (1-based) of the code, and the line's number
+    /// within that section
+    pub(super) fn locate(&self, line: usize) -> Option<(&CodeSection, usize)> {
+        if line == 0 || line > self.code.lines().count() {
+            return None;
+        }
+        self.sections
+            .iter()
+            .rev()
+            .find(|(_, start_line)| *start_line <= line)
+            .map(|(section, start_line)| (section, line - start_line + 1))
+    }
+}
+
+/// A compiler diagnostic, located in the section of code it refers to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CompileError {
+    /// `error` or `warning`
+    pub level: String,
+    /// Error code, e.g. `E0308`
+    pub code: Option<String>,
+    pub message: String,
+    /// Section of the primary span; `None` when the span is in code evcxr
+    /// generated, or there is none
+    pub section: Option<CodeSection>,
+    /// Line of the primary span within its section, 1-based
+    pub line: Option<usize>,
+    /// The source lines the span covers
+    pub source_lines: Vec<String>,
+    /// Label of the primary span, e.g. "expected `i32`, found `&str`"
+    pub label: String,
+    /// rustc's rendering, referring to the assembled code, used when the
+    /// error couldn't be located
+    #[serde(skip)]
+    pub rendered: String,
+}
+
+impl CompileError {
+    /// Locate an evcxr diagnostic using the map of the evaluated code
+    pub(super) fn from_evcxr(error: &evcxr::CompilationError, map: &SourceMap) -> Self {
+        let primary = error.primary_spanned_message();
+        let location = primary
+            .and_then(|message| message.span)
+            .and_then(|span| map.locate(span.start_line));
+        Self {
+            level: error.level().to_string(),
+            code: error.code().map(str::to_string),
+            message: error.message(),
+            section: location.map(|(section, _)| section.clone()),
+            line: location.map(|(_, line)| line),
+            source_lines: primary.map(|m| m.lines.clone()).unwrap_or_default(),
+            label: primary.map(|m| m.label.clone()).unwrap_or_default(),
+            rendered: error.rendered(),
+        }
+    }
+
+    /// Where the error is, e.g. "in your input, line 2"
+    pub fn location(&self) -> Option<String> {
+        let line = self.line.unwrap_or(1);
+        Some(match self.section.as_ref()? {
+            CodeSection::Prelude => format!("in the prelude, line {}", line),
+            CodeSection::Variable(name) => format!("in restored variable `{}`", name),
+            CodeSection::Input => format!("in your input, line {}", line),
+            CodeSection::Generated => "in code generated for the snapshot".to_string(),
+        })
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(location) = self.location() else {
+            return write!(f, "{}", self.rendered.trim_end());
+        };
+        match &self.code {
+            Some(code) => write!(f, "{}[{}]: {}", self.level, code, self.message)?,
+            None => write!(f, "{}: {}", self.level, self.message)?,
+        }
+        write!(f, "\n  --> {}", location)?;
+        for line in &self.source_lines {
+            write!(f, "\n   | {}", line)?;
+        }
+        if !self.label.is_empty() {
+            write!(f, "\n   = {}", self.label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when evaluated code doesn't compile
+///
+/// Errors come before warnings, of which only the first few are shown.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub struct CompileErrors(pub Vec<CompileError>);
+
+/// Warnings shown in full; more are cut to the first two and a count
+const MAX_SHOWN_WARNINGS: usize = 3;
+
+impl fmt::Display for CompileErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts: Vec<String> = self
+            .0
+            .iter()
+            .filter(|e| e.level == "error")
+            .map(ToString::to_string)
+            .collect();
+        let warnings: Vec<_> = self.0.iter().filter(|e| e.level == "warning").collect();
+        if warnings.len() <= MAX_SHOWN_WARNINGS {
+            parts.extend(warnings.iter().map(ToString::to_string));
+        } else {
+            parts.extend(warnings.iter().take(2).map(ToString::to_string));
+            parts.push(format!("... and {} more warnings", warnings.len() - 2));
+        }
+        write!(f, "{}", parts.join("\n").trim())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(section: Option<CodeSection>, line: Option<usize>) -> CompileError {
+        CompileError {
+            level: "error".to_string(),
+            code: Some("E0308".to_string()),
+            message: "mismatched types".to_string(),
+            section,
+            line,
+            source_lines: vec!["let port: u16 = \"x\";".to_string()],
+            label: "expected `u16`, found `&str`".to_string(),
+            rendered: "error[E0308]: mismatched types\n --> src/lib.rs:41:21".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_source_map_locates_sections() {
+        let mut map = SourceMap::new();
+        map.push(
+            CodeSection::Generated,
+            "use std::sync::Arc;\nuse std::rc::Rc;",
+        );
+        map.push(CodeSection::Variable("config".to_string()), "fn a() {\n}\n");
+        map.push(CodeSection::Variable("count".to_string()), "fn b() {}");
+        let mut input = SourceMap::single(CodeSection::Prelude, "use std::fmt;");
+        input.push(CodeSection::Input, "let x = 1;\nlet y: u16 = \"x\";");
+        map.append(input);
+
+        assert_eq!(map.code().lines().count(), 8);
+        assert_eq!(map.locate(2), Some((&CodeSection::Generated, 2)));
+        assert_eq!(
+            map.locate(4),
+            Some((&CodeSection::Variable("config".to_string()), 2))
+        );
+        assert_eq!(
+            map.locate(5),
+            Some((&CodeSection::Variable("count".to_string()), 1))
+        );
+        assert_eq!(map.locate(6), Some((&CodeSection::Prelude, 1)));
+        assert_eq!(map.locate(8), Some((&CodeSection::Input, 2)));
+        assert_eq!(map.locate(0), None);
+        assert_eq!(map.locate(9), None);
+    }
+
+    #[test]
+    fn test_compile_error_names_variable() {
+        let err = error(Some(CodeSection::Variable("config".to_string())), Some(3));
+        let text = err.to_string();
+        assert!(
+            text.starts_with("error[E0308]: mismatched types\n  --> in restored variable `config`"),
+            "Got: {}",
+            text
+        );
+        assert!(!text.contains("src/lib.rs"), "Got: {}", text);
+
+        let text = error(Some(CodeSection::Input), Some(2)).to_string();
+        assert!(text.contains("in your input, line 2"), "Got: {}", text);
+        assert!(
+            text.contains("= expected `u16`, found `&str`"),
+            "Got: {}",
+            text
+        );
+
+        // Errors in evcxr's own code keep rustc's rendering
+        let text = error(None, None).to_string();
+        assert!(text.contains("src/lib.rs:41:21"), "Got: {}", text);
+    }
+
+    #[test]
+    fn test_compile_errors_order_and_warning_limit() {
+        let warning = |message: &str| CompileError {
+            level: "warning".to_string(),
+            message: message.to_string(),
+            ..error(Some(CodeSection::Input), Some(1))
+        };
+        let mut errors = vec![warning("w1")];
+        errors.push(error(Some(CodeSection::Input), Some(1)));
+        errors.extend(["w2", "w3", "w4"].map(warning));
+        let text = CompileErrors(errors).to_string();
+        assert!(text.starts_with("error[E0308]"), "Got: {}", text);
+        assert!(text.contains("warning[E0308]: w2"), "Got: {}", text);
+        assert!(!text.contains("w3"), "Got: {}", text);
+        assert!(text.ends_with("... and 2 more warnings"), "Got: {}", text);
+    }
+}
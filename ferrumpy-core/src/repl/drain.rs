@@ -0,0 +1,329 @@
+//! Subprocess output, drained continuously into bounded buffers
+//!
+//! evcxr forwards each line the subprocess prints to an unbounded channel.
+//! A thread per stream moves the lines into a buffer capped in bytes, so
+//! code printing without end can't exhaust memory while nobody reads it.
+//! Lines for the output callback go on to a thread of their own, so a
+//! callback waiting on a lock the reader holds (e.g. the GIL) can't keep the
+//! stream from being drained.
+
+use crossbeam_channel::{Receiver, Select, Sender, TryRecvError};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Default for `ReplSession::set_output_cap`, per stream
+pub const DEFAULT_OUTPUT_CAP_BYTES: usize = 8 << 20;
+
+/// A stream of the subprocess
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Called on a callback thread with each line as it arrives
+pub type OutputCallback = Box<dyn FnMut(OutputStream, &str) + Send>;
+
+/// The callback shared by the drain threads of a session
+pub(super) type SharedCallback = Arc<Mutex<Option<OutputCallback>>>;
+
+/// Lines not read yet, keeping at most `cap` bytes by dropping the oldest
+#[derive(Debug)]
+struct OutputBuffer {
+    lines: VecDeque<String>,
+    bytes: usize,
+    cap: usize,
+    /// Lines pushed since the buffer was created
+    pushed: u64,
+    /// Lines dropped to stay under the cap
+    dropped: u64,
+}
+
+impl OutputBuffer {
+    fn new(cap: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            bytes: 0,
+            cap,
+            pushed: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        self.bytes += line.len();
+        self.lines.push_back(line);
+        self.pushed += 1;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.bytes > self.cap {
+            let Some(line) = self.lines.pop_front() else {
+                break;
+            };
+            self.bytes -= line.len();
+            self.dropped += 1;
+        }
+    }
+
+    /// The lines pushed after the first `mark`, those still buffered
+    fn take_since(&mut self, mark: u64) -> Vec<String> {
+        let count = (self.pushed.saturating_sub(mark) as usize).min(self.lines.len());
+        let lines: Vec<String> = self.lines.drain(self.lines.len() - count..).collect();
+        self.bytes -= lines.iter().map(String::len).sum::<usize>();
+        lines
+    }
+}
+
+/// The buffer of a stream, and a condvar notified each time its drain thread
+/// has moved lines into it
+#[derive(Debug)]
+struct DrainState {
+    buffer: Mutex<OutputBuffer>,
+    drained: Condvar,
+}
+
+/// One stream of the subprocess, drained on a background thread
+pub(super) struct OutputDrain {
+    receiver: Receiver<String>,
+    state: Arc<DrainState>,
+}
+
+impl OutputDrain {
+    /// Start draining `receiver`, until its sender is gone
+    pub(super) fn spawn(
+        receiver: Receiver<String>,
+        stream: OutputStream,
+        callback: SharedCallback,
+    ) -> Self {
+        let state = Arc::new(DrainState {
+            buffer: Mutex::new(OutputBuffer::new(DEFAULT_OUTPUT_CAP_BYTES)),
+            drained: Condvar::new(),
+        });
+        let drain = Self {
+            receiver: receiver.clone(),
+            state: state.clone(),
+        };
+        spawn_drain(receiver, state, stream, callback);
+        drain
+    }
+
+    /// Drain `receiver` into the same buffer from now on, e.g. the stream of
+    /// a context replacing this one's
+    pub(super) fn reattach(
+        &mut self,
+        receiver: Receiver<String>,
+        stream: OutputStream,
+        callback: SharedCallback,
+    ) {
+        self.receiver = receiver.clone();
+        spawn_drain(receiver, self.state.clone(), stream, callback);
+    }
+
+    /// Lock the buffer once the drain thread has emptied the channel
+    ///
+    /// The thread only receives with the buffer locked, so no line sent
+    /// earlier is missing. It never waits on the callback, so this doesn't
+    /// either.
+    fn lock(&self) -> MutexGuard<'_, OutputBuffer> {
+        let mut buffer = lock(&self.state.buffer);
+        while !self.receiver.is_empty() {
+            buffer = self
+                .state
+                .drained
+                .wait(buffer)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        buffer
+    }
+
+    /// Position after the lines sent so far, for `take_since`
+    pub(super) fn mark(&self) -> u64 {
+        self.lock().pushed
+    }
+
+    /// Lines sent after `mark`, leaving earlier ones buffered
+    pub(super) fn take_since(&self, mark: u64) -> Vec<String> {
+        self.lock().take_since(mark)
+    }
+
+    /// All buffered lines
+    pub(super) fn take_all(&self) -> Vec<String> {
+        self.take_since(0)
+    }
+
+    /// Put lines back, e.g. output of an evaluation the caller didn't return
+    pub(super) fn extend(&self, lines: impl IntoIterator<Item = String>) {
+        let mut buffer = self.lock();
+        for line in lines {
+            buffer.push(line);
+        }
+    }
+
+    pub(super) fn set_cap(&self, cap: usize) {
+        let mut buffer = self.lock();
+        buffer.cap = cap;
+        buffer.trim();
+    }
+
+    /// Lines dropped so far to stay under the cap
+    pub(super) fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn spawn_drain(
+    receiver: Receiver<String>,
+    state: Arc<DrainState>,
+    stream: OutputStream,
+    callback: SharedCallback,
+) {
+    let (sender, lines) = crossbeam_channel::unbounded();
+    std::thread::spawn(move || drain_lines(receiver, state, sender));
+    std::thread::spawn(move || call_back(lines, stream, callback));
+}
+
+/// Body of a drain thread: wait for lines, move them into the buffer and
+/// pass them on to the callback thread
+fn drain_lines(receiver: Receiver<String>, state: Arc<DrainState>, callback: Sender<String>) {
+    loop {
+        let mut select = Select::new();
+        select.recv(&receiver);
+        select.ready();
+
+        let mut disconnected = false;
+        {
+            let mut buffer = lock(&state.buffer);
+            loop {
+                match receiver.try_recv() {
+                    Ok(line) => {
+                        let _ = callback.send(line.clone());
+                        buffer.push(line);
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        disconnected = true;
+                        break;
+                    }
+                }
+            }
+        }
+        state.drained.notify_all();
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Body of a callback thread: pass each line to the callback, if any, until
+/// the drain thread is gone
+fn call_back(lines: Receiver<String>, stream: OutputStream, callback: SharedCallback) {
+    for line in lines {
+        if let Some(callback) = lock(&callback).as_mut() {
+            callback(stream, &line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_buffer_is_bounded() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let streamed = Arc::new(Mutex::new(0usize));
+        let counter = streamed.clone();
+        let callback: SharedCallback =
+            Arc::new(Mutex::new(Some(Box::new(move |stream, _: &str| {
+                assert_eq!(stream, OutputStream::Stdout);
+                *lock(&counter) += 1;
+            }))));
+        let drain = OutputDrain::spawn(receiver, OutputStream::Stdout, callback);
+        drain.set_cap(1 << 20);
+
+        for i in 0..100_000 {
+            sender.send(format!("line {:>15}", i)).unwrap();
+        }
+        drop(sender);
+        let lines = drain.take_all();
+
+        // 20 bytes per line, so about 52k lines fit in 1 MiB
+        let bytes: usize = lines.iter().map(String::len).sum();
+        assert!(bytes <= 1 << 20, "Got: {} bytes", bytes);
+        assert_eq!(lines.len(), (1 << 20) / 20);
+        assert_eq!(drain.dropped(), 100_000 - lines.len() as u64);
+        assert_eq!(
+            lines.last().unwrap().trim_start_matches("line").trim(),
+            "99999"
+        );
+        assert!(drain.take_all().is_empty());
+
+        // The callback sees every line, dropped or not
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while *lock(&streamed) < 100_000 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(*lock(&streamed), 100_000);
+    }
+
+    #[test]
+    fn test_take_since_mark() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let drain = OutputDrain::spawn(receiver, OutputStream::Stderr, Default::default());
+        sender.send("before".to_string()).unwrap();
+        let mark = drain.mark();
+        sender.send("during".to_string()).unwrap();
+
+        assert_eq!(drain.take_since(mark), vec!["during"]);
+        drain.extend(["put back".to_string()]);
+        assert_eq!(drain.take_all(), vec!["before", "put back"]);
+    }
+
+    #[test]
+    fn test_take_while_callback_waits_on_reader() {
+        // Stands in for the GIL: held by the reader, wanted by the callback
+        let gil = Arc::new(Mutex::new(()));
+        let streamed = Arc::new(Mutex::new(0usize));
+        let (held, counter) = (gil.clone(), streamed.clone());
+        let callback: SharedCallback = Arc::new(Mutex::new(Some(Box::new(move |_, _: &str| {
+            let _gil = lock(&held);
+            *lock(&counter) += 1;
+        }))));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let drain = OutputDrain::spawn(receiver, OutputStream::Stdout, callback);
+
+        let guard = lock(&gil);
+        let mut taken = 0;
+        for i in 0..1000 {
+            sender.send(format!("line {}", i)).unwrap();
+            if i % 100 == 99 {
+                taken += drain.take_all().len();
+            }
+        }
+        assert_eq!(taken, 1000);
+        drop(guard);
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while *lock(&streamed) < 1000 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(*lock(&streamed), 1000);
+    }
+}
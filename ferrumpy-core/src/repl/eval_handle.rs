@@ -0,0 +1,160 @@
+//! Evaluations running off the calling thread
+
+use anyhow::Result;
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use thiserror::Error;
+
+use super::session::ReplSession;
+
+/// Error returned by `ReplSession::eval_with_timeout` when the deadline
+/// passes; the session restores its snapshot before the next evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Evaluation timed out after {0:?}")]
+pub struct EvalTimedOut(pub Duration);
+
+/// Error returned by an evaluation stopped with an [`InterruptHandle`]; the
+/// session has been restarted and its snapshot restored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Evaluation interrupted")]
+pub struct Interrupted;
+
+/// Interrupts a session's evaluations from another thread, see
+/// `ReplSession::interrupt_handle`
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    state: Arc<InterruptState>,
+}
+
+/// What the clones of an [`InterruptHandle`] share
+#[derive(Debug)]
+struct InterruptState {
+    /// Handle of the session's subprocess, replaced along with the context
+    /// when an evaluation is abandoned at its deadline
+    process: Mutex<Arc<Mutex<Child>>>,
+    /// Set until the evaluation it stops reports [`Interrupted`]
+    requested: AtomicBool,
+}
+
+impl InterruptHandle {
+    pub(super) fn new(process: Arc<Mutex<Child>>) -> Self {
+        Self {
+            state: Arc::new(InterruptState {
+                process: Mutex::new(process),
+                requested: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Kill the subprocess running the evaluation in flight, which then
+    /// fails with [`Interrupted`]
+    ///
+    /// Code that is still compiling stops once the compile finishes.
+    pub fn interrupt(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        self.kill();
+    }
+
+    pub(super) fn kill(&self) {
+        if let Ok(mut child) = self.process().lock() {
+            let _ = child.kill();
+        }
+    }
+
+    /// Handle of the subprocess the interrupts kill
+    pub(super) fn process(&self) -> Arc<Mutex<Child>> {
+        let process = self.state.process.lock().unwrap_or_else(|e| e.into_inner());
+        process.clone()
+    }
+
+    /// Point this handle and its clones at a new subprocess
+    pub(super) fn set_process(&self, process: Arc<Mutex<Child>>) {
+        *self.state.process.lock().unwrap_or_else(|e| e.into_inner()) = process;
+    }
+
+    /// Whether an interrupt was requested since the last call
+    pub(super) fn take_requested(&self) -> bool {
+        self.state.requested.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// An evaluation started by `ReplSession::eval_async`
+///
+/// The handle owns the session until [`wait`](Self::wait) hands it back.
+pub struct EvalHandle {
+    thread: JoinHandle<(ReplSession, Result<String>)>,
+    interrupt: InterruptHandle,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl EvalHandle {
+    pub(super) fn spawn(mut session: ReplSession, code: String) -> Self {
+        let interrupt = session.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            // The session goes back to the caller even if evaluating panics
+            let result =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| session.eval(&code)))
+                    .unwrap_or_else(|panic| {
+                        Err(anyhow::anyhow!(
+                            "Evaluation panicked: {}",
+                            panic_message(&panic)
+                        ))
+                    });
+            (session, result)
+        });
+        Self {
+            thread,
+            interrupt,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the evaluation has finished, so `wait` won't block
+    pub fn poll(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Kill the subprocess running the evaluation
+    ///
+    /// Code that is still compiling stops once the compile finishes. `wait`
+    /// then returns [`Interrupted`], with the snapshot restored.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.interrupt.interrupt();
+    }
+
+    /// Wait for the evaluation, returning the session and its result
+    ///
+    /// The session comes back whatever happened to the evaluation, a panic
+    /// being its error.
+    pub fn wait(self) -> (ReplSession, Result<String>) {
+        let (mut session, result) = match self.thread.join() {
+            Ok(finished) => finished,
+            // The thread catches panics of the evaluation, so can't panic
+            Err(panic) => std::panic::resume_unwind(panic),
+        };
+        if !self.cancelled.load(Ordering::SeqCst) {
+            return (session, result);
+        }
+        // The kill came after the evaluation finished, so nothing restored
+        // the session yet
+        if !session.was_interrupted() {
+            if let Err(e) = session.interrupt() {
+                return (session, Err(e));
+            }
+        }
+        (session, Err(Interrupted.into()))
+    }
+}
+
+/// Message of a panic payload, if it is a string
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown error")
+}
@@ -0,0 +1,145 @@
+//! Expressions answered without compiling them
+//!
+//! Compiling `1 + 1` through evcxr takes a second or more, while the
+//! expression evaluator computes it at once. When the evaluator can't be
+//! trusted to give rustc's answer (calls, types it infers differently,
+//! non-scalar values it formats differently), evcxr gets the code instead.
+
+use std::collections::HashMap;
+
+use super::tracking::{TrackedVar, VarOrigin};
+use crate::expr::{parse_expr, value_from_snapshot_json, EvalError, Evaluator, Value, VarContext};
+
+/// Evaluate `code` as a single expression, if the evaluator can
+///
+/// `None` means evcxr has to evaluate it. Otherwise the result is the
+/// value's text as evcxr prints it (`None` for `()`), or the error
+/// evaluating it at runtime would end in, e.g. an index out of bounds.
+/// Snapshot variables of scalar types are available under their names,
+/// read from `snapshot_json`.
+pub(super) fn fast_eval(
+    code: &str,
+    snapshot_json: Option<&str>,
+    variables: &HashMap<String, TrackedVar>,
+) -> Option<Result<Option<String>, EvalError>> {
+    let expr = parse_expr(code).ok()?;
+    // Snapshots can be large, so only read one for an expression using it
+    let result = match Evaluator::new().eval(&expr) {
+        Err(EvalError::UnknownVariable { .. }) => {
+            let vars = scalar_snapshot_vars(snapshot_json?, variables);
+            Evaluator::with_variables(vars).eval(&expr)
+        }
+        result => result,
+    };
+    match result {
+        Ok(value) => evcxr_text(&value).map(Ok),
+        Err(
+            e @ (EvalError::DivisionByZero
+            | EvalError::IndexOutOfBounds { .. }
+            | EvalError::NullPointer
+            | EvalError::FieldNotFound { .. }),
+        ) => Some(Err(e)),
+        // Unsupported syntax, unknown names, types the evaluator is stricter
+        // about than rustc's inference, and overflow, which rustc may report
+        // at compile time rather than as a panic
+        Err(_) => None,
+    }
+}
+
+/// Snapshot variables of scalar types, unless shadowed or truncated
+fn scalar_snapshot_vars(
+    snapshot_json: &str,
+    variables: &HashMap<String, TrackedVar>,
+) -> VarContext {
+    let Ok(snapshot) = serde_json::from_str::<serde_json::Value>(snapshot_json) else {
+        return VarContext::new();
+    };
+    let Some(values) = snapshot.get("variables") else {
+        return VarContext::new();
+    };
+    variables
+        .iter()
+        .filter(|(_, var)| var.origin == VarOrigin::Snapshot && !var.truncated)
+        .filter_map(|(name, var)| {
+            let value = value_from_snapshot_json(values.get(name)?, &var.declared_type);
+            (value.is_numeric() || matches!(value, Value::Bool(_) | Value::Char(_)))
+                .then(|| (name.clone(), value))
+        })
+        .collect()
+}
+
+/// The value's text as evcxr prints it, with `Debug`; `None` for values
+/// whose formats differ, which evcxr has to print
+fn evcxr_text(value: &Value) -> Option<Option<String>> {
+    let text = match value {
+        Value::Unit => return Some(None),
+        Value::F32(v) => format!("{:?}", v),
+        Value::F64(v) => format!("{:?}", v),
+        Value::Char(c) => format!("{:?}", c),
+        Value::String(s) => format!("{:?}", s),
+        Value::Bool(_) => value.to_string(),
+        value if value.is_integer() => value.to_string(),
+        _ => return None,
+    };
+    Some(Some(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, TrackedVar> {
+        let mut variables = HashMap::new();
+        variables.insert(
+            "count".to_string(),
+            TrackedVar::new("i32", VarOrigin::Snapshot),
+        );
+        variables.insert(
+            "name".to_string(),
+            TrackedVar::new("String", VarOrigin::Snapshot),
+        );
+        variables.insert(
+            "x".to_string(),
+            TrackedVar::new("i32", VarOrigin::UserDefined),
+        );
+        variables
+    }
+
+    #[test]
+    fn test_fast_eval() {
+        let snapshot = r#"{"variables": {"count": 3, "name": "Ann"}}"#;
+        let eval = |code: &str| fast_eval(code, Some(snapshot), &variables());
+
+        assert_eq!(eval("2 + 3").unwrap().unwrap().as_deref(), Some("5"));
+        assert_eq!(eval("\"hi\".len()").unwrap().unwrap().as_deref(), Some("2"));
+        assert_eq!(eval("1.0 / 4.0").unwrap().unwrap().as_deref(), Some("0.25"));
+        assert_eq!(eval("2.0 * 3.0").unwrap().unwrap().as_deref(), Some("6.0"));
+        assert_eq!(eval("count * 2").unwrap().unwrap().as_deref(), Some("6"));
+        assert!(matches!(
+            eval("1 / 0"),
+            Some(Err(EvalError::DivisionByZero))
+        ));
+
+        // Left to evcxr: statements, non-scalar variables, user variables
+        // whose values aren't known, and calls
+        assert!(eval("let y = 1;").is_none());
+        assert!(eval("name.len()").is_none());
+        assert!(eval("x + 1").is_none());
+        assert!(eval("count()").is_none());
+        assert!(fast_eval("count * 2", None, &variables()).is_none());
+    }
+
+    #[test]
+    fn test_fast_eval_leaves_overflow_to_evcxr() {
+        let snapshot = r#"{"variables": {"count": 2147483646}}"#;
+        let eval = |code: &str| fast_eval(code, Some(snapshot), &variables());
+
+        assert!(eval("2147483647 + 1").is_none());
+        assert!(eval("1 + 3000000000").is_none());
+        assert!(eval("count + 2").is_none());
+        assert_eq!(
+            eval("count + 1").unwrap().unwrap().as_deref(),
+            Some("2147483647")
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Record of the code evaluated in a session, and its export as a Rust file
+
+use anyhow::Result;
+use std::time::SystemTime;
+
+use super::output::EvalOutput;
+
+/// Length, in chars, past which `HistoryEntry::summary` is cut
+const SUMMARY_MAX_CHARS: usize = 80;
+
+/// One evaluation, see `ReplSession::history`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// The evaluated code, as given
+    pub code: String,
+    /// When the evaluation finished
+    pub timestamp: SystemTime,
+    /// Whether the code compiled and ran
+    pub success: bool,
+    /// First line of the output, or of the error
+    pub summary: String,
+}
+
+impl HistoryEntry {
+    pub(super) fn new(code: &str, result: &Result<EvalOutput>) -> Self {
+        let (success, text) = match result {
+            Ok(output) => (true, output.to_string()),
+            Err(e) => (false, e.to_string()),
+        };
+        Self {
+            code: code.to_string(),
+            timestamp: SystemTime::now(),
+            success,
+            summary: summarize(&text),
+        }
+    }
+}
+
+/// First line of `text`, cut to `SUMMARY_MAX_CHARS`
+fn summarize(text: &str) -> String {
+    let line = text
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("");
+    match line.char_indices().nth(SUMMARY_MAX_CHARS) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+/// Source of a program replaying `entries` in its `main`
+///
+/// `deps` are the session's `:dep` commands, listed for the Cargo.toml
+/// the file needs. `prelude` holds the `use` statements of the session and
+/// `snapshot_code` the items restoring its snapshot, both placed before
+/// `main`. REPL commands and failed entries are kept as comments, so the
+/// file compiles, and a lone expression is printed as evcxr displays it.
+pub(super) fn history_scaffold(
+    deps: &[String],
+    prelude: &[String],
+    snapshot_code: Option<&str>,
+    entries: &[(usize, &HistoryEntry)],
+) -> String {
+    let mut source = String::from("// Session history exported by FerrumPy\n");
+    if !deps.is_empty() {
+        source.push_str("//\n// [dependencies]\n");
+        for dep in deps {
+            let dep = dep.trim().strip_prefix(":dep").unwrap_or(dep).trim();
+            source.push_str(&format!("// {}\n", dep));
+        }
+    }
+    source.push_str("#![allow(unused)]\n\n");
+    for stmt in prelude {
+        source.push_str(stmt);
+        source.push('\n');
+    }
+    if let Some(code) = snapshot_code {
+        source.push('\n');
+        source.push_str(code.trim_end());
+        source.push_str("\nuse ferrumpy_vars::*;\n");
+    }
+
+    source.push_str("\nfn main() -> Result<(), Box<dyn std::error::Error>> {\n");
+    for (index, entry) in entries {
+        let code = entry.code.trim();
+        if !entry.success {
+            source.push_str(&format!("    // [{}] failed: {}\n", index, entry.summary));
+            source.push_str(&commented(code));
+        } else if code.starts_with(':') {
+            source.push_str(&format!("    // [{}] REPL command\n", index));
+            source.push_str(&commented(code));
+        } else {
+            source.push_str(&format!("    // [{}]\n", index));
+            for line in fragment_code(code).lines() {
+                source.push_str("    ");
+                source.push_str(line);
+                source.push('\n');
+            }
+        }
+    }
+    source.push_str("    Ok(())\n}\n");
+    source
+}
+
+/// Code as one comment line per line, indented into `main`
+fn commented(code: &str) -> String {
+    code.lines()
+        .map(|line| format!("    // {}\n", line))
+        .collect()
+}
+
+/// A fragment as a statement sequence: a lone expression is printed with
+/// `Debug` (unless it prints itself), and other trailing expressions get a
+/// semicolon
+fn fragment_code(code: &str) -> String {
+    let Ok(block) = syn::parse_str::<syn::Block>(&format!("{{\n{}\n}}", code)) else {
+        return code.to_string();
+    };
+    let prints = |expr: &syn::Expr| {
+        matches!(expr, syn::Expr::Macro(m) if ["print", "println", "eprint", "eprintln"]
+            .iter()
+            .any(|name| m.mac.path.is_ident(name)))
+    };
+    match block.stmts.as_slice() {
+        [syn::Stmt::Expr(expr, None)] if !prints(expr) => {
+            format!("println!(\"{{:?}}\", {});", code)
+        }
+        [.., syn::Stmt::Expr(_, None)]
+        | [.., syn::Stmt::Macro(syn::StmtMacro {
+            semi_token: None, ..
+        })] => {
+            format!("{};", code)
+        }
+        _ => code.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(code: &str, success: bool) -> HistoryEntry {
+        HistoryEntry {
+            code: code.to_string(),
+            timestamp: SystemTime::now(),
+            success,
+            summary: if success {
+                String::new()
+            } else {
+                "error[E0425]: cannot find value".to_string()
+            },
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        assert_eq!(summarize("\n42\nprinted"), "42");
+        assert_eq!(summarize(""), "");
+        let long = "x".repeat(100);
+        assert_eq!(summarize(&long), format!("{}...", "x".repeat(80)));
+    }
+
+    #[test]
+    fn test_history_scaffold_parses() {
+        let history = [
+            entry("let x = count() + 1;", true),
+            entry("fn bump(v: i32) -> i32 { v + 1 }", true),
+            entry("bump(x)", true),
+            entry("undefined_name", false),
+        ];
+        let entries: Vec<_> = history.iter().enumerate().collect();
+        let snapshot_code = "mod ferrumpy_vars {\n    pub fn count() -> i32 { 3 }\n}";
+        let prelude = ["use std::collections::HashMap;".to_string()];
+
+        let deps = [":dep ferrumpy_snapshot = { path = \"/tmp/lib\" }".to_string()];
+
+        let source = history_scaffold(&deps, &prelude, Some(snapshot_code), &entries);
+        let file = syn::parse_file(&source).unwrap_or_else(|e| panic!("{}: {}", e, source));
+        let has_main = file
+            .items
+            .iter()
+            .any(|item| matches!(item, syn::Item::Fn(f) if f.sig.ident == "main"));
+        assert!(has_main, "Got: {}", source);
+        assert!(
+            source.contains("println!(\"{:?}\", bump(x));"),
+            "Got: {}",
+            source
+        );
+        assert!(source.contains("// undefined_name"), "Got: {}", source);
+        assert!(source.contains("use ferrumpy_vars::*;"), "Got: {}", source);
+        assert!(
+            source.contains("// ferrumpy_snapshot = { path = \"/tmp/lib\" }"),
+            "Got: {}",
+            source
+        );
+
+        // Only the successful entries, keeping their indices
+        let successful: Vec<_> = entries.iter().copied().filter(|(_, e)| e.success).collect();
+        let source = history_scaffold(&[], &prelude, None, &successful);
+        assert!(syn::parse_file(&source).is_ok(), "Got: {}", source);
+        assert!(!source.contains("undefined_name"), "Got: {}", source);
+        assert!(source.contains("// [2]"), "Got: {}", source);
+    }
+
+    #[test]
+    fn test_fragment_code() {
+        assert_eq!(fragment_code("1 + 1"), "println!(\"{:?}\", 1 + 1);");
+        assert_eq!(fragment_code("let y = 2; y * 2"), "let y = 2; y * 2;");
+        assert_eq!(fragment_code("println!(\"hi\")"), "println!(\"hi\");");
+        assert_eq!(fragment_code("let y = 2;"), "let y = 2;");
+        // Left for the compiler to report
+        assert_eq!(fragment_code("let = ;"), "let = ;");
+    }
+}
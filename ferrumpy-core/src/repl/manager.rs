@@ -0,0 +1,94 @@
+//! Several REPL sessions side by side
+//!
+//! Debugging two breakpoints or threads at once takes a session each. Every
+//! session has its own evcxr context and worker process; they only share
+//! the dependency cache, which they take turns compiling against.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+use super::config::ReplConfig;
+use super::session::ReplSession;
+
+/// Named REPL sessions, independent of each other
+#[derive(Default)]
+pub struct SessionManager {
+    sessions: HashMap<String, ReplSession>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a session named `name`
+    ///
+    /// Fails if a session of that name is open; close it first.
+    pub fn create(&mut self, name: &str, config: ReplConfig) -> Result<&mut ReplSession> {
+        if self.sessions.contains_key(name) {
+            anyhow::bail!("Session `{}` already exists", name);
+        }
+        let session = ReplSession::with_config(config)?;
+        Ok(self.sessions.entry(name.to_string()).or_insert(session))
+    }
+
+    pub fn get(&mut self, name: &str) -> Option<&mut ReplSession> {
+        self.sessions.get_mut(name)
+    }
+
+    /// Names of the open sessions, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.sessions.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Close the session named `name`, stopping its worker; `false` if there
+    /// was none
+    pub fn close(&mut self, name: &str) -> bool {
+        self.sessions.remove(name).is_some()
+    }
+
+    pub fn close_all(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_are_isolated() {
+        let mut manager = SessionManager::new();
+        let mut sessions = Vec::new();
+        for (name, count) in [("main", 1), ("worker", 2)] {
+            let session = match manager.create(name, ReplConfig::default()) {
+                Ok(session) => session,
+                Err(e) => {
+                    eprintln!("Skipping test (evcxr unavailable): {}", e);
+                    return;
+                }
+            };
+            let snapshot = serde_json::json!({
+                "variables": {"count": count},
+                "types": {"count": "i32"}
+            });
+            session.load_snapshot(&snapshot.to_string(), "{}").unwrap();
+            sessions.push(name);
+        }
+        assert!(manager.create("main", ReplConfig::default()).is_err());
+        assert_eq!(manager.names(), sessions);
+
+        let result = manager.get("main").unwrap().eval("count() * 10").unwrap();
+        assert!(result.contains("10"), "Got: {}", result);
+        let result = manager.get("worker").unwrap().eval("count() * 10").unwrap();
+        assert!(result.contains("20"), "Got: {}", result);
+
+        assert!(manager.close("main"));
+        assert!(!manager.close("main"));
+        assert!(manager.get("main").is_none());
+        manager.close_all();
+        assert!(manager.names().is_empty());
+    }
+}
@@ -3,8 +3,39 @@
 //! Provides an embedded Rust REPL using evcxr.
 //! This allows ferrumpy to run Rust expressions with captured debug state.
 
+mod codegen;
+mod completion;
+mod config;
+mod deps;
+mod diagnostics;
+mod drain;
+mod eval_handle;
+mod fast_path;
+mod history;
+mod manager;
+mod output;
+mod policy;
+mod report;
 mod scan;
 mod session;
+mod tracking;
+mod warmup;
 
-pub use scan::FragmentValidity;
+pub use completion::ReplCompletion;
+pub use config::{ReplConfig, ReplWorkerOptions, DEFAULT_CACHE_MB, DEFAULT_OPT_LEVEL};
+pub use deps::{DeclaredDep, DepConflict, DepOrigin};
+pub use diagnostics::{CodeSection, CompileError, CompileErrors};
+pub use drain::{OutputCallback, OutputStream, DEFAULT_OUTPUT_CAP_BYTES};
+pub use eval_handle::{EvalHandle, EvalTimedOut, InterruptHandle, Interrupted};
+pub use history::HistoryEntry;
+pub use manager::SessionManager;
+pub use output::EvalOutput;
+pub use policy::{OnTruncate, SnapshotPolicy};
+pub use report::{
+    LoadedVar, RestartReport, RestoreStrategy, SkippedVar, SnapshotReport, SnapshotUpdate,
+    TruncatedArray, TruncatedUnit, TypeSource,
+};
+pub use scan::{closing_brackets, validate_source_fragment, FragmentValidity};
 pub use session::ReplSession;
+pub use tracking::{TrackedVar, VarOrigin};
+pub use warmup::{WarmupHandle, WarmupStatus};
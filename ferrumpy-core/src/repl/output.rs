@@ -0,0 +1,63 @@
+//! Output of an evaluation
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What one evaluation produced
+///
+/// `to_string()` gives the value followed by the stdout lines, as `eval`
+/// returns them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvalOutput {
+    /// Display text of the final expression, if the code ended with one
+    pub value: Option<String>,
+    /// Lines the evaluated code printed to stdout
+    pub stdout: Vec<String>,
+    /// Lines the subprocess printed to stderr while the code ran
+    pub stderr: Vec<String>,
+    /// Optimization level the code was compiled at; evcxr's default when
+    /// `None`
+    #[serde(default)]
+    pub opt_level: Option<u8>,
+    /// Computed by the expression evaluator rather than compiled, see
+    /// `ReplSession::with_fast_path`
+    #[serde(default)]
+    pub fast_path: bool,
+}
+
+impl fmt::Display for EvalOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<&str> = self
+            .value
+            .iter()
+            .map(String::as_str)
+            .chain(self.stdout.iter().map(String::as_str))
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_output_display() {
+        assert_eq!(EvalOutput::default().to_string(), "");
+
+        let output = EvalOutput {
+            value: Some("42".to_string()),
+            stdout: vec!["a".to_string(), "b".to_string()],
+            stderr: vec!["warn".to_string()],
+            opt_level: Some(0),
+            fast_path: false,
+        };
+        assert_eq!(output.to_string(), "42\na\nb");
+
+        let printed = EvalOutput {
+            stdout: vec!["only".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(printed.to_string(), "only");
+    }
+}
@@ -0,0 +1,42 @@
+//! Limits on the values generated from a snapshot
+
+/// Default for `SnapshotPolicy::max_collection_len`
+pub const DEFAULT_MAX_ARRAY_ELEMENTS: usize = 10_000;
+
+/// What `load_snapshot` does with a variable exceeding the policy's limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnTruncate {
+    /// Load the truncated value; `hydrate_variable` replaces it later
+    #[default]
+    Placeholder,
+    /// Leave the variable out, reporting it as skipped
+    Skip,
+}
+
+/// Limits on the values `load_snapshot` generates, so huge locals don't turn
+/// into unbounded source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    /// Longest array (at any depth) or top-level map generated; longer ones
+    /// keep their first elements
+    pub max_collection_len: usize,
+    /// Longest string generated, in bytes; longer ones are cut at a char
+    /// boundary. Unlimited by default.
+    pub max_string_len: usize,
+    pub on_truncate: OnTruncate,
+    /// Also bundle the loaded variables into `ctx: &__FerrumpyCtx`, with a
+    /// `let` alias borrowing each field, so functions can take them all as
+    /// one argument. Fields are copies taken at load time.
+    pub as_struct: bool,
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        Self {
+            max_collection_len: DEFAULT_MAX_ARRAY_ELEMENTS,
+            max_string_len: usize::MAX,
+            on_truncate: OnTruncate::Placeholder,
+            as_struct: false,
+        }
+    }
+}
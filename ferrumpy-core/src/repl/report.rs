@@ -0,0 +1,442 @@
+//! Outcome of loading a snapshot into a REPL session
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How a snapshot variable's value is rebuilt in the REPL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestoreStrategy {
+    /// Rust literals: numbers, strings, vecs, tuples, std collections
+    Literal,
+    /// `serde_json::from_str` into the declared type
+    Serde,
+    /// The inner value rebuilt and wrapped (`Arc::new`, `RefCell::new`, ...)
+    Wrapper,
+    /// bincode read from a binary snapshot's payload file
+    Binary,
+}
+
+/// Where a snapshot variable's declared type came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeSource {
+    /// The `type_hints` passed to `load_snapshot`
+    Hint,
+    /// The snapshot's own `types`
+    #[default]
+    Snapshot,
+    /// Inferred from the shape of the JSON value
+    Inferred,
+}
+
+/// A variable available in the REPL after loading
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoadedVar {
+    pub name: String,
+    /// Type given by the snapshot
+    pub declared_type: String,
+    /// Type of the accessor in the REPL
+    pub restored_type: String,
+    pub strategy: RestoreStrategy,
+    /// Loaded with a value cut to the snapshot policy's limits
+    #[serde(default)]
+    pub truncated: bool,
+    /// Where `declared_type` came from
+    #[serde(default)]
+    pub type_source: TypeSource,
+}
+
+/// A variable left out of the REPL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedVar {
+    pub name: String,
+    pub reason: String,
+}
+
+/// What a [`TruncatedArray`]'s lengths count
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncatedUnit {
+    /// Array elements or map entries
+    #[default]
+    Elements,
+    /// String bytes
+    Bytes,
+}
+
+impl TruncatedUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TruncatedUnit::Elements => "elements",
+            TruncatedUnit::Bytes => "bytes",
+        }
+    }
+}
+
+/// A snapshot array, map or string cut to the snapshot policy's limits
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TruncatedArray {
+    /// Path of the value from its variable, e.g. `users[0].tags`
+    pub path: String,
+    /// Original length
+    pub len: usize,
+    /// Length kept
+    pub kept: usize,
+    #[serde(default)]
+    pub unit: TruncatedUnit,
+}
+
+/// Outcome of `ReplSession::load_snapshot`
+///
+/// `to_string()` gives a one-line summary for display.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotReport {
+    pub loaded: Vec<LoadedVar>,
+    pub skipped: Vec<SkippedVar>,
+    /// Loaded variables restored as `serde_json::Value` instead of their
+    /// declared type
+    pub fallback_to_json: Vec<String>,
+    /// Loaded variables restored with another type than declared for a
+    /// reason other than a JSON fallback (tuples owning their `&str`s)
+    pub adjusted: Vec<String>,
+    pub truncated: Vec<TruncatedArray>,
+    /// Output of the evaluation defining the variables
+    pub eval_output: String,
+    /// The loaded variables are also bundled in `ctx`, see
+    /// `SnapshotPolicy::as_struct`
+    #[serde(default)]
+    pub as_struct: bool,
+}
+
+impl fmt::Display for SnapshotReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.loaded.is_empty() {
+            return if self.skipped.is_empty() {
+                write!(f, "Snapshot loaded (no variables)")
+            } else {
+                write!(f, "Snapshot loaded (no supported variables)")
+            };
+        }
+
+        write!(f, "Snapshot loaded with {} items", self.loaded.len())?;
+        if !self.skipped.is_empty() {
+            write!(f, " ({} skipped)", self.skipped.len())?;
+        }
+        let sample_names: Vec<String> = self
+            .loaded
+            .iter()
+            .take(5)
+            .map(|var| {
+                // With `as_struct`, each name is a `let` borrowing from `ctx`
+                if self.as_struct {
+                    var.name.clone()
+                } else {
+                    format!("{}()", var.name)
+                }
+            })
+            .collect();
+        write!(f, ". Access: {}", sample_names.join(", "))?;
+        if self.loaded.len() > 5 {
+            write!(f, ", ...")?;
+        }
+
+        let adjusted: Vec<String> = self
+            .loaded
+            .iter()
+            .filter(|var| self.adjusted.contains(&var.name))
+            .map(|var| {
+                format!(
+                    "{}: {} -> {}",
+                    var.name, var.declared_type, var.restored_type
+                )
+            })
+            .collect();
+        if !adjusted.is_empty() {
+            write!(
+                f,
+                ". Note: restored with adjusted types: {}",
+                adjusted.join(", ")
+            )?;
+        }
+        if !self.fallback_to_json.is_empty() {
+            write!(
+                f,
+                ". Note: restored as serde_json::Value: {}",
+                self.fallback_to_json.join(", ")
+            )?;
+        }
+        for unit in [TruncatedUnit::Elements, TruncatedUnit::Bytes] {
+            let truncated: Vec<&TruncatedArray> = self
+                .truncated
+                .iter()
+                .filter(|array| array.unit == unit)
+                .collect();
+            let Some(first) = truncated.first() else {
+                continue;
+            };
+            let paths: Vec<String> = truncated
+                .iter()
+                .map(|array| format!("{} ({} {})", array.path, array.len, unit.as_str()))
+                .collect();
+            let what = match unit {
+                TruncatedUnit::Elements => "truncated",
+                TruncatedUnit::Bytes => "strings truncated",
+            };
+            write!(
+                f,
+                ". Warning: {} to {} {}: {}",
+                what,
+                first.kept,
+                unit.as_str(),
+                paths.join(", ")
+            )?;
+        }
+        if self.as_struct {
+            write!(
+                f,
+                ". Note: bundled in `ctx: &__FerrumpyCtx`; its fields are clones \
+                 taken at load time, which `update_snapshot` doesn't change"
+            )?;
+        }
+        if !self.skipped.is_empty() {
+            let skipped: Vec<String> = self
+                .skipped
+                .iter()
+                .map(|var| format!("{} ({})", var.name, var.reason))
+                .collect();
+            write!(f, ". Skipped: {}", skipped.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of `ReplSession::update_snapshot`
+///
+/// `to_string()` gives a one-line summary for display.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotUpdate {
+    /// Variables the session didn't have
+    pub added: Vec<String>,
+    /// Variables redeclared because their value or type changed
+    pub changed: Vec<String>,
+    /// Variables left as they were, without generating code
+    pub unchanged: Vec<String>,
+    /// Snapshot variables missing from the new snapshot
+    #[serde(default)]
+    pub removed: Vec<String>,
+    /// Outcome of restoring the added and changed variables
+    pub report: SnapshotReport,
+}
+
+impl fmt::Display for SnapshotUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty() {
+            return write!(f, "Snapshot unchanged ({} variables)", self.unchanged.len());
+        }
+
+        write!(f, "Snapshot updated: ")?;
+        let mut parts = Vec::new();
+        if !self.changed.is_empty() {
+            parts.push(format!("changed {}", self.changed.join(", ")));
+        }
+        if !self.added.is_empty() {
+            parts.push(format!("added {}", self.added.join(", ")));
+        }
+        if !self.removed.is_empty() {
+            parts.push(format!("removed {}", self.removed.join(", ")));
+        }
+        parts.push(format!("{} unchanged", self.unchanged.len()));
+        write!(f, "{}", parts.join("; "))?;
+        if !self.report.skipped.is_empty() {
+            let skipped: Vec<String> = self
+                .report
+                .skipped
+                .iter()
+                .map(|var| format!("{} ({})", var.name, var.reason))
+                .collect();
+            write!(f, ". Skipped: {}", skipped.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of `ReplSession::restart`
+///
+/// `to_string()` gives a one-line summary for display.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestartReport {
+    /// `:dep` commands run again
+    pub deps: Vec<String>,
+    /// Outcome of reloading the snapshot, if one was loaded
+    pub snapshot: Option<SnapshotReport>,
+    /// Code evaluated again to redefine its variables and items, in order
+    pub replayed: Vec<String>,
+    /// Commands and code that failed to replay, with the error; a snapshot
+    /// that failed to reload is listed as `snapshot`
+    pub failed: Vec<(String, String)>,
+}
+
+impl fmt::Display for RestartReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if !self.deps.is_empty() {
+            parts.push(format!("{} dependencies", self.deps.len()));
+        }
+        if let Some(snapshot) = &self.snapshot {
+            parts.push(format!("snapshot ({} items)", snapshot.loaded.len()));
+        }
+        if !self.replayed.is_empty() {
+            parts.push(format!("{} definitions", self.replayed.len()));
+        }
+        if parts.is_empty() {
+            write!(f, "Session restarted")?;
+        } else {
+            write!(f, "Session restarted, replayed {}", parts.join(", "))?;
+        }
+        if !self.failed.is_empty() {
+            let failed: Vec<String> = self
+                .failed
+                .iter()
+                .map(|(code, error)| {
+                    let first_line = code.lines().next().unwrap_or_default();
+                    format!("{} ({})", first_line, error)
+                })
+                .collect();
+            write!(f, ". Failed: {}", failed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loaded(name: &str, declared: &str, restored: &str, strategy: RestoreStrategy) -> LoadedVar {
+        LoadedVar {
+            name: name.to_string(),
+            declared_type: declared.to_string(),
+            restored_type: restored.to_string(),
+            strategy,
+            truncated: false,
+            type_source: TypeSource::Snapshot,
+        }
+    }
+
+    #[test]
+    fn test_report_display() {
+        assert_eq!(
+            SnapshotReport::default().to_string(),
+            "Snapshot loaded (no variables)"
+        );
+
+        let report = SnapshotReport {
+            loaded: vec![
+                loaded("count", "i32", "i32", RestoreStrategy::Literal),
+                loaded(
+                    "tuple",
+                    "(&str, i32)",
+                    "(String, i32)",
+                    RestoreStrategy::Literal,
+                ),
+                loaded(
+                    "ptr",
+                    "*const u8",
+                    "serde_json::Value",
+                    RestoreStrategy::Serde,
+                ),
+            ],
+            skipped: vec![SkippedVar {
+                name: "iter".to_string(),
+                reason: "type not in scope".to_string(),
+            }],
+            fallback_to_json: vec!["ptr".to_string()],
+            adjusted: vec!["tuple".to_string()],
+            truncated: vec![TruncatedArray {
+                path: "items".to_string(),
+                len: 20,
+                kept: 10,
+                unit: TruncatedUnit::Elements,
+            }],
+            eval_output: String::new(),
+            as_struct: false,
+        };
+        assert_eq!(
+            report.to_string(),
+            "Snapshot loaded with 3 items (1 skipped). Access: count(), tuple(), ptr()\
+             . Note: restored with adjusted types: tuple: (&str, i32) -> (String, i32)\
+             . Note: restored as serde_json::Value: ptr\
+             . Warning: truncated to 10 elements: items (20 elements)\
+             . Skipped: iter (type not in scope)"
+        );
+    }
+
+    #[test]
+    fn test_report_serializes_strategy() {
+        let var = loaded("user", "app::User", "User", RestoreStrategy::Serde);
+        let json = serde_json::to_value(&var).unwrap();
+        assert_eq!(json["strategy"], "serde");
+        assert_eq!(json["declared_type"], "app::User");
+    }
+
+    #[test]
+    fn test_restart_display() {
+        assert_eq!(RestartReport::default().to_string(), "Session restarted");
+
+        let report = RestartReport {
+            deps: vec![":dep rand = \"0.8\"".to_string()],
+            snapshot: Some(SnapshotReport {
+                loaded: vec![loaded("count", "i32", "i32", RestoreStrategy::Literal)],
+                ..Default::default()
+            }),
+            replayed: vec!["let x = 1;".to_string()],
+            failed: vec![(
+                "fn f() {\n    g()\n}".to_string(),
+                "cannot find function `g`".to_string(),
+            )],
+        };
+        assert_eq!(
+            report.to_string(),
+            "Session restarted, replayed 1 dependencies, snapshot (1 items), 1 definitions\
+             . Failed: fn f() { (cannot find function `g`)"
+        );
+    }
+
+    #[test]
+    fn test_update_display() {
+        let unchanged = SnapshotUpdate {
+            unchanged: vec!["a".to_string(), "b".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(unchanged.to_string(), "Snapshot unchanged (2 variables)");
+
+        let update = SnapshotUpdate {
+            added: vec!["c".to_string()],
+            changed: vec!["a".to_string()],
+            unchanged: vec!["b".to_string()],
+            removed: Vec::new(),
+            report: SnapshotReport {
+                skipped: vec![SkippedVar {
+                    name: "c".to_string(),
+                    reason: "type not in scope".to_string(),
+                }],
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            update.to_string(),
+            "Snapshot updated: changed a; added c; 1 unchanged. Skipped: c (type not in scope)"
+        );
+
+        let stepped = SnapshotUpdate {
+            unchanged: vec!["b".to_string()],
+            removed: vec!["a".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            stepped.to_string(),
+            "Snapshot updated: removed a; 1 unchanged"
+        );
+    }
+}
@@ -30,6 +30,9 @@ pub fn validate_source_fragment(source: &str) -> FragmentValidity {
     let mut expects_attr_item = false;
 
     let mut input = source.char_indices().peekable();
+    if has_shebang(source) {
+        eat_comment_line(&mut input);
+    }
     while let Some((i, c)) = input.next() {
         let mut is_attr_target = true;
 
@@ -69,6 +72,8 @@ pub fn validate_source_fragment(source: &str) -> FragmentValidity {
                 Some(_) => {}
                 None => return FragmentValidity::Incomplete,
             },
+            // `r#type`: the `#` is part of the name, not an attribute or raw string
+            'r' if is_raw_ident(source, i) => eat_raw_ident(&mut input),
             '\"' => {
                 if let Some(kind) = check_raw_str(source, i) {
                     if !eat_string(&mut input, kind) {
@@ -102,6 +107,65 @@ pub fn validate_source_fragment(source: &str) -> FragmentValidity {
     }
 }
 
+/// Closing brackets for those left open at the end of `source`, innermost
+/// first: `"))"` for `f(g(x, `
+///
+/// Brackets in strings, chars and comments are ignored. Returns `None` if
+/// `source` ends inside a string or comment or closes a bracket it didn't open.
+pub fn closing_brackets(source: &str) -> Option<String> {
+    let mut stack: Vec<Bracket> = vec![];
+
+    let mut input = source.char_indices().peekable();
+    if has_shebang(source) {
+        eat_comment_line(&mut input);
+    }
+    while let Some((i, c)) = input.next() {
+        match c {
+            '/' => match input.peek() {
+                Some((_, '/')) => eat_comment_line(&mut input),
+                Some((_, '*')) => {
+                    input.next();
+                    if !eat_comment_block(&mut input) {
+                        return None;
+                    }
+                }
+                _ => {}
+            },
+            '(' => stack.push(Bracket::Round),
+            '[' => stack.push(Bracket::Square),
+            '{' => stack.push(Bracket::Curly),
+            ')' | ']' | '}' => match (stack.pop(), c) {
+                (Some(Bracket::Round), ')')
+                | (Some(Bracket::Square), ']')
+                | (Some(Bracket::Curly), '}') => {}
+                _ => return None,
+            },
+            '\'' => match eat_char(&mut input) {
+                Some(EatCharRes::SawInvalid) | None => return None,
+                Some(_) => {}
+            },
+            'r' if is_raw_ident(source, i) => eat_raw_ident(&mut input),
+            '\"' => match check_raw_str(source, i) {
+                Some(kind) if eat_string(&mut input, kind) => {}
+                _ => return None,
+            },
+            _ => {}
+        }
+    }
+
+    Some(
+        stack
+            .iter()
+            .rev()
+            .map(|bracket| match bracket {
+                Bracket::Round => ')',
+                Bracket::Square => ']',
+                Bracket::Curly => '}',
+            })
+            .collect(),
+    )
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 enum Bracket {
     Round,
@@ -115,6 +179,14 @@ enum StrKind {
     RawStr { hashes: usize },
 }
 
+/// Whether `source` starts with a `#!/usr/bin/env ...` line, which isn't
+/// Rust; `#![attr]` is an inner attribute, not a shebang
+fn has_shebang(source: &str) -> bool {
+    source
+        .strip_prefix("#!")
+        .is_some_and(|rest| !rest.trim_start().starts_with('['))
+}
+
 #[allow(dead_code)]
 fn check_raw_str(s: &str, quote_idx: usize) -> Option<StrKind> {
     let sb = s.as_bytes();
@@ -141,6 +213,33 @@ fn check_raw_str(s: &str, quote_idx: usize) -> Option<StrKind> {
     }
 }
 
+/// Whether the `r` at `r_idx` starts a raw identifier such as `r#match`
+///
+/// An `r` ending another identifier (`bar#`) or followed by `#"` (a raw
+/// string) doesn't.
+fn is_raw_ident(s: &str, r_idx: usize) -> bool {
+    let mut after = s[r_idx + 1..].chars();
+    let starts_ident =
+        after.next() == Some('#') && after.next().is_some_and(|c| c.is_alphabetic() || c == '_');
+    let ends_ident = s[..r_idx].chars().next_back().is_some_and(is_ident_char);
+    starts_ident && !ends_ident
+}
+
+/// Consume the `#name` of a raw identifier whose `r` was just read
+fn eat_raw_ident(iter: &mut Peekable<CharIndices<'_>>) {
+    iter.next();
+    while let Some((_, c)) = iter.peek() {
+        if !is_ident_char(*c) {
+            break;
+        }
+        iter.next();
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
 #[allow(dead_code)]
 fn eat_string(iter: &mut Peekable<CharIndices<'_>>, kind: StrKind) -> bool {
     let (hashes, escapes) = match kind {
@@ -371,4 +470,108 @@ mod tests {
             FragmentValidity::Valid
         );
     }
+
+    #[test]
+    fn test_raw_identifiers() {
+        assert_eq!(
+            validate_source_fragment("let r#type = 1;"),
+            FragmentValidity::Valid
+        );
+        assert_eq!(
+            validate_source_fragment("match r#match { _ => {} }"),
+            FragmentValidity::Valid
+        );
+        assert_eq!(
+            validate_source_fragment("let r#match = ("),
+            FragmentValidity::Incomplete
+        );
+        // A raw string right after a raw identifier is still a raw string
+        assert_eq!(
+            validate_source_fragment("f(r#type, r#\"a \" ) b\"#)"),
+            FragmentValidity::Valid
+        );
+        assert_eq!(
+            validate_source_fragment("let r#type = r#\"open"),
+            FragmentValidity::Incomplete
+        );
+        // Raw identifiers don't end an attribute's item
+        assert_eq!(
+            validate_source_fragment("#[derive(Debug)] struct r#type;"),
+            FragmentValidity::Valid
+        );
+        assert_eq!(closing_brackets("f(r#type, ").as_deref(), Some(")"));
+        assert_eq!(
+            closing_brackets("f(r#match, r#\"(\"#, ").as_deref(),
+            Some(")")
+        );
+    }
+
+    #[test]
+    fn test_fragment_validity_table() {
+        use FragmentValidity::*;
+        let cases = [
+            // Raw identifiers
+            ("let r#type = 1;", Valid),
+            ("r#type.len()", Valid),
+            ("let r#fn = (", Incomplete),
+            ("br#\"bytes\"#", Valid),
+            // Lifetimes
+            ("struct S<'a, T> { x: &'a T }", Valid),
+            (
+                "fn f<'a, 'b>(x: &'a str, y: &'b str) -> Vec<'a, T> {",
+                Incomplete,
+            ),
+            ("impl<'a> Foo<'a> { fn c(&self) -> char { 'x' } }", Valid),
+            ("fn f<'a>() -> &'a u8 { &b'(' }", Valid),
+            ("'outer: loop { break 'outer; }", Valid),
+            ("let x: &'static str = \"'\";", Valid),
+            ("fn f<'a>(x: &'a [u8]) -> &'a [u8] { x }", Valid),
+            ("fn f(x: &'a", Incomplete),
+            // Shebangs and inner attributes
+            ("#!/usr/bin/env rust-script\nfn main() {}", Valid),
+            ("#!/usr/bin/env run-cargo-script\nfn main() {", Incomplete),
+            ("#!/bin/sh -c 'exec cargo' [\nlet x = 1;", Valid),
+            ("#![allow(unused)]", Valid),
+            ("#! [allow(unused)", Incomplete),
+            ("#[derive(Debug)]", Incomplete),
+            // Raw strings with hashes
+            ("r###\"a \"## b\"###", Valid),
+            ("r##\"a \"# b", Incomplete),
+            ("let s = r#\"}\"#;", Valid),
+            // Block comments
+            ("/* open", Incomplete),
+            ("/* a /* b */", Incomplete),
+            ("/* a /* b */ c */ x", Valid),
+            ("/* } */ fn f() {}", Valid),
+            // Stray closing brackets
+            ("}", Invalid),
+            ("fn f() {} extra )", Invalid),
+            ("fn f() {} }", Invalid),
+            ("(]", Invalid),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(
+                validate_source_fragment(source),
+                expected,
+                "Got wrong validity for {:?}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_closing_brackets() {
+        assert_eq!(closing_brackets("add(").as_deref(), Some(")"));
+        assert_eq!(closing_brackets("f(g(x, ").as_deref(), Some("))"));
+        assert_eq!(closing_brackets("f(vec![1, ").as_deref(), Some("])"));
+        assert_eq!(closing_brackets("f(\"(\", ").as_deref(), Some(")"));
+        assert_eq!(closing_brackets("f(')', ").as_deref(), Some(")"));
+        assert_eq!(closing_brackets("a + b").as_deref(), Some(""));
+        assert_eq!(closing_brackets("f(\"open"), None);
+        assert_eq!(closing_brackets("f)"), None);
+        assert_eq!(
+            closing_brackets("#!/usr/bin/env x (\nf(").as_deref(),
+            Some(")")
+        );
+    }
 }
@@ -3,66 +3,253 @@
 //! Manages an evcxr evaluation context with captured debug state.
 
 use anyhow::Result;
-use crossbeam_channel::Receiver;
-use evcxr::{CommandContext, Error as EvcxrError, EvalContext};
-use std::path::Path;
-use std::process::Command;
+use evcxr::{CommandContext, Error as EvcxrError, EvalContext, EvalContextOutputs, EvalOutputs};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use super::codegen::{
+    is_base64_bytes, owned_str_type, parse_restore_error, SnapshotCodegen, CTX_STRUCT_NAME,
+};
+use super::completion::{normalize_completions, ReplCompletion};
+use super::config::{check_opt_level, CommandSink, ReplConfig, ReplWorkerOptions};
+use super::deps::{parse_dep_command, DeclaredDep, DepOrigin, DepRegistry, Registration};
+use super::diagnostics::{CodeSection, CompileError, CompileErrors, SourceMap};
+use super::drain::{OutputCallback, OutputDrain, OutputStream, SharedCallback};
+use super::eval_handle::{EvalHandle, EvalTimedOut, InterruptHandle, Interrupted};
+use super::fast_path::fast_eval;
+use super::history::{history_scaffold, HistoryEntry};
+use super::output::EvalOutput;
+use super::policy::{OnTruncate, SnapshotPolicy};
+use super::report::{
+    LoadedVar, RestartReport, RestoreStrategy, SkippedVar, SnapshotReport, SnapshotUpdate,
+    TruncatedArray, TruncatedUnit, TypeSource,
+};
+use super::tracking::{defines_names, let_bindings, snapshot_value_hash, TrackedVar, VarOrigin};
+use super::warmup::{SharedContext, WarmupHandle, WarmupStatus};
+
+/// Cargo spec for the `base64` crate used to restore `Vec<u8>` snapshot values
+const BASE64_DEP_SPEC: &str = r#""0.22""#;
+
+/// Cargo spec for the `bincode` crate decoding binary snapshot payloads
+const BINCODE_DEP_SPEC: &str = r#""1""#;
+
+/// Cargo spec for `serde`, whose derives snapshot types use
+const SERDE_DEP_SPEC: &str = r#"{ version = "1", features = ["derive"] }"#;
+
+/// Cargo spec for `serde_json`, which snapshot values are restored from
+const SERDE_JSON_DEP_SPEC: &str = r#""1""#;
+
+/// Crate name of the snapshot library, unless the snapshot gives one
+const DEFAULT_LIB_NAME: &str = "ferrumpy_snapshot";
+
+/// Expression `warmup` evaluates to build the dependencies
+const WARMUP_EXPR: &str = "serde_json::Value::Null.is_null()";
+
+/// Imports available to REPL code after `load_snapshot`, unless disabled
+/// with `with_default_prelude(false)`
+const DEFAULT_PRELUDE: &[&str] = &[
+    "use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};",
+    "use std::sync::Arc;",
+    "use std::rc::Rc;",
+    "use std::cell::{Cell, RefCell};",
+    "use std::sync::{Mutex, RwLock};",
+    "use serde::{Serialize, Deserialize};",
+];
+
+/// How long to wait for an evaluation's last stderr lines, which have no
+/// completion marker, unlike stdout
+const STDERR_SETTLE: Duration = Duration::from_millis(5);
+
+/// How long to wait for the output fence of code that ran
+const FENCE_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// A REPL session that wraps evcxr's CommandContext
 pub struct ReplSession {
-    context: CommandContext,
-    stdout: Receiver<String>,
-    stderr: Receiver<String>,
+    context: SharedContext<CommandContext>,
+    /// Output of the subprocess; what isn't an evaluation's (background
+    /// threads, code that failed) stays there until
+    /// `drain_stdout`/`drain_stderr`. evcxr forwards stdout printed between
+    /// evaluations along with the next one, ahead of its fence
+    stdout: OutputDrain,
+    stderr: OutputDrain,
+    /// Called with each line of output as it arrives
+    output_callback: SharedCallback,
     project_path: Option<String>,
     initialized: bool,
     // Snapshot data for preservation across interrupts
     snapshot_json: Option<String>,
     snapshot_type_hints: Option<String>,
+    /// Payload file and manifest of a binary snapshot, restored instead of
+    /// `snapshot_json` after an interrupt
+    binary_snapshot: Option<(PathBuf, String)>,
+    /// Limits on the values generated from a snapshot
+    policy: SnapshotPolicy,
+    /// Import the `DEFAULT_PRELUDE` when loading a snapshot
+    default_prelude: bool,
+    /// Try the expression evaluator before compiling, see `with_fast_path`
+    fast_path: bool,
+    /// User `use` statements, in the order they were added
+    prelude: Vec<String>,
+    /// Prelude statements not yet executed in the current context
+    prelude_pending: bool,
+    /// Snapshot and user variables defined in the current context
+    variables: HashMap<String, TrackedVar>,
+    /// `:dep` commands the user ran, replayed by `restart`
+    deps: Vec<String>,
+    /// Every dependency registered with evcxr, checked before adding another
+    dep_registry: DepRegistry,
+    /// User code that defined variables or items, replayed by `restart`
+    definitions: Vec<String>,
+    /// Every evaluation of user code, oldest first
+    history: Vec<HistoryEntry>,
+    /// Optimization level of the next compile; evcxr's default when `None`
+    opt_level: Option<u8>,
+    /// The ferrumpy-repl-worker binary
+    worker_path: String,
+    /// Working directory, environment and arguments of the worker
+    worker_options: ReplWorkerOptions,
+    /// `worker_options` changed since the context started; the next restart
+    /// starts a new one
+    worker_options_changed: bool,
+    /// Compiler settings, applied again to a context replacing an abandoned one
+    config: ReplConfig,
+    interrupt: InterruptHandle,
+    /// Interrupted since the last evaluation started
+    interrupted: bool,
+    /// Line printed ahead of each evaluation, separating its output from
+    /// what came before; random, so no output imitates it
+    fence: String,
+    /// When the evaluation in flight gives up, see `eval_with_timeout`
+    deadline: Option<Instant>,
+    /// The context was replaced at a deadline; dependencies and snapshot
+    /// are restored before the next evaluation
+    restore_pending: bool,
 }
 
 impl ReplSession {
     /// Create a new REPL session using ferrumpy-repl-worker as subprocess
     pub fn new() -> Result<Self> {
-        // Find the ferrumpy-repl-worker binary
-        let worker_path = Self::find_worker_binary()?;
+        Self::with_config(ReplConfig::default())
+    }
 
-        // Use with_subprocess_command to specify our worker binary
-        // The worker has runtime_hook() called at startup
-        let cmd = Command::new(&worker_path);
+    /// Create a new REPL session with the given compiler settings
+    ///
+    /// A setting evcxr rejects fails the construction.
+    pub fn with_config(config: ReplConfig) -> Result<Self> {
+        Self::with_config_and_options(config, ReplWorkerOptions::default())
+    }
 
-        let (eval_context, outputs) = EvalContext::with_subprocess_command(cmd)
-            .map_err(|e| anyhow::anyhow!("Failed to create evcxr context with worker: {:?}", e))?;
+    /// Create a new REPL session whose worker starts in the given working
+    /// directory, with the given environment and arguments
+    pub fn with_options(options: ReplWorkerOptions) -> Result<Self> {
+        Self::with_config_and_options(ReplConfig::default(), options)
+    }
 
-        let context = CommandContext::with_eval_context(eval_context);
+    /// Create a new REPL session with the given compiler settings and worker
+    /// options
+    pub fn with_config_and_options(
+        config: ReplConfig,
+        worker_options: ReplWorkerOptions,
+    ) -> Result<Self> {
+        // Find the ferrumpy-repl-worker binary
+        let worker_path = Self::find_worker_binary()?;
+        let (context, outputs) = Self::start_context(&worker_path, &worker_options, &config)?;
+        let interrupt = InterruptHandle::new(context.lock().process_handle());
 
         // Using default LLVM backend
         // Note: Cranelift was tested but showed higher wall-clock time despite lower CPU usage
         // (LLVM: 22.9s total vs Cranelift: 27.6s total)
         eprintln!("[FerrumPy] Using LLVM backend");
 
-        let mut session = Self {
+        let output_callback = SharedCallback::default();
+        let session = Self {
             context,
-            stdout: outputs.stdout,
-            stderr: outputs.stderr,
+            stdout: OutputDrain::spawn(
+                outputs.stdout,
+                OutputStream::Stdout,
+                output_callback.clone(),
+            ),
+            stderr: OutputDrain::spawn(
+                outputs.stderr,
+                OutputStream::Stderr,
+                output_callback.clone(),
+            ),
+            output_callback,
             project_path: None,
             initialized: false,
             snapshot_json: None,
             snapshot_type_hints: None,
+            binary_snapshot: None,
+            policy: SnapshotPolicy::default(),
+            default_prelude: true,
+            fast_path: false,
+            prelude: Vec::new(),
+            prelude_pending: false,
+            variables: HashMap::new(),
+            deps: Vec::new(),
+            dep_registry: DepRegistry::default(),
+            definitions: Vec::new(),
+            history: Vec::new(),
+            opt_level: config.opt_level,
+            worker_path,
+            worker_options,
+            worker_options_changed: false,
+            config,
+            interrupt,
+            interrupted: false,
+            fence: output_fence(),
+            deadline: None,
+            restore_pending: false,
         };
 
-        // Enable dependency caching (512MB) for faster subsequent starts
-        // Cache persists in ~/Library/Caches/evcxr/ (macOS) or equivalent
-        if let Err(e) = session.context.execute(":cache 512") {
-            eprintln!("[FerrumPy] Warning: Failed to enable cache: {:?}", e);
-        } else {
-            eprintln!("[FerrumPy] Cache enabled (512MB)");
+        if session.config.cache_mb > 0 {
+            eprintln!("[FerrumPy] Cache enabled ({}MB)", session.config.cache_mb);
         }
 
         Ok(session)
     }
 
+    /// Create an evcxr context running the worker, with the given compiler
+    /// settings
+    fn start_context(
+        worker_path: &str,
+        worker_options: &ReplWorkerOptions,
+        config: &ReplConfig,
+    ) -> Result<(SharedContext<CommandContext>, EvalContextOutputs)> {
+        // Use with_subprocess_command to specify our worker binary
+        // The worker has runtime_hook() called at startup
+        let cmd = worker_options.command(worker_path);
+
+        let (eval_context, outputs) = EvalContext::with_subprocess_command(cmd)
+            .map_err(|e| anyhow::anyhow!("Failed to create evcxr context with worker: {:?}", e))?;
+
+        let mut context = SharedContext::new(CommandContext::with_eval_context(eval_context));
+        context.set_shares_cache(config.cache_mb > 0);
+        // The dependency cache speeds up subsequent starts; it persists in
+        // ~/Library/Caches/evcxr/ (macOS) or equivalent
+        config.apply(&mut *context.lock())?;
+        Ok((context, outputs))
+    }
+
     /// Find the ferrumpy-repl-worker binary
+    ///
+    /// The search runs once per process: later sessions reuse the path
+    /// found, so they all run the same worker. A failed search is retried.
     fn find_worker_binary() -> Result<String> {
+        static WORKER_PATH: OnceLock<String> = OnceLock::new();
+        if let Some(path) = WORKER_PATH.get() {
+            return Ok(path.clone());
+        }
+        let path = Self::locate_worker_binary()?;
+        Ok(WORKER_PATH.get_or_init(|| path).clone())
+    }
+
+    /// Search the locations the worker binary may be in
+    fn locate_worker_binary() -> Result<String> {
         // Try locations in order of priority:
         // 1. Environment variable (for manual override/testing)
         // 2. Same directory as this .so module (pip install location)
@@ -173,6 +360,53 @@ impl ReplSession {
         Ok(session)
     }
 
+    /// Enable or disable the built-in imports (`HashMap`, `Arc`, `Rc` and the
+    /// serde derives) added by `load_snapshot`
+    pub fn with_default_prelude(mut self, enabled: bool) -> Self {
+        self.default_prelude = enabled;
+        self
+    }
+
+    /// Enable or disable evaluating expressions like `1 + 1` or `count * 2`
+    /// with the expression evaluator, skipping the compile
+    ///
+    /// Only expressions of literals, operators, supported methods and scalar
+    /// snapshot variables qualify, but semantics may differ subtly: nothing
+    /// runs in the subprocess, and pending prelude statements wait for the
+    /// next compiled evaluation.
+    pub fn with_fast_path(mut self, enabled: bool) -> Self {
+        self.fast_path = enabled;
+        self
+    }
+
+    /// Add a `use` statement to the session prelude
+    ///
+    /// Prelude statements are run before the next `eval` or `load_snapshot`,
+    /// and again whenever the context is rebuilt (e.g. after an interrupt).
+    pub fn add_prelude(&mut self, stmt: &str) -> Result<()> {
+        let stmt = normalize_prelude_stmt(stmt)?;
+        if !self.prelude.contains(&stmt) {
+            self.prelude.push(stmt);
+            self.prelude_pending = true;
+        }
+        Ok(())
+    }
+
+    /// The session prelude's `use` statements
+    pub fn prelude(&self) -> &[String] {
+        &self.prelude
+    }
+
+    /// Prelude code to run ahead of the next evaluation, if any is pending
+    fn take_pending_prelude(&mut self) -> String {
+        if !std::mem::take(&mut self.prelude_pending) {
+            return String::new();
+        }
+        let mut code = self.prelude.join("\n");
+        code.push('\n');
+        code
+    }
+
     /// Add a crate dependency
     pub fn add_dep(&mut self, name: &str, spec: &str) -> Result<String> {
         let dep_cmd = format!(":dep {} = {}", name, spec);
@@ -188,207 +422,180 @@ impl ReplSession {
     /// Add a path dependency silently (no compilation until next eval)
     pub fn add_path_dep_silent(&mut self, name: &str, path: &Path) -> Result<()> {
         let config = format!(r#"{{ path = "{}" }}"#, path.display());
+        self.register_dep(name, &config, DepOrigin::Session)?;
+        self.record_definitions(&format!(":dep {} = {}", name, config));
+        Ok(())
+    }
+
+    /// Register crate `name` with evcxr, unless an equivalent spec is
+    /// registered already
+    ///
+    /// A spec conflicting with a registered one, or with the snapshot crate's,
+    /// fails with a `DepConflict` before anything compiles.
+    fn register_dep(&mut self, name: &str, spec: &str, origin: DepOrigin) -> Result<()> {
+        let Registration::Add(spec) = self.dep_registry.check(name, spec, origin)? else {
+            return Ok(());
+        };
         self.context
-            .add_dep_silent(name, &config)
-            .map_err(|e| anyhow::anyhow!("Failed to add path dep: {:?}", e))
+            .lock()
+            .add_dep_silent(name, &spec)
+            .map_err(|e| anyhow::anyhow!("Failed to add {} dep: {:?}", name, e))?;
+        self.dep_registry.record(name, &spec, origin);
+        Ok(())
     }
 
-    // ============================================================================
-    // Snapshot Item-Level Export Helpers
-    // ============================================================================
+    /// Dependencies registered with evcxr, by the user or for generated code,
+    /// sorted by crate name
+    pub fn registered_deps(&self) -> Vec<(String, DeclaredDep)> {
+        self.dep_registry.registered()
+    }
 
-    /// Extract variables from snapshot JSON for item-level module generation
-    fn extract_variables(
-        &self,
-        snapshot: &serde_json::Value,
-    ) -> Result<Vec<(String, serde_json::Value, String)>> {
-        let mut vars = Vec::new();
+    /// Build the snapshot dependencies on a background thread, so the first
+    /// `load_snapshot` or `eval` doesn't pay for it
+    ///
+    /// Registers serde and, given `project_lib`, the snapshot crate at that
+    /// path, then evaluates a trivial expression. Whatever uses the context
+    /// meanwhile waits for the warmup to finish; while one is pending,
+    /// calling this again returns it.
+    pub fn warmup(&mut self, project_lib: Option<&Path>) -> WarmupHandle {
+        let mut commands = vec![
+            format!(":dep serde = {}", SERDE_DEP_SPEC),
+            format!(":dep serde_json = {}", SERDE_JSON_DEP_SPEC),
+        ];
+        self.dep_registry
+            .record("serde", SERDE_DEP_SPEC, DepOrigin::Session);
+        self.dep_registry
+            .record("serde_json", SERDE_JSON_DEP_SPEC, DepOrigin::Session);
+        if let Some(path) = project_lib {
+            let spec = format!("{{ path = \"{}\" }}", path.display());
+            commands.push(format!(":dep {} = {}", DEFAULT_LIB_NAME, spec));
+            self.dep_registry
+                .record(DEFAULT_LIB_NAME, &spec, DepOrigin::Session);
+        }
+        commands.push(WARMUP_EXPR.to_string());
+        self.context.warmup(commands)
+    }
 
-        let variables = snapshot
-            .get("variables")
-            .and_then(|v| v.as_object())
-            .ok_or_else(|| anyhow::anyhow!("No variables in snapshot"))?;
+    /// Status of the last [`warmup`](Self::warmup); `None` if there was none
+    pub fn warmup_status(&self) -> Option<WarmupStatus> {
+        self.context.warmup_status()
+    }
 
-        let types_map = snapshot.get("types").and_then(|v| v.as_object());
+    /// Limit the number of elements generated per snapshot array
+    ///
+    /// Applies to nested arrays too. Longer arrays keep their first
+    /// `max_array_elements` elements and `load_snapshot` reports the truncation.
+    pub fn set_max_array_elements(&mut self, max_array_elements: usize) {
+        self.policy.max_collection_len = max_array_elements;
+    }
 
-        for (name, value) in variables {
-            let type_hint: &str = types_map
-                .and_then(|m| m.get(name))
-                .and_then(|v| v.as_str())
-                .unwrap_or("serde_json::Value");
+    pub fn max_array_elements(&self) -> usize {
+        self.policy.max_collection_len
+    }
 
-            let actual_type = if self.is_supported_type(type_hint) {
-                self.normalize_rust_type(type_hint)
-            } else {
-                "serde_json::Value".to_string()
-            };
+    /// Set the rustc optimization level (0 to 3) of later evaluations
+    ///
+    /// Snapshot loads compile the most code, and are fastest at 0, the
+    /// default; hot loops run faster at 2 or 3.
+    pub fn set_opt_level(&mut self, level: u8) -> Result<()> {
+        check_opt_level(level)?;
+        self.context
+            .lock()
+            .run_command(&format!(":opt {}", level))
+            .map_err(|e| e.context("Failed to set the optimization level"))?;
+        self.opt_level = Some(level);
+        self.config.opt_level = Some(level);
+        Ok(())
+    }
 
-            if !self.is_valid_for_deserialization(value, &actual_type) {
-                vars.push((name.clone(), value.clone(), "serde_json::Value".to_string()));
-            } else {
-                vars.push((name.clone(), value.clone(), actual_type));
-            }
-        }
-
-        Ok(vars)
-    }
-
-    /// Generate module containing snapshot variables as static items
-    fn generate_snapshot_module(
-        &self,
-        vars: &[(String, serde_json::Value, String)],
-    ) -> Result<String> {
-        // Use different name to avoid conflict with companion lib
-        let mut module_code = String::from("mod ferrumpy_vars {\n");
-        module_code.push_str("    use std::sync::OnceLock;\n");
-        module_code.push_str("    use std::collections::HashMap;\n");
-        module_code.push_str("    use std::sync::Arc;\n");
-        module_code.push_str("    use std::rc::Rc;\n");
-        // Import types from parent scope (user-defined types, companion lib types)
-        module_code.push_str("    use super::*;\n\n");
-
-        for (name, value, ty) in vars {
-            let item_code = self.generate_static_item(name, value, ty)?;
-            module_code.push_str(&item_code);
-            module_code.push_str("\n");
-        }
-
-        module_code.push_str("}\n");
-        Ok(module_code)
-    }
-
-    /// Generate a single static item with accessor function
-    fn generate_static_item(
-        &self,
-        name: &str,
-        value: &serde_json::Value,
-        type_hint: &str,
-    ) -> Result<String> {
-        let cell_name = format!("{}_CELL", name.to_uppercase());
-        let init_expr = self.generate_value_init_expr(value, type_hint)?;
-
-        let is_sync = self.is_likely_sync_type(type_hint);
-
-        if is_sync {
-            Ok(format!(
-                r#"    static {}: OnceLock<{}> = OnceLock::new();
-    
-    pub fn {}() -> &'static {} {{
-        {}.get_or_init(|| {{
-            {}
-        }})
-    }}
-"#,
-                cell_name, type_hint, name, type_hint, cell_name, init_expr
-            ))
-        } else {
-            Ok(format!(
-                r#"    thread_local! {{
-        static {}: std::cell::RefCell<Option<{}>> = 
-            std::cell::RefCell::new(None);
-    }}
-    
-    pub fn {}() -> {} {{
-        {}.with(|cell| {{
-            let mut opt = cell.borrow_mut();
-            if opt.is_none() {{
-                *opt = Some({});
-            }}
-            opt.as_ref().unwrap().clone()
-        }})
-    }}
-"#,
-                cell_name, type_hint, name, type_hint, cell_name, init_expr
-            ))
-        }
-    }
-
-    /// Heuristic to detect if a type is likely Sync
-    fn is_likely_sync_type(&self, type_hint: &str) -> bool {
-        let non_sync_markers = ["Rc<", "RefCell<", "Cell<", "*const", "*mut"];
-
-        for marker in &non_sync_markers {
-            if type_hint.contains(marker) {
-                return false;
-            }
-        }
-
-        true
-    }
-
-    /// Check if a type is likely to be available in the generated code scope
-    /// Types that are NOT likely available:
-    /// - Iterator adapters like Skip, Take, Map, Filter, etc. (from std::iter)
-    /// - Environment types like Args (from std::env)
-    /// - File/IO types that aren't commonly re-exported
-    /// - Raw pointers and references
-    fn is_type_likely_available(&self, type_hint: &str) -> bool {
-        // List of type patterns that are unlikely to be in scope
-        let unsupported_patterns = [
-            // Iterator adapters (std::iter)
-            "Skip<",
-            "Take<",
-            "Map<",
-            "Filter<",
-            "FilterMap<",
-            "Enumerate<",
-            "Peekable<",
-            "Chain<",
-            "Zip<",
-            "Cycle<",
-            "Fuse<",
-            "Rev<",
-            "Flatten<",
-            "FlatMap<",
-            "Scan<",
-            "TakeWhile<",
-            "SkipWhile<",
-            "StepBy<",
-            "Inspect<",
-            "Cloned<",
-            "Copied<",
-            // Environment types (std::env)
-            "Args",
-            "ArgsOs",
-            "Vars",
-            "VarsOs",
-            // IO types that may not be in scope
-            "Stdin",
-            "Stdout",
-            "Stderr",
-            "BufReader<",
-            "BufWriter<",
-            "Lines<",
-            "Bytes<",
-            "Chars<",
-            // Raw pointers and references (can't be deserialized)
-            "*const",
-            "*mut",
-            "&'", // Any reference with explicit lifetime
-        ];
+    /// Optimization level of later evaluations; evcxr's default when `None`
+    pub fn opt_level(&self) -> Option<u8> {
+        self.opt_level
+    }
 
-        for pattern in &unsupported_patterns {
-            if type_hint.contains(pattern) {
-                return false;
-            }
-        }
+    /// Working directory, environment and arguments of the worker
+    pub fn worker_options(&self) -> &ReplWorkerOptions {
+        &self.worker_options
+    }
+
+    /// Set the working directory of the worker, from the next
+    /// [`restart`](Self::restart)
+    pub fn set_worker_cwd(&mut self, cwd: Option<PathBuf>) {
+        self.worker_options.cwd = cwd;
+        self.worker_options_changed = true;
+    }
+
+    /// Set environment variables of the worker, on top of this process's,
+    /// from the next [`restart`](Self::restart)
+    pub fn set_worker_env(&mut self, env: HashMap<String, String>) {
+        self.worker_options.env = env;
+        self.worker_options_changed = true;
+    }
+
+    /// Set the arguments of the worker, seen by `std::env::args()`, from
+    /// the next [`restart`](Self::restart)
+    pub fn set_worker_args(&mut self, args: Vec<String>) {
+        self.worker_options.args = args;
+        self.worker_options_changed = true;
+    }
+
+    /// Set the limits on values generated by later snapshot loads
+    pub fn set_snapshot_policy(&mut self, policy: SnapshotPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn snapshot_policy(&self) -> &SnapshotPolicy {
+        &self.policy
+    }
 
-        // Check for types that look like they're from external crates but not imported
-        // Heuristic: if it contains "::" it should either:
-        // 1. Start with "std::", "core::", "alloc::" (std library - usually available via prelude)
-        // 2. Or be from ferrumpy_snapshot (companion lib)
-        // Otherwise it might not be in scope
+    /// `load_snapshot` with the given policy, which also applies to later
+    /// loads (and the reload after an interrupt)
+    pub fn load_snapshot_with_policy(
+        &mut self,
+        json_data: &str,
+        type_hints: &str,
+        policy: SnapshotPolicy,
+    ) -> Result<SnapshotReport> {
+        self.policy = policy;
+        self.load_snapshot(json_data, type_hints)
+    }
+
+    /// Replace a snapshot variable, typically one loaded truncated, by its
+    /// full value
+    ///
+    /// `full_json` is the variable's value in snapshot form. It is generated
+    /// whatever the snapshot policy, shadowing the previous binding.
+    pub fn hydrate_variable(&mut self, name: &str, full_json: &str) -> Result<String> {
+        let type_hint = match self.variables.get(name) {
+            Some(var) if var.origin == VarOrigin::Snapshot => var.declared_type.clone(),
+            _ => anyhow::bail!("No snapshot variable named {}", name),
+        };
+        let value: serde_json::Value = serde_json::from_str(full_json)?;
+        if is_base64_bytes(&value, &type_hint) {
+            self.register_dep("base64", BASE64_DEP_SPEC, DepOrigin::Session)?;
+        }
 
-        // For now, allow all other types - the list above catches the most common issues
-        true
+        let value_hash = snapshot_value_hash(&value, &type_hint);
+        let vars = [(name.to_string(), value, type_hint.clone())];
+        let output = self.eval_generated(SnapshotCodegen.generate_update_modules(&vars)?)?;
+        self.variables.insert(
+            name.to_string(),
+            TrackedVar::new(type_hint, VarOrigin::Snapshot).with_value_hash(value_hash),
+        );
+        Ok(output)
     }
 
     /// Load variables from serialized JSON snapshot using optimized single-compilation mode
     /// with TYPE-AWARE code generation for real Rust types
-    pub fn load_snapshot(&mut self, json_data: &str, type_hints: &str) -> Result<String> {
+    ///
+    /// The report lists which variables were loaded and how, and which were
+    /// skipped; its `to_string()` is a one-line summary. A pending
+    /// [`warmup`](Self::warmup) is waited for first.
+    pub fn load_snapshot(&mut self, json_data: &str, type_hints: &str) -> Result<SnapshotReport> {
         // Save snapshot data for potential restoration after interrupt
         self.snapshot_json = Some(json_data.to_string());
         self.snapshot_type_hints = Some(type_hints.to_string());
+        self.binary_snapshot = None;
         eprintln!(
             "[DEBUG] Saved snapshot: {} bytes JSON, {} bytes hints",
             json_data.len(),
@@ -396,26 +603,102 @@ impl ReplSession {
         );
 
         // Step 1: Register dependencies silently (no compilation yet)
-        self.context
-            .add_dep_silent("serde", r#"{ version = "1", features = ["derive"] }"#)
-            .map_err(|e| anyhow::anyhow!("Failed to add serde dep: {:?}", e))?;
-        self.context
-            .add_dep_silent("serde_json", r#""1""#)
-            .map_err(|e| anyhow::anyhow!("Failed to add serde_json dep: {:?}", e))?;
+        self.register_dep("serde", SERDE_DEP_SPEC, DepOrigin::Session)?;
+        self.register_dep("serde_json", SERDE_JSON_DEP_SPEC, DepOrigin::Session)?;
 
-        // Step 2: Parse snapshot JSON
-        let snapshot: serde_json::Value = serde_json::from_str(json_data)?;
+        // Step 2: Parse snapshot JSON, typing variables by their hints
+        let mut snapshot: serde_json::Value = serde_json::from_str(json_data)?;
+        let type_sources = SnapshotCodegen.merge_type_hints(&mut snapshot, type_hints)?;
 
         // ========== ITEM-LEVEL EXPORT PATH ==========
         if std::env::var("FERRUMPY_DEBUG").is_ok() {
             eprintln!("[DEBUG] Using item-level snapshot export");
         }
 
-        let vars = self.extract_variables(&snapshot)?;
+        let mut adjusted = Vec::new();
+        let vars = SnapshotCodegen.extract_variables(&snapshot, &mut adjusted)?;
         if vars.is_empty() {
-            return Ok("Snapshot loaded (no variables)".to_string());
+            return Ok(SnapshotReport::default());
+        }
+
+        let all_code = self.snapshot_preamble(&snapshot)?;
+        let mut report = self.restore_variables(&snapshot, vars, adjusted, all_code, false)?;
+        set_type_sources(&mut report, &type_sources);
+        Ok(report)
+    }
+
+    /// Load variables from a binary snapshot: bincode payloads in the file at
+    /// `payload_path`, located by `manifest_json`
+    ///
+    /// The manifest is a snapshot document whose `variables` map names to
+    /// `{"type", "offset", "len"}` instead of values; `lib_path`, `lib_name`
+    /// and `lib_use_stmt` work as in `load_snapshot`. The generated code reads
+    /// each value from the file when first accessed, so large buffers are
+    /// never compiled in as literals.
+    pub fn load_snapshot_binary(
+        &mut self,
+        payload_path: &Path,
+        manifest_json: &str,
+    ) -> Result<SnapshotReport> {
+        let payload_path = std::fs::canonicalize(payload_path)?;
+        self.binary_snapshot = Some((payload_path.clone(), manifest_json.to_string()));
+        self.snapshot_json = None;
+        self.snapshot_type_hints = None;
+
+        self.register_dep("serde", SERDE_DEP_SPEC, DepOrigin::Session)?;
+        self.register_dep("bincode", BINCODE_DEP_SPEC, DepOrigin::Session)?;
+
+        let manifest: serde_json::Value = serde_json::from_str(manifest_json)?;
+        let vars = SnapshotCodegen.extract_binary_variables(&manifest)?;
+        let mut report = SnapshotReport::default();
+        let (supported_vars, skipped_vars): (Vec<_>, Vec<_>) = vars
+            .into_iter()
+            .partition(|var| SnapshotCodegen.is_type_likely_available(&var.type_hint));
+        report.skipped = skipped_vars
+            .into_iter()
+            .map(|var| SkippedVar {
+                name: var.name,
+                reason: "type not in scope".to_string(),
+            })
+            .collect();
+        if supported_vars.is_empty() {
+            return Ok(report);
+        }
+
+        let mut code =
+            SourceMap::single(CodeSection::Generated, &self.snapshot_preamble(&manifest)?);
+        code.append(
+            SnapshotCodegen
+                .generate_binary_module(&payload_path.to_string_lossy(), &supported_vars)?,
+        );
+        code.push(CodeSection::Generated, "use ferrumpy_vars::*;\n");
+        report.eval_output = self.eval_generated(code)?;
+        self.initialized = true;
+
+        for var in &supported_vars {
+            self.variables.insert(
+                var.name.clone(),
+                TrackedVar::new(&var.type_hint, VarOrigin::Snapshot),
+            );
         }
+        report.loaded = supported_vars
+            .into_iter()
+            .map(|var| LoadedVar {
+                name: var.name,
+                declared_type: var.type_hint.clone(),
+                restored_type: var.type_hint,
+                strategy: RestoreStrategy::Binary,
+                truncated: false,
+                type_source: TypeSource::Snapshot,
+            })
+            .collect();
+        Ok(report)
+    }
 
+    /// Code run ahead of a snapshot's variables: the companion library's
+    /// import (registering the library as a dependency) and the default
+    /// prelude
+    fn snapshot_preamble(&mut self, snapshot: &serde_json::Value) -> Result<String> {
         let mut all_code = String::new();
 
         // Re-add companion library if it exists in the snapshot
@@ -423,7 +706,14 @@ impl ReplSession {
             let lib_name = snapshot
                 .get("lib_name")
                 .and_then(|v| v.as_str())
-                .unwrap_or("ferrumpy_snapshot");
+                .unwrap_or(DEFAULT_LIB_NAME);
+            // Its dependencies are built along with the session's, so specs
+            // cargo can't unify are reported now rather than by the build
+            if let Ok(content) = std::fs::read_to_string(Path::new(lib_path).join("Cargo.toml")) {
+                let manifest: toml::Value = content.parse()?;
+                self.dep_registry
+                    .set_snapshot_crate(&manifest, Path::new(lib_path))?;
+            }
             self.add_path_dep_silent(lib_name, Path::new(lib_path))?;
         }
 
@@ -431,12 +721,137 @@ impl ReplSession {
             all_code.push_str(lib_use);
             all_code.push('\n');
         }
-        all_code.push_str("use serde::{Serialize, Deserialize};\n");
+        if self.default_prelude {
+            for stmt in DEFAULT_PRELUDE {
+                all_code.push_str(stmt);
+                all_code.push('\n');
+            }
+        }
+        Ok(all_code)
+    }
+
+    /// Reload a snapshot into a session that has one, redeclaring only the
+    /// variables whose value or type changed
+    ///
+    /// Variables are compared with the values they were last restored from;
+    /// unchanged ones generate no code. Changed ones shadow their previous
+    /// binding. Snapshot variables missing from the new snapshot (out of
+    /// scope after a step) are reported as removed and no longer tracked,
+    /// though evcxr keeps their last binding. Without a loaded snapshot this
+    /// is `load_snapshot`, with every variable reported as added.
+    pub fn update_snapshot(&mut self, json_data: &str, type_hints: &str) -> Result<SnapshotUpdate> {
+        if !self.initialized {
+            let report = self.load_snapshot(json_data, type_hints)?;
+            return Ok(SnapshotUpdate {
+                added: report.loaded.iter().map(|var| var.name.clone()).collect(),
+                report,
+                ..Default::default()
+            });
+        }
+        self.snapshot_json = Some(json_data.to_string());
+        self.snapshot_type_hints = Some(type_hints.to_string());
+
+        let mut snapshot: serde_json::Value = serde_json::from_str(json_data)?;
+        let type_sources = SnapshotCodegen.merge_type_hints(&mut snapshot, type_hints)?;
+        let mut adjusted = Vec::new();
+        let vars = SnapshotCodegen.extract_variables(&snapshot, &mut adjusted)?;
+        let diff = diff_snapshot(vars, &self.variables);
+        for name in &diff.removed {
+            self.variables.remove(name);
+        }
+        adjusted.retain(|(name, _, _)| diff.pending.iter().any(|(pending, _, _)| pending == name));
+
+        let mut report = if diff.pending.is_empty() {
+            SnapshotReport::default()
+        } else {
+            self.restore_variables(&snapshot, diff.pending, adjusted, String::new(), true)?
+        };
+        set_type_sources(&mut report, &type_sources);
+        Ok(SnapshotUpdate {
+            added: diff.added,
+            changed: diff.changed,
+            unchanged: diff.unchanged,
+            removed: diff.removed,
+            report,
+        })
+    }
+
+    /// Restore extracted snapshot variables, evaluating `all_code` first
+    ///
+    /// A first load defines the variables in the `ferrumpy_vars` module; an
+    /// update gives each a module of its own, shadowing its previous binding.
+    fn restore_variables(
+        &mut self,
+        snapshot: &serde_json::Value,
+        mut vars: Vec<(String, serde_json::Value, String)>,
+        adjusted: Vec<(String, String, String)>,
+        all_code: String,
+        update: bool,
+    ) -> Result<SnapshotReport> {
+        let mut report = SnapshotReport::default();
+        let hashes: HashMap<String, u64> = vars
+            .iter()
+            .map(|(name, value, ty)| (name.clone(), snapshot_value_hash(value, ty)))
+            .collect();
+
+        // Byte buffers shipped as base64 strings need the decoder crate
+        if vars.iter().any(|(_, value, ty)| is_base64_bytes(value, ty)) {
+            self.register_dep("base64", BASE64_DEP_SPEC, DepOrigin::Session)?;
+        }
+
+        // Huge values would generate unbounded source, so keep only a prefix
+        let (mut truncated, mut truncated_vars) = truncate_to_policy(&mut vars, &self.policy);
+        let mut over_limits = Vec::new();
+        if self.policy.on_truncate == OnTruncate::Skip && !truncated_vars.is_empty() {
+            vars.retain(|(name, _, _)| !truncated_vars.contains(name));
+            truncated.clear();
+            over_limits = std::mem::take(&mut truncated_vars);
+        }
+        // Values that fell back to their raw JSON only for failing checks made
+        // ahead of time get a chance to deserialize as their type
+        let types_map = snapshot.get("types").and_then(|v| v.as_object());
+        let typed_fallbacks: HashMap<String, String> = vars
+            .iter()
+            .filter(|(_, _, ty)| ty == "serde_json::Value")
+            .filter_map(|(name, value, _)| {
+                let declared = types_map?.get(name)?.as_str()?;
+                let ty = SnapshotCodegen.typed_fallback_type(value, declared)?;
+                Some((name.clone(), ty))
+            })
+            .collect();
+        let noted: Vec<_> = adjusted
+            .iter()
+            .filter(|(name, _, _)| !typed_fallbacks.contains_key(name))
+            .collect();
+        if !noted.is_empty() {
+            eprintln!(
+                "[FerrumPy] Note: Restoring {} variable(s) with adjusted types:",
+                noted.len()
+            );
+            for (name, declared, restored) in noted {
+                eprintln!("  - {}: {} -> {}", name, declared, restored);
+            }
+        }
+        if !truncated.is_empty() {
+            eprintln!(
+                "[FerrumPy] Warning: Truncated {} value(s):",
+                truncated.len()
+            );
+            for array in &truncated {
+                eprintln!(
+                    "  - {} (first {} of {} {})",
+                    array.path,
+                    array.kept,
+                    array.len,
+                    array.unit.as_str()
+                );
+            }
+        }
 
         // Filter variables: skip those with unrecognized types that would cause compilation errors
         let (supported_vars, skipped_vars): (Vec<_>, Vec<_>) = vars
             .into_iter()
-            .partition(|(_, _, ty)| self.is_type_likely_available(ty));
+            .partition(|(_, _, ty)| SnapshotCodegen.is_type_likely_available(ty));
 
         // Warn about skipped variables
         if !skipped_vars.is_empty() {
@@ -449,745 +864,719 @@ impl ReplSession {
             }
             eprintln!("[FerrumPy] Tip: These are likely iterator or low-level types that can't be serialized.");
         }
+        report.skipped = skipped_vars
+            .iter()
+            .map(|(name, _, _)| SkippedVar {
+                name: name.clone(),
+                reason: "type not in scope".to_string(),
+            })
+            .collect();
+        report
+            .skipped
+            .extend(over_limits.into_iter().map(|name| SkippedVar {
+                name,
+                reason: "exceeds snapshot limits".to_string(),
+            }));
 
         if supported_vars.is_empty() {
-            return Ok("Snapshot loaded (no supported variables)".to_string());
+            return Ok(report);
         }
 
-        let module_code = self.generate_snapshot_module(&supported_vars)?;
-        all_code.push_str(&module_code);
-        all_code.push('\n');
-        all_code.push_str("use ferrumpy_vars::*;\n");
+        let mut code = SourceMap::single(CodeSection::Generated, &all_code);
+        for array in &truncated {
+            code.push(
+                CodeSection::Generated,
+                &format!(
+                    "// {}: first {} of {} {} (snapshot policy)\n",
+                    array.path,
+                    array.kept,
+                    array.len,
+                    array.unit.as_str()
+                ),
+            );
+        }
+        // Variables without a `Default` to fall back to are restored in
+        // evaluations of their own, so one that fails can't take down the rest
+        let (typed_attempts, supported_vars): (Vec<_>, Vec<_>) = supported_vars
+            .into_iter()
+            .partition(|(name, _, _)| typed_fallbacks.contains_key(name));
+        let (batch_vars, isolated_vars): (Vec<_>, Vec<_>) = supported_vars
+            .into_iter()
+            .partition(|(_, _, ty)| SnapshotCodegen.is_default_type(ty));
+        if update {
+            if !batch_vars.is_empty() {
+                code.append(SnapshotCodegen.generate_update_modules(&batch_vars)?);
+                report.eval_output = self.eval_generated(code)?;
+            }
+        } else {
+            if !batch_vars.is_empty() {
+                code.append(SnapshotCodegen.generate_snapshot_module(&batch_vars)?);
+                code.push(CodeSection::Generated, "use ferrumpy_vars::*;\n");
+            }
 
-        // Add the restore! helper macro for user-assisted type restoration
-        // This allows users to convert serde_json::Value back to specific types
-        let restore_macro = r#"
+            // Add the restore! helper macro for user-assisted type restoration
+            // This allows users to convert serde_json::Value back to specific types
+            let restore_macro = r#"
 macro_rules! restore {
     ($var:ident, $type:ty) => {
         serde_json::from_value::<$type>($var().clone()).unwrap()
     };
 }
 "#;
-        all_code.push_str(restore_macro);
+            code.push(CodeSection::Generated, restore_macro);
 
-        self.eval(&all_code)?;
-        self.initialized = true;
+            report.eval_output = self.eval_generated(code)?;
+            self.initialized = true;
+        }
 
-        let sample_names: Vec<&str> = supported_vars
+        let loaded_var = |name: &str, value: &serde_json::Value, ty: &str| LoadedVar {
+            name: name.to_string(),
+            declared_type: types_map
+                .and_then(|m| m.get(name))
+                .and_then(|v| v.as_str())
+                .unwrap_or(ty)
+                .to_string(),
+            restored_type: ty.to_string(),
+            strategy: SnapshotCodegen.restore_strategy(value, ty),
+            truncated: truncated_vars.iter().any(|truncated| truncated == name),
+            type_source: TypeSource::Snapshot,
+        };
+        let mut loaded: Vec<LoadedVar> = batch_vars
             .iter()
-            .take(5)
-            .map(|(n, _, _)| n.as_str())
+            .map(|(name, value, ty)| loaded_var(name, value, ty))
             .collect();
-        Ok(format!(
-            "Snapshot loaded with {} items{}. Access: {}{}",
-            supported_vars.len(),
-            if !skipped_vars.is_empty() {
-                format!(" ({} skipped)", skipped_vars.len())
-            } else {
-                String::new()
-            },
-            sample_names
-                .iter()
-                .map(|n| format!("{}()", n))
-                .collect::<Vec<_>>()
-                .join(", "),
-            if supported_vars.len() > 5 {
-                ", ..."
-            } else {
-                ""
-            }
-        ))
-    }
-
-    /// Generate initialization expression for a value (for let bindings)
-    fn generate_value_init_expr(
-        &self,
-        value: &serde_json::Value,
-        type_hint: &str,
-    ) -> Result<String> {
-        // Check for __ferrumpy_kind__ metadata (special type handling)
-        // BUT: Skip if type_hint is serde_json::Value (fallback case for unsupported types)
-        if type_hint != "serde_json::Value" {
-            if let Some(kind) = value.get("__ferrumpy_kind__").and_then(|v| v.as_str()) {
-                return match kind {
-                    "option" => self.generate_option_code(value, type_hint),
-                    "result" => self.generate_result_code(value, type_hint),
-                    "tuple" => self.generate_tuple_code(value, type_hint),
-                    "array" => self.generate_array_code(value, type_hint),
-                    "arc" => self.generate_arc_code(value, type_hint),
-                    "rc" => self.generate_rc_code(value, type_hint),
-                    "box" => self.generate_box_code(value, type_hint),
-                    "enum" => self.generate_enum_code(value, type_hint),
-                    _ => {
-                        // Unknown kind, fall through to default handling
-                        let json_str = serde_json::to_string(value)?;
-                        Ok(format!(
-                            "serde_json::from_str::<serde_json::Value>(r#\"{}\"#).unwrap()",
-                            json_str
-                        ))
-                    }
-                };
-            }
-        }
-
-        // Handle primitive types with literal generation
-        match type_hint {
-            // Integer types - generate literal
-            "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64"
-            | "u128" | "usize" => {
-                if let Some(n) = value.as_i64() {
-                    return Ok(format!("{}{}", n, self.type_suffix(type_hint)));
-                } else if let Some(n) = value.as_u64() {
-                    return Ok(format!("{}{}", n, self.type_suffix(type_hint)));
-                }
-            }
-
-            // Float types
-            "f32" | "f64" => {
-                if let Some(f) = value.as_f64() {
-                    return Ok(format!("{:.15}{}", f, self.type_suffix(type_hint)));
-                }
-            }
-
-            // Boolean
-            "bool" => {
-                if let Some(b) = value.as_bool() {
-                    return Ok(b.to_string());
-                }
-            }
-
-            // String - generate with to_string()
-            "String" => {
-                if let Some(s) = value.as_str() {
-                    let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
-                    return Ok(format!("\"{}\".to_string()", escaped));
-                }
-            }
-
-            // Vec types - generate vec![] macro
-            t if t.starts_with("Vec<") => {
-                if let Some(arr) = value.as_array() {
-                    // Extract inner type
-                    let inner_type = &t[4..t.len() - 1];
-                    let elements = self.generate_vec_elements(arr, inner_type)?;
-                    return Ok(format!("vec![{}]", elements));
+        for (name, value, ty) in &isolated_vars {
+            let outcome = SnapshotCodegen
+                .generate_isolated_module(name, value, ty)
+                .and_then(|code| {
+                    self.eval_generated(SourceMap::single(
+                        CodeSection::Variable(name.clone()),
+                        &code,
+                    ))
+                });
+            let error = match outcome {
+                Ok(output) => parse_restore_error(&output),
+                Err(e) => Some(e.to_string()),
+            };
+            match error {
+                None => loaded.push(loaded_var(name, value, ty)),
+                Some(e) => {
+                    eprintln!("[FerrumPy] Warning: Failed to restore {}: {}", name, e);
+                    report.skipped.push(SkippedVar {
+                        name: name.clone(),
+                        reason: format!("failed to restore: {}", e),
+                    });
                 }
             }
-
-            // Option types (legacy path - now handled by __ferrumpy_kind__)
-            t if t.starts_with("Option<") => {
-                let inner_type = &t[7..t.len() - 1];
-                if value.is_null() {
-                    return Ok("None".to_string());
-                } else {
-                    let inner_code = self.generate_value_expr(value, inner_type)?;
-                    return Ok(format!("Some({})", inner_code));
-                }
+        }
+        let mut restored_typed = Vec::new();
+        for (name, value, raw_ty) in &typed_attempts {
+            let ty = &typed_fallbacks[name];
+            let typed = SnapshotCodegen
+                .generate_typed_attempt_module(name, value, ty)
+                .and_then(|code| {
+                    self.eval_generated(SourceMap::single(
+                        CodeSection::Variable(name.clone()),
+                        &code,
+                    ))
+                })
+                .map(|output| parse_restore_error(&output))
+                .unwrap_or_else(|e| Some(e.to_string()));
+            let Some(e) = typed else {
+                loaded.push(LoadedVar {
+                    strategy: RestoreStrategy::Serde,
+                    ..loaded_var(name, value, ty)
+                });
+                restored_typed.push(name.clone());
+                continue;
+            };
+            eprintln!("[FerrumPy] Note: Keeping {} as {} ({})", name, raw_ty, e);
+            let raw = SnapshotCodegen
+                .generate_isolated_module(name, value, raw_ty)
+                .and_then(|code| {
+                    self.eval_generated(SourceMap::single(
+                        CodeSection::Variable(name.clone()),
+                        &code,
+                    ))
+                });
+            match raw {
+                Ok(_) => loaded.push(loaded_var(name, value, raw_ty)),
+                Err(e) => report.skipped.push(SkippedVar {
+                    name: name.clone(),
+                    reason: format!("failed to restore: {}", e),
+                }),
             }
-
-            _ => {}
         }
 
-        // Fallback: use serde_json for complex/user types
-        let json_str = serde_json::to_string(value)?;
-        // Use type annotation for proper deserialization
-        if type_hint != "serde_json::Value" && type_hint != "?" {
-            Ok(format!(
-                "serde_json::from_str::<{}>(r#\"{}\"#).unwrap()",
-                type_hint, json_str
-            ))
-        } else {
-            Ok(format!(
-                "serde_json::from_str::<serde_json::Value>(r#\"{}\"#).unwrap()",
-                json_str
-            ))
-        }
-    }
-
-    /// Generate code for Option<T> from __ferrumpy_kind__ metadata
-    fn generate_option_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let variant = value
-            .get("__variant__")
-            .and_then(|v| v.as_str())
-            .unwrap_or("unknown");
-
-        match variant {
-            "None" => Ok("None".to_string()),
-            "Some" => {
-                // Extract inner type from Option<T>
-                let inner_type = if type_hint.starts_with("Option<") && type_hint.ends_with(">") {
-                    &type_hint[7..type_hint.len() - 1]
-                } else {
-                    "serde_json::Value"
-                };
-
-                if let Some(inner) = value.get("__inner__") {
-                    let inner_code = self.generate_value_init_expr(inner, inner_type)?;
-                    Ok(format!("Some({})", inner_code))
-                } else {
-                    Ok("None".to_string())
-                }
-            }
-            _ => Ok("None".to_string()), // Unknown variant, default to None
+        report.fallback_to_json = loaded
+            .iter()
+            .filter(|var| {
+                var.restored_type == "serde_json::Value" && var.declared_type != var.restored_type
+            })
+            .map(|var| var.name.clone())
+            .collect();
+        report.adjusted = adjusted
+            .into_iter()
+            .filter(|(name, declared, _)| {
+                !restored_typed.contains(name) || owned_str_type(declared).is_some()
+            })
+            .map(|(name, _, _)| name)
+            .filter(|name| !report.fallback_to_json.contains(name))
+            .collect();
+        report.truncated = truncated;
+        if self.policy.as_struct && !update && !loaded.is_empty() {
+            report.as_struct = self.bundle_ctx_struct(&loaded);
         }
+        for var in &loaded {
+            let mut tracked = TrackedVar::new(&var.restored_type, VarOrigin::Snapshot)
+                .with_value_hash(hashes[&var.name]);
+            tracked.truncated = var.truncated;
+            self.variables.insert(var.name.clone(), tracked);
+        }
+        report.loaded = loaded;
+        Ok(report)
     }
 
-    /// Generate code for Result<T, E> from __ferrumpy_kind__ metadata
-    fn generate_result_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let variant = value
-            .get("__variant__")
-            .and_then(|v| v.as_str())
-            .unwrap_or("unknown");
-
-        // Parse Result<T, E> to extract T and E types
-        let (ok_type, err_type) = self.parse_result_types(type_hint);
-
-        match variant {
-            "Ok" => {
-                if let Some(inner) = value.get("__inner__") {
-                    let inner_code = self.generate_value_init_expr(inner, &ok_type)?;
-                    Ok(format!("Ok({})", inner_code))
-                } else {
-                    Ok("Ok(())".to_string())
-                }
+    /// Bundle loaded variables into `ctx`, see `SnapshotPolicy::as_struct`
+    ///
+    /// Returns whether it compiled; the variables stay available through
+    /// their accessors either way.
+    fn bundle_ctx_struct(&mut self, loaded: &[LoadedVar]) -> bool {
+        let fields: Vec<(String, String)> = loaded
+            .iter()
+            .map(|var| (var.name.clone(), var.restored_type.clone()))
+            .collect();
+        let code = SnapshotCodegen.generate_ctx_struct(&fields);
+        match self.eval_generated(SourceMap::single(CodeSection::Generated, &code)) {
+            Ok(_) => {
+                self.variables.insert(
+                    "ctx".to_string(),
+                    TrackedVar::new(
+                        format!("&'static {}", CTX_STRUCT_NAME),
+                        VarOrigin::UserDefined,
+                    ),
+                );
+                true
             }
-            "Err" => {
-                if let Some(inner) = value.get("__inner__") {
-                    let inner_code = self.generate_value_init_expr(inner, &err_type)?;
-                    Ok(format!("Err({})", inner_code))
-                } else {
-                    Ok("Err(\"unknown\".to_string())".to_string())
-                }
+            Err(e) => {
+                eprintln!(
+                    "[FerrumPy] Warning: Failed to bundle variables in ctx: {}",
+                    e
+                );
+                false
             }
-            _ => Ok("Err(\"unknown variant\".to_string())".to_string()),
         }
     }
 
-    /// Parse Result<T, E> to extract T and E
-    fn parse_result_types(&self, type_hint: &str) -> (String, String) {
-        if !type_hint.starts_with("Result<") || !type_hint.ends_with(">") {
-            return ("serde_json::Value".to_string(), "String".to_string());
-        }
+    /// Evaluate a Rust expression
+    ///
+    /// Returns the value followed by the printed lines; stderr stays
+    /// available from `drain_stderr`. See [`eval_output`](Self::eval_output).
+    pub fn eval(&mut self, code: &str) -> Result<String> {
+        let output = self.eval_output(code)?;
+        self.stderr.extend(output.stderr.iter().cloned());
+        Ok(output.to_string())
+    }
 
-        let inner = &type_hint[7..type_hint.len() - 1];
-        // Simple split on ", " - may not work for nested generics
-        if let Some(comma_pos) = inner.find(", ") {
-            let ok_type = inner[..comma_pos].to_string();
-            let err_type = inner[comma_pos + 2..].to_string();
-            (ok_type, err_type)
-        } else {
-            (inner.to_string(), "String".to_string())
+    /// Evaluate a Rust expression, separating its value from what it printed
+    ///
+    /// Only output of this evaluation is returned: lines printed earlier,
+    /// e.g. by background threads, go to `drain_stdout`/`drain_stderr`.
+    pub fn eval_output(&mut self, code: &str) -> Result<EvalOutput> {
+        self.interrupted = false;
+        let fast = self
+            .fast_path
+            .then(|| fast_eval(code, self.snapshot_json.as_deref(), &self.variables))
+            .flatten();
+        let result = match fast {
+            Some(result) => result
+                .map(|value| EvalOutput {
+                    value,
+                    fast_path: true,
+                    ..Default::default()
+                })
+                .map_err(Into::into),
+            None => self.run_code(code),
+        };
+        self.history.push(HistoryEntry::new(code, &result));
+        if result.is_ok() {
+            self.record_definitions(code);
         }
+        result
     }
 
-    /// Generate code for tuple from __ferrumpy_kind__ metadata
-    fn generate_tuple_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let elements = value.get("__elements__").and_then(|v| v.as_array());
-
-        if let Some(elems) = elements {
-            // Parse tuple types from type_hint like "(i32, String, f64)"
-            let elem_types = self.parse_tuple_types(type_hint);
-
-            let mut parts = Vec::new();
-            for (i, elem) in elems.iter().enumerate() {
-                let elem_type = elem_types
-                    .get(i)
-                    .map(|s| s.as_str())
-                    .unwrap_or("serde_json::Value");
-                let part = self.generate_value_init_expr(elem, elem_type)?;
-                parts.push(part);
-            }
-
-            Ok(format!("({})", parts.join(", ")))
-        } else {
-            Ok("()".to_string())
-        }
+    /// Code evaluated in this session, oldest first, including evaluations
+    /// that failed
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
     }
 
-    /// Parse tuple type "(T1, T2, T3)" into vec of types
-    fn parse_tuple_types(&self, type_hint: &str) -> Vec<String> {
-        if !type_hint.starts_with("(") || !type_hint.ends_with(")") {
-            return vec![];
+    /// Evaluate the history entries in `range` again, in order
+    ///
+    /// Stops at the first entry that fails. The replayed evaluations are
+    /// added to the history like any other.
+    pub fn replay(&mut self, range: std::ops::Range<usize>) -> Result<Vec<EvalOutput>> {
+        if range.end > self.history.len() || range.start > range.end {
+            anyhow::bail!(
+                "History range {}..{} out of bounds, history has {} entries",
+                range.start,
+                range.end,
+                self.history.len()
+            );
         }
-
-        let inner = &type_hint[1..type_hint.len() - 1];
-        // Simple split - may not work for nested generics with commas
-        inner.split(", ").map(|s| s.to_string()).collect()
+        let codes: Vec<String> = self.history[range.clone()]
+            .iter()
+            .map(|entry| entry.code.clone())
+            .collect();
+        let mut outputs = Vec::new();
+        for (index, code) in range.zip(codes) {
+            let output = self
+                .eval_output(&code)
+                .map_err(|e| e.context(format!("Replaying history entry {}", index)))?;
+            outputs.push(output);
+        }
+        Ok(outputs)
     }
 
-    /// Generate code for fixed array from __ferrumpy_kind__ metadata
-    fn generate_array_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let elements = value.get("__elements__").and_then(|v| v.as_array());
-
-        if let Some(elems) = elements {
-            // Parse array type like "[i32; 5]" to get element type
-            let elem_type = self.parse_array_elem_type(type_hint);
-
-            let mut parts = Vec::new();
-            for elem in elems {
-                let part = self.generate_value_init_expr(elem, &elem_type)?;
-                parts.push(part);
+    /// Write the history as a Rust program to `path`
+    ///
+    /// `main` runs the evaluated code in order, after the session's imports
+    /// and the items restoring its snapshot, so the session can be turned
+    /// into a test. Failed evaluations are left out with `only_successful`,
+    /// and kept as comments otherwise.
+    pub fn export_history(&self, path: &Path, only_successful: bool) -> Result<()> {
+        let entries: Vec<(usize, &HistoryEntry)> = self
+            .history
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.success || !only_successful)
+            .collect();
+        let snapshot_code = self.snapshot_items()?;
+        let mut prelude = Vec::new();
+        if snapshot_code.is_some() {
+            let snapshot: Option<serde_json::Value> = self
+                .snapshot_json
+                .as_deref()
+                .and_then(|json| serde_json::from_str(json).ok());
+            if let Some(lib_use) = snapshot
+                .as_ref()
+                .and_then(|s| s.get("lib_use_stmt"))
+                .and_then(|v| v.as_str())
+            {
+                prelude.push(lib_use.to_string());
+            }
+            if self.default_prelude {
+                prelude.extend(DEFAULT_PRELUDE.iter().map(|stmt| stmt.to_string()));
             }
-
-            Ok(format!("[{}]", parts.join(", ")))
-        } else {
-            Ok("[]".to_string())
         }
+        prelude.extend(self.prelude.iter().cloned());
+
+        let source = history_scaffold(&self.deps, &prelude, snapshot_code.as_deref(), &entries);
+        std::fs::write(path, source)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
     }
 
-    /// Parse array type "[T; N]" to get element type T
-    fn parse_array_elem_type(&self, type_hint: &str) -> String {
-        // Handle "[i32; 5]" format
-        if type_hint.starts_with("[") && type_hint.contains(";") {
-            if let Some(semi_pos) = type_hint.find(';') {
-                return type_hint[1..semi_pos].trim().to_string();
-            }
-        }
-        // Handle "int[5]" C-style format
-        if let Some(bracket_pos) = type_hint.find('[') {
-            return type_hint[..bracket_pos].to_string();
+    /// The `ferrumpy_vars` module restoring the loaded snapshot, if any
+    fn snapshot_items(&self) -> Result<Option<String>> {
+        if let Some((payload_path, manifest)) = &self.binary_snapshot {
+            let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+            let vars = SnapshotCodegen.extract_binary_variables(&manifest)?;
+            let payload_path = payload_path.display().to_string();
+            return SnapshotCodegen
+                .generate_binary_module(&payload_path, &vars)
+                .map(|code| Some(code.into_code()));
         }
-        "serde_json::Value".to_string()
+        let Some(json) = &self.snapshot_json else {
+            return Ok(None);
+        };
+        let mut snapshot: serde_json::Value = serde_json::from_str(json)?;
+        let hints = self.snapshot_type_hints.as_deref().unwrap_or("");
+        SnapshotCodegen.merge_type_hints(&mut snapshot, hints)?;
+        let mut adjusted = Vec::new();
+        let vars: Vec<_> = SnapshotCodegen
+            .extract_variables(&snapshot, &mut adjusted)?
+            .into_iter()
+            .filter(|(_, _, ty)| SnapshotCodegen.is_type_likely_available(ty))
+            .collect();
+        SnapshotCodegen
+            .generate_snapshot_module(&vars)
+            .map(|code| Some(code.into_code()))
     }
 
-    /// Generate code for Arc<T> from __ferrumpy_kind__ metadata
-    fn generate_arc_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let inner_type = if type_hint.starts_with("Arc<") && type_hint.ends_with(">") {
-            &type_hint[4..type_hint.len() - 1]
-        } else {
-            "serde_json::Value"
-        };
+    /// `eval` for code generated by the session itself, which `restart`
+    /// doesn't replay
+    fn eval_generated(&mut self, code: SourceMap) -> Result<String> {
+        let output = self.run_source(code)?;
+        self.stderr.extend(output.stderr.iter().cloned());
+        Ok(output.to_string())
+    }
 
-        if let Some(inner) = value.get("__inner__") {
-            let inner_code = self.generate_value_init_expr(inner, inner_type)?;
-            Ok(format!("std::sync::Arc::new({})", inner_code))
-        } else {
-            // Fallback to serde_json::Value
-            let json_str = serde_json::to_string(value)?;
-            Ok(format!(
-                "std::sync::Arc::new(serde_json::from_str::<serde_json::Value>(r#\"{}\"#).unwrap())",
-                json_str
-            ))
+    /// Run user code after any pending prelude
+    fn run_code(&mut self, code: &str) -> Result<EvalOutput> {
+        if let Some((name, spec)) = parse_dep_command(code) {
+            return self.run_dep_command(name, spec);
         }
+        self.run_source(SourceMap::single(CodeSection::Input, code))
     }
 
-    /// Generate code for Rc<T> from __ferrumpy_kind__ metadata
-    fn generate_rc_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let inner_type = if type_hint.starts_with("Rc<") && type_hint.ends_with(">") {
-            &type_hint[3..type_hint.len() - 1]
-        } else {
-            "serde_json::Value"
+    /// Run a `:dep` command, skipped if an equivalent spec is registered and
+    /// failing before it compiles if a registered one conflicts
+    fn run_dep_command(&mut self, name: &str, spec: &str) -> Result<EvalOutput> {
+        let spec = match self.dep_registry.check(name, spec, DepOrigin::User)? {
+            Registration::Add(spec) => spec,
+            Registration::Redundant => return Ok(EvalOutput::default()),
         };
+        let command = format!(":dep {} = {}", name, spec);
+        let output = self.run_source(SourceMap::single(CodeSection::Input, &command))?;
+        self.dep_registry.record(name, &spec, DepOrigin::User);
+        Ok(output)
+    }
 
-        if let Some(inner) = value.get("__inner__") {
-            let inner_code = self.generate_value_init_expr(inner, inner_type)?;
-            Ok(format!("std::rc::Rc::new({})", inner_code))
-        } else {
-            let json_str = serde_json::to_string(value)?;
-            Ok(format!(
-                "std::rc::Rc::new(serde_json::from_str::<serde_json::Value>(r#\"{}\"#).unwrap())",
-                json_str
-            ))
+    /// Run code after any pending prelude, locating compile errors with the
+    /// map of its sections
+    fn run_source(&mut self, source: SourceMap) -> Result<EvalOutput> {
+        self.restore_if_pending()?;
+        let prelude = self.take_pending_prelude();
+        // `:` commands must start the input, so run the prelude on its own first
+        if !prelude.is_empty() && source.code().trim_start().starts_with(':') {
+            let prelude_output =
+                self.run_source(SourceMap::single(CodeSection::Prelude, prelude.trim_end()))?;
+            self.stdout.extend(prelude_output.stdout);
+            self.stderr.extend(prelude_output.stderr);
+            return self.run_source(source);
         }
-    }
-
-    /// Generate code for Box<T> from __ferrumpy_kind__ metadata
-    fn generate_box_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let inner_type = if type_hint.starts_with("Box<") && type_hint.ends_with(">") {
-            &type_hint[4..type_hint.len() - 1]
-        } else {
-            "serde_json::Value"
-        };
-
-        if let Some(inner) = value.get("__inner__") {
-            let inner_code = self.generate_value_init_expr(inner, inner_type)?;
-            Ok(format!("Box::new({})", inner_code))
-        } else {
-            let json_str = serde_json::to_string(value)?;
-            Ok(format!(
-                "Box::new(serde_json::from_str::<serde_json::Value>(r#\"{}\"#).unwrap())",
-                json_str
-            ))
-        }
-    }
-
-    /// Generate code for user-defined enum from __ferrumpy_kind__: enum metadata
-    fn generate_enum_code(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        let enum_type = value
-            .get("__enum_type__")
-            .and_then(|v| v.as_str())
-            .unwrap_or("Unknown");
-        let variant = value
-            .get("__variant__")
-            .and_then(|v| v.as_str())
-            .unwrap_or("Unknown");
-        let payload = value.get("__payload__");
-
-        // Use the type_hint if it includes the full path, otherwise construct from enum_type
-        let base_type = if type_hint.contains("::") {
-            // Remove the variant part if present in type_hint
-            if let Some(pos) = type_hint.rfind("::") {
-                let before_last = &type_hint[..pos];
-                if let Some(pos2) = before_last.rfind("::") {
-                    // Check if last part looks like a variant (capitalized)
-                    let last = &before_last[pos2 + 2..];
-                    if !last.is_empty() && last.chars().next().unwrap().is_uppercase() {
-                        before_last.to_string()
-                    } else {
-                        type_hint.to_string()
-                    }
-                } else {
-                    before_last.to_string()
+        let mut full_code = SourceMap::new();
+        if !source.code().trim_start().starts_with(':') {
+            full_code.push(CodeSection::Generated, &self.fence_statement());
+        }
+        full_code.push(CodeSection::Prelude, &prelude);
+        full_code.append(source.clone());
+
+        let output = match self.execute(full_code.code()) {
+            Ok(output) => {
+                // An interrupt that came too late to stop anything
+                self.interrupt.take_requested();
+                output
+            }
+            Err(e) => {
+                // Retry the prelude with the next evaluation
+                self.prelude_pending |= !prelude.is_empty();
+                if matches!(e, EvcxrError::SubprocessTerminated(_))
+                    && self.interrupt.take_requested()
+                {
+                    self.interrupted = true;
+                    self.restore_after_interrupt()?;
+                    return Err(Interrupted.into());
                 }
-            } else {
-                type_hint.to_string()
+                return Err(eval_error(e, &full_code));
             }
-        } else {
-            enum_type.to_string()
         };
+        self.track_let_bindings(source.code());
+        Ok(output)
+    }
 
-        // Handle different payload types
-        match payload {
-            None | Some(serde_json::Value::Null) => {
-                // Unit variant: Status::Active
-                Ok(format!("{}::{}", base_type, variant))
-            }
-            Some(serde_json::Value::Array(arr)) => {
-                // Tuple variant with multiple fields: Status::MultiValue(a, b, c)
-                let mut parts = Vec::new();
-                for elem in arr {
-                    let elem_code = self.primitive_to_code(elem);
-                    parts.push(elem_code);
-                }
-                Ok(format!("{}::{}({})", base_type, variant, parts.join(", ")))
-            }
-            Some(serde_json::Value::Object(obj)) => {
-                // Struct variant: Status::Inactive { reason: "..." }
-                let mut fields = Vec::new();
-                for (key, val) in obj {
-                    let val_code = self.primitive_to_code(val);
-                    fields.push(format!("{}: {}", key, val_code));
-                }
-                Ok(format!(
-                    "{}::{} {{ {} }}",
-                    base_type,
-                    variant,
-                    fields.join(", ")
-                ))
-            }
-            Some(single_val) => {
-                // Tuple variant with single field: Status::Pending(42)
-                let val_code = self.primitive_to_code(single_val);
-                Ok(format!("{}::{}({})", base_type, variant, val_code))
-            }
-        }
-    }
-
-    /// Convert a JSON value to a Rust literal for enum payloads
-    fn primitive_to_code(&self, val: &serde_json::Value) -> String {
-        match val {
-            serde_json::Value::Null => "()".to_string(),
-            serde_json::Value::Bool(b) => b.to_string(),
-            serde_json::Value::Number(n) => n.to_string(),
-            serde_json::Value::String(s) => format!("{:?}.to_string()", s),
-            serde_json::Value::Array(arr) => {
-                let elements: Vec<String> = arr.iter().map(|e| self.primitive_to_code(e)).collect();
-                format!("vec![{}]", elements.join(", "))
-            }
-            serde_json::Value::Object(_) => {
-                // For complex objects, fall back to serde
-                let json_str = serde_json::to_string(val).unwrap_or_default();
-                format!(
-                    "serde_json::from_str::<serde_json::Value>(r#\"{}\"#).unwrap()",
-                    json_str
-                )
-            }
-        }
-    }
-
-    /// Get type suffix for numeric literals (e.g., i32 -> "i32", f64 -> "f64")
-    fn type_suffix(&self, type_hint: &str) -> &'static str {
-        match type_hint {
-            "i8" => "i8",
-            "i16" => "i16",
-            "i32" => "i32",
-            "i64" => "i64",
-            "i128" => "i128",
-            "isize" => "isize",
-            "u8" => "u8",
-            "u16" => "u16",
-            "u32" => "u32",
-            "u64" => "u64",
-            "u128" => "u128",
-            "usize" => "usize",
-            "f32" => "f32",
-            "f64" => "f64",
-            _ => "",
-        }
-    }
-
-    /// Generate vec elements as comma-separated expressions
-    fn generate_vec_elements(&self, arr: &[serde_json::Value], inner_type: &str) -> Result<String> {
-        let elements: Result<Vec<String>> = arr
-            .iter()
-            .map(|v| self.generate_value_expr(v, inner_type))
-            .collect();
-        Ok(elements?.join(", "))
-    }
-
-    /// Generate a value expression for a given type
-    fn generate_value_expr(&self, value: &serde_json::Value, type_hint: &str) -> Result<String> {
-        match type_hint {
-            "i8" | "i16" | "i32" | "i64" | "i128" | "isize" => {
-                Ok(value.as_i64().map(|n| n.to_string()).unwrap_or("0".into()))
-            }
-            "u8" | "u16" | "u32" | "u64" | "u128" | "usize" => {
-                Ok(value.as_u64().map(|n| n.to_string()).unwrap_or("0".into()))
-            }
-            "f32" | "f64" => Ok(value
-                .as_f64()
-                .map(|f| format!("{:.15}", f))
-                .unwrap_or("0.0".into())),
-            "bool" => Ok(value
-                .as_bool()
-                .map(|b| b.to_string())
-                .unwrap_or("false".into())),
-            "String" => {
-                let s = value.as_str().unwrap_or("");
-                let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
-                Ok(format!("\"{}\".to_string()", escaped))
-            }
-            _ => {
-                // For complex types, use serde
-                let json_str = serde_json::to_string(value)?;
-                Ok(format!(
-                    "serde_json::from_str::<{}>(r#\"{}\"#).unwrap()",
-                    type_hint, json_str
-                ))
-            }
-        }
-    }
-
-    /// Fix user type paths: remove original crate name prefix
-    /// e.g., "Arc<rust_sample::User>" -> "Arc<User>"
-    fn fix_user_type_path(&self, type_hint: &str) -> String {
-        // Remove the original crate name prefix (keep the type name)
-        // Match pattern: some_crate::TypeName -> TypeName
-        let mut result = type_hint.to_string();
-
-        // Simple approach: strip everything before the last ::
-        // This handles "rust_sample::User" -> "User"
-        // And "Arc<rust_sample::User>" -> "Arc<User>" via regex-like replacement
-        while let Some(start) = result.find("::") {
-            // Find the word before ::
-            let before = &result[..start];
-            if let Some(word_start) = before.rfind(|c: char| !c.is_alphanumeric() && c != '_') {
-                let prefix = &result[word_start + 1..=start + 1];
-                result = result.replacen(prefix, "", 1);
-            } else {
-                // Word at start
-                let prefix = &result[..=start + 1];
-                result = result.replacen(prefix, "", 1);
+    /// Run code with `CommandContext::execute`, collecting its output
+    ///
+    /// Code starting with the [`fence_statement`](Self::fence_statement)
+    /// returns only the stdout lines after the fence; earlier ones, e.g.
+    /// printed by background threads since the last evaluation, stay
+    /// buffered.
+    fn execute(&mut self, code: &str) -> std::result::Result<EvalOutput, EvcxrError> {
+        let fenced = code.starts_with(&self.fence_statement());
+        let marks = (self.stdout.mark(), self.stderr.mark());
+        let outputs = match self.deadline {
+            Some(deadline) => self.execute_until(code, deadline)?,
+            None => {
+                let mut context = self.context.lock();
+                let _cache = self.context.cache_guard();
+                context.execute(code)?
             }
-        }
+        };
+        Ok(self.collect_output(&outputs, marks, fenced))
+    }
 
-        result
+    /// Statement printing the session's output fence, put ahead of evaluated
+    /// code
+    fn fence_statement(&self) -> String {
+        format!("println!(\"{}\");", self.fence)
     }
 
-    /// Check if a type is supported for snapshot restoration
-    /// With improved type normalization from Python, we can now support more types
-    fn is_supported_type(&self, type_hint: &str) -> bool {
-        // Skip pointer types (raw pointers)
-        if type_hint.contains(" *") || type_hint.contains("*const") || type_hint.contains("*mut") {
-            return false;
-        }
+    /// Fenced `json_eval_code`
+    fn json_eval_source(&self, expr: &str) -> String {
+        format!("{}\n{}", self.fence_statement(), json_eval_code(expr))
+    }
 
-        // Skip references (can't deserialize references)
-        if type_hint.starts_with("&") {
-            return false;
+    /// `execute` on a thread of its own, abandoning the context if the code
+    /// is still compiling or running at `deadline`
+    fn execute_until(
+        &mut self,
+        code: &str,
+        deadline: Instant,
+    ) -> std::result::Result<EvalOutputs, EvcxrError> {
+        let code = code.to_string();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self
+            .context
+            .run_with_timeout(timeout, move |context| context.execute(&code))
+        {
+            Some(outputs) => outputs,
+            None => {
+                self.replace_context()
+                    .map_err(|e| EvcxrError::from(format!("{:#}", e)))?;
+                Err(EvcxrError::from("Evaluation timed out"))
+            }
         }
+    }
 
-        // Skip smart pointers if they don't have a type hint or are malformed
-        if type_hint.contains("Arc<") || type_hint.contains("Rc<") || type_hint.contains("Box<") {
-            return type_hint.contains('<') && type_hint.ends_with('>');
-        }
+    /// Kill the subprocess and swap in a new context for the one an
+    /// evaluation still holds, as `interrupt` would restart it
+    ///
+    /// The abandoned context is dropped once its compile finishes. The
+    /// dependencies and snapshot are restored at the next evaluation.
+    fn replace_context(&mut self) -> Result<()> {
+        self.swap_context()?;
+        self.dep_registry.clear_context();
+        self.variables.clear();
+        self.interrupted = true;
+        self.restore_pending = true;
+        Ok(())
+    }
 
-        // Skip RefCell/Cell (complex internal state, can't deserialize)
-        if type_hint.contains("RefCell<") || type_hint.contains("Cell<") {
-            return false;
-        }
+    /// Kill the subprocess and start a new context running the worker with
+    /// the current options, moving the output streams and interrupt handles
+    /// over to it
+    fn swap_context(&mut self) -> Result<()> {
+        self.interrupt.kill();
+        let (context, outputs) =
+            Self::start_context(&self.worker_path, &self.worker_options, &self.config)?;
+        self.interrupt.set_process(context.lock().process_handle());
+        self.stdout.reattach(
+            outputs.stdout,
+            OutputStream::Stdout,
+            self.output_callback.clone(),
+        );
+        self.stderr.reattach(
+            outputs.stderr,
+            OutputStream::Stderr,
+            self.output_callback.clone(),
+        );
+        self.context = context;
+        self.worker_options_changed = false;
+        Ok(())
+    }
 
-        // Skip allocator types (should be normalized away by Python, but double-check)
-        if type_hint.contains("Global") || type_hint.contains("alloc::") {
-            return false;
+    /// Restart the subprocess with a cleared context: `:clear`, or a new
+    /// context if the worker options changed since this one started
+    fn clear_context(&mut self) -> Result<()> {
+        if self.worker_options_changed {
+            return self.swap_context();
         }
+        self.context
+            .lock()
+            .execute(":clear")
+            .map(|_| ())
+            .map_err(|e| anyhow::anyhow!("{:?}", e))
+    }
 
-        // NOW SUPPORTED: C-style arrays and Rust arrays via __ferrumpy_kind__ metadata
-        // (Removed the array exclusion)
-
-        // Skip tuples containing references (e.g., (&str, i32))
-        if type_hint.starts_with("(") && type_hint.contains("&") {
-            return false;
+    /// Restore the dependencies and snapshot into a context replaced at a
+    /// deadline
+    fn restore_if_pending(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.restore_pending) {
+            return Ok(());
         }
+        self.restore_state()
+    }
 
-        // Skip unknown types
-        if type_hint == "?" || type_hint.is_empty() {
-            return false;
+    /// Evaluate code, giving up once `timeout` has passed
+    ///
+    /// At the deadline the subprocess is killed as by
+    /// [`interrupt`](Self::interrupt) and the error is [`EvalTimedOut`],
+    /// even if the code is still compiling: the compile goes on in the
+    /// background, in a context the session no longer uses. The dependencies
+    /// and snapshot are restored at the start of the next evaluation.
+    pub fn eval_with_timeout(&mut self, code: &str, timeout: Duration) -> Result<String> {
+        self.restore_if_pending()?;
+        self.deadline = Some(Instant::now() + timeout);
+        let result = self.eval(code);
+        self.deadline = None;
+        if self.restore_pending {
+            return Err(EvalTimedOut(timeout).into());
         }
-
-        // NOW ALLOW:
-        // - Result<T, E> (serde can deserialize)
-        // - HashMap<K, V> (serde can deserialize)
-        // - Nested generics Vec<Vec<T>> (now properly normalized)
-        // - User-defined types (Config, User etc. from companion lib)
-        // - Tuples without references (T1, T2, T3)
-
-        true
+        result
     }
 
-    /// Check if a serialized value is valid for deserialization to the target type
-    /// Returns false for values that would cause serde::from_str to panic
-    fn is_valid_for_deserialization(&self, value: &serde_json::Value, type_hint: &str) -> bool {
-        // Empty strings cannot be deserialized to most types
-        if let Some(s) = value.as_str() {
-            if s.is_empty() {
-                return false;
-            }
-        }
+    /// Evaluate code on a worker thread, handing the session to the returned
+    /// handle until the evaluation finishes
+    pub fn eval_async(self, code: &str) -> EvalHandle {
+        EvalHandle::spawn(self, code.to_string())
+    }
 
-        // Check for error marker objects from Python serializer
-        if let Some(obj) = value.as_object() {
-            // Allow __ferrumpy_kind__ metadata - but only if the type is actually supported
-            if obj.contains_key("__ferrumpy_kind__") {
-                // For types with references, we still can't deserialize
-                // is_supported_type already checked for this, so trust the type_hint
-                if type_hint.contains("&") {
-                    return false; // Contains reference, can't restore
-                }
-                return true;
-            }
+    /// Handle interrupting this session's evaluations from another thread
+    ///
+    /// The evaluation in flight fails with [`Interrupted`], once the session
+    /// is restored as by [`interrupt`](Self::interrupt).
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
 
-            for key in obj.keys() {
-                // These markers indicate serialization failed in Python
-                if key.starts_with("__") && key.ends_with("__") {
-                    return false;
-                }
-            }
+    /// Whether the session was interrupted since the last evaluation
+    /// started, losing the variables it had bound
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupted
+    }
 
-            // For Result<T, E>, need {"Ok": ...} or {"Err": ...} format
-            // (Legacy path - now handled by __ferrumpy_kind__)
-            if type_hint.starts_with("Result<") {
-                if !obj.contains_key("Ok") && !obj.contains_key("Err") {
-                    return false;
-                }
+    /// Keep successfully evaluated `:dep` commands and defining code for
+    /// `restart` to replay
+    fn record_definitions(&mut self, code: &str) {
+        let code = code.trim();
+        if code.starts_with(":dep ") {
+            if !self.deps.iter().any(|dep| dep == code) {
+                self.deps.push(code.to_string());
             }
-
-            // For HashMap<K, V>, the object should be a simple key-value map
-            // (not containing error markers, which we already checked)
+        } else if !code.starts_with(':') && defines_names(code) {
+            self.definitions.push(code.to_string());
         }
+    }
 
-        // serde_json::Value can always be deserialized
-        if type_hint == "serde_json::Value" {
-            return true;
+    /// Record the variables bound by evaluated code, taking types evcxr
+    /// inferred for bindings without an annotation
+    fn track_let_bindings(&mut self, code: &str) {
+        for (name, ty) in let_bindings(code) {
+            let ty = ty
+                .or_else(|| {
+                    self.context
+                        .lock()
+                        .variables_and_types()
+                        .find(|(var, _)| *var == name)
+                        .map(|(_, ty)| ty.to_string())
+                })
+                .unwrap_or_else(|| "_".to_string());
+            self.variables
+                .insert(name, TrackedVar::new(ty, VarOrigin::UserDefined));
         }
-
-        true
     }
 
-    /// Normalize Rust type: remove allocator, convert C types, strip crate prefixes
-    fn normalize_rust_type(&self, type_hint: &str) -> String {
-        let mut result = type_hint.to_string();
-
-        // Remove Global allocator from generics
-        // Vec<i32, alloc::alloc::Global> -> Vec<i32>
-        result = result.replace(", alloc::alloc::Global", "");
-        result = result.replace(",alloc::alloc::Global", "");
-        result = result.replace(", Global", "");
-        result = result.replace(",Global", "");
-
-        // Convert C types to Rust types
-        result = result.replace("int", "i32");
-        result = result.replace("unsigned long", "u64");
-        result = result.replace("long", "i64");
-        result = result.replace("unsigned short", "u16");
-        result = result.replace("short", "i16");
-        result = result.replace("unsigned char", "u8");
-        result = result.replace("double", "f64");
-        result = result.replace("float", "f32");
-
-        // Strip crate prefixes (rust_sample::User -> User)
-        result = self.fix_user_type_path(&result);
+    /// Evaluate an expression and return its value as JSON
+    ///
+    /// The result type must implement `Serialize`; otherwise (the wrapped code
+    /// fails to compile) its display text is returned as `{"__display__": "..."}`.
+    pub fn eval_json(&mut self, expr: &str) -> Result<serde_json::Value> {
+        self.restore_if_pending()?;
+        self.register_dep("serde_json", SERDE_JSON_DEP_SPEC, DepOrigin::Session)?;
+
+        let output = match self.execute(&self.json_eval_source(expr)) {
+            Ok(output) => output,
+            Err(EvcxrError::CompilationErrors(_)) => {
+                let text = self.eval(expr)?;
+                return Ok(serde_json::json!({ "__display__": text }));
+            }
+            Err(e) => return Err(eval_error(e, &SourceMap::new())),
+        };
 
-        result
+        parse_json_output(&output.to_string())
     }
 
-    /// Evaluate a Rust expression
-    pub fn eval(&mut self, code: &str) -> Result<String> {
-        // Use CommandContext::execute instead of EvalContext::eval
-        let outputs = self.context.execute(code).map_err(|e| match e {
-            EvcxrError::CompilationErrors(errors) => {
-                // Prioritize errors over warnings to avoid important errors being hidden
-                let mut formatted = String::new();
-
-                // First, collect and show errors
-                for err in errors.iter().filter(|e| e.level() == "error") {
-                    formatted.push_str(&err.rendered());
-                    formatted.push('\n');
-                }
+    /// Serialize tracked variables back into a snapshot document
+    ///
+    /// The JSON has the `variables` and `types` maps of a snapshot, plus
+    /// `skipped`: `{"name", "reason"}` for names that aren't defined in the
+    /// session or whose type doesn't implement `Serialize`.
+    pub fn export_snapshot(&mut self, names: &[String]) -> Result<String> {
+        self.restore_if_pending()?;
+        self.register_dep("serde_json", SERDE_JSON_DEP_SPEC, DepOrigin::Session)?;
+
+        let mut variables = serde_json::Map::new();
+        let mut types = serde_json::Map::new();
+        let mut skipped = Vec::new();
+        for name in names {
+            let Some(var) = self.variables.get(name) else {
+                skipped.push(serde_json::json!({"name": name, "reason": "not defined"}));
+                continue;
+            };
+            let declared_type = var.declared_type.clone();
+            let expr = match var.origin {
+                VarOrigin::Snapshot => format!("{}()", name),
+                VarOrigin::UserDefined => name.clone(),
+            };
 
-                // Then, show warnings (limit to avoid overwhelming output)
-                let warnings: Vec<_> = errors.iter().filter(|e| e.level() == "warning").collect();
-                if !warnings.is_empty() {
-                    if warnings.len() <= 3 {
-                        for err in &warnings {
-                            formatted.push_str(&err.rendered());
-                            formatted.push('\n');
-                        }
-                    } else {
-                        // Show first 2 warnings and a summary
-                        for err in warnings.iter().take(2) {
-                            formatted.push_str(&err.rendered());
-                            formatted.push('\n');
-                        }
-                        formatted
-                            .push_str(&format!("... and {} more warnings\n", warnings.len() - 2));
-                    }
+            let value = match self.execute(&self.json_eval_source(&expr)) {
+                Ok(output) => parse_json_output(&output.to_string()),
+                Err(EvcxrError::CompilationErrors(_)) => Err(anyhow::anyhow!(
+                    "{} does not implement Serialize",
+                    declared_type
+                )),
+                Err(e) => Err(eval_error(e, &SourceMap::new())),
+            };
+            match value {
+                Ok(value) => {
+                    variables.insert(name.clone(), value);
+                    types.insert(name.clone(), declared_type.into());
+                }
+                Err(e) => {
+                    skipped.push(serde_json::json!({"name": name, "reason": e.to_string()}));
                 }
-
-                anyhow::anyhow!("{}", formatted.trim())
-            }
-            EvcxrError::SubprocessTerminated(msg) => {
-                anyhow::anyhow!("Subprocess terminated: {}", msg)
             }
-            other => anyhow::anyhow!("Eval error: {:?}", other),
-        })?;
+        }
 
-        // Collect any output from the internal stdout/stderr
-        let mut result = String::new();
+        Ok(serde_json::json!({
+            "variables": variables,
+            "types": types,
+            "skipped": skipped,
+        })
+        .to_string())
+    }
 
-        // Get content_by_mime_type for the result
-        if let Some(text) = outputs.content_by_mime_type.get("text/plain") {
-            result.push_str(text);
+    /// Collect the result text and the output produced by an evaluation
+    ///
+    /// `marks` are the positions of the stdout and stderr buffers before the
+    /// evaluation started.
+    fn collect_output(
+        &mut self,
+        outputs: &EvalOutputs,
+        marks: (u64, u64),
+        fenced: bool,
+    ) -> EvalOutput {
+        let value = outputs
+            .content_by_mime_type
+            .get("text/plain")
+            .filter(|text| !text.is_empty())
+            .cloned();
+        let stdout = if fenced {
+            self.take_fenced_stdout(marks.0)
+        } else {
+            self.stdout.take_since(marks.0)
+        };
+        std::thread::sleep(STDERR_SETTLE);
+        let stderr = self.stderr.take_since(marks.1);
+        EvalOutput {
+            value,
+            stdout,
+            stderr,
+            opt_level: self.opt_level,
+            fast_path: false,
         }
+    }
 
-        // Also check for stdout from the channels
-        while let Ok(line) = self.stdout.try_recv() {
-            if !result.is_empty() {
-                result.push('\n');
-            }
-            result.push_str(&line);
+    /// Take the stdout lines after `mark` that follow the fence, waiting up
+    /// to `FENCE_TIMEOUT` for it to come through
+    ///
+    /// The lines ahead of the fence are put back for `drain_stdout`. Without
+    /// a fence, e.g. if the code closed stdout, all the lines are returned.
+    fn take_fenced_stdout(&mut self, mark: u64) -> Vec<String> {
+        let deadline = Instant::now() + FENCE_TIMEOUT;
+        let mut lines = self.stdout.take_since(mark);
+        while !lines.contains(&self.fence) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+            lines.extend(self.stdout.take_since(mark));
         }
-
-        Ok(result)
+        let (before, after) = split_at_fence(lines, &self.fence);
+        self.stdout.extend(before);
+        after
     }
 
     /// Get any stderr output
-    pub fn get_stderr(&self) -> Vec<String> {
-        let mut errors = Vec::new();
-        while let Ok(line) = self.stderr.try_recv() {
-            errors.push(line);
-        }
-        errors
+    pub fn get_stderr(&mut self) -> Vec<String> {
+        self.drain_stderr()
     }
 
     /// Check if the session is initialized with a snapshot
@@ -1195,27 +1584,33 @@ macro_rules! restore {
         self.initialized
     }
 
-    /// Get available variables (if tracked)
-    pub fn variables(&self) -> Vec<String> {
-        // Note: evcxr doesn't expose defined variables directly
-        // We would need to track them ourselves
-        Vec::new()
+    /// Variables defined in the session: restored from the snapshot or
+    /// bound by a top-level `let` in evaluated code
+    pub fn variables(&self) -> &HashMap<String, TrackedVar> {
+        &self.variables
     }
 
     /// Get completions for the given source code at the specified position
     ///
     /// Returns a tuple of (completions, start_offset, end_offset) where:
-    /// - completions: list of completion strings
+    /// - completions: deduped and ranked against the word at the cursor,
+    ///   with the session's variables merged in for a bare identifier
     /// - start_offset: byte offset where the replacement should start
     /// - end_offset: byte offset where the replacement should end
     pub fn completions(
         &mut self,
         src: &str,
         position: usize,
-    ) -> Result<(Vec<evcxr::Completion>, usize, usize)> {
-        match self.context.completions(src, position) {
+    ) -> Result<(Vec<ReplCompletion>, usize, usize)> {
+        match self.context.lock().completions(src, position) {
             Ok(completions) => Ok((
-                completions.completions,
+                normalize_completions(
+                    src,
+                    completions.start_offset,
+                    position,
+                    completions.completions,
+                    &self.variables,
+                ),
                 completions.start_offset,
                 completions.end_offset,
             )),
@@ -1231,76 +1626,465 @@ macro_rules! restore {
     /// Interrupt any currently running evaluation by restarting the subprocess
     ///
     /// This is a forceful interruption that kills the subprocess and starts a new one.
-    /// User-defined variables and functions will be lost, but LLDB snapshot variables
-    /// are automatically restored.
+    /// User-defined variables will be lost, but dependencies and LLDB snapshot
+    /// variables are automatically restored. To interrupt an evaluation in
+    /// flight, use [`interrupt_handle`](Self::interrupt_handle).
     ///
     /// Returns Ok(()) if the interrupt was successful.
     pub fn interrupt(&mut self) -> Result<()> {
         eprintln!("[DEBUG] Interrupt called");
 
-        // Execute :clear command which forces a subprocess restart
-        // This effectively kills any running compilation or execution
-        self.context
-            .execute(":clear")
-            .map_err(|e| anyhow::anyhow!("Failed to interrupt: {:?}", e))?;
-        eprintln!("[DEBUG] :clear executed");
+        // Kill the subprocess through its handle first, so a wedged one
+        // doesn't have to respond for the restart to go through
+        self.interrupt.kill();
+        self.interrupt.take_requested();
+        self.interrupted = true;
+        self.restore_after_interrupt()
+    }
 
-        // Restore snapshot if it was previously loaded
-        if let (Some(json), Some(hints)) = (&self.snapshot_json, &self.snapshot_type_hints) {
-            eprintln!(
-                "[DEBUG] Found snapshot to restore: {} bytes JSON",
-                json.len()
-            );
-            // Clone the data before calling load_snapshot (which needs &mut self)
-            let json_clone = json.clone();
-            let hints_clone = hints.clone();
+    /// Restart the subprocess after an interrupt, replaying the dependencies
+    /// and snapshot as `restart` does, but not the user's code, which may be
+    /// what had to be interrupted
+    fn restore_after_interrupt(&mut self) -> Result<()> {
+        // Restart the subprocess in a cleared context
+        // This effectively kills any running compilation or execution
+        self.clear_context()
+            .map_err(|e| anyhow::anyhow!("Failed to interrupt: {:#}", e))?;
+        eprintln!("[DEBUG] Context cleared");
+        self.dep_registry.clear_context();
+        // User variables are gone; snapshot ones come back with the reload
+        self.variables.clear();
+        self.restore_state()
+    }
 
-            // Re-initialize the session state
-            self.initialized = false;
+    /// Replay the dependencies and reload the snapshot into a cleared context
+    fn restore_state(&mut self) -> Result<()> {
+        self.restore_pending = false;
+        let mut report = RestartReport::default();
+        self.replay_deps(&mut report);
+        for (dep, e) in &report.failed {
+            eprintln!("[FerrumPy] Warning: Failed to restore {}: {}", dep, e);
+        }
 
-            // Reload the snapshot to restore LLDB variables
-            self.load_snapshot(&json_clone, &hints_clone).map_err(|e| {
-                eprintln!("[DEBUG] Snapshot restoration failed: {:?}", e);
-                anyhow::anyhow!("Failed to restore snapshot after interrupt: {:?}", e)
-            })?;
-            eprintln!("[DEBUG] Snapshot restored successfully");
-        } else {
-            eprintln!(
+        // Restore snapshot if it was previously loaded
+        match self.reload_snapshot() {
+            Ok(Some(_)) => eprintln!("[DEBUG] Snapshot restored successfully"),
+            Ok(None) => eprintln!(
                 "[DEBUG] No snapshot to restore (snapshot_json: {}, snapshot_type_hints: {})",
                 self.snapshot_json.is_some(),
                 self.snapshot_type_hints.is_some()
-            );
+            ),
+            Err(e) => {
+                eprintln!("[DEBUG] Snapshot restoration failed: {:?}", e);
+                return Err(anyhow::anyhow!(
+                    "Failed to restore snapshot after interrupt: {:?}",
+                    e
+                ));
+            }
         }
 
         Ok(())
     }
 
-    /// Drain all pending stdout lines from the subprocess
-    ///
-    /// This prevents the stdout pipe from filling up and blocking the subprocess.
-    /// Should be called periodically, especially after eval() operations.
+    /// Load the last snapshot (binary or JSON) again into a cleared context
     ///
-    /// Returns a vector of output lines.
-    pub fn drain_stdout(&mut self) -> Vec<String> {
-        let mut output = Vec::new();
-        while let Ok(line) = self.stdout.try_recv() {
-            output.push(line);
+    /// Returns `None` if no snapshot was loaded.
+    fn reload_snapshot(&mut self) -> Result<Option<SnapshotReport>> {
+        // Re-initialize the session state
+        self.initialized = false;
+        self.prelude_pending = !self.prelude.is_empty();
+
+        if let Some((payload_path, manifest)) = self.binary_snapshot.clone() {
+            return self
+                .load_snapshot_binary(&payload_path, &manifest)
+                .map(Some);
+        }
+        match (self.snapshot_json.clone(), self.snapshot_type_hints.clone()) {
+            (Some(json), Some(hints)) => {
+                eprintln!(
+                    "[DEBUG] Found snapshot to restore: {} bytes JSON",
+                    json.len()
+                );
+                // Reload the snapshot to restore LLDB variables
+                self.load_snapshot(&json, &hints).map(Some)
+            }
+            _ => Ok(None),
         }
-        output
     }
 
-    /// Drain all pending stderr lines from the subprocess
+    /// Restart the subprocess, e.g. when it is wedged or uses too much memory
     ///
-    /// This prevents the stderr pipe from filling up and blocking the subprocess.
-    /// Should be called periodically, especially after eval() operations.
+    /// With `preserve_state`, the user's `:dep` commands, the last snapshot
+    /// and the code that defined variables and items are replayed, in that
+    /// order; what fails to replay is reported rather than returned as an
+    /// error. Otherwise the session starts clean, keeping only its prelude.
+    pub fn restart(&mut self, preserve_state: bool) -> Result<RestartReport> {
+        self.clear_context()
+            .map_err(|e| anyhow::anyhow!("Failed to restart: {:#}", e))?;
+        self.dep_registry.clear_context();
+        self.variables.clear();
+        self.restore_pending = false;
+        self.initialized = false;
+        self.prelude_pending = !self.prelude.is_empty();
+
+        let mut report = RestartReport::default();
+        if !preserve_state {
+            self.snapshot_json = None;
+            self.snapshot_type_hints = None;
+            self.binary_snapshot = None;
+            self.deps.clear();
+            self.definitions.clear();
+            return Ok(report);
+        }
+
+        // Replaying records the commands and code again if they succeed,
+        // without adding them to the history
+        self.replay_deps(&mut report);
+        match self.reload_snapshot() {
+            Ok(snapshot) => report.snapshot = snapshot,
+            Err(e) => report.failed.push(("snapshot".to_string(), e.to_string())),
+        }
+        for code in std::mem::take(&mut self.definitions) {
+            match self.replay_definition(&code) {
+                Ok(()) => report.replayed.push(code),
+                Err(e) => report.failed.push((code, e.to_string())),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Run the recorded `:dep` commands again, into `report`
+    fn replay_deps(&mut self, report: &mut RestartReport) {
+        for dep in std::mem::take(&mut self.deps) {
+            match self.replay_definition(&dep) {
+                Ok(()) => report.deps.push(dep),
+                Err(e) => report.failed.push((dep, e.to_string())),
+            }
+        }
+    }
+
+    /// Run a recorded `:dep` command or definition for `restart`
+    fn replay_definition(&mut self, code: &str) -> Result<()> {
+        let output = self.run_code(code)?;
+        self.stdout.extend(output.stdout);
+        self.stderr.extend(output.stderr);
+        self.record_definitions(code);
+        Ok(())
+    }
+
+    /// Take the buffered stdout lines no evaluation returned, e.g. those
+    /// printed by background threads
     ///
-    /// Returns a vector of error lines.
+    /// Past the output cap the oldest lines are dropped, see
+    /// [`set_output_cap`](Self::set_output_cap).
+    pub fn drain_stdout(&mut self) -> Vec<String> {
+        self.stdout.take_all()
+    }
+
+    /// Take the buffered stderr lines, see [`drain_stdout`](Self::drain_stdout)
     pub fn drain_stderr(&mut self) -> Vec<String> {
-        let mut output = Vec::new();
-        while let Ok(line) = self.stderr.try_recv() {
-            output.push(line);
+        self.stderr.take_all()
+    }
+
+    /// Keep at most `max_bytes` of each stream's unread output, dropping the
+    /// oldest lines past it
+    ///
+    /// Output is drained from the subprocess continuously, so code printing
+    /// without end doesn't grow memory while nothing reads it. Defaults to
+    /// [`DEFAULT_OUTPUT_CAP_BYTES`](super::DEFAULT_OUTPUT_CAP_BYTES).
+    pub fn set_output_cap(&mut self, max_bytes: usize) {
+        self.stdout.set_cap(max_bytes);
+        self.stderr.set_cap(max_bytes);
+    }
+
+    /// Lines of `stream` dropped so far to stay under the output cap
+    pub fn dropped_lines(&self, stream: OutputStream) -> u64 {
+        match stream {
+            OutputStream::Stdout => self.stdout.dropped(),
+            OutputStream::Stderr => self.stderr.dropped(),
+        }
+    }
+
+    /// Stream output lines to `callback` as they arrive, replacing any
+    /// previous callback
+    ///
+    /// The callback runs on a background thread. Lines are still buffered
+    /// for `eval` and `drain_stdout`/`drain_stderr`.
+    pub fn set_output_callback(
+        &mut self,
+        mut callback: impl FnMut(OutputStream, &str) + Send + 'static,
+    ) {
+        let fence = self.fence.clone();
+        let callback: OutputCallback = Box::new(move |stream, line| {
+            if line != fence {
+                callback(stream, line);
+            }
+        });
+        *self
+            .output_callback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(callback);
+    }
+
+    /// Stop streaming output to the callback of `set_output_callback`
+    pub fn clear_output_callback(&mut self) {
+        *self
+            .output_callback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Snapshot variables compared with the ones restored in a session
+#[derive(Default)]
+struct SnapshotDiff {
+    /// Added and changed variables, to be redeclared
+    pending: Vec<(String, serde_json::Value, String)>,
+    added: Vec<String>,
+    changed: Vec<String>,
+    unchanged: Vec<String>,
+    /// Tracked snapshot variables the snapshot no longer has, sorted
+    removed: Vec<String>,
+}
+
+/// Compare extracted snapshot variables with the tracked snapshot variables
+/// by value hash
+fn diff_snapshot(
+    vars: Vec<(String, serde_json::Value, String)>,
+    tracked: &HashMap<String, TrackedVar>,
+) -> SnapshotDiff {
+    let mut diff = SnapshotDiff::default();
+    for (name, value, ty) in vars {
+        let previous = tracked
+            .get(&name)
+            .filter(|var| var.origin == VarOrigin::Snapshot)
+            .and_then(|var| var.value_hash);
+        match previous {
+            None => diff.added.push(name.clone()),
+            Some(hash) if hash == snapshot_value_hash(&value, &ty) => {
+                diff.unchanged.push(name);
+                continue;
+            }
+            Some(_) => diff.changed.push(name.clone()),
+        }
+        diff.pending.push((name, value, ty));
+    }
+    diff.removed = tracked
+        .iter()
+        .filter(|(name, var)| {
+            var.origin == VarOrigin::Snapshot
+                && !diff.pending.iter().any(|(pending, _, _)| pending == *name)
+                && !diff.unchanged.contains(name)
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    diff.removed.sort();
+    diff
+}
+
+/// Record in the loaded variables of `report` where their types came from
+fn set_type_sources(report: &mut SnapshotReport, sources: &HashMap<String, TypeSource>) {
+    for var in &mut report.loaded {
+        if let Some(source) = sources.get(&var.name) {
+            var.type_source = *source;
+        }
+    }
+}
+
+/// Convert an evcxr error into a user-facing error
+fn eval_error(e: EvcxrError, code: &SourceMap) -> anyhow::Error {
+    match e {
+        EvcxrError::CompilationErrors(errors) => CompileErrors(
+            errors
+                .iter()
+                .map(|error| CompileError::from_evcxr(error, code))
+                .collect(),
+        )
+        .into(),
+        EvcxrError::SubprocessTerminated(msg) => {
+            anyhow::anyhow!("Subprocess terminated: {}", msg)
+        }
+        other => anyhow::anyhow!("Eval error: {:?}", other),
+    }
+}
+
+/// Marker prefixing the line printed by the code from `json_eval_code`
+const JSON_OUTPUT_MARKER: &str = "__ferrumpy_json__:";
+
+/// Wrap an expression so that its value is printed as a marked JSON line
+fn json_eval_code(expr: &str) -> String {
+    let expr = expr.trim().trim_end_matches(';');
+    format!(
+        "println!(\"{}{{}}\", serde_json::to_value(&({})).unwrap());",
+        JSON_OUTPUT_MARKER, expr
+    )
+}
+
+/// A line no evaluated code prints by chance, to fence the session's output
+fn output_fence() -> String {
+    let nonce = RandomState::new().hash_one(std::process::id());
+    format!("__ferrumpy_fence_{:016x}__", nonce)
+}
+
+/// Split stdout lines at the first `fence`, dropping it; all the lines come
+/// after it if there is none
+fn split_at_fence(mut lines: Vec<String>, fence: &str) -> (Vec<String>, Vec<String>) {
+    match lines.iter().position(|line| line == fence) {
+        Some(index) => {
+            let after = lines.split_off(index + 1);
+            lines.pop();
+            (lines, after)
+        }
+        None => (Vec::new(), lines),
+    }
+}
+
+/// Find the marked JSON line in the output of `json_eval_code`
+fn parse_json_output(output: &str) -> Result<serde_json::Value> {
+    let json = output
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(JSON_OUTPUT_MARKER))
+        .ok_or_else(|| anyhow::anyhow!("Expression produced no JSON output"))?;
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Validate a prelude statement as a `use` item, adding a missing `;`
+fn normalize_prelude_stmt(stmt: &str) -> Result<String> {
+    let stmt = stmt.trim();
+    let stmt = if stmt.ends_with(';') {
+        stmt.to_string()
+    } else {
+        format!("{};", stmt)
+    };
+    syn::parse_str::<syn::ItemUse>(&stmt)
+        .map_err(|e| anyhow::anyhow!("Not a use statement: {} ({})", stmt, e))?;
+    Ok(stmt)
+}
+
+/// Truncate the variables' values to the policy's limits, returning what was
+/// cut and the names of the variables affected
+fn truncate_to_policy(
+    vars: &mut [(String, serde_json::Value, String)],
+    policy: &SnapshotPolicy,
+) -> (Vec<TruncatedArray>, Vec<String>) {
+    let max = policy.max_collection_len;
+    let mut truncated = Vec::new();
+    let mut truncated_vars = Vec::new();
+    for (name, value, ty) in vars.iter_mut() {
+        let mut arrays = Vec::new();
+        if SnapshotCodegen.is_map_type(ty) {
+            if let Some(map) = value.as_object_mut().filter(|map| map.len() > max) {
+                arrays.push((name.clone(), map.len()));
+                let dropped: Vec<String> = map.keys().skip(max).cloned().collect();
+                for key in dropped {
+                    map.remove(&key);
+                }
+            }
+        }
+        truncate_arrays(value, max, name, &mut arrays);
+        let before = truncated.len();
+        truncated.extend(arrays.into_iter().map(|(path, len)| TruncatedArray {
+            path,
+            len,
+            kept: max,
+            unit: TruncatedUnit::Elements,
+        }));
+        // Byte buffers are base64 strings, which a prefix of wouldn't decode
+        if !is_base64_bytes(value, ty) {
+            truncate_strings(value, policy.max_string_len, name, &mut truncated);
+        }
+        if truncated.len() > before {
+            truncated_vars.push(name.clone());
+        }
+    }
+    (truncated, truncated_vars)
+}
+
+/// Cut strings longer than `max` bytes in place at a char boundary,
+/// recursing into nested values
+fn truncate_strings(
+    value: &mut serde_json::Value,
+    max: usize,
+    path: &str,
+    truncated: &mut Vec<TruncatedArray>,
+) {
+    match value {
+        serde_json::Value::String(s) if s.len() > max => {
+            let mut end = max;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            truncated.push(TruncatedArray {
+                path: path.to_string(),
+                len: s.len(),
+                kept: end,
+                unit: TruncatedUnit::Bytes,
+            });
+            s.truncate(end);
+        }
+        serde_json::Value::Array(arr) => {
+            for (i, elem) in arr.iter_mut().enumerate() {
+                truncate_strings(elem, max, &format!("{}[{}]", path, i), truncated);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                let field_path = if key.starts_with("__") {
+                    path.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                truncate_strings(field, max, &field_path, truncated);
+            }
         }
-        output
+        _ => {}
+    }
+}
+
+/// Truncate arrays longer than `max` in place, recursing into nested values.
+/// Each truncated array is recorded in `truncated` as its path and original length.
+///
+/// The elements of fixed-size arrays are kept, since a shorter array
+/// literal would not match the `[T; N]` type.
+fn truncate_arrays(
+    value: &mut serde_json::Value,
+    max: usize,
+    path: &str,
+    truncated: &mut Vec<(String, usize)>,
+) {
+    match value {
+        serde_json::Value::Array(arr) => {
+            if arr.len() > max {
+                truncated.push((path.to_string(), arr.len()));
+                arr.truncate(max);
+            }
+            for (i, elem) in arr.iter_mut().enumerate() {
+                truncate_arrays(elem, max, &format!("{}[{}]", path, i), truncated);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            let is_fixed_array =
+                map.get("__ferrumpy_kind__").and_then(|k| k.as_str()) == Some("array");
+            for (key, field) in map.iter_mut() {
+                if is_fixed_array && key == "__elements__" {
+                    if let Some(elems) = field.as_array_mut() {
+                        for (i, elem) in elems.iter_mut().enumerate() {
+                            truncate_arrays(elem, max, &format!("{}[{}]", path, i), truncated);
+                        }
+                    }
+                    continue;
+                }
+                // Metadata keys like `__inner__` are not part of the user-visible path
+                let field_path = if key.starts_with("__") {
+                    path.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                truncate_arrays(field, max, &field_path, truncated);
+            }
+        }
+        _ => {}
     }
 }
 
@@ -1334,4 +2118,931 @@ mod tests {
             Err(_) => {}
         }
     }
+
+    #[test]
+    fn test_load_snapshot_restores_cells() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{
+                    "variables": {"cell": [1, 2, 3], "lock": "hi"},
+                    "types": {"cell": "RefCell<Vec<i32>>", "lock": "Arc<Mutex<String>>"}
+                }"#;
+                session.load_snapshot(snapshot, "{}").unwrap();
+                let len = session.eval("cell().borrow().len()").unwrap();
+                assert!(len.contains('3'), "Got: {}", len);
+                let s = session.eval("lock().lock().unwrap().clone()").unwrap();
+                assert!(s.contains("hi"), "Got: {}", s);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_snapshot_restores_btree_map_and_hash_set() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{
+                    "variables": {"scores": {"bob": 7, "ann": 9}, "ids": [3, 1, 2]},
+                    "types": {"scores": "BTreeMap<String, i32>", "ids": "HashSet<i32>"}
+                }"#;
+                let report = session.load_snapshot(snapshot, "{}").unwrap();
+                assert_eq!(report.loaded.len(), 2, "Got: {}", report);
+                let first = session
+                    .eval("scores().keys().next().unwrap().clone()")
+                    .unwrap();
+                assert!(first.contains("ann"), "Got: {}", first);
+                let total = session.eval("scores().values().sum::<i32>()").unwrap();
+                assert!(total.contains("16"), "Got: {}", total);
+                let found = session
+                    .eval("ids().contains(&2) && ids().len() == 3")
+                    .unwrap();
+                assert!(found.contains("true"), "Got: {}", found);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_snapshot_tries_typed_fallback() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{
+                    "variables": {"name": "", "count": ""},
+                    "types": {"name": "String", "count": "i32"}
+                }"#;
+                let report = session.load_snapshot(snapshot, "{}").unwrap();
+                // An empty string is a valid `String`, but not an `i32`
+                let restored: Vec<_> = report
+                    .loaded
+                    .iter()
+                    .map(|var| (var.name.as_str(), var.restored_type.as_str()))
+                    .collect();
+                assert!(
+                    restored.contains(&("name", "String")),
+                    "Got: {:?}",
+                    restored
+                );
+                assert!(
+                    restored.contains(&("count", "serde_json::Value")),
+                    "Got: {:?}",
+                    restored
+                );
+                assert_eq!(report.fallback_to_json, vec!["count"]);
+                let len = session.eval("name().len()").unwrap();
+                assert!(len.contains('0'), "Got: {}", len);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_variables_tracks_snapshot_and_user_bindings() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{
+                    "variables": {"count": 3},
+                    "types": {"count": "i32"}
+                }"#;
+                session.load_snapshot(snapshot, "{}").unwrap();
+                session.eval("let doubled = count() * 2;").unwrap();
+
+                let vars = session.variables();
+                assert_eq!(vars["count"].origin, VarOrigin::Snapshot);
+                assert_eq!(vars["count"].declared_type, "i32");
+                assert_eq!(vars["doubled"].origin, VarOrigin::UserDefined);
+                assert_eq!(vars["doubled"].declared_type, "i32");
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_snapshot_round_trip() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{
+                    "variables": {"n": 1, "s": "hi", "v": [1, 2]},
+                    "types": {"n": "i32", "s": "String", "v": "Vec<i32>"}
+                }"#;
+                session.load_snapshot(snapshot, "{}").unwrap();
+                session.eval("let n = n() + 1;").unwrap();
+                session
+                    .eval("let mut s = s().clone(); s.push('!');")
+                    .unwrap();
+                session.eval("let mut v = v().clone(); v.push(3);").unwrap();
+
+                let names: Vec<String> = ["n", "s", "v", "missing"]
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect();
+                let exported: serde_json::Value =
+                    serde_json::from_str(&session.export_snapshot(&names).unwrap()).unwrap();
+                assert_eq!(
+                    exported["variables"],
+                    serde_json::json!({"n": 2, "s": "hi!", "v": [1, 2, 3]})
+                );
+                assert_eq!(exported["types"]["n"], "i32");
+                assert_eq!(exported["skipped"][0]["name"], "missing");
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_warmup_before_first_eval() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                assert_eq!(session.warmup_status(), None);
+                let warmup = session.warmup(None);
+                let result = session.eval("40 + 2").unwrap();
+                assert!(result.contains("42"), "Got: {}", result);
+                assert_eq!(warmup.status(), WarmupStatus::Ready);
+                assert_eq!(session.warmup_status(), Some(WarmupStatus::Ready));
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_opt_level() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                assert_eq!(session.opt_level(), Some(0));
+                session.set_opt_level(0).unwrap();
+                assert_eq!(session.eval_output("1 + 1").unwrap().opt_level, Some(0));
+                session.set_opt_level(2).unwrap();
+                let output = session.eval_output("2 + 2").unwrap();
+                assert_eq!(output.value.as_deref(), Some("4"));
+                assert_eq!(output.opt_level, Some(2));
+
+                assert!(session.set_opt_level(4).is_err());
+                assert_eq!(session.opt_level(), Some(2));
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_worker_options_cwd() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = std::fs::canonicalize(dir.path()).unwrap();
+        let options = ReplWorkerOptions {
+            cwd: Some(cwd.clone()),
+            ..Default::default()
+        };
+        match ReplSession::with_options(options) {
+            Ok(mut session) => {
+                let result = session.eval("std::env::current_dir().unwrap()").unwrap();
+                assert!(result.contains(&*cwd.to_string_lossy()), "Got: {}", result);
+
+                // Set for the next restart
+                let other = tempfile::tempdir().unwrap();
+                let other = std::fs::canonicalize(other.path()).unwrap();
+                session.set_worker_cwd(Some(other.clone()));
+                session.set_worker_args(vec!["--flag".to_string()]);
+                session.restart(false).unwrap();
+                let result = session.eval("std::env::current_dir().unwrap()").unwrap();
+                assert!(
+                    result.contains(&*other.to_string_lossy()),
+                    "Got: {}",
+                    result
+                );
+                let result = session
+                    .eval("std::env::args().skip(1).collect::<Vec<_>>()")
+                    .unwrap();
+                assert!(result.contains("--flag"), "Got: {}", result);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_with_timeout_recovers() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let err = session
+                    .eval_with_timeout("loop {}", Duration::from_millis(500))
+                    .unwrap_err();
+                assert!(err.downcast_ref::<EvalTimedOut>().is_some(), "Got: {}", err);
+
+                let result = session.eval("1 + 1").unwrap();
+                assert!(result.contains('2'), "Got: {}", result);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_async_cancel() {
+        match ReplSession::new() {
+            Ok(session) => {
+                let handle = session.eval_async("1 + 2");
+                let (session, result) = handle.wait();
+                assert!(result.unwrap().contains('3'));
+
+                let handle = session.eval_async("loop {}");
+                assert!(!handle.poll());
+                handle.cancel();
+                let (mut session, result) = handle.wait();
+                assert!(result.unwrap_err().downcast_ref::<Interrupted>().is_some());
+                let result = session.eval("1 + 1").unwrap();
+                assert!(result.contains('2'), "Got: {}", result);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_snapshot_binary() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                // bincode 1: u64 length prefix, then little-endian elements
+                let mut payload = 3u64.to_le_bytes().to_vec();
+                for x in [1.5f64, 2.5, 3.0] {
+                    payload.extend_from_slice(&x.to_le_bytes());
+                }
+                let path = std::env::temp_dir().join("ferrumpy_test_payload.bin");
+                std::fs::write(&path, &payload).unwrap();
+                let manifest = serde_json::json!({
+                    "variables": {
+                        "samples": {"type": "Vec<f64>", "offset": 0, "len": payload.len()}
+                    }
+                });
+
+                let report = session
+                    .load_snapshot_binary(&path, &manifest.to_string())
+                    .unwrap();
+                assert_eq!(report.loaded[0].strategy, RestoreStrategy::Binary);
+                let result = session.eval("samples().iter().sum::<f64>()").unwrap();
+                assert!(result.contains("7"), "Got: {}", result);
+                let _ = std::fs::remove_file(&path);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_output_is_fenced_per_eval() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let first = session.eval_output("println!(\"first\"); 1 + 1").unwrap();
+                let second = session.eval_output("println!(\"second\");").unwrap();
+                assert_eq!(first.value.as_deref(), Some("2"));
+                assert_eq!(first.stdout, vec!["first"]);
+                assert_eq!(second.value, None);
+                assert_eq!(second.stdout, vec!["second"]);
+
+                // A line printed after an eval returned isn't the next one's
+                session
+                    .eval("std::thread::spawn(|| { std::thread::sleep(std::time::Duration::from_millis(200)); println!(\"late\"); });")
+                    .unwrap();
+                std::thread::sleep(Duration::from_millis(500));
+                let third = session.eval_output("println!(\"third\");").unwrap();
+                assert_eq!(third.stdout, vec!["third"]);
+                assert_eq!(session.drain_stdout(), vec!["late"]);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_output_imitating_protocol_lines() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let fence = session.fence.clone();
+                let code = format!(
+                    "println!(\"EVCXR_END_CONTENT\"); println!(\"EVCXR_READY\"); println!(\"{}x\"); 7",
+                    fence
+                );
+                let output = session.eval_output(&code).unwrap();
+                assert_eq!(output.value.as_deref(), Some("7"));
+                assert_eq!(
+                    output.stdout,
+                    vec![
+                        "EVCXR_END_CONTENT".to_string(),
+                        "EVCXR_READY".to_string(),
+                        format!("{}x", fence),
+                    ]
+                );
+                assert!(session.drain_stdout().is_empty());
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_drain_after_printing_eval() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                // eval() returns its stdout, leaving its stderr to drain_stderr
+                let result = session
+                    .eval("println!(\"out\"); eprintln!(\"err\");")
+                    .unwrap();
+                assert_eq!(result, "out");
+                assert!(session.drain_stdout().is_empty());
+                assert!(session.drain_stderr().contains(&"err".to_string()));
+                assert!(session.drain_stderr().is_empty());
+
+                // Output printed after eval() returned is left to drain_stdout
+                session
+                    .eval("std::thread::spawn(|| { std::thread::sleep(std::time::Duration::from_millis(100)); for i in 0..3 { println!(\"bg {}\", i); } });")
+                    .unwrap();
+                std::thread::sleep(Duration::from_millis(500));
+                assert_eq!(session.drain_stdout(), vec!["bg 0", "bg 1", "bg 2"]);
+                assert!(session.drain_stdout().is_empty());
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interrupt_kills_and_restores() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{"variables": {"count": 3}, "types": {"count": "i32"}}"#;
+                session.load_snapshot(snapshot, "{}").unwrap();
+                session.eval("let user = 1;").unwrap();
+                let process = session.interrupt.process();
+
+                session.interrupt().unwrap();
+                assert!(process.lock().unwrap().try_wait().unwrap().is_none());
+                assert!(!session.variables().contains_key("user"));
+                let result = session.eval("count()").unwrap();
+                assert!(result.contains("3"), "Got: {}", result);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot_crate_dep_conflict() {
+        let lib = tempfile::tempdir().unwrap();
+        std::fs::write(
+            lib.path().join("Cargo.toml"),
+            "[package]\nname = \"ferrumpy_snapshot\"\n\n[dependencies]\nserde = \"0.9\"\n",
+        )
+        .unwrap();
+        let snapshot = serde_json::json!({
+            "variables": {"count": 1},
+            "types": {"count": "i32"},
+            "lib_path": lib.path().to_string_lossy(),
+        });
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let e = session
+                    .load_snapshot(&snapshot.to_string(), "{}")
+                    .unwrap_err();
+                let conflict = e.downcast_ref::<crate::repl::DepConflict>().unwrap();
+                assert_eq!(conflict.name, "serde");
+                assert_eq!(conflict.existing.origin, DepOrigin::Session);
+                assert_eq!(conflict.requested.origin, DepOrigin::SnapshotCrate);
+                assert!(session.variables().is_empty());
+
+                // An equivalent `:dep` is skipped without compiling
+                let names: Vec<String> = session
+                    .registered_deps()
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .collect();
+                assert_eq!(names, vec!["serde", "serde_json"]);
+                assert_eq!(session.add_dep("serde", "\"1\"").unwrap(), "");
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fast_path_skips_compile() {
+        match ReplSession::new() {
+            Ok(session) => {
+                let mut session = session.with_fast_path(true);
+                let output = session.eval_output("2 + 3").unwrap();
+                assert!(output.fast_path);
+                assert_eq!(output.value.as_deref(), Some("5"));
+                assert_eq!(session.history().len(), 1);
+
+                let output = session.eval_output("vec![1, 2].len()").unwrap();
+                assert!(!output.fast_path);
+                assert_eq!(output.value.as_deref(), Some("2"));
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interrupt_eval_in_flight() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{"variables": {"count": 3}, "types": {"count": "i32"}}"#;
+                session.load_snapshot(snapshot, "{}").unwrap();
+                // Compile the loop first, so the interrupt lands while it runs
+                session
+                    .eval("fn spin() { loop { std::hint::spin_loop(); } }")
+                    .unwrap();
+                let interrupt = session.interrupt_handle();
+
+                let result = std::thread::scope(|scope| {
+                    let eval = scope.spawn(|| session.eval("spin()"));
+                    std::thread::sleep(Duration::from_millis(500));
+                    interrupt.interrupt();
+                    eval.join().unwrap()
+                });
+                let err = result.unwrap_err();
+                assert!(err.downcast_ref::<Interrupted>().is_some(), "Got: {}", err);
+                assert!(session.was_interrupted());
+
+                let result = session.eval("count()").unwrap();
+                assert!(result.contains("3"), "Got: {}", result);
+                assert!(!session.was_interrupted());
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_restart_replays_definitions() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                session.eval("let x = 41;").unwrap();
+                session.eval("fn bump(v: i32) -> i32 { v + 1 }").unwrap();
+                session.eval("x + 100").unwrap();
+
+                let report = session.restart(true).unwrap();
+                assert_eq!(report.replayed.len(), 2, "Got: {:?}", report);
+                assert!(report.failed.is_empty(), "Got: {:?}", report.failed);
+                let result = session.eval("bump(x)").unwrap();
+                assert!(result.contains("42"), "Got: {}", result);
+                assert!(session.variables().contains_key("x"));
+
+                let report = session.restart(false).unwrap();
+                assert!(report.replayed.is_empty());
+                assert!(session.eval("x").is_err());
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_history_replay_and_export() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                session.eval("let x = 41;").unwrap();
+                assert!(session.eval("undefined_name").is_err());
+                session.eval("x + 1").unwrap();
+
+                let history = session.history();
+                assert_eq!(history.len(), 3);
+                assert!(history[0].success && !history[1].success && history[2].success);
+                assert!(history[2].summary.contains("42"), "Got: {:?}", history[2]);
+
+                let outputs = session.replay(2..3).unwrap();
+                assert_eq!(outputs[0].value.as_deref(), Some("42"));
+                assert_eq!(session.history().len(), 4);
+                assert!(session.replay(3..9).is_err());
+
+                let dir = tempfile::tempdir().unwrap();
+                let path = dir.path().join("history.rs");
+                session.export_history(&path, true).unwrap();
+                let source = std::fs::read_to_string(&path).unwrap();
+                assert!(syn::parse_file(&source).is_ok(), "Got: {}", source);
+                assert!(!source.contains("undefined_name"), "Got: {}", source);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_error_names_restored_variable() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                // `NoSuchType` passes the type filter but doesn't compile
+                let snapshot = r#"{
+                    "variables": {"count": 3, "config": {"port": 1}},
+                    "types": {"count": "i32", "config": "std::collections::NoSuchType"}
+                }"#;
+                let report = session.load_snapshot(snapshot, "{}").unwrap();
+                let skipped = report
+                    .skipped
+                    .iter()
+                    .find(|var| var.name == "config")
+                    .unwrap_or_else(|| panic!("Got: {:?}", report.skipped));
+                assert!(
+                    skipped.reason.contains("in restored variable `config`"),
+                    "Got: {}",
+                    skipped.reason
+                );
+
+                let err = session.eval("let a = 1;\nlet b: u16 = \"x\";").unwrap_err();
+                let errors = err.downcast_ref::<CompileErrors>().unwrap();
+                assert_eq!(errors.0[0].section, Some(CodeSection::Input));
+                assert_eq!(errors.0[0].line, Some(2));
+                assert!(
+                    err.to_string().contains("in your input, line 2"),
+                    "Got: {}",
+                    err
+                );
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_snapshot_redeclares_changed_variables() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = r#"{
+                    "variables": {"count": 1, "name": "a"},
+                    "types": {"count": "i32", "name": "String"}
+                }"#;
+                session.load_snapshot(snapshot, "{}").unwrap();
+                let updated = r#"{
+                    "variables": {"count": 2, "name": "a", "flag": true},
+                    "types": {"count": "i32", "name": "String", "flag": "bool"}
+                }"#;
+                let update = session.update_snapshot(updated, "{}").unwrap();
+                assert_eq!(update.changed, vec!["count"]);
+                assert_eq!(update.added, vec!["flag"]);
+                assert_eq!(update.unchanged, vec!["name"]);
+
+                let result = session.eval("(count(), name().clone(), flag())").unwrap();
+                assert!(result.contains("2"), "Got: {}", result);
+                assert!(result.contains("true"), "Got: {}", result);
+
+                let again = session.update_snapshot(updated, "{}").unwrap();
+                assert_eq!(again.unchanged.len(), 3);
+                assert!(again.report.loaded.is_empty());
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_snapshot_as_struct() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let snapshot = serde_json::json!({
+                    "variables": {"port": 8080, "hosts": ["a", "b"]},
+                    "types": {"port": "u16", "hosts": "Vec<String>"}
+                })
+                .to_string();
+                let policy = SnapshotPolicy {
+                    as_struct: true,
+                    ..Default::default()
+                };
+                let report = session
+                    .load_snapshot_with_policy(&snapshot, "{}", policy)
+                    .unwrap();
+                assert!(report.as_struct, "Got: {}", report);
+                assert!(report.to_string().contains("ctx: &__FerrumpyCtx"));
+
+                session
+                    .eval("fn describe(ctx: &__FerrumpyCtx) -> String { format!(\"{}:{}\", ctx.hosts.len(), ctx.port) }")
+                    .unwrap();
+                let result = session.eval("describe(ctx)").unwrap();
+                assert!(result.contains("2:8080"), "Got: {}", result);
+                let result = session.eval("*port + 1").unwrap();
+                assert!(result.contains("8081"), "Got: {}", result);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_snapshot_policy_and_hydrate() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                let full: Vec<i64> = (0..10_000).collect();
+                let snapshot = serde_json::json!({
+                    "variables": {"big": full, "small": [1, 2]},
+                    "types": {"big": "Vec<i64>", "small": "Vec<i64>"}
+                })
+                .to_string();
+                let policy = SnapshotPolicy {
+                    max_collection_len: 100,
+                    ..Default::default()
+                };
+                let report = session
+                    .load_snapshot_with_policy(&snapshot, "{}", policy)
+                    .unwrap();
+                let big = report.loaded.iter().find(|var| var.name == "big").unwrap();
+                assert!(big.truncated);
+                assert!(!report
+                    .loaded
+                    .iter()
+                    .any(|var| var.name == "small" && var.truncated));
+                assert!(session.variables()["big"].truncated);
+                let result = session.eval("big().len()").unwrap();
+                assert!(result.contains("100"), "Got: {}", result);
+
+                let full_json = serde_json::to_string(&full).unwrap();
+                session.hydrate_variable("big", &full_json).unwrap();
+                assert!(!session.variables()["big"].truncated);
+                let result = session.eval("big().len()").unwrap();
+                assert!(result.contains("10000"), "Got: {}", result);
+                assert!(session.hydrate_variable("missing", "1").is_err());
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff_snapshot_only_generates_changed() {
+        let snapshot = |bravo: i32| {
+            serde_json::json!({
+                "variables": {
+                    "alpha": 1,
+                    "bravo": bravo,
+                    "charlie": "c",
+                    "delta": [1, 2],
+                    "echo": true
+                },
+                "types": {
+                    "alpha": "i32",
+                    "bravo": "i32",
+                    "charlie": "String",
+                    "delta": "Vec<i32>",
+                    "echo": "bool"
+                }
+            })
+        };
+        let extract = |snapshot: &serde_json::Value| {
+            SnapshotCodegen
+                .extract_variables(snapshot, &mut Vec::new())
+                .unwrap()
+        };
+        let tracked: HashMap<String, TrackedVar> = extract(&snapshot(2))
+            .into_iter()
+            .map(|(name, value, ty)| {
+                let var = TrackedVar::new(&ty, VarOrigin::Snapshot)
+                    .with_value_hash(snapshot_value_hash(&value, &ty));
+                (name, var)
+            })
+            .collect();
+
+        let diff = diff_snapshot(extract(&snapshot(3)), &tracked);
+        assert_eq!(diff.changed, vec!["bravo"]);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.unchanged.len(), 4);
+
+        // A variable that went out of scope, and one defined by the user
+        let mut stepped = snapshot(2);
+        stepped["variables"]
+            .as_object_mut()
+            .unwrap()
+            .remove("charlie");
+        let mut with_user = tracked.clone();
+        with_user.insert(
+            "x".to_string(),
+            TrackedVar::new("i32", VarOrigin::UserDefined),
+        );
+        let stepped_diff = diff_snapshot(extract(&stepped), &with_user);
+        assert_eq!(stepped_diff.removed, vec!["charlie"]);
+        assert!(stepped_diff.pending.is_empty());
+
+        let code = SnapshotCodegen
+            .generate_update_modules(&diff.pending)
+            .unwrap()
+            .into_code();
+        assert!(
+            code.contains("use ferrumpy_var_bravo::bravo;"),
+            "Got: {}",
+            code
+        );
+        for name in ["alpha", "charlie", "delta", "echo"] {
+            assert!(!code.contains(name), "Got: {}", code);
+        }
+    }
+
+    #[test]
+    fn test_load_snapshot_survives_bad_variable() {
+        match ReplSession::new() {
+            Ok(mut session) => {
+                // `Ipv4Addr` has no `Default` to fall back to
+                let snapshot = r#"{
+                    "variables": {"count": 3, "bad": "not an address", "name": "ann"},
+                    "types": {"count": "i32", "bad": "std::net::Ipv4Addr", "name": "String"}
+                }"#;
+                let report = session.load_snapshot(snapshot, "{}").unwrap();
+                assert_eq!(report.skipped.len(), 1, "Got: {}", report);
+                assert_eq!(report.skipped[0].name, "bad");
+                assert!(
+                    report.skipped[0].reason.starts_with("failed to restore"),
+                    "Got: {}",
+                    report
+                );
+                assert_eq!(report.loaded.len(), 2);
+                let count = session.eval("count()").unwrap();
+                assert!(count.contains('3'), "Got: {}", count);
+                let name = session.eval("name().len()").unwrap();
+                assert!(name.contains('3'), "Got: {}", name);
+            }
+            Err(e) => {
+                eprintln!("Skipping test (evcxr unavailable): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_eval_code_wraps_expression() {
+        assert_eq!(
+            json_eval_code(" config; "),
+            "println!(\"__ferrumpy_json__:{}\", serde_json::to_value(&(config)).unwrap());"
+        );
+    }
+
+    #[test]
+    fn test_split_at_fence() {
+        let lines = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let fence = output_fence();
+        assert_ne!(fence, output_fence());
+
+        let (before, after) = split_at_fence(
+            lines(&["late", &fence, "EVCXR_END_CONTENT", &format!("{} ", fence)]),
+            &fence,
+        );
+        assert_eq!(before, lines(&["late"]));
+        assert_eq!(after, lines(&["EVCXR_END_CONTENT", &format!("{} ", fence)]));
+
+        let (before, after) = split_at_fence(lines(&["a", "b"]), &fence);
+        assert!(before.is_empty());
+        assert_eq!(after, lines(&["a", "b"]));
+    }
+
+    #[test]
+    fn test_parse_json_output() {
+        let output = "user print\n__ferrumpy_json__:{\"name\":\"a\",\"ids\":[1,2]}";
+        assert_eq!(
+            parse_json_output(output).unwrap(),
+            serde_json::json!({"name": "a", "ids": [1, 2]})
+        );
+        assert!(parse_json_output("no marker").is_err());
+    }
+    #[test]
+    fn test_normalize_prelude_stmt() {
+        assert_eq!(
+            normalize_prelude_stmt(" use std::collections::BTreeMap ").unwrap(),
+            "use std::collections::BTreeMap;"
+        );
+        assert_eq!(
+            normalize_prelude_stmt("pub use my_app::prelude::*;").unwrap(),
+            "pub use my_app::prelude::*;"
+        );
+        assert!(normalize_prelude_stmt("let x = 1;").is_err());
+        assert!(normalize_prelude_stmt("use ;").is_err());
+    }
+
+    #[test]
+    fn test_truncate_arrays_nested() {
+        let mut value = serde_json::json!({
+            "rows": [[1, 2, 3, 4], [5]],
+            "names": ["a", "b", "c"],
+        });
+        let mut truncated = Vec::new();
+        truncate_arrays(&mut value, 2, "data", &mut truncated);
+
+        assert_eq!(
+            value,
+            serde_json::json!({"rows": [[1, 2], [5]], "names": ["a", "b"]})
+        );
+        assert_eq!(
+            truncated,
+            vec![
+                ("data.names".to_string(), 3),
+                ("data.rows[0]".to_string(), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate_to_policy() {
+        let mut vars = vec![
+            (
+                "big".to_string(),
+                serde_json::json!((0..10_000).collect::<Vec<i64>>()),
+                "Vec<i64>".to_string(),
+            ),
+            (
+                "note".to_string(),
+                serde_json::json!("héllo world"),
+                "String".to_string(),
+            ),
+            (
+                "flag".to_string(),
+                serde_json::json!(true),
+                "bool".to_string(),
+            ),
+        ];
+        let policy = SnapshotPolicy {
+            max_collection_len: 100,
+            max_string_len: 2,
+            on_truncate: OnTruncate::Placeholder,
+            as_struct: false,
+        };
+        let (truncated, truncated_vars) = truncate_to_policy(&mut vars, &policy);
+
+        assert_eq!(vars[0].1.as_array().unwrap().len(), 100);
+        // `é` spans bytes 1..3, so only `h` fits in two bytes
+        assert_eq!(vars[1].1, serde_json::json!("h"));
+        assert_eq!(truncated_vars, vec!["big", "note"]);
+        assert_eq!(
+            truncated,
+            vec![
+                TruncatedArray {
+                    path: "big".to_string(),
+                    len: 10_000,
+                    kept: 100,
+                    unit: TruncatedUnit::Elements,
+                },
+                TruncatedArray {
+                    path: "note".to_string(),
+                    len: 12,
+                    kept: 1,
+                    unit: TruncatedUnit::Bytes,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate_to_policy_maps() {
+        let entries: serde_json::Map<String, serde_json::Value> = (0..10)
+            .map(|i| (format!("k{}", i), serde_json::json!(i)))
+            .collect();
+        let mut vars = vec![(
+            "counts".to_string(),
+            serde_json::Value::Object(entries),
+            "HashMap<String, i32>".to_string(),
+        )];
+        let policy = SnapshotPolicy {
+            max_collection_len: 3,
+            ..Default::default()
+        };
+        let (truncated, _) = truncate_to_policy(&mut vars, &policy);
+        assert_eq!(vars[0].1.as_object().unwrap().len(), 3);
+        assert_eq!(truncated[0].len, 10);
+    }
+
+    #[test]
+    fn test_truncate_arrays_keeps_fixed_arrays() {
+        let mut value = serde_json::json!({
+            "__ferrumpy_kind__": "array",
+            "__elements__": [[1, 2, 3], [4], [5]],
+        });
+        let mut truncated = Vec::new();
+        truncate_arrays(&mut value, 2, "grid", &mut truncated);
+
+        let elems = value["__elements__"].as_array().unwrap();
+        assert_eq!(elems.len(), 3);
+        assert_eq!(elems[0], serde_json::json!([1, 2]));
+        assert_eq!(truncated, vec![("grid[0]".to_string(), 3)]);
+    }
 }
@@ -0,0 +1,181 @@
+//! Variables defined in a REPL session
+//!
+//! evcxr doesn't report which names an evaluation defined, so the session
+//! records snapshot variables as it generates them and parses the `let`
+//! bindings of user code itself.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
+
+/// Where a tracked variable came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarOrigin {
+    /// Restored by `load_snapshot`, accessed as `name()`
+    Snapshot,
+    /// Bound by a `let` in evaluated code
+    UserDefined,
+}
+
+impl VarOrigin {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VarOrigin::Snapshot => "snapshot",
+            VarOrigin::UserDefined => "user",
+        }
+    }
+}
+
+/// A variable available in the session
+#[derive(Debug, Clone)]
+pub struct TrackedVar {
+    /// Type as written or inferred; `_` when unknown
+    pub declared_type: String,
+    pub origin: VarOrigin,
+    pub last_assigned: Instant,
+    /// Hash of the snapshot value a snapshot variable was restored from,
+    /// see [`snapshot_value_hash`]
+    pub value_hash: Option<u64>,
+    /// Restored from a value cut to the snapshot policy's limits, until
+    /// `hydrate_variable` replaces it
+    pub truncated: bool,
+}
+
+impl TrackedVar {
+    pub(super) fn new(declared_type: impl Into<String>, origin: VarOrigin) -> Self {
+        Self {
+            declared_type: declared_type.into(),
+            origin,
+            last_assigned: Instant::now(),
+            value_hash: None,
+            truncated: false,
+        }
+    }
+
+    pub(super) fn with_value_hash(mut self, value_hash: u64) -> Self {
+        self.value_hash = Some(value_hash);
+        self
+    }
+}
+
+/// Hash identifying a snapshot variable's value and restored type, to tell
+/// whether `update_snapshot` needs to redeclare it
+pub(super) fn snapshot_value_hash(value: &serde_json::Value, type_hint: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.to_string().hash(&mut hasher);
+    type_hint.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Names bound by the top-level `let` statements of `code`, with their type
+/// annotation if any
+///
+/// Code that doesn't parse as statements binds nothing.
+pub(super) fn let_bindings(code: &str) -> Vec<(String, Option<String>)> {
+    let Ok(block) = syn::parse_str::<syn::Block>(&format!("{{\n{}\n}}", code)) else {
+        return Vec::new();
+    };
+    let mut bindings = Vec::new();
+    for stmt in &block.stmts {
+        if let syn::Stmt::Local(local) = stmt {
+            collect_pat_bindings(&local.pat, None, &mut bindings);
+        }
+    }
+    bindings
+}
+
+/// Whether `code` binds variables or defines items (functions, types,
+/// `use` imports, ...) that later evaluations can refer to
+pub(super) fn defines_names(code: &str) -> bool {
+    let Ok(block) = syn::parse_str::<syn::Block>(&format!("{{\n{}\n}}", code)) else {
+        return false;
+    };
+    block
+        .stmts
+        .iter()
+        .any(|stmt| matches!(stmt, syn::Stmt::Local(_) | syn::Stmt::Item(_)))
+}
+
+fn collect_pat_bindings(
+    pat: &syn::Pat,
+    ty: Option<&syn::Type>,
+    bindings: &mut Vec<(String, Option<String>)>,
+) {
+    match pat {
+        syn::Pat::Ident(ident) => {
+            let ty = ty.map(|ty| quote::quote!(#ty).to_string());
+            bindings.push((ident.ident.to_string(), ty));
+        }
+        syn::Pat::Type(typed) => collect_pat_bindings(&typed.pat, Some(&typed.ty), bindings),
+        syn::Pat::Tuple(tuple) => {
+            for (i, elem) in tuple.elems.iter().enumerate() {
+                let elem_ty = match ty {
+                    Some(syn::Type::Tuple(types)) => types.elems.iter().nth(i),
+                    _ => None,
+                };
+                collect_pat_bindings(elem, elem_ty, bindings);
+            }
+        }
+        syn::Pat::Reference(reference) => collect_pat_bindings(&reference.pat, None, bindings),
+        syn::Pat::TupleStruct(tuple) => {
+            for elem in &tuple.elems {
+                collect_pat_bindings(elem, None, bindings);
+            }
+        }
+        syn::Pat::Struct(strukt) => {
+            for field in &strukt.fields {
+                collect_pat_bindings(&field.pat, None, bindings);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_let_bindings() {
+        let bindings = let_bindings(
+            "let x = 1;\nlet mut name: String = \"a\".into();\nlet (a, b): (i32, u8) = (1, 2);\nx + 1",
+        );
+        assert_eq!(
+            bindings,
+            vec![
+                ("x".to_string(), None),
+                ("name".to_string(), Some("String".to_string())),
+                ("a".to_string(), Some("i32".to_string())),
+                ("b".to_string(), Some("u8".to_string())),
+            ]
+        );
+
+        // Bindings inside blocks are not in scope afterwards
+        assert!(let_bindings("{ let inner = 1; }").is_empty());
+        assert!(let_bindings("fn f() { let y = 2; }").is_empty());
+        assert!(let_bindings("let = ;").is_empty());
+        assert!(let_bindings(":dep serde").is_empty());
+    }
+
+    #[test]
+    fn test_defines_names() {
+        assert!(defines_names("let x = 1;"));
+        assert!(defines_names("fn double(x: i32) -> i32 { x * 2 }"));
+        assert!(defines_names("use std::fmt::Write;"));
+        assert!(defines_names("struct Point { x: i32 }\nPoint { x: 1 }.x"));
+        assert!(!defines_names("x + 1"));
+        assert!(!defines_names("println!(\"{}\", x);"));
+        assert!(!defines_names("{ let inner = 1; }"));
+        assert!(!defines_names("let = ;"));
+    }
+
+    #[test]
+    fn test_let_bindings_destructuring() {
+        let names: Vec<String> =
+            let_bindings("let Point { x, y: ref py } = p; let Some(v) = o else { return };")
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+        assert_eq!(names, vec!["x", "py", "v"]);
+    }
+}
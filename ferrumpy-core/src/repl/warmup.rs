@@ -0,0 +1,287 @@
+//! Compiling a session's dependencies ahead of its first evaluation
+//!
+//! The first evaluation builds serde and the snapshot crate, a multi-second
+//! pause right when the user hits a breakpoint. A warmup runs that build on
+//! a thread of its own, e.g. while the debugger attaches; the session waits
+//! for it before using the context again, rather than racing it.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::config::{lock_shared_cache, CacheLease, CacheRelease, CommandSink};
+
+/// Progress of a warmup, see `ReplSession::warmup`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "lowercase")]
+pub enum WarmupStatus {
+    Pending,
+    Ready,
+    /// A command failed; later evaluations report the error again
+    Failed(String),
+}
+
+/// A warmup running in the background
+#[derive(Debug, Clone)]
+pub struct WarmupHandle {
+    state: Arc<(Mutex<WarmupStatus>, Condvar)>,
+}
+
+impl WarmupHandle {
+    fn pending() -> Self {
+        Self {
+            state: Arc::new((Mutex::new(WarmupStatus::Pending), Condvar::new())),
+        }
+    }
+
+    pub fn status(&self) -> WarmupStatus {
+        lock(&self.state.0).clone()
+    }
+
+    /// Block until the warmup has finished, returning how
+    pub fn wait(&self) -> WarmupStatus {
+        let (status, finished) = &*self.state;
+        let status = finished
+            .wait_while(lock(status), |status| *status == WarmupStatus::Pending)
+            .unwrap_or_else(|e| e.into_inner());
+        status.clone()
+    }
+
+    fn finish(&self, result: Result<()>) {
+        let (status, finished) = &*self.state;
+        *lock(status) = match result {
+            Ok(()) => WarmupStatus::Ready,
+            Err(e) => WarmupStatus::Failed(format!("{:#}", e)),
+        };
+        finished.notify_all();
+    }
+}
+
+/// A context that a warmup thread may be using
+pub(super) struct SharedContext<C> {
+    context: Arc<Mutex<C>>,
+    warmup: Option<WarmupHandle>,
+    /// Compiles with evcxr's dependency cache, shared with other sessions, see
+    /// [`cache_guard`](Self::cache_guard)
+    shares_cache: bool,
+}
+
+impl<C: CommandSink + Send + 'static> SharedContext<C> {
+    pub(super) fn new(context: C) -> Self {
+        Self {
+            context: Arc::new(Mutex::new(context)),
+            warmup: None,
+            shares_cache: false,
+        }
+    }
+
+    pub(super) fn set_shares_cache(&mut self, shares_cache: bool) {
+        self.shares_cache = shares_cache;
+    }
+
+    /// Lock to hold while compiling, if the context uses the shared cache
+    ///
+    /// Take it after [`lock`](Self::lock), as the warmup thread does, so
+    /// sessions can't wait on each other in a cycle.
+    pub(super) fn cache_guard(&self) -> Option<CacheLease> {
+        self.shares_cache.then(lock_shared_cache)
+    }
+
+    /// Run `commands` on a background thread, stopping at the first one that
+    /// fails
+    ///
+    /// While a warmup is pending, it is returned instead of starting another.
+    pub(super) fn warmup(&mut self, commands: Vec<String>) -> WarmupHandle {
+        if let Some(warmup) = &self.warmup {
+            if warmup.status() == WarmupStatus::Pending {
+                return warmup.clone();
+            }
+        }
+        let warmup = WarmupHandle::pending();
+        let handle = warmup.clone();
+        let context = self.context.clone();
+        let shares_cache = self.shares_cache;
+        std::thread::spawn(move || {
+            let mut context = lock(&context);
+            let _cache = shares_cache.then(lock_shared_cache);
+            let result = commands
+                .iter()
+                .try_for_each(|command| context.run_command(command));
+            drop(_cache);
+            drop(context);
+            handle.finish(result);
+        });
+        self.warmup = Some(warmup.clone());
+        warmup
+    }
+
+    /// Status of the last warmup; `None` if there was none
+    pub(super) fn warmup_status(&self) -> Option<WarmupStatus> {
+        self.warmup.as_ref().map(WarmupHandle::status)
+    }
+
+    /// Run `f` on the context from a thread of its own, giving up on it once
+    /// `timeout` has passed
+    ///
+    /// Returns `None` at the deadline. The thread keeps the context locked
+    /// until `f` returns, so it must then be abandoned rather than locked.
+    /// The shared cache is released at the deadline, for other sessions to
+    /// compile, and `f` doesn't run if the thread only got it afterwards.
+    pub(super) fn run_with_timeout<T: Send + 'static>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut C) -> T + Send + 'static,
+    ) -> Option<T> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let context = self.context.clone();
+        let warmup = self.warmup.clone();
+        let shares_cache = self.shares_cache;
+        let abandoned = Arc::new(Mutex::new(Abandoned::default()));
+        let run = abandoned.clone();
+        std::thread::spawn(move || {
+            if let Some(warmup) = warmup {
+                warmup.wait();
+            }
+            let mut context = lock(&context);
+            let cache = shares_cache.then(lock_shared_cache);
+            {
+                let mut run = lock(&run);
+                if run.abandoned {
+                    return;
+                }
+                run.cache = cache.as_ref().map(CacheLease::releaser);
+            }
+            let _ = tx.send(f(&mut context));
+        });
+        let result = rx.recv_timeout(timeout).ok();
+        if result.is_none() {
+            let mut abandoned = lock(&abandoned);
+            abandoned.abandoned = true;
+            if let Some(cache) = abandoned.cache.take() {
+                cache.release();
+            }
+        }
+        result
+    }
+
+    /// Lock the context, once any pending warmup has finished
+    pub(super) fn lock(&self) -> MutexGuard<'_, C> {
+        if let Some(warmup) = &self.warmup {
+            warmup.wait();
+        }
+        lock(&self.context)
+    }
+}
+
+/// Shared by `run_with_timeout` and its thread
+#[derive(Default)]
+struct Abandoned {
+    /// The caller gave up waiting
+    abandoned: bool,
+    /// Releases the thread's hold on the shared cache
+    cache: Option<CacheRelease>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Records commands, taking a while over each, and rejects `fail_on`
+    #[derive(Default)]
+    struct SlowSink {
+        commands: Vec<String>,
+        fail_on: Option<&'static str>,
+    }
+
+    impl CommandSink for SlowSink {
+        fn run_command(&mut self, command: &str) -> Result<()> {
+            std::thread::sleep(Duration::from_millis(50));
+            if self.fail_on == Some(command) {
+                anyhow::bail!("could not compile `serde`");
+            }
+            self.commands.push(command.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_context_waits_for_warmup() {
+        let mut context = SharedContext::new(SlowSink::default());
+        assert_eq!(context.warmup_status(), None);
+        let warmup = context.warmup(vec![":dep serde = \"1\"".to_string(), "()".to_string()]);
+        // A second warmup while the first is pending is the same one
+        context.warmup(vec!["ignored".to_string()]);
+
+        // The evaluation only gets the context once the warmup is done
+        let mut sink = context.lock();
+        assert_eq!(warmup.status(), WarmupStatus::Ready);
+        sink.run_command("1 + 1").unwrap();
+        assert_eq!(sink.commands, vec![":dep serde = \"1\"", "()", "1 + 1"]);
+        drop(sink);
+        assert_eq!(context.warmup_status(), Some(WarmupStatus::Ready));
+    }
+
+    #[test]
+    fn test_failed_warmup_releases_context() {
+        let mut context = SharedContext::new(SlowSink {
+            fail_on: Some("()"),
+            ..Default::default()
+        });
+        let warmup = context.warmup(vec!["()".to_string(), "never run".to_string()]);
+        assert_eq!(
+            warmup.wait(),
+            WarmupStatus::Failed("could not compile `serde`".to_string())
+        );
+        assert!(context.lock().commands.is_empty());
+        assert_eq!(
+            serde_json::to_value(warmup.status()).unwrap(),
+            serde_json::json!({"status": "failed", "error": "could not compile `serde`"})
+        );
+    }
+
+    #[test]
+    fn test_run_with_timeout_abandons_stalled_context() {
+        let context = SharedContext::new(SlowSink::default());
+        let result =
+            context.run_with_timeout(Duration::from_secs(5), |sink| sink.run_command("1 + 1"));
+        assert!(matches!(result, Some(Ok(()))));
+
+        // A compile that never finishes doesn't hold up the caller
+        let start = Instant::now();
+        let result = context.run_with_timeout(Duration::from_millis(100), |sink| {
+            std::thread::sleep(Duration::from_secs(3600));
+            sink.run_command("loop {}")
+        });
+        assert!(result.is_none());
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "Got: {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_run_with_timeout_releases_shared_cache() {
+        let mut context = SharedContext::new(SlowSink::default());
+        context.set_shares_cache(true);
+        let result = context.run_with_timeout(Duration::from_millis(100), |sink| {
+            std::thread::sleep(Duration::from_secs(3600));
+            sink.run_command("loop {}")
+        });
+        assert!(result.is_none());
+
+        // Another session can take the cache while the compile is stalled
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            let _cache = lock_shared_cache();
+            let _ = tx.send(());
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
@@ -1,13 +1,301 @@
 //! Request handler for ferrumpy-server
 
-use ferrumpy_core::expr::{parse_expr, Evaluator, Value};
-use ferrumpy_core::lsp::{CompletionItem, CompletionKind, RustAnalyzerClient};
+use ferrumpy_core::expr::{
+    parse_expr, value_from_snapshot_json, EvalError, Evaluator, Expr, Value,
+};
+use ferrumpy_core::lsp::{fuzzy_score, CompletionItem, CompletionKind, RustAnalyzerClient};
+use ferrumpy_core::protocol::{
+    error_codes, CompanionLib, FrameEvalOutcome, FrameInfo, LocalSummary,
+};
+use ferrumpy_core::repl::{closing_brackets, EvalOutput, OutputStream, ReplSession};
 use ferrumpy_core::{Request, Response};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing::{debug, info, warn};
 
+/// Virtual scope location used when no companion lib is available
+const FALLBACK_SCOPE_PATH: &str = "/tmp/__ferrumpy_scope.rs";
+
+/// Number of recent completion responses kept by the handler
+const COMPLETION_CACHE_CAPACITY: usize = 16;
+
+/// How long `Initialize` waits for rust-analyzer to finish indexing
+const INDEXING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default wait before asking rust-analyzer again when it returned no
+/// completions, see `Request::Initialize::max_completion_wait_ms`
+const DEFAULT_COMPLETION_RETRY_WAIT: Duration = Duration::from_millis(150);
+
+/// The subset of rust-analyzer the handler talks to.
+///
+/// Abstracted so tests can substitute a fake language server.
+pub trait CompletionSource {
+    fn is_initialized(&self) -> bool;
+
+    fn open_virtual_document(&mut self, uri: &str, content: &str) -> anyhow::Result<()>;
+
+    fn completions_with_resolve(
+        &mut self,
+        uri: &str,
+        line: u32,
+        character: u32,
+        resolve_top: usize,
+    ) -> anyhow::Result<Vec<CompletionItem>>;
+}
+
+impl CompletionSource for RustAnalyzerClient {
+    fn is_initialized(&self) -> bool {
+        RustAnalyzerClient::is_initialized(self)
+    }
+
+    fn open_virtual_document(&mut self, uri: &str, content: &str) -> anyhow::Result<()> {
+        RustAnalyzerClient::open_virtual_document(self, uri, content)
+    }
+
+    fn completions_with_resolve(
+        &mut self,
+        uri: &str,
+        line: u32,
+        character: u32,
+        resolve_top: usize,
+    ) -> anyhow::Result<Vec<CompletionItem>> {
+        RustAnalyzerClient::completions_with_resolve(self, uri, line, character, resolve_top)
+    }
+}
+
+/// How long a streaming evaluation waits, once the code has finished, for
+/// the output callback to deliver the lines it printed
+const STREAM_SETTLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// What a streaming evaluation receives while the code runs
+enum StreamEvent {
+    Line(OutputStream, String),
+    Finished(anyhow::Result<EvalOutput>),
+}
+
+/// The REPL running `EvalStream` code.
+///
+/// Abstracted so tests can substitute a fake REPL.
+pub trait StreamingRepl {
+    /// Make the frame's locals available to later code
+    fn load_frame(&mut self, frame: &FrameInfo) -> anyhow::Result<()>;
+
+    /// Run `code`, passing each line it prints to `output` as it arrives;
+    /// returns the display text of its value, if it ended with an expression
+    fn eval_streaming(
+        &mut self,
+        code: &str,
+        output: &mut dyn FnMut(OutputStream, String),
+    ) -> anyhow::Result<Option<String>>;
+}
+
+impl StreamingRepl for ReplSession {
+    fn load_frame(&mut self, frame: &FrameInfo) -> anyhow::Result<()> {
+        let snapshot = frame_snapshot(frame);
+        if snapshot["variables"]
+            .as_object()
+            .is_some_and(|v| v.is_empty())
+        {
+            return Ok(());
+        }
+        self.update_snapshot(&snapshot.to_string(), "").map(|_| ())
+    }
+
+    fn eval_streaming(
+        &mut self,
+        code: &str,
+        output: &mut dyn FnMut(OutputStream, String),
+    ) -> anyhow::Result<Option<String>> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let lines = sender.clone();
+        self.set_output_callback(move |stream, line| {
+            let _ = lines.send(StreamEvent::Line(stream, line.to_string()));
+        });
+
+        // Lines of each stream passed to `output`, so the ones the callback
+        // hasn't delivered yet can be taken from the result
+        let mut streamed: HashMap<OutputStream, usize> = HashMap::new();
+        let mut forward = |streamed: &mut HashMap<_, usize>, stream, line| {
+            *streamed.entry(stream).or_default() += 1;
+            output(stream, line);
+        };
+        let result = std::thread::scope(|scope| {
+            let session = &mut *self;
+            scope.spawn(move || {
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    session.eval_output(code)
+                }))
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Evaluation panicked")));
+                let _ = sender.send(StreamEvent::Finished(result));
+            });
+            loop {
+                match receiver.recv() {
+                    Ok(StreamEvent::Line(stream, line)) => forward(&mut streamed, stream, line),
+                    Ok(StreamEvent::Finished(result)) => break result,
+                    // The evaluating thread sends its result, even on panic
+                    Err(_) => unreachable!("evaluation thread gone without a result"),
+                }
+            }
+        });
+
+        // The callback runs behind the evaluation, so give it a moment to
+        // deliver the lines of the result it hasn't streamed yet
+        let printed = |stream| match &result {
+            Ok(result) if stream == OutputStream::Stdout => result.stdout.len(),
+            Ok(result) => result.stderr.len(),
+            Err(_) => 0,
+        };
+        let behind = |streamed: &HashMap<_, usize>| {
+            [OutputStream::Stdout, OutputStream::Stderr]
+                .into_iter()
+                .any(|stream| streamed.get(&stream).copied().unwrap_or(0) < printed(stream))
+        };
+        while behind(&streamed) {
+            match receiver.recv_timeout(STREAM_SETTLE_TIMEOUT) {
+                Ok(StreamEvent::Line(stream, line)) => forward(&mut streamed, stream, line),
+                _ => break,
+            }
+        }
+        self.clear_output_callback();
+        for event in receiver.try_iter() {
+            if let StreamEvent::Line(stream, line) = event {
+                forward(&mut streamed, stream, line);
+            }
+        }
+
+        let result = result?;
+        for (stream, lines) in [
+            (OutputStream::Stdout, result.stdout),
+            (OutputStream::Stderr, result.stderr),
+        ] {
+            let sent = streamed.get(&stream).copied().unwrap_or(0);
+            for line in lines.into_iter().skip(sent) {
+                output(stream, line);
+            }
+        }
+        Ok(result.value)
+    }
+}
+
+/// Snapshot JSON of the frame's locals that have a value
+fn frame_snapshot(frame: &FrameInfo) -> serde_json::Value {
+    let mut variables = serde_json::Map::new();
+    let mut types = serde_json::Map::new();
+    for local in &frame.locals {
+        let value = match &local.structured_value {
+            Some(json) => json.clone(),
+            None if local.value.is_empty() => continue,
+            None => serde_json::from_str(&local.value)
+                .unwrap_or_else(|_| serde_json::Value::String(local.value.clone())),
+        };
+        variables.insert(local.name.clone(), value);
+        types.insert(local.name.clone(), local.rust_type.clone().into());
+    }
+    serde_json::json!({"variables": variables, "types": types})
+}
+
+/// Bounded LRU of rust-analyzer completion responses.
+///
+/// The front-end sends identical `Complete` requests while the user types,
+/// so an exact repeat is answered without another round trip.
+struct CompletionCache {
+    /// Most recently used entry first
+    entries: VecDeque<(u64, Response)>,
+    capacity: usize,
+}
+
+impl CompletionCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Cache key for a request: the frame locals plus the input and cursor
+    fn key(
+        frame: &ferrumpy_core::protocol::FrameInfo,
+        input: &str,
+        cursor: usize,
+        resolve_top: usize,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for local in &frame.locals {
+            local.name.hash(&mut hasher);
+            local.rust_type.hash(&mut hasher);
+            local.value.hash(&mut hasher);
+        }
+        input.hash(&mut hasher);
+        cursor.hash(&mut hasher);
+        resolve_top.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn get(&mut self, key: u64) -> Option<Response> {
+        let pos = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(pos)?;
+        let response = entry.1.clone();
+        self.entries.push_front(entry);
+        Some(response)
+    }
+
+    fn insert(&mut self, key: u64, response: Response) {
+        self.entries.retain(|(k, _)| *k != key);
+        self.entries.push_front((key, response));
+        self.entries.truncate(self.capacity);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// The identifier being typed at the end of `input` (`na` in `user.na`)
+fn completion_query(input: &str) -> &str {
+    let start = input
+        .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .map_or(0, |i| i + 1);
+    &input[start..]
+}
+
+/// Score completions against the query, dropping those that don't match,
+/// best first
+fn rank_completions(completions: Vec<CompletionItem>, query: &str) -> Vec<CompletionItem> {
+    let mut ranked: Vec<CompletionItem> = completions
+        .into_iter()
+        .filter_map(|mut item| {
+            item.score = fuzzy_score(&item.label, query)?;
+            Some(item)
+        })
+        .collect();
+    // Stable, so equal scores keep rust-analyzer's order
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score));
+    ranked
+}
+
+/// Error response for a failed parse or evaluation, coded by its kind
+fn eval_error_response(e: &EvalError) -> Response {
+    let code = match e {
+        EvalError::ParseError { .. } => error_codes::EXPRESSION_PARSE_ERROR,
+        EvalError::UnknownVariable { .. } => error_codes::UNKNOWN_VARIABLE,
+        EvalError::UnsupportedExpression { .. } => error_codes::UNSUPPORTED_EXPRESSION,
+        _ => error_codes::EVAL_ERROR,
+    };
+    Response::error_with_code(code, e.to_string())
+}
+
 pub struct Handler {
-    ra_client: Option<RustAnalyzerClient>,
+    ra_client: Option<Box<dyn CompletionSource>>,
     project_root: Option<String>,
+    companion: Option<CompanionLib>,
+    completion_cache: CompletionCache,
+    /// Wait before the one retry of an empty completion list; zero disables it
+    completion_retry_wait: Duration,
+    /// REPL for `EvalStream`, started by the first one
+    repl: Option<Box<dyn StreamingRepl>>,
 }
 
 impl Handler {
@@ -15,20 +303,56 @@ impl Handler {
         Self {
             ra_client: None,
             project_root: None,
+            companion: None,
+            completion_cache: CompletionCache::new(COMPLETION_CACHE_CAPACITY),
+            completion_retry_wait: DEFAULT_COMPLETION_RETRY_WAIT,
+            repl: None,
+        }
+    }
+
+    /// Handle a request, passing the intermediate responses of a streaming
+    /// one to `emit` before returning its final response
+    pub fn handle_streaming(
+        &mut self,
+        request: &Request,
+        emit: &mut dyn FnMut(Response),
+    ) -> Response {
+        match request {
+            Request::EvalStream { frame, expr } => self.handle_eval_stream(frame, expr, emit),
+            _ => self.handle(request),
         }
     }
 
     pub fn handle(&mut self, request: &Request) -> Response {
         match request {
-            Request::Initialize { project_root } => self.handle_initialize(project_root),
+            Request::Ping => Response::success(),
+            Request::Initialize {
+                project_root,
+                companion,
+                max_completion_wait_ms,
+            } => {
+                self.completion_retry_wait = max_completion_wait_ms
+                    .map_or(DEFAULT_COMPLETION_RETRY_WAIT, Duration::from_millis);
+                self.handle_initialize(project_root, companion.as_ref())
+            }
             Request::Complete {
                 frame,
                 input,
                 cursor,
-            } => self.handle_complete(frame, input, *cursor),
+                resolve_top,
+                kinds,
+            } => self.handle_complete(frame, input, *cursor, *resolve_top, kinds.as_deref()),
             Request::TypeInfo { frame, expr } => self.handle_type_info(frame, expr),
             Request::Eval { frame, expr } => self.handle_eval(frame, expr),
+            Request::EvalStream { frame, expr } => {
+                // Without a way to stream, the output is dropped
+                self.handle_eval_stream(frame, expr, &mut |_| {})
+            }
+            Request::EvalAcrossFrames { frames, expr } => {
+                self.handle_eval_across_frames(frames, expr)
+            }
             Request::Hover { frame, path } => self.handle_hover(frame, path),
+            Request::ListLocals { frame } => self.handle_list_locals(frame),
             Request::Shutdown => {
                 info!("Shutdown requested");
                 Response::success()
@@ -36,19 +360,47 @@ impl Handler {
         }
     }
 
-    fn handle_initialize(&mut self, project_root: &str) -> Response {
+    fn handle_initialize(
+        &mut self,
+        project_root: &str,
+        companion: Option<&CompanionLib>,
+    ) -> Response {
         info!("Initializing for project: {}", project_root);
 
         self.project_root = Some(project_root.to_string());
+        self.companion = companion.cloned();
+        self.completion_cache.clear();
+
+        // Analyze the companion lib when available: it holds the user's types,
+        // and the scope file lives in it so rust-analyzer sees it as a target.
+        let ra_root = match companion {
+            Some(companion) => {
+                let scope_path = Self::scope_path(Some(companion));
+                if let Err(e) = Self::write_scope_placeholder(&scope_path) {
+                    warn!("Failed to create scope file {:?}: {}", scope_path, e);
+                }
+                companion.path.as_str()
+            }
+            None => project_root,
+        };
 
         // Create rust-analyzer client
-        let mut client = RustAnalyzerClient::new(project_root);
+        let mut client = RustAnalyzerClient::new(ra_root);
 
         // Try to start rust-analyzer
         match client.start() {
             Ok(()) => {
                 info!("rust-analyzer started successfully");
-                self.ra_client = Some(client);
+                // Completions are mostly empty until indexing is done
+                match client.wait_for_indexing(INDEXING_TIMEOUT) {
+                    Ok(true) => info!("rust-analyzer finished indexing"),
+                    Ok(false) => warn!(
+                        "rust-analyzer still indexing after {:?}; early completions may be incomplete",
+                        INDEXING_TIMEOUT
+                    ),
+                    Err(e) => warn!("Failed while waiting for rust-analyzer indexing: {}", e),
+                }
+                self.ra_client = Some(Box::new(client));
             }
             Err(e) => {
                 warn!(
@@ -56,7 +408,7 @@ impl Handler {
                     e
                 );
                 // Keep client anyway for basic functionality
-                self.ra_client = Some(RustAnalyzerClient::new(project_root));
+                self.ra_client = Some(Box::new(RustAnalyzerClient::new(ra_root)));
             }
         }
 
@@ -68,29 +420,89 @@ impl Handler {
         frame: &ferrumpy_core::protocol::FrameInfo,
         input: &str,
         cursor: usize,
+        resolve_top: usize,
+        kinds: Option<&[CompletionKind]>,
+    ) -> Response {
+        let response = self.collect_completions(frame, input, cursor, resolve_top);
+
+        // Filter after the cache so requests differing only in kinds share entries
+        match response {
+            Response::Completions { completions } => {
+                let completions = completions
+                    .into_iter()
+                    .filter(|item| kinds.is_none_or(|kinds| kinds.contains(&item.kind)))
+                    .collect();
+                Response::completions(rank_completions(completions, completion_query(input)))
+            }
+            response => response,
+        }
+    }
+
+    /// Completions from rust-analyzer, falling back to the frame's locals
+    fn collect_completions(
+        &mut self,
+        frame: &ferrumpy_core::protocol::FrameInfo,
+        input: &str,
+        cursor: usize,
+        resolve_top: usize,
     ) -> Response {
         debug!("Complete request: input={}, cursor={}", input, cursor);
 
         let mut completions = Vec::new();
 
+        // Inside a call's arguments, anything usable as an argument completes
+        let call_closers = closing_brackets(input).filter(|closers| closers.starts_with(')'));
+
         // Try rust-analyzer first if available and input contains '.'
-        if input.ends_with('.') {
+        if input.ends_with('.') || call_closers.is_some() {
+            let cache_key = CompletionCache::key(frame, input, cursor, resolve_top);
+            if let Some(response) = self.completion_cache.get(cache_key) {
+                debug!("Completion cache hit");
+                return response;
+            }
+
             // Take the client out to avoid borrow issues
             if let Some(mut ra) = self.ra_client.take() {
                 if ra.is_initialized() {
                     // Generate virtual scope for RA
-                    let virtual_content = Self::generate_virtual_scope_static(frame);
-                    let uri = "file:///tmp/__ferrumpy_scope.rs";
+                    let crate_name = self.companion.as_ref().map(|c| c.crate_name.as_str());
+                    let call = call_closers.as_deref().map(|closers| (input, closers));
+                    let virtual_content =
+                        Self::generate_virtual_scope_static(frame, crate_name, call);
+                    let uri = Self::scope_uri(self.companion.as_ref());
 
-                    if ra.open_virtual_document(uri, &virtual_content).is_ok() {
+                    if ra.open_virtual_document(&uri, &virtual_content).is_ok() {
                         let lines: Vec<&str> = virtual_content.lines().collect();
-                        let line = lines.len().saturating_sub(1) as u32;
-                        let character = lines.last().map(|l| l.len()).unwrap_or(0) as u32;
+                        // In a call the cursor ends the line before the closers
+                        let cursor_line = if call.is_some() {
+                            lines.len().saturating_sub(3)
+                        } else {
+                            lines.len().saturating_sub(1)
+                        };
+                        let line = cursor_line as u32;
+                        let character = lines.get(cursor_line).map(|l| l.len()).unwrap_or(0) as u32;
 
-                        if let Ok(items) = ra.completions(uri, line, character) {
+                        let mut result =
+                            ra.completions_with_resolve(&uri, line, character, resolve_top);
+                        // Right after startup rust-analyzer often answers with
+                        // nothing while it is still indexing; ask once more
+                        if matches!(&result, Ok(items) if items.is_empty())
+                            && !self.completion_retry_wait.is_zero()
+                        {
+                            debug!(
+                                "No completions from rust-analyzer, retrying in {:?}",
+                                self.completion_retry_wait
+                            );
+                            std::thread::sleep(self.completion_retry_wait);
+                            result =
+                                ra.completions_with_resolve(&uri, line, character, resolve_top);
+                        }
+                        if let Ok(items) = result {
                             if !items.is_empty() {
                                 self.ra_client = Some(ra);
-                                return Response::completions(items);
+                                let response = Response::completions(items);
+                                self.completion_cache.insert(cache_key, response.clone());
+                                return response;
                             }
                         }
                     }
@@ -98,7 +510,9 @@ impl Handler {
                 // Put it back
                 self.ra_client = Some(ra);
             }
+        }
 
+        if input.ends_with('.') {
             // Fallback: suggest based on type info from locals
             let var_name = input.trim_end_matches('.');
             for local in &frame.locals {
@@ -108,18 +522,20 @@ impl Handler {
                         kind: CompletionKind::Field,
                         detail: Some(format!("Type: {}", local.rust_type)),
                         documentation: Some("Use 'ferrumpy type' to see fields".to_string()),
+                        score: 0.0,
                     });
                 }
             }
         } else {
-            // Suggest local variables matching prefix
+            // Suggest local variables matching what was typed
             for local in &frame.locals {
-                if local.name.starts_with(input) {
+                if fuzzy_score(&local.name, completion_query(input)).is_some() {
                     completions.push(CompletionItem {
                         label: local.name.clone(),
                         kind: CompletionKind::Variable,
                         detail: Some(local.rust_type.clone()),
                         documentation: None,
+                        score: 0.0,
                     });
                 }
             }
@@ -136,11 +552,12 @@ impl Handler {
         _input: &str,
     ) -> Option<Vec<CompletionItem>> {
         // Generate virtual scope file
-        let virtual_content = Self::generate_virtual_scope_static(frame);
-        let uri = "file:///tmp/__ferrumpy_scope.rs";
+        let crate_name = self.companion.as_ref().map(|c| c.crate_name.as_str());
+        let virtual_content = Self::generate_virtual_scope_static(frame, crate_name, None);
+        let uri = Self::scope_uri(self.companion.as_ref());
 
         // Open virtual document
-        if let Err(e) = ra.open_virtual_document(uri, &virtual_content) {
+        if let Err(e) = ra.open_virtual_document(&uri, &virtual_content) {
             debug!("Failed to open virtual document: {}", e);
             return None;
         }
@@ -151,7 +568,7 @@ impl Handler {
         let character = lines.last().map(|l| l.len()).unwrap_or(0) as u32;
 
         // Request completions
-        match ra.completions(uri, line, character) {
+        match ra.completions(&uri, line, character) {
             Ok(items) => {
                 debug!("Got {} completions from rust-analyzer", items.len());
                 Some(items)
@@ -163,9 +580,53 @@ impl Handler {
         }
     }
 
-    fn generate_virtual_scope_static(frame: &ferrumpy_core::protocol::FrameInfo) -> String {
+    /// Location of the virtual scope file.
+    ///
+    /// Inside a companion lib it is placed under `src/bin/`, so cargo discovers it
+    /// as a binary target that depends on the lib and can `use` its items.
+    fn scope_path(companion: Option<&CompanionLib>) -> PathBuf {
+        match companion {
+            Some(companion) => Path::new(&companion.path).join("src/bin/__ferrumpy_scope.rs"),
+            None => PathBuf::from(FALLBACK_SCOPE_PATH),
+        }
+    }
+
+    fn scope_uri(companion: Option<&CompanionLib>) -> String {
+        format!("file://{}", Self::scope_path(companion).display())
+    }
+
+    /// Write an on-disk placeholder so the scope target exists when the workspace loads
+    fn write_scope_placeholder(path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, "fn main() {}\n")
+    }
+
+    /// Scope file declaring the frame's locals, for rust-analyzer completions
+    ///
+    /// With `call` as `(input, closers)`, the scope ends in the input, an open
+    /// call, on a line of its own followed by its closing brackets.
+    fn generate_virtual_scope_static(
+        frame: &ferrumpy_core::protocol::FrameInfo,
+        companion_crate: Option<&str>,
+        call: Option<(&str, &str)>,
+    ) -> String {
         let mut code = String::new();
 
+        // Bring the user's types into scope (enum variants, associated functions)
+        if let Some(crate_name) = companion_crate {
+            code.push_str(&format!(
+                "#[allow(unused_imports)]\nuse {}::*;\n\n",
+                crate_name
+            ));
+        }
+
+        // Scope files inside the companion lib are binary targets
+        if companion_crate.is_some() {
+            code.push_str("fn main() {}\n\n");
+        }
+
         // Add a function scope with local variable declarations
         code.push_str("fn __ferrumpy_scope() {\n");
 
@@ -178,8 +639,13 @@ impl Handler {
             ));
         }
 
-        // Add cursor position marker
-        code.push_str("    // Cursor here\n");
+        match call {
+            Some((input, closers)) => {
+                code.push_str(&format!("    let _ = {}\n    {};\n", input, closers));
+            }
+            // Add cursor position marker
+            None => code.push_str("    // Cursor here\n"),
+        }
         code.push_str("}\n");
 
         code
@@ -197,7 +663,10 @@ impl Handler {
             }
         }
 
-        Response::error(format!("Unknown expression: {}", expr))
+        Response::error_with_code(
+            error_codes::UNKNOWN_VARIABLE,
+            format!("Unknown expression: {}", expr),
+        )
     }
 
     fn handle_eval(&self, frame: &ferrumpy_core::protocol::FrameInfo, expr_str: &str) -> Response {
@@ -206,50 +675,133 @@ impl Handler {
         // Parse expression
         let ast = match parse_expr(expr_str) {
             Ok(ast) => ast,
-            Err(e) => return Response::error(e.to_string()),
+            Err(e) => return eval_error_response(&e),
         };
 
+        match self.eval_in_frame(frame, &ast) {
+            Ok(value) => Response::eval_result(value.to_string(), value.type_name()),
+            Err(e) => eval_error_response(&e),
+        }
+    }
+
+    /// Run code in the REPL, emitting a `Response::Output` per printed line
+    ///
+    /// The REPL doesn't report types, so the result's `value_type` is empty.
+    fn handle_eval_stream(
+        &mut self,
+        frame: &FrameInfo,
+        code: &str,
+        emit: &mut dyn FnMut(Response),
+    ) -> Response {
+        debug!("Eval stream request: expr={}", code);
+
+        let repl = match &mut self.repl {
+            Some(repl) => repl,
+            None => match ReplSession::new() {
+                Ok(session) => self.repl.insert(Box::new(session)),
+                Err(e) => return Response::error(format!("REPL unavailable: {:#}", e)),
+            },
+        };
+        if let Err(e) = repl.load_frame(frame) {
+            return Response::error_with_code(
+                error_codes::EVAL_ERROR,
+                format!("Failed to load the frame's locals: {:#}", e),
+            );
+        }
+
+        let mut output = |stream, chunk| emit(Response::Output { stream, chunk });
+        match repl.eval_streaming(code, &mut output) {
+            Ok(value) => Response::eval_result(value.unwrap_or_default(), ""),
+            Err(e) => Response::error_with_code(error_codes::EVAL_ERROR, format!("{:#}", e)),
+        }
+    }
+
+    fn handle_eval_across_frames(&self, frames: &[FrameInfo], expr_str: &str) -> Response {
+        debug!(
+            "Eval across frames request: expr={}, {} frames",
+            expr_str,
+            frames.len()
+        );
+
+        let ast = match parse_expr(expr_str) {
+            Ok(ast) => ast,
+            Err(e) => return eval_error_response(&e),
+        };
+
+        // A frame that fails (e.g. the variable isn't in scope there) only
+        // gets an error entry
+        let results = frames
+            .iter()
+            .enumerate()
+            .map(|(index, frame)| {
+                let outcome = match self.eval_in_frame(frame, &ast) {
+                    Ok(value) => FrameEvalOutcome::Value {
+                        value: value.to_string(),
+                        value_type: value.type_name().to_string(),
+                    },
+                    Err(e) => FrameEvalOutcome::Error {
+                        error: e.to_string(),
+                    },
+                };
+                (index, outcome)
+            })
+            .collect();
+        Response::FrameEvalResults { results }
+    }
+
+    /// Evaluate a parsed expression against a frame's locals
+    fn eval_in_frame(&self, frame: &FrameInfo, ast: &Expr) -> Result<Value, EvalError> {
         // Build evaluator with variables from frame
         let mut evaluator = Evaluator::new();
 
-        // Add local variables to evaluator
-        // Note: Currently we only support primitive types
+        // Add local variables to evaluator, preferring the structured value
+        // (which field paths can walk) over the primitive's display string
         for local in &frame.locals {
-            if let Some(value) = self.parse_variable_value(&local.rust_type, &local.value) {
+            let value = match &local.structured_value {
+                Some(json) => Some(value_from_snapshot_json(json, &local.rust_type)),
+                None => self.parse_variable_value(&local.rust_type, &local.value),
+            };
+            if let Some(value) = value {
                 evaluator.set_variable(&local.name, value);
             }
         }
 
-        // Evaluate
-        match evaluator.eval(&ast) {
-            Ok(value) => Response::eval_result(value.to_string(), value.type_name()),
-            Err(e) => Response::error(e.to_string()),
-        }
+        evaluator.eval(ast)
     }
 
     /// Parse a variable value string to Value
-    /// This is a simplified parser for common types
+    /// This is a simplified parser for common types: the string is parsed
+    /// loosely and then coerced to the declared type, so a value that doesn't
+    /// fit (e.g. a negative number for `usize`) is left out.
     fn parse_variable_value(&self, type_name: &str, value_str: &str) -> Option<Value> {
         let type_name = type_name.trim();
         let value_str = value_str.trim();
 
-        match type_name {
-            "i8" => value_str.parse().ok().map(Value::I8),
-            "i16" => value_str.parse().ok().map(Value::I16),
-            "i32" => value_str.parse().ok().map(Value::I32),
-            "i64" => value_str.parse().ok().map(Value::I64),
-            "i128" => value_str.parse().ok().map(Value::I128),
-            "isize" => value_str.parse().ok().map(Value::Isize),
-            "u8" => value_str.parse().ok().map(Value::U8),
-            "u16" => value_str.parse().ok().map(Value::U16),
-            "u32" => value_str.parse().ok().map(Value::U32),
-            "u64" => value_str.parse().ok().map(Value::U64),
-            "u128" => value_str.parse().ok().map(Value::U128),
-            "usize" => value_str.parse().ok().map(Value::Usize),
-            "f32" => value_str.parse().ok().map(Value::F32),
-            "f64" => value_str.parse().ok().map(Value::F64),
-            "bool" => value_str.parse().ok().map(Value::Bool),
-            _ => None, // Complex types not yet supported
+        let loose = if let Ok(v) = value_str.parse::<i128>() {
+            Value::I128(v)
+        } else if let Ok(v) = value_str.parse::<u128>() {
+            Value::U128(v)
+        } else if let Ok(v) = value_str.parse::<f64>() {
+            // f32 values are printed at f32 precision, which f64 can't round-trip
+            if type_name == "f32" {
+                Value::F32(value_str.parse().ok()?)
+            } else {
+                Value::F64(v)
+            }
+        } else if let Ok(v) = value_str.parse::<bool>() {
+            Value::Bool(v)
+        } else {
+            return None; // Complex types not yet supported
+        };
+
+        loose.coerce_to(type_name)
+    }
+
+    fn handle_list_locals(&self, frame: &ferrumpy_core::protocol::FrameInfo) -> Response {
+        debug!("List locals request: {} locals", frame.locals.len());
+
+        Response::Locals {
+            locals: frame.locals.iter().map(LocalSummary::from).collect(),
         }
     }
 
@@ -260,3 +812,620 @@ impl Handler {
         Response::Hover { content: None }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ferrumpy_core::dwarf::VariableInfo;
+    use ferrumpy_core::repl::{validate_source_fragment, FragmentValidity};
+
+    fn frame() -> FrameInfo {
+        FrameInfo {
+            function: "main".to_string(),
+            file: None,
+            line: None,
+            locals: vec![VariableInfo::new("status".to_string(), "Status".to_string()).unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_virtual_scope_imports_companion_crate() {
+        let code =
+            Handler::generate_virtual_scope_static(&frame(), Some("ferrumpy_snapshot"), None);
+        assert!(code.starts_with("#[allow(unused_imports)]\nuse ferrumpy_snapshot::*;"));
+        assert!(code.contains("let status: Status = todo!();"));
+        assert!(code.trim_end().ends_with("// Cursor here\n}"));
+
+        let code = Handler::generate_virtual_scope_static(&frame(), None, None);
+        assert!(!code.contains("use "));
+    }
+
+    #[test]
+    fn test_scope_path_inside_companion_src() {
+        let companion = CompanionLib {
+            path: "/tmp/ferrumpy_lib_1".to_string(),
+            crate_name: "ferrumpy_snapshot".to_string(),
+        };
+        assert_eq!(
+            Handler::scope_uri(Some(&companion)),
+            "file:///tmp/ferrumpy_lib_1/src/bin/__ferrumpy_scope.rs"
+        );
+        assert_eq!(Handler::scope_uri(None), "file:///tmp/__ferrumpy_scope.rs");
+    }
+
+    /// Fake language server that counts completion round trips
+    struct CountingSource {
+        calls: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl CompletionSource for CountingSource {
+        fn is_initialized(&self) -> bool {
+            true
+        }
+
+        fn open_virtual_document(&mut self, _uri: &str, _content: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn completions_with_resolve(
+            &mut self,
+            _uri: &str,
+            _line: u32,
+            _character: u32,
+            _resolve_top: usize,
+        ) -> anyhow::Result<Vec<CompletionItem>> {
+            self.calls.set(self.calls.get() + 1);
+            Ok(vec![
+                CompletionItem {
+                    label: "is_active".to_string(),
+                    kind: CompletionKind::Method,
+                    detail: None,
+                    documentation: None,
+                    score: 0.0,
+                },
+                CompletionItem {
+                    label: "code".to_string(),
+                    kind: CompletionKind::Field,
+                    detail: None,
+                    documentation: None,
+                    score: 0.0,
+                },
+            ])
+        }
+    }
+
+    /// Fake language server that has no completions until asked `ready_after` times
+    struct WarmingSource {
+        calls: std::rc::Rc<std::cell::Cell<usize>>,
+        ready_after: usize,
+    }
+
+    impl CompletionSource for WarmingSource {
+        fn is_initialized(&self) -> bool {
+            true
+        }
+
+        fn open_virtual_document(&mut self, _uri: &str, _content: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn completions_with_resolve(
+            &mut self,
+            _uri: &str,
+            _line: u32,
+            _character: u32,
+            _resolve_top: usize,
+        ) -> anyhow::Result<Vec<CompletionItem>> {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() < self.ready_after {
+                return Ok(Vec::new());
+            }
+            Ok(vec![CompletionItem {
+                label: "is_active".to_string(),
+                kind: CompletionKind::Method,
+                detail: None,
+                documentation: None,
+                score: 0.0,
+            }])
+        }
+    }
+
+    #[test]
+    fn test_empty_completions_are_retried_once() {
+        let labels = |response: Response| match response {
+            Response::Completions { completions } => completions
+                .into_iter()
+                .map(|item| item.label)
+                .collect::<Vec<_>>(),
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let handler_with = |ready_after: usize, wait: Duration| {
+            let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+            let mut handler = Handler::new();
+            handler.completion_retry_wait = wait;
+            handler.ra_client = Some(Box::new(WarmingSource {
+                calls: calls.clone(),
+                ready_after,
+            }));
+            (handler, calls)
+        };
+
+        let (mut handler, calls) = handler_with(2, Duration::from_millis(1));
+        assert_eq!(labels(complete(&mut handler, "status.")), vec!["is_active"]);
+        assert_eq!(calls.get(), 2);
+
+        // Only one retry, then the locals fallback
+        let (mut handler, calls) = handler_with(3, Duration::from_millis(1));
+        let fallback = labels(complete(&mut handler, "status."));
+        assert!(fallback[0].contains("no field info"), "Got: {:?}", fallback);
+        assert_eq!(calls.get(), 2);
+
+        // Disabled
+        let (mut handler, calls) = handler_with(2, Duration::ZERO);
+        complete(&mut handler, "status.");
+        assert_eq!(calls.get(), 1);
+    }
+
+    /// Prints each line of its code, then evaluates to the line count
+    #[derive(Default)]
+    struct EchoRepl {
+        loaded: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    }
+
+    impl StreamingRepl for EchoRepl {
+        fn load_frame(&mut self, frame: &FrameInfo) -> anyhow::Result<()> {
+            let snapshot = frame_snapshot(frame);
+            let names = snapshot["variables"].as_object().unwrap().keys().cloned();
+            self.loaded.borrow_mut().extend(names);
+            Ok(())
+        }
+
+        fn eval_streaming(
+            &mut self,
+            code: &str,
+            output: &mut dyn FnMut(OutputStream, String),
+        ) -> anyhow::Result<Option<String>> {
+            if code == "fail" {
+                output(OutputStream::Stderr, "about to fail".to_string());
+                anyhow::bail!("error[E0425]: cannot find value `fail`");
+            }
+            for line in code.lines() {
+                output(OutputStream::Stdout, line.to_string());
+            }
+            Ok(Some(code.lines().count().to_string()))
+        }
+    }
+
+    #[test]
+    fn test_eval_stream_emits_output_then_result() {
+        let mut handler = Handler::new();
+        let repl = EchoRepl::default();
+        let loaded = repl.loaded.clone();
+        handler.repl = Some(Box::new(repl));
+        let mut with_count = frame();
+        with_count.locals.push(
+            VariableInfo::with_value("count".to_string(), "i32".to_string(), "3".to_string())
+                .unwrap(),
+        );
+
+        let mut messages = Vec::new();
+        let request = Request::EvalStream {
+            frame: with_count,
+            expr: "tick 1\ntick 2".to_string(),
+        };
+        let last = handler.handle_streaming(&request, &mut |r| messages.push(r));
+        messages.push(last);
+
+        // Only locals with a value are loaded
+        assert_eq!(*loaded.borrow(), vec!["count"]);
+        let chunks: Vec<_> = messages[..2]
+            .iter()
+            .map(|m| match m {
+                Response::Output { stream, chunk } => (*stream, chunk.as_str()),
+                other => panic!("expected output, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (OutputStream::Stdout, "tick 1"),
+                (OutputStream::Stdout, "tick 2")
+            ]
+        );
+        match messages.last().unwrap() {
+            Response::EvalResult { value, .. } => assert_eq!(value, "2"),
+            other => panic!("expected a result, got {:?}", other),
+        }
+        assert_eq!(messages.len(), 3);
+
+        let mut messages = Vec::new();
+        let request = Request::EvalStream {
+            frame: FrameInfo {
+                locals: vec![],
+                ..frame()
+            },
+            expr: "fail".to_string(),
+        };
+        let last = handler.handle_streaming(&request, &mut |r| messages.push(r));
+        assert!(matches!(
+            messages.as_slice(),
+            [Response::Output {
+                stream: OutputStream::Stderr,
+                ..
+            }]
+        ));
+        match last {
+            Response::Error { error, error_code } => {
+                assert!(error.contains("E0425"), "Got: {}", error);
+                assert_eq!(error_code, Some(error_codes::EVAL_ERROR));
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    fn complete(handler: &mut Handler, input: &str) -> Response {
+        handler.handle(&Request::Complete {
+            frame: frame(),
+            input: input.to_string(),
+            cursor: input.len(),
+            resolve_top: 0,
+            kinds: None,
+        })
+    }
+
+    #[test]
+    fn test_repeated_complete_is_served_from_cache() {
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut handler = Handler::new();
+        handler.ra_client = Some(Box::new(CountingSource {
+            calls: calls.clone(),
+        }));
+
+        let first = complete(&mut handler, "status.");
+        let second = complete(&mut handler, "status.");
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            serde_json::to_string(&first).unwrap(),
+            serde_json::to_string(&second).unwrap()
+        );
+
+        // A different request goes back to rust-analyzer
+        complete(&mut handler, "status.is_");
+        complete(&mut handler, "(status).");
+        assert_eq!(calls.get(), 2);
+    }
+
+    /// Fake language server recording the document and position it is
+    /// asked to complete
+    struct RecordingSource {
+        requests: std::rc::Rc<std::cell::RefCell<Vec<(String, u32, u32)>>>,
+        content: String,
+    }
+
+    impl CompletionSource for RecordingSource {
+        fn is_initialized(&self) -> bool {
+            true
+        }
+
+        fn open_virtual_document(&mut self, _uri: &str, content: &str) -> anyhow::Result<()> {
+            self.content = content.to_string();
+            Ok(())
+        }
+
+        fn completions_with_resolve(
+            &mut self,
+            _uri: &str,
+            line: u32,
+            character: u32,
+            _resolve_top: usize,
+        ) -> anyhow::Result<Vec<CompletionItem>> {
+            self.requests
+                .borrow_mut()
+                .push((self.content.clone(), line, character));
+            Ok(vec![CompletionItem {
+                label: "status".to_string(),
+                kind: CompletionKind::Variable,
+                detail: None,
+                documentation: None,
+                score: 0.0,
+            }])
+        }
+    }
+
+    #[test]
+    fn test_complete_inside_call_arguments() {
+        let requests = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut handler = Handler::new();
+        handler.ra_client = Some(Box::new(RecordingSource {
+            requests: requests.clone(),
+            content: String::new(),
+        }));
+
+        let response = complete(&mut handler, "add(");
+        assert!(
+            matches!(&response, Response::Completions { completions } if completions[0].label == "status"),
+            "Got: {:?}",
+            response
+        );
+
+        let requests = requests.borrow();
+        assert_eq!(requests.len(), 1);
+        let (content, line, character) = &requests[0];
+        assert_eq!(validate_source_fragment(content), FragmentValidity::Valid);
+        let cursor_line = content.lines().nth(*line as usize).unwrap();
+        assert_eq!(cursor_line, "    let _ = add(");
+        assert_eq!(*character as usize, cursor_line.len());
+        assert!(content.contains("let status: Status = todo!();"));
+    }
+
+    #[test]
+    fn test_complete_filters_by_kind() {
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut handler = Handler::new();
+        handler.ra_client = Some(Box::new(CountingSource {
+            calls: calls.clone(),
+        }));
+
+        let labels = |response: Response| match response {
+            Response::Completions { completions } => completions
+                .into_iter()
+                .map(|item| item.label)
+                .collect::<Vec<_>>(),
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let request = |input: &str, kinds: Option<Vec<CompletionKind>>| Request::Complete {
+            frame: frame(),
+            input: input.to_string(),
+            cursor: input.len(),
+            resolve_top: 0,
+            kinds,
+        };
+
+        let methods = handler.handle(&request("status.", Some(vec![CompletionKind::Method])));
+        assert_eq!(labels(methods), vec!["is_active"]);
+        // The unfiltered list comes from the cache
+        let all = handler.handle(&request("status.", None));
+        assert_eq!(labels(all), vec!["is_active", "code"]);
+        assert_eq!(calls.get(), 1);
+
+        // Fallback completions are filtered too
+        let vars = handler.handle(&request("sta", Some(vec![CompletionKind::Method])));
+        assert!(labels(vars).is_empty());
+    }
+
+    #[test]
+    fn test_complete_ranks_fuzzy_matches() {
+        let local = |name: &str| VariableInfo::new(name.to_string(), "i32".to_string()).unwrap();
+        let frame = FrameInfo {
+            locals: ["discount", "user_count", "name", "count"]
+                .into_iter()
+                .map(local)
+                .collect(),
+            ..frame()
+        };
+        let completions = match Handler::new().handle(&Request::Complete {
+            frame,
+            input: "co".to_string(),
+            cursor: 2,
+            resolve_top: 0,
+            kinds: None,
+        }) {
+            Response::Completions { completions } => completions,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        let labels: Vec<&str> = completions.iter().map(|c| c.label.as_str()).collect();
+        assert_eq!(labels, vec!["count", "user_count", "discount"]);
+        assert!(completions[0].score > completions[1].score);
+    }
+
+    #[test]
+    fn test_completion_query() {
+        assert_eq!(completion_query("user.na"), "na");
+        assert_eq!(completion_query("status."), "");
+        assert_eq!(completion_query("cou"), "cou");
+        assert_eq!(completion_query("a + b_c"), "b_c");
+    }
+
+    #[test]
+    fn test_list_locals() {
+        let var = |name: &str, ty: &str, value: &str| {
+            VariableInfo::with_value(name.to_string(), ty.to_string(), value.to_string()).unwrap()
+        };
+        let frame = FrameInfo {
+            locals: vec![
+                var("count", "i32", "42"),
+                var("name", "alloc::string::String", "\"ferris\""),
+                var(
+                    "items",
+                    "alloc::vec::Vec<core::option::Option<u8>>",
+                    "vec![\n    Some(1),\n    None,\n]",
+                ),
+            ],
+            ..frame()
+        };
+
+        let locals = match Handler::new().handle(&Request::ListLocals { frame }) {
+            Response::Locals { locals } => locals,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let rows: Vec<_> = locals
+            .iter()
+            .map(|l| {
+                (
+                    l.name.as_str(),
+                    l.rust_type.as_str(),
+                    l.value_preview.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("count", "i32", "42"),
+                ("name", "String", "\"ferris\""),
+                ("items", "Vec<Option<u8>>", "vec![ Some(1), None, ]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_eval_field_path_of_structured_local() {
+        let config = VariableInfo::new("config".to_string(), "app::Config".to_string())
+            .unwrap()
+            .with_structured_value(serde_json::json!({
+                "database": {"host": "db", "port": 5432},
+                "replicas": [1, 2, 3]
+            }));
+        let frame = FrameInfo {
+            locals: vec![config],
+            ..frame()
+        };
+        let eval = |expr: &str| {
+            Handler::new().handle(&Request::Eval {
+                frame: frame.clone(),
+                expr: expr.to_string(),
+            })
+        };
+
+        match eval("config.database.port + 1") {
+            Response::EvalResult { value, .. } => assert_eq!(value, "5433"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        match eval("config.replicas[2]") {
+            Response::EvalResult { value, .. } => assert_eq!(value, "3"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        match eval("config.database.user") {
+            Response::Error {
+                error, error_code, ..
+            } => {
+                assert!(error.contains("'user'"), "Got: {}", error);
+                assert_eq!(error_code, Some(error_codes::EVAL_ERROR));
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_eval_error_codes() {
+        let eval = |expr: &str| {
+            Handler::new().handle(&Request::Eval {
+                frame: frame(),
+                expr: expr.to_string(),
+            })
+        };
+        let code = |response: Response| match response {
+            Response::Error { error_code, .. } => error_code,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        assert_eq!(code(eval("1 +")), Some(error_codes::EXPRESSION_PARSE_ERROR));
+        assert_eq!(
+            code(eval("missing + 1")),
+            Some(error_codes::UNKNOWN_VARIABLE)
+        );
+
+        let json = serde_json::to_value(Response::error_with_code(
+            error_codes::EXPRESSION_PARSE_ERROR,
+            "Parse error",
+        ))
+        .unwrap();
+        assert_eq!(json["error_code"], -32001);
+        // Uncoded errors serialize as before
+        assert!(serde_json::to_value(Response::error("x")).unwrap()["error_code"].is_null());
+    }
+
+    #[test]
+    fn test_eval_across_frames() {
+        let frame_with = |locals: &[(&str, &str)]| FrameInfo {
+            locals: locals
+                .iter()
+                .map(|(name, value)| {
+                    VariableInfo::with_value(name.to_string(), "i32".to_string(), value.to_string())
+                        .unwrap()
+                })
+                .collect(),
+            ..frame()
+        };
+        let frames = vec![
+            frame_with(&[("n", "1")]),
+            frame_with(&[("other", "7")]),
+            frame_with(&[("n", "5")]),
+        ];
+
+        let request = Request::EvalAcrossFrames {
+            frames,
+            expr: "n > 3".to_string(),
+        };
+        let results = match Handler::new().handle(&request) {
+            Response::FrameEvalResults { results } => results,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        let outcomes: Vec<_> = results
+            .iter()
+            .map(|(index, outcome)| match outcome {
+                FrameEvalOutcome::Value { value, .. } => (*index, value.clone()),
+                FrameEvalOutcome::Error { error } => (*index, format!("error: {}", error)),
+            })
+            .collect();
+        assert_eq!(outcomes[0], (0, "false".to_string()));
+        assert!(
+            outcomes[1].1.starts_with("error: ") && outcomes[1].1.contains('n'),
+            "Got: {:?}",
+            outcomes[1]
+        );
+        assert_eq!(outcomes[2], (2, "true".to_string()));
+    }
+
+    #[test]
+    fn test_ping() {
+        let request: Request = serde_json::from_str(r#"{"method":"ping","params":null}"#).unwrap();
+        assert!(matches!(request, Request::Ping));
+
+        // Answered before `initialize`, so no rust-analyzer or REPL is needed
+        let mut handler = Handler::new();
+        let response = handler.handle(&request);
+        assert!(handler.ra_client.is_none());
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({"ok": true})
+        );
+    }
+
+    #[test]
+    fn test_completion_cache_evicts_least_recently_used() {
+        let mut cache = CompletionCache::new(2);
+        cache.insert(1, Response::success());
+        cache.insert(2, Response::success());
+        assert!(cache.get(1).is_some());
+        cache.insert(3, Response::success());
+
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(3).is_some());
+
+        cache.clear();
+        assert!(cache.get(1).is_none());
+    }
+
+    #[test]
+    fn test_parse_variable_value_coerces_to_declared_type() {
+        let handler = Handler::new();
+        assert!(matches!(
+            handler.parse_variable_value("usize", "42"),
+            Some(Value::Usize(42))
+        ));
+        assert!(matches!(
+            handler.parse_variable_value("f32", "2"),
+            Some(Value::F32(v)) if v == 2.0
+        ));
+        assert!(matches!(
+            handler.parse_variable_value("f32", "0.1"),
+            Some(Value::F32(v)) if v == 0.1
+        ));
+        assert!(handler.parse_variable_value("usize", "-1").is_none());
+        assert!(handler.parse_variable_value("Vec<i32>", "[1, 2]").is_none());
+    }
+}
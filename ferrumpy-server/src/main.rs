@@ -4,6 +4,7 @@
 //! Communicates via stdin/stdout for easy subprocess management.
 
 use anyhow::Result;
+use ferrumpy_core::protocol::{error_codes, RpcMessage};
 use ferrumpy_core::{Request, Response};
 use std::io::{self, BufRead, Write};
 use tracing::{debug, error, info};
@@ -36,26 +37,44 @@ fn main() -> Result<()> {
 
         debug!("Received: {}", line);
 
-        // Parse JSON-RPC request
-        let response =
-            match serde_json::from_str::<ferrumpy_core::protocol::RpcMessage<Request>>(&line) {
-                Ok(msg) => {
-                    let result = handler.handle(&msg.content);
-                    ferrumpy_core::protocol::RpcMessage::new(msg.id.unwrap_or(0), result)
-                }
-                Err(e) => ferrumpy_core::protocol::RpcMessage::new(
+        // Parse JSON-RPC request; a streaming request sends its intermediate
+        // responses first, with the same id
+        let response = match serde_json::from_str::<RpcMessage<Request>>(&line) {
+            Ok(msg) => {
+                let id = msg.id.unwrap_or(0);
+                let result = handler.handle_streaming(&msg.content, &mut |output| {
+                    if let Err(e) = send(&mut stdout, &RpcMessage::new(id, output)) {
+                        error!("Failed to send output: {}", e);
+                    }
+                });
+                RpcMessage::new(id, result)
+            }
+            Err(e) => {
+                // Valid JSON that isn't a known request is a different failure
+                let code = if serde_json::from_str::<serde_json::Value>(&line).is_ok() {
+                    error_codes::INVALID_REQUEST
+                } else {
+                    error_codes::PARSE_ERROR
+                };
+                RpcMessage::new(
                     0,
-                    Response::error(format!("Parse error: {}", e)),
-                ),
-            };
-
-        // Send response
-        let response_json = serde_json::to_string(&response)?;
-        debug!("Sending: {}", response_json);
-        writeln!(stdout, "{}", response_json)?;
-        stdout.flush()?;
+                    Response::error_with_code(code, format!("Parse error: {}", e)),
+                )
+            }
+        };
+
+        send(&mut stdout, &response)?;
     }
 
     info!("ferrumpy-server shutting down");
     Ok(())
 }
+
+/// Write a message as one line of JSON
+fn send(stdout: &mut impl Write, message: &RpcMessage<Response>) -> Result<()> {
+    let json = serde_json::to_string(message)?;
+    debug!("Sending: {}", json);
+    writeln!(stdout, "{}", json)?;
+    stdout.flush()?;
+    Ok(())
+}
@@ -77,8 +77,13 @@ class ServerConnection:
         self._process = None
         self._initialized = False
 
-    def _send_request(self, method: str, params: Dict[str, Any]) -> Dict[str, Any]:
-        """Send a JSON-RPC request and get response."""
+    def _send_request(self, method: str, params: Optional[Dict[str, Any]],
+                      on_output=None) -> Dict[str, Any]:
+        """Send a JSON-RPC request and get response.
+
+        Output messages of a streaming request are passed to
+        on_output(stream, chunk) until the final response arrives.
+        """
         if self._process is None:
             raise RuntimeError("Server not started")
 
@@ -94,18 +99,44 @@ class ServerConnection:
         self._process.stdin.write(request_json + "\n")
         self._process.stdin.flush()
 
-        response_line = self._process.stdout.readline()
-        if not response_line:
-            raise RuntimeError("Server closed connection")
+        while True:
+            response_line = self._process.stdout.readline()
+            if not response_line:
+                raise RuntimeError("Server closed connection")
+
+            response = json.loads(response_line)
+            if "chunk" in response and "stream" in response:
+                if on_output is not None:
+                    on_output(response["stream"], response["chunk"])
+                continue
+            return response
+
+    def ping(self) -> bool:
+        """Check that the server is alive and responding."""
+        if self._process is None:
+            return False
+
+        try:
+            # Unit requests take null params
+            response = self._send_request("ping", None)
+        except Exception:
+            return False
+
+        return bool(response.get("ok") or response.get("result", {}).get("ok"))
 
-        response = json.loads(response_line)
-        return response
+    def initialize(self, project_root: str,
+                   max_completion_wait_ms: Optional[int] = None) -> bool:
+        """Initialize the server for a project.
 
-    def initialize(self, project_root: str) -> bool:
-        """Initialize the server for a project."""
+        max_completion_wait_ms: wait before retrying when rust-analyzer
+        returns no completions (server default when None, 0 disables).
+        """
         self.start()
 
-        response = self._send_request("initialize", {"project_root": project_root})
+        params = {"project_root": project_root}
+        if max_completion_wait_ms is not None:
+            params["max_completion_wait_ms"] = max_completion_wait_ms
+        response = self._send_request("initialize", params)
 
         # Handle both response formats: {ok: true} and {result: {ok: true}}
         if response.get("ok"):
@@ -117,16 +148,20 @@ class ServerConnection:
 
         return False
 
-    def complete(self, frame_info: Dict, input_text: str, cursor: int) -> List[Dict]:
-        """Request completions."""
+    def complete(self, frame_info: Dict, input_text: str, cursor: int,
+                 kinds: Optional[List[str]] = None) -> List[Dict]:
+        """Request completions, optionally only of the given kinds (e.g. ["method"])."""
         if not self._initialized:
             return []
 
-        response = self._send_request("complete", {
+        params = {
             "frame": frame_info,
             "input": input_text,
             "cursor": cursor,
-        })
+        }
+        if kinds is not None:
+            params["kinds"] = kinds
+        response = self._send_request("complete", params)
 
         if "result" in response and "completions" in response["result"]:
             return response["result"]["completions"]
@@ -162,7 +197,7 @@ class ServerConnection:
         if "value" in response:
             return {"value": response["value"], "value_type": response.get("value_type", "")}
         if "error" in response:
-            return {"error": response["error"]}
+            return {"error": response["error"], "error_code": response.get("error_code")}
 
         # Handle nested format: {result: {value: ...}}
         if "result" in response:
@@ -170,10 +205,65 @@ class ServerConnection:
             if "value" in result:
                 return result
             if "error" in result:
-                return {"error": result["error"]}
+                return {"error": result["error"], "error_code": result.get("error_code")}
 
         return None
 
+    def eval_stream(self, frame_info: Dict, code: str, on_output) -> Optional[Dict]:
+        """Run code in the server's REPL, with the frame's locals.
+
+        Each line the code prints is passed to on_output(stream, line) as
+        it arrives, stream being "stdout" or "stderr". Returns a dict with
+        "value", or "error" and "error_code".
+        """
+        if not self._initialized:
+            return None
+
+        response = self._send_request("eval_stream", {
+            "frame": frame_info,
+            "expr": code,
+        }, on_output=on_output)
+
+        if "value" in response:
+            return {"value": response["value"]}
+        if "error" in response:
+            return {"error": response["error"], "error_code": response.get("error_code")}
+        return None
+
+    def list_locals(self, frame_info: Dict) -> List[Dict]:
+        """List the frame's locals as {name, rust_type, value_preview} dicts."""
+        if not self._initialized:
+            return []
+
+        response = self._send_request("locals", {"frame": frame_info})
+
+        if "locals" in response:
+            return response["locals"]
+        if "result" in response and "locals" in response["result"]:
+            return response["result"]["locals"]
+
+        return []
+
+    def eval_across_frames(self, frames: List[Dict], expr: str) -> List[Dict]:
+        """Evaluate expr in each frame.
+
+        Returns one dict per frame, in order, with either value/value_type or
+        error (e.g. when a variable isn't in scope in that frame).
+        """
+        if not self._initialized:
+            return []
+
+        response = self._send_request("eval_frames", {"frames": frames, "expr": expr})
+
+        if "result" in response:
+            response = response["result"]
+        if "results" in response:
+            return [outcome for _, outcome in sorted(response["results"], key=lambda r: r[0])]
+        if "error" in response:
+            return [{"error": response["error"]} for _ in frames]
+
+        return []
+
 
 # Global connection instance
 _connection: Optional[ServerConnection] = None
@@ -222,19 +312,30 @@ def frame_to_info(frame) -> Dict[str, Any]:
             # Convert DWARF type name to Rust syntax (simplified)
             rust_type = _simplify_type_name(type_name)
 
-            # Get value for primitive types
+            # Get value for primitive types, and the serialized value of
+            # others so field paths can be evaluated
             value_str = ""
+            structured = None
             if rust_type in ("i8", "i16", "i32", "i64", "i128", "isize",
                            "u8", "u16", "u32", "u64", "u128", "usize",
                            "f32", "f64", "bool"):
                 value_str = var.GetValue() or ""
-
-            info["locals"].append({
+            else:
+                try:
+                    from .serializer import value_to_json
+                    structured = value_to_json(var)
+                except Exception:
+                    structured = None
+
+            local = {
                 "name": var.GetName(),
                 "type_name": type_name,
                 "rust_type": rust_type,
                 "value": value_str,
-            })
+            }
+            if structured is not None:
+                local["structured_value"] = structured
+            info["locals"].append(local)
 
     return info
 
@@ -32,7 +32,8 @@ def eval_expression_ffi(expr: str, variables: Dict[str, Dict]) -> Optional[Dict]
         variables: Dict of variable name -> {"type": type_name, "value": value_str}
 
     Returns:
-        Dict with "value" and "type" keys, or None if FFI not available
+        Dict with "value" and "value_type" keys (and "notes", if any), or
+        None if FFI not available
     """
     core = _get_core()
     if core is None:
@@ -40,7 +41,10 @@ def eval_expression_ffi(expr: str, variables: Dict[str, Dict]) -> Optional[Dict]
 
     try:
         result = core.eval_expression(expr, variables)
-        return {"value": result["value"], "value_type": result["type"]}
+        output = {"value": result["value"], "value_type": result["type"]}
+        if "notes" in result:
+            output["notes"] = result["notes"]
+        return output
     except ValueError as e:
         return {"error": str(e)}
     except RuntimeError as e:
@@ -61,7 +65,7 @@ def parse_expression_ffi(expr: str) -> Optional[str]:
         return None
 
     try:
-        return core.parse_expression(expr)
+        return core.parse_expression_ast(expr)
     except Exception:
         return None
 
@@ -382,20 +382,24 @@ class EmbeddedReplSession:
     without needing a separate evcxr installation.
     """
 
-    def __init__(self, frame=None, project_path: str = None):
+    def __init__(self, frame=None, project_path: str = None, opt_level: int = None):
         """
         Initialize an embedded REPL session.
 
         Args:
             frame: LLDB SBFrame to capture variables from
             project_path: Path to user's Rust project (auto-detected if None)
+            opt_level: rustc optimization level, 0 to 3 (0, the fastest to
+                compile, if None)
         """
         self.frame = frame
         self.project_path = project_path
+        self.opt_level = opt_level
         self._session = None
         self._initialized = False
         self._lib_path = None
         self._lib_name = None
+        self._lib_use_stmt = ""
         self._drainer = None  # Background thread for output draining
 
     def _get_rust_session(self):
@@ -403,7 +407,10 @@ class EmbeddedReplSession:
         if self._session is None:
             try:
                 from .ferrumpy_core import PyReplSession
-                self._session = PyReplSession()
+                config = {}
+                if self.opt_level is not None:
+                    config["opt_level"] = self.opt_level
+                self._session = PyReplSession(config=config)
             except ImportError as ie:
                 raise RuntimeError(
                     f"ferrumpy_core not available: {ie}. "
@@ -455,7 +462,12 @@ class EmbeddedReplSession:
 
         try:
             from .ferrumpy_core import generate_lib
-            lib_path, crate_name = generate_lib(project_path, None)
+            lib_path, crate_name, skipped_deps = generate_lib(project_path, None)
+            for dep in skipped_deps:
+                print(
+                    f"Warning: Dependency '{dep['name']}' left out of the companion lib "
+                    f"({dep['reason']}); its types can't be restored"
+                )
             return lib_path, crate_name
         except Exception as e:
             print(f"Warning: Failed to generate companion lib: {e}")
@@ -490,25 +502,14 @@ class EmbeddedReplSession:
 
         # Step 3: Load variable snapshot (single compilation with all deps)
         if self.frame:
-            data = serialize_frame(self.frame)
-            # Add lib metadata to snapshot for potential restoration after interrupt
-            if lib_use_stmt:
-                data['lib_use_stmt'] = lib_use_stmt
-            if self._lib_path:
-                data['lib_path'] = str(self._lib_path)
-            if self._lib_name:
-                data['lib_name'] = self._lib_name
-
-            json_data = json.dumps(data)
-            type_hints = ",".join(
-                f"{k}:{v}" for k, v in data.get('types', {}).items()
-            )
-            result = session.load_snapshot(json_data, type_hints)
+            self._lib_use_stmt = lib_use_stmt
+            json_data, type_hints = self._snapshot_documents(self.frame)
+            report = session.load_snapshot(json_data, type_hints)
             self._initialized = True
 
             # Note: OutputDrainer is disabled - evcxr handles draining internally
 
-            return result
+            return report["summary"]
         else:
             # If no frame, still need to compile lib dep
             if lib_use_stmt:
@@ -519,6 +520,36 @@ class EmbeddedReplSession:
 
             return "Initialized (no frame data)"
 
+    def _snapshot_documents(self, frame) -> tuple:
+        """Serialize a frame into the snapshot JSON and its type hints."""
+        data = serialize_frame(frame)
+        # Add lib metadata to snapshot for potential restoration after interrupt
+        if self._lib_use_stmt:
+            data['lib_use_stmt'] = self._lib_use_stmt
+        if self._lib_path:
+            data['lib_path'] = str(self._lib_path)
+        if self._lib_name:
+            data['lib_name'] = self._lib_name
+        return json.dumps(data), json.dumps(data.get('types', {}))
+
+    def update_frame(self, frame) -> str:
+        """
+        Refresh the snapshot from a new frame, e.g. after stepping.
+
+        Only variables whose value or type changed, or that are new, are
+        recompiled; the rest keep their bindings. Variables no longer in
+        the frame are reported as removed.
+
+        Returns:
+            One-line summary of the update
+        """
+        self.frame = frame
+        if not self._initialized:
+            return self.initialize()
+        json_data, type_hints = self._snapshot_documents(frame)
+        update = self._get_rust_session().update_snapshot(json_data, type_hints)
+        return update["summary"]
+
     def eval(self, code: str) -> str:
         """
         Evaluate Rust code in the REPL.
@@ -537,7 +568,7 @@ class EmbeddedReplSession:
 
         try:
             result = session.eval(code)
-            return result
+            return result["text"]
         except Exception as e:
             error_msg = str(e)
             # Check if the subprocess was terminated (e.g., by SIGINT)
@@ -589,12 +620,68 @@ class EmbeddedReplSession:
         session = self._get_rust_session()
         return session.add_dep(name, spec)
 
+    def warmup(self):
+        """
+        Start building the REPL's dependencies in the background.
+
+        Call it at attach time so the first snapshot load doesn't pay for
+        the build; `warmup_status()` reports its progress.
+        """
+        self._get_rust_session().warmup()
+
+    def warmup_status(self):
+        """Status of the warmup: a dict with "status" ("pending", "ready" or
+        "failed") and, when it failed, "error"; None if none was started."""
+        if self._session is None:
+            return None
+        return self._session.warmup_status()
+
+    def set_opt_level(self, level: int):
+        """
+        Set the rustc optimization level (0 to 3) of later evaluations.
+
+        0 keeps snapshot loads fast; 2 or 3 speed up hot loops.
+        """
+        self._get_rust_session().set_opt_level(level)
+        self.opt_level = level
+
     def is_initialized(self) -> bool:
         """Check if the session has been initialized with frame data."""
         if self._session:
             return self._session.is_initialized()
         return False
 
+    def export_snapshot(self, names: list) -> dict:
+        """Serialize the named variables back into a snapshot dict.
+
+        Returns {"variables", "types", "skipped"}; variables that aren't
+        defined or aren't Serialize are listed in "skipped".
+        """
+        session = self._get_rust_session()
+        return session.export_snapshot(names)
+
+    def list_variables(self) -> list:
+        """List session variables as {name, type, origin} dicts."""
+        if self._session:
+            return self._session.list_variables()
+        return []
+
+    def history(self) -> list:
+        """List evaluated code as {code, timestamp, success, summary} dicts."""
+        if self._session:
+            return self._session.history()
+        return []
+
+    def replay(self, start: int, end: int = None) -> list:
+        """Evaluate history entries start..end again, returning their output."""
+        session = self._get_rust_session()
+        return session.replay(start, end)
+
+    def export_history(self, path: str, only_successful: bool = False):
+        """Write the history to `path` as a Rust program with a `fn main`."""
+        session = self._get_rust_session()
+        session.export_history(path, only_successful)
+
     def get_stderr(self) -> list:
         """Get any stderr output from the REPL."""
         if self._session:
@@ -628,6 +715,17 @@ class EmbeddedReplSession:
             if code in (':q', ':quit', ':exit'):
                 break
 
+            if code == ':vars':
+                for var in self.list_variables():
+                    print(f"  {var['name']}: {var['type']} ({var['origin']})")
+                continue
+
+            if code == ':history':
+                for i, entry in enumerate(self.history()):
+                    mark = ' ' if entry['success'] else '!'
+                    print(f"  {i:>3}{mark} {entry['code']}")
+                continue
+
             try:
                 result = self.eval(code)
                 if result:
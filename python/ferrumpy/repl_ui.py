@@ -137,7 +137,7 @@ class RustCompleter(Completer):
 
     # REPL commands
     COMMANDS = [":q", ":quit", ":exit", ":vars", ":help", ":clear",
-                ":type", ":t", ":dep", ":restart"]
+                ":type", ":t", ":dep", ":restart", ":history"]
 
     def __init__(self, session=None, snapshot_vars: Optional[List[str]] = None):
         """
@@ -453,17 +453,43 @@ def run_enhanced_repl(
 
             if text == ':vars':
                 output_callback("Variables:")
-                for name, type_name in snapshot_vars.items():
-                    output_callback(f"  {name}: {type_name}")
+                try:
+                    tracked = session.list_variables()
+                except Exception:
+                    tracked = None
+                if tracked is not None:
+                    for var in tracked:
+                        output_callback(f"  {var['name']}: {var['type']} ({var['origin']})")
+                else:
+                    for name, type_name in snapshot_vars.items():
+                        output_callback(f"  {name}: {type_name}")
+                continue
+
+            if text == ':history' or text.startswith(':history '):
+                args = text.split(maxsplit=2)[1:]
+                try:
+                    if args and args[0] == 'save' and len(args) == 2:
+                        session.export_history(args[1])
+                        output_callback(f"History saved to {args[1]}")
+                    elif args:
+                        error_callback("Usage: :history [save <path>]")
+                    else:
+                        for i, entry in enumerate(session.history()):
+                            mark = ' ' if entry['success'] else '!'
+                            output_callback(f"  {i:>3}{mark} {entry['code']}")
+                except Exception as e:
+                    error_callback(str(e))
                 continue
 
             if text == ':help':
                 output_callback("Commands:")
                 output_callback("  :q, :quit, :exit  - Exit REPL")
-                output_callback("  :vars             - Show captured variables")
+                output_callback("  :vars             - Show snapshot and user variables")
                 output_callback("  :help             - Show this help")
                 output_callback("  :clear            - Clear screen")
                 output_callback("  :type <expr>      - Show type of expression")
+                output_callback("  :history          - Show evaluated code (! marks failures)")
+                output_callback("  :history save <f> - Write the history to a .rs file")
                 output_callback("\nRust Evaluation:")
                 output_callback("  Type any Rust expression to evaluate")
                 output_callback("  Multi-line: open braces auto-continue")
@@ -381,6 +381,19 @@ def value_to_json(value, visited: Optional[Set[int]] = None, depth: int = 0) ->
     if type_name == '&str':
         return _serialize_str_ref(value)
 
+    # Handle PathBuf/OsString - restored from their text
+    if type_name.split('::')[-1] in ('PathBuf', 'OsString'):
+        summary = value.GetSummary()
+        if summary:
+            return summary.strip('"')
+
+    # Handle maps and sets first, as their element types may contain `Vec<`
+    collection = type_name.split('<')[0].split('::')[-1]
+    if collection in ('HashMap', 'BTreeMap'):
+        return _serialize_hashmap(value, visited, depth)
+    if collection in ('HashSet', 'BTreeSet'):
+        return _serialize_set(value, visited, depth)
+
     # Handle Vec
     if 'Vec<' in type_name:
         return _serialize_vec(value, visited, depth)
@@ -393,10 +406,6 @@ def value_to_json(value, visited: Optional[Set[int]] = None, depth: int = 0) ->
     if 'Result<' in type_name:
         return _serialize_result(value, visited, depth)
 
-    # Handle HashMap - use GetSummary() which returns formatted key-value pairs
-    if 'HashMap<' in type_name:
-        return _serialize_hashmap(value, visited, depth)
-
     # Handle Box/Arc/Rc (smart pointers)
     if any(p in type_name for p in ['Box<', 'Arc<', 'Rc<']):
         return _serialize_smart_pointer(value, visited, depth)
@@ -466,7 +475,12 @@ def _serialize_primitive(value) -> Any:
     else:
         # Integer types - try to parse
         try:
-            return int(val_str, 0)  # 0 allows hex/octal
+            number = int(val_str, 0)  # 0 allows hex/octal
+            # JSON numbers beyond 64 bits lose precision, so the Rust
+            # side reads i128/u128 values from decimal strings
+            if not -(1 << 63) <= number < (1 << 64):
+                return str(number)
+            return number
         except ValueError:
             # Maybe it's a float in disguise
             try:
@@ -920,7 +934,7 @@ def _serialize_smart_pointer(value, visited: Set[int], depth: int = 0) -> Any:
 
 
 def _serialize_hashmap(value, visited: Set[int], depth: int = 0) -> Dict[str, Any]:
-    """Serialize a HashMap using LLDB's GetSummary() which formats key-value pairs.
+    """Serialize a HashMap or BTreeMap using LLDB's GetSummary() which formats key-value pairs.
 
     LLDB's summary for HashMap typically looks like: '{"key1": value1, "key2": value2}'
     We try to parse this, falling back to a structured representation if parsing fails.
@@ -985,6 +999,19 @@ def _serialize_hashmap(value, visited: Set[int], depth: int = 0) -> Dict[str, An
         "__error__": "Could not extract HashMap contents"
     }
 
+def _serialize_set(value, visited: Set[int], depth: int = 0) -> List[Any]:
+    """Serialize a HashSet or BTreeSet as an array of its elements.
+
+    The std formatters list a set's elements as its children.
+    """
+    elements = []
+    num_children = value.GetNumChildren()
+    for i in range(min(num_children, 100)):  # Same limit as Vec
+        child = value.GetChildAtIndex(i)
+        if child.IsValid():
+            elements.append(value_to_json(child, visited, depth+1))
+    return elements
+
 def _serialize_tuple(value, visited: Set[int], depth: int = 0) -> Dict[str, Any]:
     """Serialize a tuple with __ferrumpy_kind__ metadata."""
     elements = []